@@ -0,0 +1,7 @@
+use clap::Parser;
+use void_lib::cli::{CliArgs, run_cli};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    run_cli(CliArgs::parse()).await
+}
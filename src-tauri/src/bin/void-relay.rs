@@ -0,0 +1,7 @@
+use clap::Parser;
+use void_lib::network::relay_server::{RelayArgs, run_relay};
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    run_relay(RelayArgs::parse()).await
+}
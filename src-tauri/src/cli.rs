@@ -1,231 +1,1034 @@
 
-use clap::Parser;
+use clap::{Parser, Subcommand};
+use serde::Deserialize;
+use serde_json::json;
 use std::error::Error;
 use std::path::PathBuf;
-use crate::network::swarm::{build_swarm, VoidEvent, SignalingRequest, SignalingResponse};
+use std::time::Duration;
+use crate::network::swarm::{build_swarm, Signal, SignalingResponse, VoidBehaviour, VoidEvent};
 use libp2p::{
-    Multiaddr, futures::StreamExt, swarm::SwarmEvent,
-    request_response::Message,
+    Multiaddr, PeerId, Swarm, autonat, futures::StreamExt, identify, identity::Keypair, mdns,
+    swarm::SwarmEvent, request_response::Message,
 };
 use tokio::io::{self, AsyncBufReadExt};
-use crate::network::utils;
-use rusqlite::{params, Connection};
+use crate::network::messaging::{MessageContent, Messenger, MessengerEvent};
+use crate::network::outbox::{self, StatusChange};
+use crate::network::config::{Endpoint, EndpointKind, NetworkConfig};
+use crate::network::files::{DiskDone, FileEvent, FileTransfers, LocalFile};
+use crate::network::groups::{self, GroupEvent};
+use crate::network::mailbox::{MailboxClient, MailboxConfig, MailboxServer};
+use crate::network::relays::RelayManager;
+use crate::network::{self, signaling, utils};
+use crate::storage::contacts::KeyPin;
+use crate::storage::identity;
+use crate::storage::db::MessageStore;
+use crate::storage::outbox::MessageStatus;
+
+const DEFAULT_DB: &str = "void-cli.db";
+const DEFAULT_DOWNLOADS: &str = "downloads";
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(name = "void-cli", author, version, about = "Headless VOID node", long_about = None)]
 pub struct CliArgs {
-    #[arg(long)]
+    /// JSON config file providing defaults for the options below
+    #[arg(long, global = true)]
+    pub config: Option<String>,
+
+    /// TCP/QUIC port to listen on (0 picks a free one)
+    #[arg(long, global = true)]
     pub port: Option<u16>,
 
-    #[arg(long)]
+    #[arg(long, global = true)]
     pub db: Option<String>,
-    
+
+    /// VOID code to dial on startup (interactive mode)
     #[arg(long)]
     pub dial: Option<String>,
+
+    /// Path to the encrypted node identity (defaults to `<db>.key`)
+    #[arg(long, global = true)]
+    pub identity: Option<String>,
+
+    /// JSON network config with relay / bootstrap endpoints
+    #[arg(long, global = true)]
+    pub network_config: Option<String>,
+
+    /// Extra relay multiaddr (repeatable), tried before configured ones
+    #[arg(long = "relay", global = true)]
+    pub relays: Vec<String>,
+
+    /// Extra bootstrap multiaddr (repeatable)
+    #[arg(long = "bootstrap", global = true)]
+    pub bootstrap: Vec<String>,
+
+    /// Mailbox node multiaddr (repeatable) holding messages while we're offline
+    #[arg(long = "mailbox", global = true)]
+    pub mailboxes: Vec<String>,
+
+    /// Serve as a mailbox for other peers, storing their mail in this database
+    #[arg(long, global = true)]
+    pub serve_mailbox: Option<String>,
+
+    /// Directory accepted files are saved to
+    #[arg(long, global = true)]
+    pub downloads: Option<String>,
+
+    /// Print one JSON object per line instead of human-readable text
+    #[arg(long, global = true)]
+    pub json: bool,
+
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
 }
 
-pub async fn run_cli(port: u16, db_path: String, dial_addr: Option<String>) -> Result<(), Box<dyn Error>> {
-    // Setup logging
-    if std::env::var("RUST_LOG").is_err() {
-        std::env::set_var("RUST_LOG", "info");
-    }
-    env_logger::init();
-    
-    log::info!("Starting VOID CLI Mode");
-    log::info!("Port: {}", port);
-    log::info!("DB: {}", db_path);
-
-    // Setup DB
-    let conn = Connection::open(&db_path)?;
-    conn.execute(
-        "CREATE TABLE IF NOT EXISTS messages (
-            id INTEGER PRIMARY KEY,
-            peer_id TEXT NOT NULL,
-            content TEXT NOT NULL,
-            is_sent BOOLEAN NOT NULL,
-            timestamp INTEGER NOT NULL
-        )",
-        [],
-    )?;
-
-    // Build Swarm
-    let mut swarm = build_swarm().await.map_err(|e| format!("Failed to build swarm: {}", e))?;
-
-    // Listen on TCP
-    let listen_addr: Multiaddr = format!("/ip4/0.0.0.0/tcp/{}", port).parse()?;
-    swarm.listen_on(listen_addr.clone())?;
-    log::info!("Listening on {}", listen_addr);
-
-    // Hardcoded Public Relays (Bootnodes)
-    let bootnodes = [
-        "/dnsaddr/bootstrap.libp2p.io/p2p/QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN",
-        "/dnsaddr/bootstrap.libp2p.io/p2p/QmQCU2EcMqAqQPR2i9bChDtGNJchTeq5s0GNHw5zXIov6U",
-        "/dnsaddr/bootstrap.libp2p.io/p2p/QmbLHAnMoJPWSCR5Zhtx6BHJX9CkJv68846kJcCPaQFjNA",
-        "/dnsaddr/bootstrap.libp2p.io/p2p/QmcZf59bWwK5XFi76CZX8cbJ4BhTzzA3gU1Ubuu79rfVP3",
-    ];
-
-    for peer in bootnodes {
-        if let Ok(addr) = peer.parse::<Multiaddr>() {
-            log::info!("Dialing bootnode: {}", addr);
-            if let Err(e) = swarm.dial(addr) {
-                log::warn!("Failed to dial bootnode: {}", e);
-            }
-        }
-    }
-
-    // Listen on Relay (p2p-circuit)
-    // We need to wait until we are connected to a relay to listen effectively, 
-    // but we can try adding the listen addr now.
-    let relay_addr: Multiaddr = "/p2p-circuit".parse()?;
-    swarm.listen_on(relay_addr)?;
-    log::info!("Attempting to listen on relay...");
-
-    // Initial Dial if provided
-    if let Some(code) = dial_addr {
-        match utils::parse_void_code(&code) {
-             Ok(addr) => {
-                 log::info!("Dialing initial peer: {}", addr);
-                 if let Err(e) = swarm.dial(addr) {
-                     log::error!("Initial Dial Error: {}", e);
-                 }
-             }
-             Err(e) => log::error!("Invalid initial dial code: {}", e),
-        }
-    }
-
-    // Stdin reader
-    let mut stdin = io::BufReader::new(io::stdin()).lines();
-
-    println!("Commands:");
-    println!("  dial <void_code>  - Connect to a peer");
-    println!("  send <peer_id> <msg> - Send message");
-    println!("  info - Show my info");
-    println!("  exit - Quit");
-
-    loop {
-        tokio::select! {
-            line = stdin.next_line() => {
-                match line {
-                    Ok(Some(line)) => {
-                        let parts: Vec<&str> = line.split_whitespace().collect();
-                        if parts.is_empty() { continue; }
-                        match parts[0] {
-                            "dial" => {
-                                if parts.len() < 2 {
-                                    println!("Usage: dial <void_code>");
-                                } else {
-                                    match utils::parse_void_code(parts[1]) {
-                                        Ok(addr) => {
-                                            println!("Dialing {}", addr);
-                                            if let Err(e) = swarm.dial(addr) {
-                                                println!("Dial Error: {}", e);
-                                            }
+#[derive(Subcommand, Debug)]
+pub enum CliCommand {
+    /// Interactive session reading commands from stdin (the default)
+    Run,
+    /// Send one encrypted message and exit once it is delivered. Undelivered
+    /// messages stay queued and go out the next time the node runs.
+    Send {
+        peer: String,
+        message: String,
+        /// VOID code of the peer, used to reach it
+        #[arg(long)]
+        code: Option<String>,
+        /// Seconds to wait for delivery
+        #[arg(long, default_value_t = 30)]
+        timeout: u64,
+    },
+    /// Print this node's PeerId, addresses and VOID code
+    Info {
+        /// Seconds to wait for a relay reservation before printing
+        #[arg(long, default_value_t = 10)]
+        wait: u64,
+    },
+    /// Print stored messages with a peer or group, newest first
+    History {
+        peer: String,
+        #[arg(long, default_value_t = 50)]
+        limit: u32,
+    },
+}
+
+/// Contents of the `--config` file. Command line options take precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct CliConfig {
+    pub port: Option<u16>,
+    pub db: Option<String>,
+    pub identity: Option<String>,
+    pub network_config: Option<String>,
+    pub relays: Vec<String>,
+    pub bootstrap: Vec<String>,
+    pub mailboxes: Vec<String>,
+    pub serve_mailbox: Option<String>,
+    pub downloads: Option<String>,
+}
+
+impl CliArgs {
+    /// Fills options not given on the command line from `--config`.
+    pub fn apply_config(&mut self) -> Result<(), String> {
+        let Some(path) = &self.config else {
+            return Ok(());
+        };
+        let data = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        let config: CliConfig =
+            serde_json::from_str(&data).map_err(|e| format!("Invalid config {}: {}", path, e))?;
+
+        self.port = self.port.or(config.port);
+        self.db = self.db.take().or(config.db);
+        self.identity = self.identity.take().or(config.identity);
+        self.network_config = self.network_config.take().or(config.network_config);
+        self.relays.extend(config.relays);
+        self.bootstrap.extend(config.bootstrap);
+        self.mailboxes.extend(config.mailboxes);
+        self.serve_mailbox = self.serve_mailbox.take().or(config.serve_mailbox);
+        self.downloads = self.downloads.take().or(config.downloads);
+        Ok(())
+    }
+
+    /// Loads `--network-config` (or the defaults) and puts endpoints given on
+    /// the command line in front of it, keeping the rest as fallbacks.
+    pub fn network_config(&self) -> Result<NetworkConfig, String> {
+        let mut config = match &self.network_config {
+            Some(path) => NetworkConfig::load(std::path::Path::new(path))?,
+            None => NetworkConfig::default(),
+        };
+
+        let overrides = self
+            .relays
+            .iter()
+            .map(|addr| Endpoint::new(addr.clone(), EndpointKind::Relay))
+            .chain(
+                self.bootstrap
+                    .iter()
+                    .map(|addr| Endpoint::new(addr.clone(), EndpointKind::Bootstrap)),
+            )
+            .chain(
+                self.mailboxes
+                    .iter()
+                    .map(|addr| Endpoint::new(addr.clone(), EndpointKind::Mailbox)),
+            )
+            .collect::<Vec<_>>();
+        if !overrides.is_empty() {
+            for endpoint in &mut config.endpoints {
+                endpoint.priority = endpoint.priority.saturating_add(1);
+            }
+            for endpoint in overrides {
+                config.upsert(endpoint)?;
+            }
+        }
+        Ok(config)
+    }
+
+    fn db_path(&self) -> PathBuf {
+        PathBuf::from(self.db.as_deref().unwrap_or(DEFAULT_DB))
+    }
+
+    fn identity_path(&self) -> PathBuf {
+        self.identity
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| self.db_path().with_extension("key"))
+    }
+}
+
+/// Prints either plain text or a JSON line tagged with `event`.
+#[derive(Clone, Copy)]
+struct Output {
+    json: bool,
+}
+
+impl Output {
+    fn print(&self, event: &str, mut fields: serde_json::Value, text: String) {
+        if self.json {
+            if let Some(obj) = fields.as_object_mut() {
+                obj.insert("event".into(), event.into());
+            }
+            println!("{}", fields);
+        } else {
+            println!("{}", text);
+        }
+    }
+}
+
+/// The passphrase sealing the identity, sessions and history, from
+/// `VOID_IDENTITY_PASSPHRASE`.
+fn passphrase() -> Result<String, String> {
+    identity::require_passphrase(std::env::var("VOID_IDENTITY_PASSPHRASE").ok())
+        .map_err(|_| "Set VOID_IDENTITY_PASSPHRASE; it encrypts your identity and message history".to_string())
+}
+
+pub async fn run_cli(mut args: CliArgs) -> Result<(), Box<dyn Error>> {
+    // Setup logging (stderr, so --json output stays parseable)
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+    args.apply_config()?;
+    let out = Output { json: args.json };
+
+    match args.command.take().unwrap_or(CliCommand::Run) {
+        CliCommand::History { peer, limit } => {
+            let mut store = MessageStore::open(&args.db_path(), &passphrase()?)?;
+            let messages = store.history(&peer, None, limit)?;
+            if out.json {
+                out.print("history", json!({ "peer": peer, "messages": messages }), String::new());
+            } else {
+                for m in messages {
+                    if m.is_sent {
+                        println!("[{}] me: {} ({:?})", m.timestamp, m.content, m.status);
+                    } else {
+                        let from = m.sender.as_deref().unwrap_or(&m.peer_id);
+                        println!("[{}] {}: {}", m.timestamp, from, m.content);
+                    }
+                }
+            }
+            Ok(())
+        }
+        CliCommand::Run => {
+            let mut node = Node::start(&args, out).await?;
+            node.run_interactive(args.dial.take()).await
+        }
+        CliCommand::Send { peer, message, code, timeout } => {
+            let mut node = Node::start(&args, out).await?;
+            node.send_once(&peer, message, code, Duration::from_secs(timeout)).await
+        }
+        CliCommand::Info { wait } => {
+            let mut node = Node::start(&args, out).await?;
+            node.wait_for_relay(Duration::from_secs(wait)).await;
+            node.print_info();
+            Ok(())
+        }
+    }
+}
+
+struct Node {
+    swarm: Swarm<VoidBehaviour>,
+    messenger: Messenger,
+    store: MessageStore,
+    relays: RelayManager,
+    mailbox: MailboxClient,
+    mailbox_server: Option<MailboxServer>,
+    files: FileTransfers,
+    key: Keypair,
+    out: Output,
+}
+
+impl Node {
+    async fn start(args: &CliArgs, out: Output) -> Result<Self, Box<dyn Error>> {
+        let port = args.port.unwrap_or(0);
+        let db_path = args.db_path();
+        log::info!("Starting VOID CLI Mode");
+        log::info!("Port: {}", port);
+        log::info!("DB: {}", db_path.display());
+
+        // Load (or create) the persistent node identity
+        let identity_path = args.identity_path();
+        let passphrase = passphrase()?;
+        let key = identity::load_or_create(&identity_path, &passphrase)?;
+        log::info!("Identity: {}", identity_path.display());
+        let messenger = Messenger::load_or_create(
+            identity_path.with_extension("sessions"),
+            &passphrase,
+            key.clone(),
+        )?;
+
+        // Setup DB
+        let store = MessageStore::open(&db_path, &passphrase)?;
+
+        let mailbox_server = match &args.serve_mailbox {
+            Some(path) => {
+                log::info!("Serving mailbox: {}", path);
+                Some(MailboxServer::open(std::path::Path::new(path), MailboxConfig::default())?)
+            }
+            None => None,
+        };
+
+        // Build Swarm
+        let mut swarm = build_swarm(key.clone(), mailbox_server.is_some()).await.map_err(|e| format!("Failed to build swarm: {}", e))?;
+
+        // Listen on TCP and QUIC (same port number, UDP for QUIC)
+        let listen_addr: Multiaddr = format!("/ip4/0.0.0.0/tcp/{}", port).parse()?;
+        swarm.listen_on(listen_addr.clone())?;
+        log::info!("Listening on {}", listen_addr);
+        let quic_addr: Multiaddr = format!("/ip4/0.0.0.0/udp/{}/quic-v1", port).parse()?;
+        swarm.listen_on(quic_addr.clone())?;
+        log::info!("Listening on {}", quic_addr);
+
+        // Relays & bootstrap nodes
+        let network_config = args.network_config()?;
+        let mut relays = RelayManager::new(&network_config);
+        relays.dial_due(&mut swarm);
+        network::dial_contacts(&mut swarm, &store.contacts()?);
+        let mailbox = MailboxClient::new(network_config.mailbox_peers());
+        let files = FileTransfers::new(PathBuf::from(args.downloads.as_deref().unwrap_or(DEFAULT_DOWNLOADS)));
+
+        Ok(Self {
+            swarm,
+            messenger,
+            store,
+            relays,
+            mailbox,
+            mailbox_server,
+            files,
+            key,
+            out,
+        })
+    }
+
+    /// Parses a VOID code, remembers its addresses and dials it.
+    fn dial_code(&mut self, code: &str) -> Result<Option<PeerId>, String> {
+        let code = utils::parse_void_code(code)?;
+        if let Some(peer_id) = code.peer_id {
+            for addr in &code.addresses {
+                self.swarm.add_peer_address(peer_id, addr.clone());
+            }
+        }
+        log::info!("Dialing {:?} at {:?}", code.peer_id, code.addresses);
+        self.swarm.dial(code.dial_opts()).map_err(|e| e.to_string())?;
+        Ok(code.peer_id)
+    }
+
+    /// Queues a message in the outbox (and history) and tries to send it.
+    fn send_text(&mut self, peer_id: PeerId, text: String) -> Result<String, String> {
+        let (id, changes) = outbox::send(
+            &mut self.store,
+            &mut self.messenger,
+            &mut self.swarm,
+            peer_id,
+            MessageContent::Text { text },
+        )?;
+        self.print_status(&changes);
+        Ok(id)
+    }
+
+    fn print_status(&self, changes: &[StatusChange]) {
+        for change in changes {
+            let mut text = format!("Message {} to {}: {:?}", change.id, change.peer_id, change.status);
+            if let Some(error) = &change.error {
+                text.push_str(&format!(" ({})", error));
+            }
+            if let Some(retry_at) = change.retry_at {
+                text.push_str(&format!(", retrying in {}s", retry_at - chrono::Utc::now().timestamp()));
+            }
+            self.out.print("status", json!(change), text);
+        }
+    }
+
+    /// Periodic work: relay reconnects and outbox retries.
+    fn on_tick(&mut self) {
+        self.relays.dial_due(&mut self.swarm);
+        let changes = outbox::retry_due(&mut self.store, &mut self.messenger, &mut self.swarm);
+        self.mailbox.deposit(&mut self.store, &mut self.messenger, &mut self.swarm, &changes);
+        self.print_status(&changes);
+    }
+
+    fn void_code(&self) -> Result<String, String> {
+        let addrs = utils::code_addresses(self.swarm.listeners().chain(self.swarm.external_addresses()));
+        utils::generate_void_code(&self.key, &addrs, utils::DEFAULT_CODE_TTL_SECS)
+    }
+
+    fn has_reservation(&self) -> bool {
+        self.swarm.listeners().any(|a| a.to_string().contains("p2p-circuit"))
+    }
+
+    fn print_info(&self) {
+        let listeners: Vec<String> = self.swarm.listeners().map(|a| a.to_string()).collect();
+        let code = self.void_code().ok();
+        if self.out.json {
+            self.out.print(
+                "info",
+                json!({
+                    "peerId": self.swarm.local_peer_id().to_string(),
+                    "listeners": listeners,
+                    "code": code,
+                    "relayed": self.has_reservation(),
+                }),
+                String::new(),
+            );
+            return;
+        }
+
+        println!("My PeerId: {}", self.swarm.local_peer_id());
+        println!("Listeners:");
+        for addr in &listeners {
+            println!(" - {}", addr);
+        }
+        match code {
+            Some(code) => println!("VOID CODE: {}", code),
+            None => println!("No VOID code yet"),
+        }
+        if !self.has_reservation() {
+            println!("(Not listening on Relay yet. Wait for connection...)");
+        }
+    }
+
+    fn print_groups(&self) {
+        let groups = match self.store.groups() {
+            Ok(groups) => groups,
+            Err(e) => {
+                self.out.print("error", json!({ "error": e }), format!("Group Error: {}", e));
+                return;
+            }
+        };
+        if self.out.json {
+            self.out.print("groups", json!({ "groups": groups }), String::new());
+            return;
+        }
+        for group in groups {
+            println!(
+                " - {} \"{}\" members={} epoch={}{}",
+                group.id,
+                group.name,
+                group.members.len(),
+                group.epoch,
+                if group.active { "" } else { " (left)" },
+            );
+        }
+    }
+
+    /// Runs one of the interactive `g*` group commands.
+    fn group_command(&mut self, parts: &[&str]) -> Result<(), String> {
+        let parse_peer = |p: &str| p.parse::<PeerId>().map_err(|e| format!("Invalid PeerId: {}", e));
+        let (store, messenger, swarm) = (&mut self.store, &mut self.messenger, &mut self.swarm);
+        let changes = match parts {
+            ["gcreate", name, peers @ ..] => {
+                let peers = peers.iter().map(|p| parse_peer(p)).collect::<Result<Vec<_>, _>>()?;
+                let (group, changes) = groups::create(store, messenger, swarm, name, peers)?;
+                self.print_group_event(&GroupEvent::Updated { group });
+                changes
+            }
+            ["ginvite", group_id, peer] => {
+                let (group, changes) = groups::invite(store, messenger, swarm, group_id, parse_peer(peer)?)?;
+                self.print_group_event(&GroupEvent::Updated { group });
+                changes
+            }
+            ["gremove", group_id, peer] => {
+                let (group, changes) = groups::remove(store, messenger, swarm, group_id, parse_peer(peer)?)?;
+                self.print_group_event(&GroupEvent::Updated { group });
+                changes
+            }
+            ["gleave", group_id] => {
+                let changes = groups::leave(store, messenger, swarm, group_id)?;
+                self.print_group_event(&GroupEvent::Left {
+                    group_id: group_id.to_string(),
+                    removed: false,
+                });
+                changes
+            }
+            ["gsend", group_id, words @ ..] if !words.is_empty() => {
+                let (id, changes) = groups::send(store, messenger, swarm, group_id, words.join(" "))?;
+                self.out.print(
+                    "sending",
+                    json!({ "group": group_id, "id": id }),
+                    format!("Sending to group {} ({})", group_id, id),
+                );
+                changes
+            }
+            _ => {
+                let usage = match parts[0] {
+                    "gcreate" => "gcreate <name> [peer_id...]",
+                    "ginvite" => "ginvite <group_id> <peer_id>",
+                    "gremove" => "gremove <group_id> <peer_id>",
+                    "gleave" => "gleave <group_id>",
+                    _ => "gsend <group_id> <msg>",
+                };
+                return Err(format!("Usage: {}", usage));
+            }
+        };
+        self.mailbox.deposit(&mut self.store, &mut self.messenger, &mut self.swarm, &changes);
+        self.print_status(&changes);
+        Ok(())
+    }
+
+    fn print_group_event(&self, event: &GroupEvent) {
+        let text = match event {
+            GroupEvent::Updated { group } => format!(
+                "Group {} \"{}\" (epoch {}): {}",
+                group.id,
+                group.name,
+                group.epoch,
+                group.members.join(", ")
+            ),
+            GroupEvent::Message { group_id, sender, text, .. } => {
+                format!("\n[Group {}] {}: {}", group_id, sender, text)
+            }
+            GroupEvent::Left { group_id, removed: true } => format!("Removed from group {}", group_id),
+            GroupEvent::Left { group_id, .. } => format!("Left group {}", group_id),
+        };
+        self.out.print("group", json!(event), text);
+    }
+
+    fn print_transfers(&self) {
+        let transfers = match self.store.transfers() {
+            Ok(transfers) => transfers,
+            Err(e) => {
+                self.out.print("error", json!({ "error": e }), format!("Transfer Error: {}", e));
+                return;
+            }
+        };
+        if self.out.json {
+            self.out.print("transfers", json!({ "transfers": transfers }), String::new());
+            return;
+        }
+        for transfer in transfers {
+            println!(
+                " - {} {} {} \"{}\" {}/{} bytes {:?}",
+                transfer.id,
+                if transfer.outgoing { "to" } else { "from" },
+                transfer.peer_id,
+                transfer.name,
+                transfer.transferred,
+                transfer.size,
+                transfer.state,
+            );
+        }
+    }
+
+    /// Runs one of the interactive file transfer commands.
+    fn file_command(&mut self, parts: &[&str]) -> Result<(), String> {
+        let events = match parts {
+            ["sendfile", peer, path] => {
+                let peer = peer.parse::<PeerId>().map_err(|e| format!("Invalid PeerId: {}", e))?;
+                let file = LocalFile::prepare(std::path::Path::new(path))?;
+                let (transfer, changes) =
+                    self.files.offer(&mut self.store, &mut self.messenger, &mut self.swarm, peer, file)?;
+                self.mailbox.deposit(&mut self.store, &mut self.messenger, &mut self.swarm, &changes);
+                self.print_status(&changes);
+                vec![FileEvent::Progress { transfer }]
+            }
+            ["accept", id] => self.files.accept(&mut self.store, id, None)?,
+            ["accept", id, dir] => self.files.accept(&mut self.store, id, Some(PathBuf::from(dir)))?,
+            ["reject", id] => self.files.reject(&mut self.store, &mut self.swarm, id)?,
+            ["cancel", id] => self.files.cancel(&mut self.store, &mut self.swarm, id)?,
+            _ => {
+                let usage = match parts[0] {
+                    "sendfile" => "sendfile <peer_id> <path>",
+                    "accept" => "accept <transfer_id> [dir]",
+                    "reject" => "reject <transfer_id>",
+                    _ => "cancel <transfer_id>",
+                };
+                return Err(format!("Usage: {}", usage));
+            }
+        };
+        for event in &events {
+            self.print_file_event(event);
+        }
+        Ok(())
+    }
+
+    /// Applies finished file transfer I/O.
+    fn on_disk(&mut self, done: DiskDone) {
+        for event in self.files.on_disk(&mut self.store, &mut self.swarm, done) {
+            self.print_file_event(&event);
+        }
+    }
+
+    fn print_file_event(&self, event: &FileEvent) {
+        let text = match event {
+            FileEvent::Offer { transfer } => format!(
+                "\n[File offer from {}]: \"{}\" ({} bytes). Type `accept {}` or `reject {}`",
+                transfer.peer_id, transfer.name, transfer.size, transfer.id, transfer.id
+            ),
+            FileEvent::Progress { transfer } => format!(
+                "Transfer {} \"{}\": {}/{} bytes {:?}",
+                transfer.id, transfer.name, transfer.transferred, transfer.size, transfer.state
+            ),
+        };
+        self.out.print("file", json!(event), text);
+    }
+
+    fn print_relays(&self) {
+        let health = self.relays.health();
+        if self.out.json {
+            self.out.print("relays", json!({ "endpoints": health }), String::new());
+            return;
+        }
+        for health in health {
+            println!(
+                " - [{:?}] {} state={:?} failures={} reserved={}{}",
+                health.kind,
+                health.address,
+                health.state,
+                health.failures,
+                health.reserved,
+                health.retry_in_secs.map(|s| format!(" retry in {}s", s)).unwrap_or_default(),
+            );
+        }
+    }
+
+    async fn wait_for_relay(&mut self, wait: Duration) {
+        let deadline = tokio::time::sleep(wait);
+        tokio::pin!(deadline);
+        let mut reconnect = tokio::time::interval(Duration::from_secs(5));
+
+        while !self.has_reservation() {
+            tokio::select! {
+                _ = &mut deadline => break,
+                _ = reconnect.tick() => self.on_tick(),
+                Some(done) = self.files.next_disk() => self.on_disk(done),
+                event = self.swarm.select_next_some() => {
+                    self.handle_event(event);
+                }
+            }
+        }
+    }
+
+    async fn send_once(
+        &mut self,
+        peer: &str,
+        message: String,
+        code: Option<String>,
+        timeout: Duration,
+    ) -> Result<(), Box<dyn Error>> {
+        let peer_id: PeerId = peer.parse().map_err(|e| format!("Invalid PeerId: {}", e))?;
+        if let Some(code) = code {
+            if let Some(code_peer) = self.dial_code(&code)? {
+                if code_peer != peer_id {
+                    return Err(format!("VOID code belongs to {}, not {}", code_peer, peer_id).into());
+                }
+            }
+        }
+
+        let id = self.send_text(peer_id, message)?;
+        let deadline = tokio::time::sleep(timeout);
+        tokio::pin!(deadline);
+        let mut reconnect = tokio::time::interval(Duration::from_secs(5));
+
+        loop {
+            tokio::select! {
+                _ = &mut deadline => {
+                    self.out.print(
+                        "timeout",
+                        json!({ "peer": peer, "id": id }),
+                        format!(
+                            "No delivery receipt from {} within {}s; the message stays queued",
+                            peer,
+                            timeout.as_secs()
+                        ),
+                    );
+                    return Err("Timed out waiting for delivery".into());
+                }
+                _ = reconnect.tick() => self.on_tick(),
+                Some(done) = self.files.next_disk() => self.on_disk(done),
+                event = self.swarm.select_next_some() => {
+                    for change in self.handle_event(event) {
+                        if change.id != id {
+                            continue;
+                        }
+                        match change.status {
+                            MessageStatus::Delivered | MessageStatus::Read => return Ok(()),
+                            MessageStatus::Failed => {
+                                return Err(change.error.unwrap_or_else(|| "Delivery failed".into()).into())
+                            }
+                            MessageStatus::Queued | MessageStatus::Sent => {}
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    async fn run_interactive(&mut self, dial: Option<String>) -> Result<(), Box<dyn Error>> {
+        // Initial Dial if provided
+        if let Some(code) = dial {
+            if let Err(e) = self.dial_code(&code) {
+                log::error!("Initial dial failed: {}", e);
+            }
+        }
+
+        // Stdin reader
+        let mut stdin = io::BufReader::new(io::stdin()).lines();
+        let mut reconnect = tokio::time::interval(Duration::from_secs(5));
+
+        if !self.out.json {
+            println!("Commands:");
+            println!("  dial <void_code>  - Connect to a peer");
+            println!("  send <peer_id> <msg> - Send message");
+            println!("  retry <peer_id> <msg_id> - Retry a failed message");
+            println!("  groups - List groups");
+            println!("  gcreate <name> [peer_id...] - Create a group");
+            println!("  ginvite <group_id> <peer_id> - Add a member (owner only)");
+            println!("  gremove <group_id> <peer_id> - Remove a member (owner only)");
+            println!("  gleave <group_id> - Leave a group");
+            println!("  gsend <group_id> <msg> - Send to a group");
+            println!("  transfers - List file transfers");
+            println!("  sendfile <peer_id> <path> - Offer a file");
+            println!("  accept <transfer_id> [dir] - Accept an offered file");
+            println!("  reject <transfer_id> - Decline an offered file");
+            println!("  cancel <transfer_id> - Stop a transfer");
+            println!("  info - Show my info");
+            println!("  relays - Show relay / bootstrap health");
+            println!("  exit - Quit");
+        }
+
+        loop {
+            tokio::select! {
+                line = stdin.next_line() => {
+                    match line {
+                        Ok(Some(line)) => {
+                            let parts: Vec<&str> = line.split_whitespace().collect();
+                            if parts.is_empty() { continue; }
+                            match parts[0] {
+                                "dial" => {
+                                    if parts.len() < 2 {
+                                        println!("Usage: dial <void_code>");
+                                    } else if let Err(e) = self.dial_code(parts[1]) {
+                                        self.out.print("error", json!({ "error": e }), format!("Dial Error: {}", e));
+                                    }
+                                }
+                                "send" => {
+                                    if parts.len() < 3 {
+                                        println!("Usage: send <peer_id> <msg>");
+                                    } else {
+                                        let msg = parts[2..].join(" ");
+                                        let result = parts[1]
+                                            .parse::<PeerId>()
+                                            .map_err(|e| format!("Invalid PeerId: {}", e))
+                                            .and_then(|peer_id| self.send_text(peer_id, msg));
+                                        match result {
+                                            Ok(id) => self.out.print(
+                                                "sending",
+                                                json!({ "peer": parts[1], "id": id }),
+                                                format!("Sending to {} ({})", parts[1], id),
+                                            ),
+                                            Err(e) => self.out.print("error", json!({ "error": e }), format!("Send Error: {}", e)),
                                         }
-                                        Err(e) => println!("Invalid code: {}", e),
                                     }
                                 }
-                            }
-                            "send" => {
-                                if parts.len() < 3 {
-                                    println!("Usage: send <peer_id> <msg>");
-                                } else {
-                                    let peer_id_str = parts[1];
-                                    let msg = parts[2..].join(" ");
-                                    match peer_id_str.parse::<libp2p::PeerId>() {
-                                        Ok(peer_id) => {
-                                            println!("Sending to {}: {}", peer_id, msg);
-                                            swarm.behaviour_mut().signaling.send_request(&peer_id, SignalingRequest(msg.clone()));
-                                            
-                                            // Log to DB
-                                            conn.execute(
-                                                "INSERT INTO messages (peer_id, content, is_sent, timestamp) VALUES (?1, ?2, ?3, ?4)",
-                                                params![peer_id_str, msg, true, chrono::Utc::now().timestamp()],
-                                            ).unwrap_or_else(|e| {
-                                                println!("DB Error: {}", e);
-                                                0
+                                "retry" => {
+                                    if parts.len() < 3 {
+                                        println!("Usage: retry <peer_id> <msg_id>");
+                                    } else {
+                                        let result = parts[1]
+                                            .parse::<PeerId>()
+                                            .map_err(|e| format!("Invalid PeerId: {}", e))
+                                            .and_then(|peer_id| {
+                                                outbox::retry(&mut self.store, &mut self.messenger, &mut self.swarm, peer_id, parts[2])
                                             });
+                                        match result {
+                                            Ok(changes) => self.print_status(&changes),
+                                            Err(e) => self.out.print("error", json!({ "error": e }), format!("Retry Error: {}", e)),
                                         }
-                                        Err(e) => println!("Invalid PeerId: {}", e),
                                     }
                                 }
-                            }
-                            "info" => {
-                                let local_peer_id = *swarm.local_peer_id();
-                                println!("My PeerId: {}", local_peer_id);
-                                println!("Listeners:");
-                                let mut relay_found = false;
-                                for addr in swarm.listeners() {
-                                    println!(" - {}", addr);
-                                    if addr.to_string().contains("p2p-circuit") {
-                                        relay_found = true;
-                                        let code = utils::generate_void_code(addr);
-                                        println!("VOID CODE (Relay): {}", code);
+                                "groups" => self.print_groups(),
+                                "gcreate" | "ginvite" | "gremove" | "gleave" | "gsend" => {
+                                    if let Err(e) = self.group_command(&parts) {
+                                        self.out.print("error", json!({ "error": e }), format!("Group Error: {}", e));
                                     }
                                 }
-                                if !relay_found {
-                                    println!("(Not listening on Relay yet. Wait for connection...)");
+                                "transfers" => self.print_transfers(),
+                                "sendfile" | "accept" | "reject" | "cancel" => {
+                                    if let Err(e) = self.file_command(&parts) {
+                                        self.out.print("error", json!({ "error": e }), format!("Transfer Error: {}", e));
+                                    }
                                 }
+                                "info" => self.print_info(),
+                                "relays" => self.print_relays(),
+                                "exit" => break,
+                                _ => println!("Unknown command"),
                             }
-                            "exit" => break,
-                            _ => println!("Unknown command"),
+                        }
+                        Ok(None) => break,
+                        Err(e) => {
+                            log::error!("Error reading stdin: {}", e);
+                            break;
                         }
                     }
-                    Ok(None) => break,
-                    Err(e) => {
-                        println!("Error reading stdin: {}", e);
-                        break;
+                }
+                _ = reconnect.tick() => self.on_tick(),
+                Some(done) = self.files.next_disk() => self.on_disk(done),
+                event = self.swarm.select_next_some() => {
+                    self.handle_event(event);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handles one swarm event and prints anything user-facing. Message status
+    /// changes are returned so one-shot commands can wait on them.
+    fn handle_event(&mut self, event: SwarmEvent<VoidEvent>) -> Vec<StatusChange> {
+        let out = self.out;
+        match event {
+            SwarmEvent::NewListenAddr { address, .. } => {
+                log::info!("New Listen Addr: {}", address);
+                if address.to_string().contains("p2p-circuit") {
+                    if let Ok(code) = self.void_code() {
+                        out.print(
+                            "ready",
+                            json!({ "address": address.to_string(), "code": code }),
+                            format!("\n*** READY ***\nVOID CODE: {}\n*************\n", code),
+                        );
                     }
                 }
             }
-            event = swarm.select_next_some() => {
+            SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
+                log::info!("Peer connected: {}", peer_id);
+                log::info!("Endpoint: {:?}", endpoint);
+                log::info!("Secure channel established");
+                self.relays.on_connected(&mut self.swarm, &peer_id);
+                self.mailbox.on_connected(&mut self.swarm, &peer_id);
+                let changes = outbox::flush(&mut self.store, &mut self.messenger, &mut self.swarm, &peer_id);
+                self.print_status(&changes);
+                for event in self.files.on_connected(&mut self.store, &peer_id) {
+                    self.print_file_event(&event);
+                }
+                return changes;
+            }
+            SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                self.relays.on_disconnected(&mut self.swarm, &peer_id);
+            }
+            SwarmEvent::OutgoingConnectionError { peer_id: Some(peer_id), error, .. } => {
+                self.relays.on_dial_failure(&peer_id, error.to_string());
+            }
+            SwarmEvent::ListenerClosed { listener_id, reason, .. } => {
+                self.relays.on_listener_closed(
+                    &mut self.swarm,
+                    listener_id,
+                    reason.map_err(|e| e.to_string()),
+                );
+            }
+            SwarmEvent::Behaviour(VoidEvent::Signaling(event)) => {
                 match event {
-                    SwarmEvent::NewListenAddr { address, .. } => {
-                        log::info!("New Listen Addr: {}", address);
-                        if address.to_string().contains("p2p-circuit") {
-                            let code = utils::generate_void_code(&address);
-                            println!("\n*** READY ***");
-                            println!("VOID CODE: {}", code);
-                            println!("*************\n");
-                        }
-                    }
-                    SwarmEvent::ConnectionEstablished { peer_id, endpoint, .. } => {
-                        log::info!("Peer connected: {}", peer_id);
-                        log::info!("Endpoint: {:?}", endpoint);
-                        log::info!("Secure channel established");
-                    }
-                    SwarmEvent::Behaviour(VoidEvent::Signaling(event)) => {
-                        match event {
-                            libp2p::request_response::Event::Message { peer, message, .. } => {
-                                match message {
-                                    Message::Request { request, channel, .. } => {
-                                        println!("\n[Message from {}]: {}", peer, request.0);
-                                        // Log to DB
-                                        conn.execute(
-                                            "INSERT INTO messages (peer_id, content, is_sent, timestamp) VALUES (?1, ?2, ?3, ?4)",
-                                            params![peer.to_string(), request.0, false, chrono::Utc::now().timestamp()],
-                                        ).unwrap_or_else(|e| {
-                                            println!("DB Error: {}", e);
-                                            0
-                                        });
-
-                                        let _ = swarm.behaviour_mut().signaling.send_response(channel, SignalingResponse("ACK".into()));
-                                    }
-                                    Message::Response { .. } => {
-                                        log::info!("Message delivered to {}", peer);
+                    libp2p::request_response::Event::Message { peer, message, .. } => match message {
+                        Message::Request { request, channel, .. } => {
+                            let response = match signaling::validate(&request) {
+                                Ok(()) => {
+                                    match request.signal {
+                                        Signal::Chat { id, text } => {
+                                            out.print(
+                                                "message",
+                                                json!({ "peer": peer.to_string(), "id": id, "text": text, "encrypted": false }),
+                                                format!("\n[Message from {}]: {}", peer, text),
+                                            );
+                                            // Log to DB
+                                            if let Err(e) = self.store.insert(&id, &peer.to_string(), &text, false, chrono::Utc::now().timestamp()) {
+                                                log::error!("DB Error: {}", e);
+                                            }
+                                        }
+                                        signal => log::info!("Signal from {}: {:?}", peer, signal),
                                     }
+                                    SignalingResponse::Ack
                                 }
-                            }
-                            _ => {}
+                                Err(rejection) => rejection,
+                            };
+                            let _ = self.swarm.behaviour_mut().signaling.send_response(channel, response);
                         }
-                    }
-                     SwarmEvent::Behaviour(VoidEvent::RelayClient(e)) => {
-                        log::debug!("Relay Event: {:?}", e);
+                        Message::Response { response, .. } => {
+                            log::info!("Signal response from {}: {:?}", peer, response);
+                        }
+                    },
+                    libp2p::request_response::Event::OutboundFailure { peer, error, .. } => {
+                        log::warn!("Signal to {} failed: {}", peer, error);
                     }
                     _ => {}
                 }
             }
+            SwarmEvent::Behaviour(VoidEvent::Messaging(event)) => {
+                let mut changes = Vec::new();
+                for event in self.messenger.handle_event(&mut self.swarm, event) {
+                    changes.extend(self.on_messenger_event(event));
+                }
+                self.print_status(&changes);
+                return changes;
+            }
+            SwarmEvent::Behaviour(VoidEvent::Mailbox(event)) => {
+                let outcome = self.mailbox.handle_event(
+                    &mut self.store,
+                    &mut self.messenger,
+                    &mut self.swarm,
+                    self.mailbox_server.as_mut(),
+                    event,
+                );
+                for dropped in &outcome.dropped {
+                    self.out.print(
+                        "mail-dropped",
+                        json!(dropped),
+                        format!(
+                            "Dropped mail {} from {} via {}: {}",
+                            dropped.id, dropped.sender, dropped.mailbox, dropped.error
+                        ),
+                    );
+                }
+                let mut changes = outcome.changes;
+                for event in outcome.messages {
+                    changes.extend(self.on_messenger_event(event));
+                }
+                self.print_status(&changes);
+                return changes;
+            }
+            SwarmEvent::Behaviour(VoidEvent::Files(event)) => {
+                for event in self.files.handle_event(&mut self.store, &mut self.swarm, event) {
+                    self.print_file_event(&event);
+                }
+            }
+            SwarmEvent::Behaviour(VoidEvent::RelayClient(e)) => {
+                log::debug!("Relay Event: {:?}", e);
+            }
+            SwarmEvent::Behaviour(VoidEvent::Identify(identify::Event::Received { peer_id, info, .. })) => {
+                if let Err(e) = network::record_seen(&mut self.store, &peer_id, &info.listen_addrs) {
+                    log::warn!("Failed to update contact: {}", e);
+                }
+            }
+            SwarmEvent::Behaviour(VoidEvent::Mdns(mdns::Event::Discovered(list))) => {
+                for (peer_id, addr) in list {
+                    log::info!("mDNS: found {} at {}", peer_id, addr);
+                    self.swarm.add_peer_address(peer_id, addr);
+                    if !self.swarm.is_connected(&peer_id) {
+                        let _ = self.swarm.dial(peer_id);
+                    }
+                }
+            }
+            SwarmEvent::Behaviour(VoidEvent::Mdns(mdns::Event::Expired(list))) => {
+                for (peer_id, addr) in list {
+                    log::debug!("mDNS: expired {} at {}", peer_id, addr);
+                }
+            }
+            SwarmEvent::Behaviour(VoidEvent::Dcutr(e)) => match e.result {
+                Ok(_) => log::info!("Direct connection to {} via hole punch", e.remote_peer_id),
+                Err(err) => log::warn!("Hole punch to {} failed: {}", e.remote_peer_id, err),
+            },
+            SwarmEvent::Behaviour(VoidEvent::Autonat(autonat::Event::StatusChanged { new, .. })) => {
+                log::info!("NAT status: {:?}", new);
+            }
+            _ => {}
+        }
+        Vec::new()
+    }
+
+    /// Prints and stores a received message, or updates the outbox for a
+    /// receipt. Used for direct messages and those fetched from a mailbox.
+    fn on_messenger_event(&mut self, event: MessengerEvent) -> Vec<StatusChange> {
+        if let MessengerEvent::Received { peer, .. }
+        | MessengerEvent::Delivered { peer, .. }
+        | MessengerEvent::KeyChanged { peer } = &event
+        {
+            self.pin_contact_key(peer);
+        }
+        if let MessengerEvent::Received { peer, content: MessageContent::FileOffer(offer), .. } = &event {
+            match self.files.on_offer(&mut self.store, &mut self.swarm, peer, offer) {
+                Ok(events) => {
+                    for event in &events {
+                        self.print_file_event(event);
+                    }
+                }
+                Err(e) => log::warn!("Ignoring file offer from {}: {}", peer, e),
+            }
+            return Vec::new();
         }
+        if let MessengerEvent::Received { peer, sent_at, content, .. } = &event {
+            if content.is_group() {
+                let outcome =
+                    groups::on_received(&mut self.store, &mut self.messenger, &mut self.swarm, peer, *sent_at, content);
+                for event in &outcome.events {
+                    self.print_group_event(event);
+                }
+                self.mailbox.deposit(&mut self.store, &mut self.messenger, &mut self.swarm, &outcome.changes);
+                return outcome.changes;
+            }
+        }
+        if let MessengerEvent::Received { peer, id, sent_at, content: MessageContent::Text { text } } = &event {
+            self.out.print(
+                "message",
+                json!({ "peer": peer.to_string(), "id": id, "text": text, "sentAt": sent_at, "encrypted": true }),
+                format!("\n[Encrypted message from {}]: {}", peer, text),
+            );
+            if let Err(e) = self.store.insert(id, &peer.to_string(), text, false, *sent_at) {
+                log::error!("DB Error: {}", e);
+            }
+            // Printed means read
+            let read = std::slice::from_ref(id);
+            if let Err(e) = outbox::mark_read(&mut self.store, &mut self.messenger, &mut self.swarm, *peer, read) {
+                log::warn!("Failed to send read receipt: {}", e);
+            }
+        }
+        let changes = outbox::on_event(&mut self.store, &event);
+        self.mailbox.deposit(&mut self.store, &mut self.messenger, &mut self.swarm, &changes);
+        changes
     }
 
-    Ok(())
+    /// Pins the peer's messaging identity key if it is a contact, warning
+    /// when it differs from the pinned one.
+    fn pin_contact_key(&mut self, peer: &PeerId) {
+        match network::pin_session_key(&mut self.store, &self.messenger, peer) {
+            Ok(KeyPin::Changed { was_verified }) => {
+                let mut text = format!(
+                    "\n*** WARNING: the identity key of contact {} changed. Compare safety numbers before trusting it. ***",
+                    peer
+                );
+                if was_verified {
+                    text.push_str("\n*** It was verified before; it is no longer. ***");
+                }
+                self.out.print(
+                    "contact-key-changed",
+                    json!({ "peerId": peer.to_string(), "wasVerified": was_verified }),
+                    text,
+                );
+            }
+            Ok(_) => {}
+            Err(e) => log::warn!("Failed to pin contact key: {}", e),
+        }
+    }
 }
@@ -1,9 +1,94 @@
+pub mod audio;
+pub mod cli;
+pub mod network;
+pub mod security;
+pub mod storage;
+pub mod vpn;
+
+use network::NetworkState;
+use storage::db::HistoryState;
+use storage::vault_session::VaultState;
+
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
 pub fn run() {
     tauri::Builder::default()
         .plugin(tauri_plugin_process::init())
         .plugin(tauri_plugin_opener::init())
         .plugin(tauri_plugin_store::Builder::default().build())
+        .manage(NetworkState::new())
+        .manage(HistoryState::new())
+        .manage(VaultState::new())
+        .invoke_handler(tauri::generate_handler![
+            network::start_node,
+            network::stop_node,
+            network::restart_node,
+            network::send_signal,
+            network::send_typed_signal,
+            network::send_message,
+            network::mark_messages_read,
+            network::retry_message,
+            network::groups::create_group,
+            network::groups::invite_to_group,
+            network::groups::remove_from_group,
+            network::groups::leave_group,
+            network::groups::send_group_message,
+            network::files::send_file,
+            network::files::accept_file,
+            network::files::reject_file,
+            network::files::cancel_file,
+            network::rooms::join_room,
+            network::rooms::leave_room,
+            network::rooms::publish_room,
+            network::rooms::get_room_peers,
+            network::dial_peer,
+            network::connect_via_code,
+            network::get_my_void_code,
+            network::get_peer_links,
+            network::get_lan_peers,
+            network::config::get_network_config,
+            network::config::set_network_config,
+            network::config::add_endpoint,
+            network::config::remove_endpoint,
+            network::config::get_endpoint_health,
+            storage::vault::encrypt_file,
+            storage::vault::decrypt_file,
+            storage::vault::decrypt_file_to,
+            storage::vault::migrate_vault_file,
+            storage::vault::upgrade_vault_files,
+            storage::vault_session::vault_status,
+            storage::vault_session::unlock_vault,
+            storage::vault_session::lock_vault,
+            storage::vault_session::create_recovery_key,
+            storage::vault_session::recover_vault,
+            storage::vault_session::change_vault_pin,
+            storage::vault_session::rotate_vault_key,
+            storage::vault_session::tune_vault_kdf,
+            security::pin_strength::check_vault_pin,
+            storage::manifest::list_vault_files,
+            storage::manifest::rename_vault_file,
+            storage::manifest::tag_vault_file,
+            storage::manifest::search_vault,
+            storage::identity::export_identity,
+            storage::identity::import_identity,
+            storage::identity::rotate_identity,
+            storage::db::get_message_history,
+            storage::db::search_messages,
+            storage::db::list_conversations,
+            storage::db::delete_message,
+            storage::db::delete_conversation,
+            storage::groups::list_groups,
+            storage::groups::delete_group,
+            storage::transfers::list_transfers,
+            storage::transfers::delete_transfer,
+            storage::contacts::list_contacts,
+            storage::contacts::add_contact,
+            storage::contacts::remove_contact,
+            storage::contacts::rename_contact,
+            storage::contacts::block_contact,
+            storage::contacts::set_contact_verified,
+            security::safety::get_safety_number,
+            security::safety::verify_safety_qr,
+        ])
         .run(tauri::generate_context!())
         .expect("error while running tauri application");
 }
@@ -0,0 +1,229 @@
+use crate::network::relays::EndpointHealth;
+use crate::network::{self, NetworkCommand, NetworkState};
+use libp2p::{Multiaddr, PeerId, multiaddr::Protocol};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tauri::{AppHandle, Manager, State};
+
+const CONFIG_FILE: &str = "network.json";
+
+/// Public libp2p bootstrap nodes. These are IPFS DHT nodes rather than VOID
+/// relays, but they run circuit relay v2, so they stay as the default relays
+/// until the user configures their own.
+const DEFAULT_BOOTSTRAP: [&str; 4] = [
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN",
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmQCU2EcMqAqQPR2i9bChDtGNJchTeq5s0GNHw5zXIov6U",
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmbLHAnMoJPWSCR5Zhtx6BHJX9CkJv68846kJcCPaQFjNA",
+    "/dnsaddr/bootstrap.libp2p.io/p2p/QmcZf59bWwK5XFi76CZX8cbJ4BhTzzA3gU1Ubuu79rfVP3",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum EndpointKind {
+    /// Circuit relay we reserve a slot on so others can reach us.
+    Relay,
+    /// Node we only dial to join the network.
+    Bootstrap,
+    /// Store-and-forward node that holds our mail while we're offline.
+    Mailbox,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Endpoint {
+    /// Full multiaddr, including the trailing `/p2p/<PeerId>`.
+    pub address: String,
+    pub kind: EndpointKind,
+    /// Lower values are tried first.
+    #[serde(default)]
+    pub priority: u32,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl Endpoint {
+    pub fn new(address: String, kind: EndpointKind) -> Self {
+        Self {
+            address,
+            kind,
+            priority: 0,
+            enabled: true,
+        }
+    }
+
+    pub fn parse(&self) -> Result<(PeerId, Multiaddr), String> {
+        let addr: Multiaddr = self
+            .address
+            .parse()
+            .map_err(|e| format!("Invalid address {}: {}", self.address, e))?;
+        match addr.iter().last() {
+            Some(Protocol::P2p(peer_id)) => Ok((peer_id, addr)),
+            _ => Err(format!("{} must end with /p2p/<PeerId>", self.address)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BackoffConfig {
+    pub initial_secs: u64,
+    pub max_secs: u64,
+}
+
+impl Default for BackoffConfig {
+    fn default() -> Self {
+        Self {
+            initial_secs: 5,
+            max_secs: 300,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct NetworkConfig {
+    pub endpoints: Vec<Endpoint>,
+    /// How many relays to hold a reservation on at once.
+    #[serde(default = "default_max_reservations")]
+    pub max_reservations: usize,
+    #[serde(default)]
+    pub backoff: BackoffConfig,
+}
+
+fn default_max_reservations() -> usize {
+    2
+}
+
+impl Default for NetworkConfig {
+    fn default() -> Self {
+        Self {
+            endpoints: DEFAULT_BOOTSTRAP
+                .iter()
+                .map(|addr| Endpoint::new(addr.to_string(), EndpointKind::Relay))
+                .collect(),
+            max_reservations: default_max_reservations(),
+            backoff: BackoffConfig::default(),
+        }
+    }
+}
+
+impl NetworkConfig {
+    /// Reads the config at `path`, falling back to the defaults if it doesn't exist yet.
+    pub fn load(path: &Path) -> Result<Self, String> {
+        if !path.exists() {
+            return Ok(Self::default());
+        }
+        let data = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        serde_json::from_str(&data).map_err(|e| format!("Invalid network config: {}", e))
+    }
+
+    pub fn save(&self, path: &Path) -> Result<(), String> {
+        self.validate()?;
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let data = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        fs::write(path, data).map_err(|e| e.to_string())
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for endpoint in &self.endpoints {
+            endpoint.parse()?;
+        }
+        Ok(())
+    }
+
+    /// PeerIds of the enabled mailbox endpoints.
+    pub fn mailbox_peers(&self) -> Vec<PeerId> {
+        self.endpoints
+            .iter()
+            .filter(|e| e.enabled && e.kind == EndpointKind::Mailbox)
+            .filter_map(|e| e.parse().ok().map(|(peer_id, _)| peer_id))
+            .collect()
+    }
+
+    /// Adds or replaces the endpoint with the same address.
+    pub fn upsert(&mut self, endpoint: Endpoint) -> Result<(), String> {
+        endpoint.parse()?;
+        self.endpoints.retain(|e| e.address != endpoint.address);
+        self.endpoints.push(endpoint);
+        Ok(())
+    }
+}
+
+pub fn config_path(app: &AppHandle) -> Result<PathBuf, String> {
+    Ok(app
+        .path()
+        .app_data_dir()
+        .map_err(|e| e.to_string())?
+        .join(CONFIG_FILE))
+}
+
+/// Persists `config` and pushes it to the running node, if any.
+async fn apply(
+    app: &AppHandle,
+    state: &State<'_, NetworkState>,
+    config: NetworkConfig,
+) -> Result<NetworkConfig, String> {
+    config.save(&config_path(app)?)?;
+
+    let sender_guard = state.sender.lock().await;
+    if let Some(tx) = sender_guard.as_ref() {
+        tx.send(NetworkCommand::UpdateEndpoints(config.clone()))
+            .await
+            .map_err(|e| e.to_string())?;
+    }
+    Ok(config)
+}
+
+#[tauri::command]
+pub async fn get_network_config(app: AppHandle) -> Result<NetworkConfig, String> {
+    NetworkConfig::load(&config_path(&app)?)
+}
+
+#[tauri::command]
+pub async fn set_network_config(
+    config: NetworkConfig,
+    app: AppHandle,
+    state: State<'_, NetworkState>,
+) -> Result<NetworkConfig, String> {
+    apply(&app, &state, config).await
+}
+
+#[tauri::command]
+pub async fn add_endpoint(
+    address: String,
+    kind: EndpointKind,
+    priority: Option<u32>,
+    app: AppHandle,
+    state: State<'_, NetworkState>,
+) -> Result<NetworkConfig, String> {
+    let mut config = NetworkConfig::load(&config_path(&app)?)?;
+    let mut endpoint = Endpoint::new(address, kind);
+    endpoint.priority = priority.unwrap_or(0);
+    config.upsert(endpoint)?;
+    apply(&app, &state, config).await
+}
+
+#[tauri::command]
+pub async fn remove_endpoint(
+    address: String,
+    app: AppHandle,
+    state: State<'_, NetworkState>,
+) -> Result<NetworkConfig, String> {
+    let mut config = NetworkConfig::load(&config_path(&app)?)?;
+    config.endpoints.retain(|e| e.address != address);
+    apply(&app, &state, config).await
+}
+
+#[tauri::command]
+pub async fn get_endpoint_health(
+    state: State<'_, NetworkState>,
+) -> Result<Vec<EndpointHealth>, String> {
+    network::query(&state, NetworkCommand::GetEndpointHealth).await
+}
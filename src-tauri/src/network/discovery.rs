@@ -0,0 +1,95 @@
+use libp2p::{Multiaddr, PeerId};
+use serde::Serialize;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LanPeer {
+    pub peer_id: String,
+    pub addresses: Vec<String>,
+}
+
+struct Tracked {
+    addresses: Vec<Multiaddr>,
+    /// Set once identify confirms the peer speaks the VOID protocol. mDNS
+    /// also finds unrelated libp2p nodes (IPFS etc.) on the same network.
+    verified: bool,
+}
+
+/// Peers found on the local network via mDNS.
+#[derive(Default)]
+pub struct LanPeers {
+    peers: HashMap<PeerId, Tracked>,
+}
+
+impl LanPeers {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records mDNS results. Returns the peers seen for the first time, which
+    /// should be dialed so identify can tell whether they are VOID nodes.
+    pub fn on_discovered(&mut self, list: Vec<(PeerId, Multiaddr)>) -> Vec<PeerId> {
+        let mut new_peers = Vec::new();
+        for (peer, addr) in list {
+            let tracked = self.peers.entry(peer).or_insert_with(|| {
+                new_peers.push(peer);
+                Tracked {
+                    addresses: Vec::new(),
+                    verified: false,
+                }
+            });
+            if !tracked.addresses.contains(&addr) {
+                tracked.addresses.push(addr);
+            }
+        }
+        new_peers
+    }
+
+    /// Returns the peer if identify just confirmed it as a VOID node.
+    pub fn on_identified(&mut self, peer: &PeerId, protocol_version: &str) -> Option<LanPeer> {
+        let tracked = self.peers.get_mut(peer)?;
+        if tracked.verified || !is_void_node(protocol_version) {
+            return None;
+        }
+        tracked.verified = true;
+        Some(to_lan_peer(peer, tracked))
+    }
+
+    /// Drops expired addresses. Returns verified peers that are now gone.
+    pub fn on_expired(&mut self, list: Vec<(PeerId, Multiaddr)>) -> Vec<PeerId> {
+        let mut gone = Vec::new();
+        for (peer, addr) in list {
+            let Some(tracked) = self.peers.get_mut(&peer) else {
+                continue;
+            };
+            tracked.addresses.retain(|a| *a != addr);
+            if tracked.addresses.is_empty() {
+                if tracked.verified {
+                    gone.push(peer);
+                }
+                self.peers.remove(&peer);
+            }
+        }
+        gone
+    }
+
+    pub fn snapshot(&self) -> Vec<LanPeer> {
+        self.peers
+            .iter()
+            .filter(|(_, t)| t.verified)
+            .map(|(peer, t)| to_lan_peer(peer, t))
+            .collect()
+    }
+}
+
+fn is_void_node(protocol_version: &str) -> bool {
+    protocol_version.starts_with("void/")
+}
+
+fn to_lan_peer(peer: &PeerId, tracked: &Tracked) -> LanPeer {
+    LanPeer {
+        peer_id: peer.to_string(),
+        addresses: tracked.addresses.iter().map(|a| a.to_string()).collect(),
+    }
+}
@@ -0,0 +1,1095 @@
+// File transfers
+//
+// The sender offers a file over the message channel, so the offer and its
+// per-transfer key are end-to-end encrypted and retried like any message. If
+// the receiver accepts, it pulls the file chunk by chunk on `/void/file/1.0.0`,
+// a few requests at a time; every chunk is sealed with the transfer key and
+// bound to its position. Chunks are written in order to `<dest>.part`, so an
+// interrupted transfer resumes from what is on disk once the peer is back, and
+// the part file is only renamed after the whole-file SHA-256 matches the offer.
+// The running hash is saved with the progress, so resuming doesn't re-read the
+// part file. Disk I/O runs on the blocking pool; the results come back to the
+// swarm task through `next_disk`.
+use crate::network::messaging::{MessageContent, Messenger};
+use crate::network::outbox::{self, StatusChange};
+use crate::network::swarm::{FileRequest, FileResponse, VoidBehaviour};
+use crate::network::{NetworkCommand, NetworkState, request};
+use crate::storage::db::MessageStore;
+use crate::storage::transfers::{FileTransfer, TransferState};
+use crate::storage::vault;
+use libp2p::{
+    PeerId, Swarm,
+    request_response::{self, Message, OutboundRequestId, ResponseChannel},
+};
+use serde::{Deserialize, Serialize};
+use sha2::digest::{consts::U64, generic_array::GenericArray};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tauri::State;
+use tokio::sync::mpsc;
+
+/// Largest file we offer or accept.
+pub const MAX_FILE_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+pub const CHUNK_SIZE: u64 = 256 * 1024;
+/// Offers with larger chunks are rejected; they must fit in one response.
+const MAX_CHUNK_SIZE: u64 = 1024 * 1024;
+/// Chunks requested but not yet written, per download.
+const WINDOW: u64 = 4;
+const MAX_NAME_LEN: usize = 255;
+
+/// Sent over the message channel to propose a transfer.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileOffer {
+    pub transfer_id: String,
+    pub name: String,
+    pub size: u64,
+    pub chunk_size: u64,
+    /// Hex SHA-256 of the whole file.
+    pub sha256: String,
+    pub key: [u8; 32],
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum FileEvent {
+    /// A peer offered us a file; accept or reject it.
+    Offer { transfer: FileTransfer },
+    /// A transfer made progress or changed state.
+    Progress { transfer: FileTransfer },
+}
+
+/// A local file hashed and ready to offer.
+#[derive(Debug, Clone)]
+pub struct LocalFile {
+    pub path: PathBuf,
+    pub name: String,
+    pub size: u64,
+    pub sha256: String,
+}
+
+impl LocalFile {
+    /// Reads the whole file to hash it, so call it off the swarm task.
+    pub fn prepare(path: &Path) -> Result<Self, String> {
+        let metadata = fs::metadata(path).map_err(|e| e.to_string())?;
+        if !metadata.is_file() {
+            return Err("Not a file".into());
+        }
+        if metadata.len() > MAX_FILE_SIZE {
+            return Err(format!("Files are limited to {} MiB", MAX_FILE_SIZE / (1024 * 1024)));
+        }
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .ok_or("Invalid file name")?;
+        let mut hasher = Sha256::new();
+        hash_reader(&mut hasher, &mut File::open(path).map_err(|e| e.to_string())?)?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            name,
+            size: metadata.len(),
+            sha256: format!("{:x}", hasher.finalize()),
+        })
+    }
+}
+
+/// SHA-256 whose state can be saved and picked up again.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct RunningHash {
+    state: [u32; 8],
+    /// Tail that doesn't fill a block yet.
+    buffer: Vec<u8>,
+    len: u64,
+}
+
+impl RunningHash {
+    const INITIAL: [u32; 8] = [
+        0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+    ];
+
+    fn new() -> Self {
+        Self {
+            state: Self::INITIAL,
+            buffer: Vec::new(),
+            len: 0,
+        }
+    }
+
+    fn update(&mut self, mut data: &[u8]) {
+        self.len += data.len() as u64;
+        if !self.buffer.is_empty() {
+            let take = (64 - self.buffer.len()).min(data.len());
+            self.buffer.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.buffer.len() < 64 {
+                return;
+            }
+            sha2::compress256(&mut self.state, &[*GenericArray::<u8, U64>::from_slice(&self.buffer)]);
+            self.buffer.clear();
+        }
+        let blocks = data.chunks_exact(64);
+        self.buffer.extend_from_slice(blocks.remainder());
+        let blocks: Vec<_> = blocks.map(|block| *GenericArray::<u8, U64>::from_slice(block)).collect();
+        sha2::compress256(&mut self.state, &blocks);
+    }
+
+    /// Hex digest.
+    fn finalize(mut self) -> String {
+        let bits = self.len.wrapping_mul(8);
+        let mut tail = std::mem::take(&mut self.buffer);
+        tail.push(0x80);
+        while tail.len() % 64 != 56 {
+            tail.push(0);
+        }
+        tail.extend_from_slice(&bits.to_be_bytes());
+        let blocks: Vec<_> = tail
+            .chunks_exact(64)
+            .map(|block| *GenericArray::<u8, U64>::from_slice(block))
+            .collect();
+        sha2::compress256(&mut self.state, &blocks);
+        self.state.iter().map(|word| format!("{:08x}", word)).collect()
+    }
+
+    /// State, length, then the unfinished block.
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes: Vec<u8> = self.state.iter().flat_map(|word| word.to_be_bytes()).collect();
+        bytes.extend_from_slice(&self.len.to_be_bytes());
+        bytes.extend_from_slice(&self.buffer);
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 40 {
+            return None;
+        }
+        let (state, rest) = bytes.split_at(32);
+        let (len, buffer) = rest.split_at(8);
+        let len = u64::from_be_bytes(len.try_into().ok()?);
+        if buffer.len() as u64 != len % 64 {
+            return None;
+        }
+        let mut words = [0u32; 8];
+        for (word, bytes) in words.iter_mut().zip(state.chunks_exact(4)) {
+            *word = u32::from_be_bytes(bytes.try_into().ok()?);
+        }
+        Some(Self {
+            state: words,
+            buffer: buffer.to_vec(),
+            len,
+        })
+    }
+}
+
+/// An accepted incoming transfer we are pulling chunks for.
+struct Download {
+    /// Tells results of disk jobs from an earlier run of the transfer apart.
+    generation: u64,
+    peer: PeerId,
+    key: [u8; 32],
+    /// `<dest>.part`, holding the first `written` chunks, and their hash.
+    /// Taken while the file is being opened, written or verified.
+    part: Option<(File, RunningHash)>,
+    written: u64,
+    /// Next chunk to request.
+    next: u64,
+    pending: HashMap<OutboundRequestId, u64>,
+    /// Chunks that arrived ahead of `written`.
+    early: BTreeMap<u64, Vec<u8>>,
+}
+
+/// A finished disk job, for `FileTransfers::on_disk`.
+pub struct DiskDone(Disk);
+
+enum Disk {
+    Opened {
+        id: String,
+        generation: u64,
+        result: Result<(File, RunningHash, u64), String>,
+    },
+    Written {
+        id: String,
+        generation: u64,
+        chunks: u64,
+        result: Result<(File, RunningHash), String>,
+    },
+    /// Whether the hash matched and the file was moved into place.
+    Verified {
+        id: String,
+        generation: u64,
+        result: Result<bool, String>,
+    },
+    ChunkRead {
+        transfer_id: String,
+        index: u64,
+        channel: ResponseChannel<FileResponse>,
+        result: Result<Vec<u8>, String>,
+    },
+}
+
+pub struct FileTransfers {
+    download_dir: PathBuf,
+    downloads: HashMap<String, Download>,
+    generation: u64,
+    /// Last whole percentage reported per transfer, to keep progress events sparse.
+    reported: HashMap<String, u64>,
+    disk_tx: mpsc::UnboundedSender<DiskDone>,
+    disk_rx: mpsc::UnboundedReceiver<DiskDone>,
+}
+
+fn chunk_aad(transfer_id: &str, index: u64) -> Vec<u8> {
+    format!("file:{}:{}", transfer_id, index).into_bytes()
+}
+
+fn hash_reader(hasher: &mut Sha256, reader: &mut impl Read) -> Result<(), String> {
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let read = reader.read(&mut buffer).map_err(|e| e.to_string())?;
+        if read == 0 {
+            return Ok(());
+        }
+        hasher.update(&buffer[..read]);
+    }
+}
+
+fn part_path(path: &str) -> PathBuf {
+    PathBuf::from(format!("{}.part", path))
+}
+
+/// Length of chunk `index`; the last one may be short.
+fn chunk_len(transfer: &FileTransfer, index: u64) -> u64 {
+    let start = index * transfer.chunk_size;
+    transfer.chunk_size.min(transfer.size.saturating_sub(start))
+}
+
+/// Keeps only the final path component and drops anything a file system
+/// might trip over.
+fn sanitize_name(name: &str) -> Option<String> {
+    let name = Path::new(name).file_name()?.to_string_lossy();
+    let clean: String = name
+        .chars()
+        .map(|c| if c.is_control() || "<>:\"/\\|?*".contains(c) { '_' } else { c })
+        .collect();
+    let clean = clean.trim().trim_start_matches('.').to_string();
+    (!clean.is_empty() && clean.len() <= MAX_NAME_LEN).then_some(clean)
+}
+
+/// `dir/name`, or `dir/name (n)` if that is taken by a file or a download.
+fn unique_path(dir: &Path, name: &str) -> PathBuf {
+    unique_path_by(dir, name, |path| part_path(&path.to_string_lossy()).exists())
+}
+
+/// `dir/name`, or `dir/name (n)` if that exists or `in_use` says it is about
+/// to.
+pub(crate) fn unique_path_by(dir: &Path, name: &str, in_use: impl Fn(&Path) -> bool) -> PathBuf {
+    let taken = |path: &Path| path.exists() || in_use(path);
+    let candidate = dir.join(name);
+    if !taken(&candidate) {
+        return candidate;
+    }
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => (stem, format!(".{}", extension)),
+        _ => (name, String::new()),
+    };
+    (1..)
+        .map(|n| dir.join(format!("{} ({}){}", stem, n, extension)))
+        .find(|path| !taken(path))
+        .expect("unbounded range")
+}
+
+impl FileTransfers {
+    pub fn new(download_dir: PathBuf) -> Self {
+        let (disk_tx, disk_rx) = mpsc::unbounded_channel();
+        Self {
+            download_dir,
+            downloads: HashMap::new(),
+            generation: 0,
+            reported: HashMap::new(),
+            disk_tx,
+            disk_rx,
+        }
+    }
+
+    /// Waits for the next finished disk job; hand it to `on_disk`.
+    pub async fn next_disk(&mut self) -> Option<DiskDone> {
+        self.disk_rx.recv().await
+    }
+
+    /// Offers `file` to `peer`. The transfer starts once the peer accepts.
+    pub fn offer(
+        &mut self,
+        store: &mut MessageStore,
+        messenger: &mut Messenger,
+        swarm: &mut Swarm<VoidBehaviour>,
+        peer: PeerId,
+        file: LocalFile,
+    ) -> Result<(FileTransfer, Vec<StatusChange>), String> {
+        if file.size > MAX_FILE_SIZE {
+            return Err(format!("Files are limited to {} MiB", MAX_FILE_SIZE / (1024 * 1024)));
+        }
+        let now = chrono::Utc::now().timestamp();
+        let transfer = FileTransfer {
+            id: format!("{:032x}", rand::random::<u128>()),
+            peer_id: peer.to_string(),
+            outgoing: true,
+            name: file.name,
+            path: Some(file.path.to_string_lossy().to_string()),
+            size: file.size,
+            sha256: file.sha256,
+            chunk_size: CHUNK_SIZE,
+            state: TransferState::Offered,
+            transferred: 0,
+            created_at: now,
+            updated_at: now,
+        };
+        let key: [u8; 32] = rand::random();
+        store.insert_transfer(&transfer, &key)?;
+
+        let content = MessageContent::FileOffer(FileOffer {
+            transfer_id: transfer.id.clone(),
+            name: transfer.name.clone(),
+            size: transfer.size,
+            chunk_size: transfer.chunk_size,
+            sha256: transfer.sha256.clone(),
+            key,
+        });
+        let changes = outbox::send_control(store, messenger, swarm, peer, &content)?;
+        Ok((transfer, changes))
+    }
+
+    /// Records an offer received from `peer`. Offers over the size limit are
+    /// turned down without asking.
+    pub fn on_offer(
+        &mut self,
+        store: &mut MessageStore,
+        swarm: &mut Swarm<VoidBehaviour>,
+        peer: &PeerId,
+        offer: &FileOffer,
+    ) -> Result<Vec<FileEvent>, String> {
+        let name = sanitize_name(&offer.name).ok_or("Invalid file name")?;
+        if offer.chunk_size == 0 || offer.chunk_size > MAX_CHUNK_SIZE {
+            return Err("Invalid chunk size".into());
+        }
+        let too_large = offer.size > MAX_FILE_SIZE;
+        let now = chrono::Utc::now().timestamp();
+        let transfer = FileTransfer {
+            id: offer.transfer_id.clone(),
+            peer_id: peer.to_string(),
+            outgoing: false,
+            name,
+            path: None,
+            size: offer.size,
+            sha256: offer.sha256.to_lowercase(),
+            chunk_size: offer.chunk_size,
+            state: if too_large { TransferState::Rejected } else { TransferState::Offered },
+            transferred: 0,
+            created_at: now,
+            updated_at: now,
+        };
+        // A re-delivered offer must not reset a transfer we already have
+        if !store.insert_transfer(&transfer, &offer.key)? {
+            return Ok(Vec::new());
+        }
+        if too_large {
+            send(swarm, peer, FileRequest::Reject {
+                transfer_id: transfer.id.clone(),
+                reason: "File too large".into(),
+            });
+            return Ok(vec![FileEvent::Progress { transfer }]);
+        }
+        Ok(vec![FileEvent::Offer { transfer }])
+    }
+
+    /// Accepts an offered file and starts pulling it into `dir`, or the
+    /// download directory.
+    pub fn accept(
+        &mut self,
+        store: &mut MessageStore,
+        id: &str,
+        dir: Option<PathBuf>,
+    ) -> Result<Vec<FileEvent>, String> {
+        let transfer = store.transfer(id)?.ok_or("Unknown transfer")?;
+        if transfer.outgoing || transfer.state != TransferState::Offered {
+            return Err("Transfer is not waiting to be accepted".into());
+        }
+        let dir = dir.unwrap_or_else(|| self.download_dir.clone());
+        fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+        let path = unique_path(&dir, &transfer.name);
+        // Claim the name right away so a second download can't pick it too
+        File::create(part_path(&path.to_string_lossy())).map_err(|e| e.to_string())?;
+        store.set_transfer_path(id, &path.to_string_lossy())?;
+        store.set_transfer_state(id, TransferState::Transferring)?;
+        self.start(store, id)
+    }
+
+    /// Declines an offered file.
+    pub fn reject(
+        &mut self,
+        store: &mut MessageStore,
+        swarm: &mut Swarm<VoidBehaviour>,
+        id: &str,
+    ) -> Result<Vec<FileEvent>, String> {
+        let transfer = store.transfer(id)?.ok_or("Unknown transfer")?;
+        if transfer.outgoing || transfer.state != TransferState::Offered {
+            return Err("Transfer is not waiting to be accepted".into());
+        }
+        let peer = parse_peer(&transfer.peer_id)?;
+        send(swarm, &peer, FileRequest::Reject {
+            transfer_id: transfer.id.clone(),
+            reason: "Declined".into(),
+        });
+        self.finish(store, id, TransferState::Rejected)
+    }
+
+    /// Stops a transfer in either direction and tells the peer.
+    pub fn cancel(
+        &mut self,
+        store: &mut MessageStore,
+        swarm: &mut Swarm<VoidBehaviour>,
+        id: &str,
+    ) -> Result<Vec<FileEvent>, String> {
+        let transfer = store.transfer(id)?.ok_or("Unknown transfer")?;
+        if transfer.state.is_finished() {
+            return Err("Transfer already finished".into());
+        }
+        let peer = parse_peer(&transfer.peer_id)?;
+        let request = if transfer.outgoing {
+            FileRequest::Cancel {
+                transfer_id: transfer.id.clone(),
+            }
+        } else {
+            FileRequest::Reject {
+                transfer_id: transfer.id.clone(),
+                reason: "Cancelled".into(),
+            }
+        };
+        send(swarm, &peer, request);
+        self.finish(store, id, TransferState::Cancelled)
+    }
+
+    /// Resumes downloads from `peer` that were cut off.
+    pub fn on_connected(&mut self, store: &mut MessageStore, peer: &PeerId) -> Vec<FileEvent> {
+        let transfers = match store.resumable_transfers(&peer.to_string()) {
+            Ok(transfers) => transfers,
+            Err(e) => {
+                eprintln!("Failed to load transfers: {}", e);
+                return Vec::new();
+            }
+        };
+        let mut events = Vec::new();
+        for transfer in transfers {
+            if self.downloads.contains_key(&transfer.id) {
+                continue;
+            }
+            let resumed = store
+                .set_transfer_state(&transfer.id, TransferState::Transferring)
+                .and_then(|_| self.start(store, &transfer.id));
+            match resumed {
+                Ok(started) => events.extend(started),
+                Err(e) => {
+                    eprintln!("Failed to resume transfer {}: {}", transfer.id, e);
+                    events.extend(self.finish(store, &transfer.id, TransferState::Failed).unwrap_or_default());
+                }
+            }
+        }
+        events
+    }
+
+    pub fn handle_event(
+        &mut self,
+        store: &mut MessageStore,
+        swarm: &mut Swarm<VoidBehaviour>,
+        event: request_response::Event<FileRequest, FileResponse>,
+    ) -> Vec<FileEvent> {
+        match event {
+            request_response::Event::Message { peer, message, .. } => match message {
+                // Answered from `on_disk` once the chunk is read
+                Message::Request {
+                    request: FileRequest::Chunk { transfer_id, index },
+                    channel,
+                    ..
+                } => {
+                    match self.chunk_reader(store, &peer, &transfer_id, index) {
+                        Ok(read) => self.spawn_disk(move || Disk::ChunkRead {
+                            transfer_id,
+                            index,
+                            channel,
+                            result: read(),
+                        }),
+                        Err(reason) => {
+                            let _ = swarm
+                                .behaviour_mut()
+                                .files
+                                .send_response(channel, FileResponse::Rejected { reason });
+                        }
+                    }
+                    Vec::new()
+                }
+                Message::Request { request, channel, .. } => {
+                    let (response, events) = match self.on_request(store, &peer, request) {
+                        Ok(handled) => handled,
+                        Err(reason) => (FileResponse::Rejected { reason }, Vec::new()),
+                    };
+                    let _ = swarm.behaviour_mut().files.send_response(channel, response);
+                    events
+                }
+                Message::Response { request_id, response } => {
+                    let download = self.download_of(&request_id);
+                    match self.on_response(store, swarm, &peer, request_id, response) {
+                        Ok(events) => events,
+                        Err(e) => {
+                            eprintln!("File transfer with {} failed: {}", peer, e);
+                            download
+                                .and_then(|id| self.finish(store, &id, TransferState::Failed).ok())
+                                .unwrap_or_default()
+                        }
+                    }
+                }
+            },
+            request_response::Event::OutboundFailure { peer, request_id, error, .. } => {
+                let Some(id) = self.download_of(&request_id) else {
+                    return Vec::new();
+                };
+                println!("Pausing transfer {} from {}: {}", id, peer, error);
+                // Picked up again by `on_connected`
+                self.downloads.remove(&id);
+                self.update(store, &id, TransferState::Paused).unwrap_or_default()
+            }
+            request_response::Event::InboundFailure { peer, error, .. } => {
+                println!("File request from {} failed: {}", peer, error);
+                Vec::new()
+            }
+            request_response::Event::ResponseSent { .. } => Vec::new(),
+        }
+    }
+
+    fn on_request(
+        &mut self,
+        store: &mut MessageStore,
+        peer: &PeerId,
+        request: FileRequest,
+    ) -> Result<(FileResponse, Vec<FileEvent>), String> {
+        let transfer_id = match &request {
+            FileRequest::Chunk { transfer_id, .. }
+            | FileRequest::Reject { transfer_id, .. }
+            | FileRequest::Complete { transfer_id }
+            | FileRequest::Cancel { transfer_id } => transfer_id,
+        };
+        let transfer = active_transfer(store, peer, transfer_id)?;
+
+        match request {
+            FileRequest::Reject { reason, .. } if transfer.outgoing => {
+                println!("{} declined transfer {}: {}", peer, transfer.id, reason);
+                let state = if transfer.state == TransferState::Offered {
+                    TransferState::Rejected
+                } else {
+                    TransferState::Cancelled
+                };
+                Ok((FileResponse::Ack, self.finish(store, &transfer.id, state)?))
+            }
+            FileRequest::Complete { .. } if transfer.outgoing => {
+                store.set_transfer_progress(&transfer.id, transfer.size)?;
+                Ok((FileResponse::Ack, self.finish(store, &transfer.id, TransferState::Complete)?))
+            }
+            FileRequest::Cancel { .. } if !transfer.outgoing => {
+                Ok((FileResponse::Ack, self.finish(store, &transfer.id, TransferState::Cancelled)?))
+            }
+            _ => Err("Unexpected request".into()),
+        }
+    }
+
+    fn on_response(
+        &mut self,
+        store: &mut MessageStore,
+        swarm: &mut Swarm<VoidBehaviour>,
+        peer: &PeerId,
+        request_id: OutboundRequestId,
+        response: FileResponse,
+    ) -> Result<Vec<FileEvent>, String> {
+        let Some(id) = self.download_of(&request_id) else {
+            // Answer to a reject, complete or cancel
+            if let FileResponse::Rejected { reason } = response {
+                println!("{} rejected file request: {}", peer, reason);
+            }
+            return Ok(Vec::new());
+        };
+        let transfer = store.transfer(&id)?.ok_or("Unknown transfer")?;
+        let download = self.downloads.get_mut(&id).ok_or("Unknown transfer")?;
+        let index = download.pending.remove(&request_id).ok_or("Unexpected chunk")?;
+
+        let data = match response {
+            FileResponse::Chunk {
+                transfer_id,
+                index: got,
+                data,
+            } if transfer_id == id && got == index => data,
+            FileResponse::Rejected { reason } => return Err(reason),
+            _ => return Err("Unexpected response".into()),
+        };
+        let chunk = vault::open_with_key(&download.key, &chunk_aad(&id, index), &data)?;
+        if chunk.len() as u64 != chunk_len(&transfer, index) {
+            return Err(format!("Chunk {} has the wrong length", index));
+        }
+        download.early.insert(index, chunk);
+
+        self.write_ready(&transfer);
+        self.pump(swarm, &transfer);
+        Ok(Vec::new())
+    }
+
+    /// Applies a finished disk job.
+    pub fn on_disk(
+        &mut self,
+        store: &mut MessageStore,
+        swarm: &mut Swarm<VoidBehaviour>,
+        done: DiskDone,
+    ) -> Vec<FileEvent> {
+        let (id, result) = match done.0 {
+            Disk::ChunkRead {
+                transfer_id,
+                index,
+                channel,
+                result,
+            } => {
+                let (response, events) = match result.and_then(|data| self.on_chunk_read(store, &transfer_id, index, data)) {
+                    Ok(handled) => handled,
+                    Err(reason) => (FileResponse::Rejected { reason }, Vec::new()),
+                };
+                let _ = swarm.behaviour_mut().files.send_response(channel, response);
+                return events;
+            }
+            Disk::Opened { id, generation, .. }
+            | Disk::Written { id, generation, .. }
+            | Disk::Verified { id, generation, .. }
+                if !self.downloads.get(&id).is_some_and(|d| d.generation == generation) =>
+            {
+                discard_stale(store, &id);
+                return Vec::new();
+            }
+            Disk::Opened { id, result, .. } => {
+                let result = result.and_then(|(file, hash, written)| self.on_opened(store, swarm, &id, file, hash, written));
+                (id, result)
+            }
+            Disk::Written { id, chunks, result, .. } => {
+                let result = result.and_then(|(file, hash)| self.on_written(store, swarm, &id, file, hash, chunks));
+                (id, result)
+            }
+            Disk::Verified { id, result, .. } => {
+                let result = result.and_then(|matched| self.on_verified(store, swarm, &id, matched));
+                (id, result)
+            }
+        };
+        result.unwrap_or_else(|e| {
+            eprintln!("File transfer {} failed: {}", id, e);
+            self.finish(store, &id, TransferState::Failed).unwrap_or_default()
+        })
+    }
+
+    /// Validates a chunk request and returns the job that reads the chunk.
+    fn chunk_reader(
+        &self,
+        store: &MessageStore,
+        peer: &PeerId,
+        transfer_id: &str,
+        index: u64,
+    ) -> Result<impl FnOnce() -> Result<Vec<u8>, String> + Send + 'static, String> {
+        let transfer = active_transfer(store, peer, transfer_id)?;
+        if !transfer.outgoing || index >= transfer.chunk_count() {
+            return Err("Invalid chunk request".into());
+        }
+        let key = store.transfer_key(&transfer.id)?.ok_or("Missing transfer key")?;
+        Ok(move || read_chunk(&key, &transfer, index))
+    }
+
+    fn on_chunk_read(
+        &mut self,
+        store: &mut MessageStore,
+        transfer_id: &str,
+        index: u64,
+        data: Vec<u8>,
+    ) -> Result<(FileResponse, Vec<FileEvent>), String> {
+        let transfer = store.transfer(transfer_id)?.ok_or("Unknown transfer")?;
+        // Cancelled while the chunk was read
+        if transfer.state.is_finished() {
+            return Err(format!("Transfer {:?}", transfer.state).to_lowercase());
+        }
+        let sent = (index * transfer.chunk_size + chunk_len(&transfer, index)).max(transfer.transferred);
+        store.set_transfer_progress(&transfer.id, sent)?;
+        let events = self.update(store, &transfer.id, TransferState::Transferring)?;
+        let response = FileResponse::Chunk {
+            transfer_id: transfer.id,
+            index,
+            data,
+        };
+        Ok((response, events))
+    }
+
+    fn on_opened(
+        &mut self,
+        store: &mut MessageStore,
+        swarm: &mut Swarm<VoidBehaviour>,
+        id: &str,
+        file: File,
+        hash: RunningHash,
+        written: u64,
+    ) -> Result<Vec<FileEvent>, String> {
+        let transfer = store.transfer(id)?.ok_or("Unknown transfer")?;
+        store.save_download_progress(id, hash.len, &hash.to_bytes())?;
+        let download = self.downloads.get_mut(id).ok_or("Unknown transfer")?;
+        download.part = Some((file, hash));
+        download.written = written;
+        download.next = written;
+
+        if written == transfer.chunk_count() {
+            return self.complete(&transfer);
+        }
+        self.pump(swarm, &transfer);
+        self.update(store, id, TransferState::Transferring)
+    }
+
+    fn on_written(
+        &mut self,
+        store: &mut MessageStore,
+        swarm: &mut Swarm<VoidBehaviour>,
+        id: &str,
+        file: File,
+        hash: RunningHash,
+        chunks: u64,
+    ) -> Result<Vec<FileEvent>, String> {
+        let transfer = store.transfer(id)?.ok_or("Unknown transfer")?;
+        store.save_download_progress(id, hash.len, &hash.to_bytes())?;
+        let download = self.downloads.get_mut(id).ok_or("Unknown transfer")?;
+        download.part = Some((file, hash));
+        download.written += chunks;
+
+        if download.written == transfer.chunk_count() {
+            return self.complete(&transfer);
+        }
+        self.write_ready(&transfer);
+        self.pump(swarm, &transfer);
+        self.update(store, id, TransferState::Transferring)
+    }
+
+    fn on_verified(
+        &mut self,
+        store: &mut MessageStore,
+        swarm: &mut Swarm<VoidBehaviour>,
+        id: &str,
+        matched: bool,
+    ) -> Result<Vec<FileEvent>, String> {
+        let peer = self.downloads.get(id).ok_or("Unknown transfer")?.peer;
+        if !matched {
+            send(swarm, &peer, FileRequest::Reject {
+                transfer_id: id.to_string(),
+                reason: "Hash mismatch".into(),
+            });
+            return self.finish(store, id, TransferState::Failed);
+        }
+        send(swarm, &peer, FileRequest::Complete {
+            transfer_id: id.to_string(),
+        });
+        self.finish(store, id, TransferState::Complete)
+    }
+
+    /// Runs `job` on the blocking pool; its result comes back through `next_disk`.
+    fn spawn_disk(&self, job: impl FnOnce() -> Disk + Send + 'static) {
+        let done = self.disk_tx.clone();
+        tokio::task::spawn_blocking(move || {
+            let _ = done.send(DiskDone(job()));
+        });
+    }
+
+    /// Hands the chunks that are now contiguous to a write job, unless one is
+    /// already running.
+    fn write_ready(&mut self, transfer: &FileTransfer) {
+        let Some(download) = self.downloads.get_mut(&transfer.id) else {
+            return;
+        };
+        if !download.early.contains_key(&download.written) {
+            return;
+        }
+        let Some((mut file, mut hash)) = download.part.take() else {
+            return;
+        };
+        let mut chunks = Vec::new();
+        while let Some(chunk) = download.early.remove(&(download.written + chunks.len() as u64)) {
+            chunks.push(chunk);
+        }
+
+        let (id, generation, count) = (transfer.id.clone(), download.generation, chunks.len() as u64);
+        self.spawn_disk(move || {
+            let result = chunks
+                .iter()
+                .try_for_each(|chunk| -> std::io::Result<()> {
+                    file.write_all(chunk)?;
+                    hash.update(chunk);
+                    Ok(())
+                })
+                // The saved hash must not get ahead of what is on disk
+                .and_then(|_| file.sync_data())
+                .map(|_| (file, hash))
+                .map_err(|e| e.to_string());
+            Disk::Written {
+                id,
+                generation,
+                chunks: count,
+                result,
+            }
+        });
+    }
+
+    /// Opens the part file of an accepted download in the background. Chunks
+    /// are requested from where the saved hash left off once it is open.
+    fn start(&mut self, store: &mut MessageStore, id: &str) -> Result<Vec<FileEvent>, String> {
+        let transfer = store.transfer(id)?.ok_or("Unknown transfer")?;
+        let key = store.transfer_key(id)?.ok_or("Missing transfer key")?;
+        let peer = parse_peer(&transfer.peer_id)?;
+        let path = part_path(transfer.path.as_deref().ok_or("Transfer has no destination")?);
+
+        // Resume only where the saved hash covers exactly the chunks written;
+        // anything else starts over
+        let written = if transfer.transferred == transfer.size {
+            transfer.chunk_count()
+        } else {
+            transfer.transferred / transfer.chunk_size
+        };
+        let (written, hash) = match store.download_hash(id)?.and_then(|bytes| RunningHash::from_bytes(&bytes)) {
+            Some(hash) if hash.len == (written * transfer.chunk_size).min(transfer.size) => (written, hash),
+            _ => (0, RunningHash::new()),
+        };
+
+        self.generation += 1;
+        let generation = self.generation;
+        self.downloads.insert(
+            id.to_string(),
+            Download {
+                generation,
+                peer,
+                key,
+                part: None,
+                written,
+                next: written,
+                pending: HashMap::new(),
+                early: BTreeMap::new(),
+            },
+        );
+        let job_id = id.to_string();
+        self.spawn_disk(move || Disk::Opened {
+            result: open_part(&path, written, hash),
+            id: job_id,
+            generation,
+        });
+        self.update(store, id, TransferState::Transferring)
+    }
+
+    /// Keeps up to `WINDOW` chunks requested ahead of what is written.
+    fn pump(&mut self, swarm: &mut Swarm<VoidBehaviour>, transfer: &FileTransfer) {
+        let Some(download) = self.downloads.get_mut(&transfer.id) else {
+            return;
+        };
+        while download.next < download.written + WINDOW && download.next < transfer.chunk_count() {
+            let index = download.next;
+            let request_id = swarm.behaviour_mut().files.send_request(
+                &download.peer,
+                FileRequest::Chunk {
+                    transfer_id: transfer.id.clone(),
+                    index,
+                },
+            );
+            download.pending.insert(request_id, index);
+            download.next += 1;
+        }
+    }
+
+    /// Checks the hash of a fully received file and moves it into place in
+    /// the background; `on_verified` reports back to the peer.
+    fn complete(&mut self, transfer: &FileTransfer) -> Result<Vec<FileEvent>, String> {
+        let download = self.downloads.get_mut(&transfer.id).ok_or("Unknown transfer")?;
+        let (file, hash) = download.part.take().ok_or("Transfer is busy")?;
+        let path = transfer.path.clone().ok_or("Transfer has no destination")?;
+        let (id, generation, expected) = (transfer.id.clone(), download.generation, transfer.sha256.clone());
+        self.spawn_disk(move || {
+            let result = file.sync_all().map_err(|e| e.to_string()).and_then(|_| {
+                if hash.finalize() != expected {
+                    return Ok(false);
+                }
+                fs::rename(part_path(&path), &path).map_err(|e| e.to_string())?;
+                Ok(true)
+            });
+            Disk::Verified { id, generation, result }
+        });
+        Ok(Vec::new())
+    }
+
+    /// Moves a transfer to a final state. Unfinished downloads lose their part file.
+    fn finish(&mut self, store: &mut MessageStore, id: &str, state: TransferState) -> Result<Vec<FileEvent>, String> {
+        self.downloads.remove(id);
+        if state != TransferState::Complete {
+            if let Some(path) = store.transfer(id)?.filter(|t| !t.outgoing).and_then(|t| t.path) {
+                let _ = fs::remove_file(part_path(&path));
+            }
+        }
+        let events = self.update(store, id, state)?;
+        self.reported.remove(id);
+        Ok(events)
+    }
+
+    /// Stores `state` and reports the transfer, unless it only moved by less
+    /// than a percent.
+    fn update(&mut self, store: &mut MessageStore, id: &str, state: TransferState) -> Result<Vec<FileEvent>, String> {
+        let previous = store.transfer(id)?.ok_or("Unknown transfer")?.state;
+        if previous != state {
+            store.set_transfer_state(id, state)?;
+        }
+        let transfer = store.transfer(id)?.ok_or("Unknown transfer")?;
+        let percent = (transfer.transferred * 100).checked_div(transfer.size).unwrap_or(100);
+        if previous == state && self.reported.get(id) == Some(&percent) {
+            return Ok(Vec::new());
+        }
+        self.reported.insert(id.to_string(), percent);
+        Ok(vec![FileEvent::Progress { transfer }])
+    }
+
+    fn download_of(&self, request_id: &OutboundRequestId) -> Option<String> {
+        self.downloads
+            .iter()
+            .find(|(_, download)| download.pending.contains_key(request_id))
+            .map(|(id, _)| id.clone())
+    }
+}
+
+/// A transfer with `peer` that is still going.
+fn active_transfer(store: &MessageStore, peer: &PeerId, transfer_id: &str) -> Result<FileTransfer, String> {
+    let transfer = store
+        .transfer(transfer_id)?
+        .filter(|t| t.peer_id == peer.to_string())
+        .ok_or("Unknown transfer")?;
+    if transfer.state.is_finished() {
+        return Err(format!("Transfer {:?}", transfer.state).to_lowercase());
+    }
+    Ok(transfer)
+}
+
+/// Opens `<dest>.part` after the first `written` chunks, which `hash` covers.
+/// Starts over if less than that made it to disk.
+fn open_part(path: &Path, written: u64, hash: RunningHash) -> Result<(File, RunningHash, u64), String> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+        .map_err(|e| e.to_string())?;
+    let on_disk = file.metadata().map_err(|e| e.to_string())?.len();
+    let (written, hash) = if on_disk < hash.len {
+        (0, RunningHash::new())
+    } else {
+        (written, hash)
+    };
+    // Drop a partial write from before a crash
+    file.set_len(hash.len).map_err(|e| e.to_string())?;
+    file.seek(SeekFrom::Start(hash.len)).map_err(|e| e.to_string())?;
+    Ok((file, hash, written))
+}
+
+/// A disk job finished after its download was stopped. Make sure a cancelled
+/// or failed download leaves no part file behind.
+fn discard_stale(store: &MessageStore, id: &str) {
+    if let Ok(Some(transfer)) = store.transfer(id) {
+        if transfer.state.is_finished() && transfer.state != TransferState::Complete {
+            if let Some(path) = transfer.path {
+                let _ = fs::remove_file(part_path(&path));
+            }
+        }
+    }
+}
+
+/// Reads and seals chunk `index` of an outgoing file. Blocks on disk I/O.
+fn read_chunk(key: &[u8; 32], transfer: &FileTransfer, index: u64) -> Result<Vec<u8>, String> {
+    let path = transfer.path.as_deref().ok_or("File unavailable")?;
+    let mut file = File::open(path).map_err(|_| "File unavailable".to_string())?;
+    if file.metadata().map_err(|e| e.to_string())?.len() != transfer.size {
+        return Err("File changed since it was offered".into());
+    }
+    let mut chunk = vec![0u8; chunk_len(transfer, index) as usize];
+    file.seek(SeekFrom::Start(index * transfer.chunk_size))
+        .map_err(|e| e.to_string())?;
+    file.read_exact(&mut chunk).map_err(|e| e.to_string())?;
+    vault::seal_with_key(key, &chunk_aad(&transfer.id, index), &chunk)
+}
+
+fn send(swarm: &mut Swarm<VoidBehaviour>, peer: &PeerId, request: FileRequest) {
+    swarm.behaviour_mut().files.send_request(peer, request);
+}
+
+fn parse_peer(peer_id: &str) -> Result<PeerId, String> {
+    peer_id.parse().map_err(|e| format!("Invalid PeerId: {}", e))
+}
+
+/// Offers a file to a peer and returns the transfer. Hashing happens here,
+/// before the swarm task sees the file.
+#[tauri::command]
+pub async fn send_file(
+    peer_id: String,
+    file_path: String,
+    state: State<'_, NetworkState>,
+) -> Result<FileTransfer, String> {
+    let peer = parse_peer(&peer_id)?;
+    let file = tauri::async_runtime::spawn_blocking(move || LocalFile::prepare(Path::new(&file_path)))
+        .await
+        .map_err(|e| e.to_string())??;
+    request(&state, |tx| NetworkCommand::SendFile(peer, file, tx)).await
+}
+
+/// Accepts an offered file. It is saved to `directory`, or the download
+/// directory if none is given.
+#[tauri::command]
+pub async fn accept_file(
+    transfer_id: String,
+    directory: Option<String>,
+    state: State<'_, NetworkState>,
+) -> Result<(), String> {
+    request(&state, |tx| NetworkCommand::AcceptFile(transfer_id, directory.map(PathBuf::from), tx)).await
+}
+
+#[tauri::command]
+pub async fn reject_file(transfer_id: String, state: State<'_, NetworkState>) -> Result<(), String> {
+    request(&state, |tx| NetworkCommand::RejectFile(transfer_id, tx)).await
+}
+
+#[tauri::command]
+pub async fn cancel_file(transfer_id: String, state: State<'_, NetworkState>) -> Result<(), String> {
+    request(&state, |tx| NetworkCommand::CancelFile(transfer_id, tx)).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn running_hash_matches_sha256_across_saves() {
+        let data: Vec<u8> = (0..10_000u32).map(|i| (i * 31 % 251) as u8).collect();
+        let expected = format!("{:x}", Sha256::digest(&data));
+
+        for split in [0, 1, 63, 64, 65, 4096, 9_999, 10_000] {
+            let mut hash = RunningHash::new();
+            hash.update(&data[..split]);
+            let mut hash = RunningHash::from_bytes(&hash.to_bytes()).unwrap();
+            for piece in data[split..].chunks(1000) {
+                hash.update(piece);
+            }
+            assert_eq!(hash.finalize(), expected, "split at {}", split);
+        }
+        assert_eq!(RunningHash::new().finalize(), format!("{:x}", Sha256::digest(b"")));
+    }
+
+    #[test]
+    fn running_hash_rejects_inconsistent_state() {
+        let mut hash = RunningHash::new();
+        hash.update(&[7; 100]);
+        let bytes = hash.to_bytes();
+        assert!(RunningHash::from_bytes(&bytes[..bytes.len() - 1]).is_none());
+        assert!(RunningHash::from_bytes(&bytes[..39]).is_none());
+    }
+}
@@ -0,0 +1,518 @@
+// Group chats
+//
+// Groups ride on the pairwise sessions: every member gets its own copy through
+// the outbox, so group traffic is retried and can be left in mailboxes like any
+// other message. The owner manages membership and hands out a fresh key with a
+// new epoch on every change. Group messages are sealed with that key, so a
+// removed member can't read anything sent after it was removed. An owner that
+// leaves hands the group over without a key; the new owner re-keys it.
+use crate::network::messaging::{self, MessageContent, Messenger};
+use crate::network::outbox::{self, StatusChange};
+use crate::network::swarm::VoidBehaviour;
+use crate::network::{NetworkCommand, NetworkState, request};
+use crate::security::crypto;
+use crate::storage::db::MessageStore;
+use crate::storage::groups::Group;
+use libp2p::{PeerId, Swarm};
+use serde::{Deserialize, Serialize};
+use tauri::State;
+
+/// Upper bound on members, owner included; every message is sent once per member.
+pub const MAX_GROUP_MEMBERS: usize = 64;
+const MAX_NAME_LEN: usize = 100;
+
+/// Sent by the owner to every member whenever the group changes.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupState {
+    pub group_id: String,
+    pub name: String,
+    pub owner: String,
+    pub members: Vec<String>,
+    pub epoch: u64,
+    /// `None` when a leaving owner hands the group over: it must not pick the
+    /// key the group moves on with.
+    pub key: Option<[u8; 32]>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct GroupMessage {
+    pub group_id: String,
+    /// Same for every member's copy, unlike the envelope id.
+    pub id: String,
+    pub epoch: u64,
+    pub nonce: Vec<u8>,
+    pub ciphertext: Vec<u8>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum GroupEvent {
+    /// Joined a group, or its name, members or key changed.
+    Updated { group: Group },
+    #[serde(rename_all = "camelCase")]
+    Message {
+        group_id: String,
+        id: String,
+        sender: String,
+        sent_at: i64,
+        text: String,
+    },
+    /// We left or were removed.
+    #[serde(rename_all = "camelCase")]
+    Left { group_id: String, removed: bool },
+}
+
+/// What incoming group traffic produced for the rest of the node.
+#[derive(Default)]
+pub struct GroupOutcome {
+    pub events: Vec<GroupEvent>,
+    /// Status of updates the owner sent out in response.
+    pub changes: Vec<StatusChange>,
+}
+
+fn message_aad(group_id: &str, epoch: u64, id: &str) -> Vec<u8> {
+    format!("group-message:{}:{}:{}", group_id, epoch, id).into_bytes()
+}
+
+pub fn create(
+    store: &mut MessageStore,
+    messenger: &mut Messenger,
+    swarm: &mut Swarm<VoidBehaviour>,
+    name: &str,
+    members: Vec<PeerId>,
+) -> Result<(Group, Vec<StatusChange>), String> {
+    let name = name.trim();
+    if name.is_empty() || name.len() > MAX_NAME_LEN {
+        return Err(format!("Group name must be 1-{} characters", MAX_NAME_LEN));
+    }
+    let local = swarm.local_peer_id().to_string();
+    let mut member_ids = vec![local.clone()];
+    for peer in members {
+        let peer = peer.to_string();
+        if !member_ids.contains(&peer) {
+            member_ids.push(peer);
+        }
+    }
+    if member_ids.len() > MAX_GROUP_MEMBERS {
+        return Err(format!("Groups are limited to {} members", MAX_GROUP_MEMBERS));
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let group = Group {
+        id: format!("{:032x}", rand::random::<u128>()),
+        name: name.to_string(),
+        owner: local,
+        members: member_ids,
+        epoch: 1,
+        active: true,
+        created_at: now,
+        updated_at: now,
+    };
+    rekey(store, messenger, swarm, group)
+}
+
+pub fn invite(
+    store: &mut MessageStore,
+    messenger: &mut Messenger,
+    swarm: &mut Swarm<VoidBehaviour>,
+    group_id: &str,
+    peer: PeerId,
+) -> Result<(Group, Vec<StatusChange>), String> {
+    let mut group = owned_group(store, swarm, group_id)?;
+    let peer = peer.to_string();
+    if group.members.contains(&peer) {
+        return Err("Already a member".into());
+    }
+    if group.members.len() >= MAX_GROUP_MEMBERS {
+        return Err(format!("Groups are limited to {} members", MAX_GROUP_MEMBERS));
+    }
+    group.members.push(peer);
+    group.epoch += 1;
+    rekey(store, messenger, swarm, group)
+}
+
+pub fn remove(
+    store: &mut MessageStore,
+    messenger: &mut Messenger,
+    swarm: &mut Swarm<VoidBehaviour>,
+    group_id: &str,
+    peer: PeerId,
+) -> Result<(Group, Vec<StatusChange>), String> {
+    let mut group = owned_group(store, swarm, group_id)?;
+    let member = peer.to_string();
+    if member == group.owner {
+        return Err("Leave the group instead of removing yourself".into());
+    }
+    if !group.members.contains(&member) {
+        return Err("Not a member".into());
+    }
+    group.members.retain(|m| *m != member);
+    group.epoch += 1;
+    let (group, mut changes) = rekey(store, messenger, swarm, group)?;
+
+    let notice = MessageContent::GroupLeave {
+        group_id: group.id.clone(),
+        member,
+    };
+    changes.extend(outbox::send_control(store, messenger, swarm, peer, &notice)?);
+    Ok((group, changes))
+}
+
+/// Leaves a group. An owner hands the group to the longest-standing member,
+/// which re-keys it; everyone else asks the owner to re-key without them.
+pub fn leave(
+    store: &mut MessageStore,
+    messenger: &mut Messenger,
+    swarm: &mut Swarm<VoidBehaviour>,
+    group_id: &str,
+) -> Result<Vec<StatusChange>, String> {
+    let mut group = store.group(group_id)?.ok_or("Unknown group")?;
+    if !group.active {
+        return Err("Not a member of this group".into());
+    }
+    let local = swarm.local_peer_id().to_string();
+    group.members.retain(|m| *m != local);
+
+    let mut changes = Vec::new();
+    if group.owner == local {
+        if let Some(next) = group.members.first().cloned() {
+            group.owner = next;
+            changes = broadcast_state(store, messenger, swarm, &group, None);
+        }
+    } else {
+        let owner = group.owner.parse::<PeerId>().map_err(|e| e.to_string())?;
+        let notice = MessageContent::GroupLeave {
+            group_id: group.id.clone(),
+            member: local,
+        };
+        changes = outbox::send_control(store, messenger, swarm, owner, &notice)?;
+    }
+    store.deactivate_group(&group.id)?;
+    Ok(changes)
+}
+
+/// Seals `text` with the group key and sends a copy to every other member.
+/// Returns the message id.
+pub fn send(
+    store: &mut MessageStore,
+    messenger: &mut Messenger,
+    swarm: &mut Swarm<VoidBehaviour>,
+    group_id: &str,
+    text: String,
+) -> Result<(String, Vec<StatusChange>), String> {
+    let group = store.group(group_id)?.ok_or("Unknown group")?;
+    if !group.active {
+        return Err("Not a member of this group".into());
+    }
+    let key = store.group_key(&group.id, group.epoch)?.ok_or("Missing group key")?;
+
+    let id = messaging::new_message_id();
+    let sent_at = chrono::Utc::now().timestamp();
+    let (nonce, ciphertext) =
+        crypto::seal_group(&key, &message_aad(&group.id, group.epoch, &id), text.as_bytes())?;
+    store.insert_group_message(&id, &group.id, None, &text, sent_at)?;
+
+    let content = MessageContent::GroupMessage(GroupMessage {
+        group_id: group.id.clone(),
+        id: id.clone(),
+        epoch: group.epoch,
+        nonce,
+        ciphertext,
+    });
+    Ok((id, fan_out(store, messenger, swarm, &group, &content)))
+}
+
+/// Applies group traffic received from `peer`.
+pub fn on_received(
+    store: &mut MessageStore,
+    messenger: &mut Messenger,
+    swarm: &mut Swarm<VoidBehaviour>,
+    peer: &PeerId,
+    sent_at: i64,
+    content: &MessageContent,
+) -> GroupOutcome {
+    let local = swarm.local_peer_id().to_string();
+    let sender = peer.to_string();
+    let mut outcome = GroupOutcome::default();
+
+    let result = match content {
+        MessageContent::Text { .. } | MessageContent::FileOffer(_) => Ok(()),
+        MessageContent::GroupState(state) => on_state(store, &local, &sender, state).and_then(|event| {
+            match event {
+                // Handed the group: the old owner still has the current key
+                Some(GroupEvent::Updated { mut group }) if state.key.is_none() && group.owner == local => {
+                    group.epoch += 1;
+                    let (group, changes) = rekey(store, messenger, swarm, group)?;
+                    outcome.events.push(GroupEvent::Updated { group });
+                    outcome.changes = changes;
+                }
+                event => outcome.events.extend(event),
+            }
+            Ok(())
+        }),
+        MessageContent::GroupMessage(message) => {
+            on_message(store, &sender, sent_at, message).map(|event| outcome.events.push(event))
+        }
+        MessageContent::GroupLeave { group_id, member } => {
+            on_leave(store, messenger, swarm, &local, &sender, group_id, member).map(|(event, changes)| {
+                outcome.events.extend(event);
+                outcome.changes = changes;
+            })
+        }
+    };
+    if let Err(e) = result {
+        eprintln!("Ignoring group update from {}: {}", peer, e);
+    }
+    outcome
+}
+
+fn on_state(
+    store: &mut MessageStore,
+    local: &str,
+    sender: &str,
+    state: &GroupState,
+) -> Result<Option<GroupEvent>, String> {
+    let existing = store.group(&state.group_id)?;
+    let Some(key) = state.key else {
+        return on_handover(store, sender, existing, state);
+    };
+    match &existing {
+        // Duplicate or reordered update
+        Some(group) if state.epoch <= group.epoch => return Ok(None),
+        Some(group) if group.owner != sender => return Err("update from a non-owner".into()),
+        None if state.owner != sender => return Err("invitation from a non-owner".into()),
+        _ => {}
+    }
+    if state.members.len() > MAX_GROUP_MEMBERS || state.name.len() > MAX_NAME_LEN {
+        return Err("group exceeds limits".into());
+    }
+    if !state.members.iter().any(|m| m == local) {
+        let removed = existing.is_some() && store.deactivate_group(&state.group_id)?;
+        return Ok(removed.then(|| GroupEvent::Left {
+            group_id: state.group_id.clone(),
+            removed: true,
+        }));
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let group = Group {
+        id: state.group_id.clone(),
+        name: state.name.clone(),
+        owner: state.owner.clone(),
+        members: state.members.clone(),
+        epoch: state.epoch,
+        active: true,
+        created_at: existing.map(|g| g.created_at).unwrap_or(now),
+        updated_at: now,
+    };
+    store.save_group(&group, &key)?;
+    Ok(Some(GroupEvent::Updated { group }))
+}
+
+/// The owner left and passed the group on, keeping the current epoch.
+fn on_handover(
+    store: &mut MessageStore,
+    sender: &str,
+    existing: Option<Group>,
+    state: &GroupState,
+) -> Result<Option<GroupEvent>, String> {
+    let Some(mut group) = existing.filter(|g| g.active) else {
+        return Err("handover of an unknown group".into());
+    };
+    if group.owner != sender {
+        return Err("handover from a non-owner".into());
+    }
+    if state.epoch != group.epoch
+        || state.members.iter().any(|m| m == sender)
+        || !state.members.iter().any(|m| *m == state.owner)
+        || state.members.iter().any(|m| !group.members.contains(m))
+    {
+        return Err("invalid handover".into());
+    }
+
+    store.hand_over_group(&group.id, &state.owner, &state.members)?;
+    group.owner = state.owner.clone();
+    group.members = state.members.clone();
+    group.updated_at = chrono::Utc::now().timestamp();
+    Ok(Some(GroupEvent::Updated { group }))
+}
+
+fn on_message(
+    store: &mut MessageStore,
+    sender: &str,
+    sent_at: i64,
+    message: &GroupMessage,
+) -> Result<GroupEvent, String> {
+    let group = store.group(&message.group_id)?.ok_or("unknown group")?;
+    if !group.active || !group.members.iter().any(|m| m == sender) {
+        return Err("message from a non-member".into());
+    }
+    let key = store
+        .group_key(&group.id, message.epoch)?
+        .ok_or("message sealed with an outdated group key")?;
+    let plaintext = crypto::open_group(
+        &key,
+        &message_aad(&group.id, message.epoch, &message.id),
+        &message.nonce,
+        &message.ciphertext,
+    )?;
+    let text = String::from_utf8(plaintext).map_err(|e| e.to_string())?;
+
+    store.insert_group_message(&message.id, &group.id, Some(sender), &text, sent_at)?;
+    Ok(GroupEvent::Message {
+        group_id: group.id,
+        id: message.id.clone(),
+        sender: sender.to_string(),
+        sent_at,
+        text,
+    })
+}
+
+fn on_leave(
+    store: &mut MessageStore,
+    messenger: &mut Messenger,
+    swarm: &mut Swarm<VoidBehaviour>,
+    local: &str,
+    sender: &str,
+    group_id: &str,
+    member: &str,
+) -> Result<(Option<GroupEvent>, Vec<StatusChange>), String> {
+    let Some(mut group) = store.group(group_id)? else {
+        return Ok((None, Vec::new()));
+    };
+
+    // Removed by the owner
+    if member == local && sender == group.owner {
+        let event = store.deactivate_group(group_id)?.then(|| GroupEvent::Left {
+            group_id: group_id.to_string(),
+            removed: true,
+        });
+        return Ok((event, Vec::new()));
+    }
+
+    // A member left; only the owner re-keys, the others hear about it from there
+    if member != sender || group.owner != local || !group.active {
+        return Ok((None, Vec::new()));
+    }
+    if !group.members.iter().any(|m| m == member) {
+        return Ok((None, Vec::new()));
+    }
+    group.members.retain(|m| m != member);
+    group.epoch += 1;
+    let (group, changes) = rekey(store, messenger, swarm, group)?;
+    Ok((Some(GroupEvent::Updated { group }), changes))
+}
+
+/// The group, if we own it and are still in it.
+fn owned_group(store: &MessageStore, swarm: &Swarm<VoidBehaviour>, group_id: &str) -> Result<Group, String> {
+    let group = store.group(group_id)?.ok_or("Unknown group")?;
+    if !group.active || group.owner != swarm.local_peer_id().to_string() {
+        return Err("Only the group owner can change members".into());
+    }
+    Ok(group)
+}
+
+/// Stores `group` with a fresh key and sends the new state to its members.
+fn rekey(
+    store: &mut MessageStore,
+    messenger: &mut Messenger,
+    swarm: &mut Swarm<VoidBehaviour>,
+    mut group: Group,
+) -> Result<(Group, Vec<StatusChange>), String> {
+    let key = crypto::generate_group_key();
+    group.updated_at = chrono::Utc::now().timestamp();
+    store.save_group(&group, &key)?;
+    let changes = broadcast_state(store, messenger, swarm, &group, Some(key));
+    Ok((group, changes))
+}
+
+fn broadcast_state(
+    store: &mut MessageStore,
+    messenger: &mut Messenger,
+    swarm: &mut Swarm<VoidBehaviour>,
+    group: &Group,
+    key: Option<[u8; 32]>,
+) -> Vec<StatusChange> {
+    let state = MessageContent::GroupState(GroupState {
+        group_id: group.id.clone(),
+        name: group.name.clone(),
+        owner: group.owner.clone(),
+        members: group.members.clone(),
+        epoch: group.epoch,
+        key,
+    });
+    fan_out(store, messenger, swarm, group, &state)
+}
+
+/// Sends `content` to every member except us.
+fn fan_out(
+    store: &mut MessageStore,
+    messenger: &mut Messenger,
+    swarm: &mut Swarm<VoidBehaviour>,
+    group: &Group,
+    content: &MessageContent,
+) -> Vec<StatusChange> {
+    let local = *swarm.local_peer_id();
+    let mut changes = Vec::new();
+    for member in group.members.iter().filter_map(|m| m.parse::<PeerId>().ok()) {
+        if member == local {
+            continue;
+        }
+        match outbox::send_control(store, messenger, swarm, member, content) {
+            Ok(sent) => changes.extend(sent),
+            Err(e) => eprintln!("Failed to queue group traffic for {}: {}", member, e),
+        }
+    }
+    changes
+}
+
+fn parse_peer(peer_id: &str) -> Result<PeerId, String> {
+    peer_id.parse().map_err(|e| format!("Invalid PeerId: {}", e))
+}
+
+#[tauri::command]
+pub async fn create_group(
+    name: String,
+    members: Vec<String>,
+    state: State<'_, NetworkState>,
+) -> Result<Group, String> {
+    let members = members.iter().map(|m| parse_peer(m)).collect::<Result<Vec<_>, _>>()?;
+    request(&state, |tx| NetworkCommand::CreateGroup(name, members, tx)).await
+}
+
+#[tauri::command]
+pub async fn invite_to_group(
+    group_id: String,
+    peer_id: String,
+    state: State<'_, NetworkState>,
+) -> Result<Group, String> {
+    let peer = parse_peer(&peer_id)?;
+    request(&state, |tx| NetworkCommand::InviteToGroup(group_id, peer, tx)).await
+}
+
+#[tauri::command]
+pub async fn remove_from_group(
+    group_id: String,
+    peer_id: String,
+    state: State<'_, NetworkState>,
+) -> Result<Group, String> {
+    let peer = parse_peer(&peer_id)?;
+    request(&state, |tx| NetworkCommand::RemoveFromGroup(group_id, peer, tx)).await
+}
+
+#[tauri::command]
+pub async fn leave_group(group_id: String, state: State<'_, NetworkState>) -> Result<(), String> {
+    request(&state, |tx| NetworkCommand::LeaveGroup(group_id, tx)).await
+}
+
+/// Sends a text message to a group and returns its id.
+#[tauri::command]
+pub async fn send_group_message(
+    group_id: String,
+    text: String,
+    state: State<'_, NetworkState>,
+) -> Result<String, String> {
+    request(&state, |tx| NetworkCommand::SendGroupMessage(group_id, text, tx)).await
+}
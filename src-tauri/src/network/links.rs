@@ -0,0 +1,89 @@
+use libp2p::{
+    Multiaddr, PeerId,
+    core::ConnectedPoint,
+    multiaddr::Protocol,
+    swarm::ConnectionId,
+};
+use serde::Serialize;
+use std::collections::HashMap;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum LinkKind {
+    /// Only reachable through a `/p2p-circuit` relay hop.
+    Relayed,
+    /// At least one direct connection (possibly after hole punching).
+    Direct,
+}
+
+pub fn is_relayed(addr: &Multiaddr) -> bool {
+    addr.iter().any(|p| matches!(p, Protocol::P2pCircuit))
+}
+
+/// Tracks open connections per peer so the UI can show whether a peer link is
+/// relayed or direct.
+#[derive(Default)]
+pub struct PeerLinks {
+    connections: HashMap<PeerId, HashMap<ConnectionId, LinkKind>>,
+}
+
+impl PeerLinks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn kind_of(&self, peer: &PeerId) -> Option<LinkKind> {
+        let conns = self.connections.get(peer)?;
+        if conns.values().any(|k| *k == LinkKind::Direct) {
+            Some(LinkKind::Direct)
+        } else if conns.is_empty() {
+            None
+        } else {
+            Some(LinkKind::Relayed)
+        }
+    }
+
+    /// Records a new connection. Returns the peer's link kind if it changed.
+    pub fn on_established(
+        &mut self,
+        peer: PeerId,
+        connection_id: ConnectionId,
+        endpoint: &ConnectedPoint,
+    ) -> Option<LinkKind> {
+        let before = self.kind_of(&peer);
+        let kind = if is_relayed(endpoint.get_remote_address()) {
+            LinkKind::Relayed
+        } else {
+            LinkKind::Direct
+        };
+        self.connections
+            .entry(peer)
+            .or_default()
+            .insert(connection_id, kind);
+
+        let after = self.kind_of(&peer);
+        if after != before { after } else { None }
+    }
+
+    /// Records a closed connection. Returns `Some(new kind)` if the link kind
+    /// changed, where `None` inside means the peer is now disconnected.
+    pub fn on_closed(&mut self, peer: PeerId, connection_id: ConnectionId) -> Option<Option<LinkKind>> {
+        let before = self.kind_of(&peer);
+        if let Some(conns) = self.connections.get_mut(&peer) {
+            conns.remove(&connection_id);
+            if conns.is_empty() {
+                self.connections.remove(&peer);
+            }
+        }
+
+        let after = self.kind_of(&peer);
+        (after != before).then_some(after)
+    }
+
+    pub fn snapshot(&self) -> HashMap<String, LinkKind> {
+        self.connections
+            .keys()
+            .filter_map(|peer| Some((peer.to_string(), self.kind_of(peer)?)))
+            .collect()
+    }
+}
@@ -0,0 +1,517 @@
+// Store-and-forward mailboxes
+//
+// A mailbox node (a relay or headless CLI started with a mailbox database)
+// holds end-to-end encrypted envelopes for peers that registered with it,
+// until they come back online and fetch them. The node can't read the mail;
+// it only sees who sent what to whom and how big it is. The sender it reports
+// isn't trusted: a handshake has to be signed by that peer's node identity,
+// and stored mail never replaces an existing session, so a mailbox can't pass
+// off a message as someone else's.
+use crate::network::messaging::{MessageContent, Messenger, MessengerEvent};
+use crate::network::outbox::StatusChange;
+use crate::network::swarm::{MailboxItem, MailboxRequest, MailboxResponse, MessageEnvelope, VoidBehaviour};
+use crate::storage::db::MessageStore;
+use crate::storage::outbox::MessageStatus;
+use libp2p::{
+    PeerId, Swarm,
+    request_response::{self, Message, OutboundRequestId},
+};
+use rusqlite::{Connection, params};
+use serde::Serialize;
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+/// Envelopes requested per fetch round trip.
+const FETCH_BATCH: u32 = 50;
+const MAX_FETCH_BATCH: u32 = 100;
+const MAX_ENVELOPE_BYTES: usize = 256 * 1024;
+
+#[derive(Debug, Clone)]
+pub struct MailboxConfig {
+    /// How long envelopes are kept before they expire unfetched.
+    pub ttl_secs: u64,
+    /// How long a registration lasts without being renewed.
+    pub registration_ttl_secs: u64,
+    /// Per-owner quota.
+    pub max_messages: u32,
+    pub max_bytes: u64,
+    pub max_owners: u32,
+}
+
+impl Default for MailboxConfig {
+    fn default() -> Self {
+        Self {
+            ttl_secs: 7 * 24 * 60 * 60,
+            registration_ttl_secs: 30 * 24 * 60 * 60,
+            max_messages: 500,
+            max_bytes: 10 * 1024 * 1024,
+            max_owners: 1000,
+        }
+    }
+}
+
+/// Server side: mail held for registered owners, in its own SQLite file.
+pub struct MailboxServer {
+    conn: Connection,
+    config: MailboxConfig,
+}
+
+impl MailboxServer {
+    pub fn open(path: &Path, config: MailboxConfig) -> Result<Self, String> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let conn = Connection::open(path).map_err(|e| e.to_string())?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS owners (
+                peer_id TEXT PRIMARY KEY,
+                expires_at INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS mail (
+                seq INTEGER PRIMARY KEY AUTOINCREMENT,
+                recipient TEXT NOT NULL,
+                sender TEXT NOT NULL,
+                id TEXT NOT NULL,
+                deposited_at INTEGER NOT NULL,
+                expires_at INTEGER NOT NULL,
+                envelope BLOB NOT NULL,
+                UNIQUE(recipient, sender, id)
+            );
+            CREATE INDEX IF NOT EXISTS idx_mail_recipient ON mail(recipient, seq);",
+        )
+        .map_err(|e| format!("Failed to open mailbox: {}", e))?;
+        Ok(Self { conn, config })
+    }
+
+    /// Answers a request from `peer`. Never fails; errors become `Rejected`.
+    pub fn handle(&mut self, peer: &PeerId, request: MailboxRequest) -> MailboxResponse {
+        let result = self.purge_expired().and_then(|_| match request {
+            MailboxRequest::Register => self.register(peer),
+            MailboxRequest::Unregister => self.unregister(peer),
+            MailboxRequest::Deposit { recipient, envelope } => self.deposit(peer, &recipient, &envelope),
+            MailboxRequest::Fetch { limit } => self.fetch(peer, limit),
+            MailboxRequest::Ack { ids } => self.ack(peer, &ids),
+        });
+        result.unwrap_or_else(|reason| MailboxResponse::Rejected { reason })
+    }
+
+    /// Drops expired mail and registrations. Returns how many envelopes went.
+    pub fn purge_expired(&mut self) -> Result<usize, String> {
+        let now = chrono::Utc::now().timestamp();
+        let removed = self
+            .conn
+            .execute("DELETE FROM mail WHERE expires_at <= ?1", params![now])
+            .map_err(|e| e.to_string())?;
+        self.conn
+            .execute(
+                "DELETE FROM mail WHERE recipient IN (SELECT peer_id FROM owners WHERE expires_at <= ?1)",
+                params![now],
+            )
+            .map_err(|e| e.to_string())?;
+        self.conn
+            .execute("DELETE FROM owners WHERE expires_at <= ?1", params![now])
+            .map_err(|e| e.to_string())?;
+        Ok(removed)
+    }
+
+    fn is_owner(&self, peer_id: &str) -> Result<bool, String> {
+        self.conn
+            .query_row(
+                "SELECT EXISTS(SELECT 1 FROM owners WHERE peer_id = ?1)",
+                params![peer_id],
+                |row| row.get(0),
+            )
+            .map_err(|e| e.to_string())
+    }
+
+    fn register(&mut self, peer: &PeerId) -> Result<MailboxResponse, String> {
+        let peer_id = peer.to_string();
+        if !self.is_owner(&peer_id)? {
+            let owners: u32 = self
+                .conn
+                .query_row("SELECT COUNT(*) FROM owners", [], |row| row.get(0))
+                .map_err(|e| e.to_string())?;
+            if owners >= self.config.max_owners {
+                return Err("Mailbox is not accepting new owners".into());
+            }
+        }
+
+        let expires_at = chrono::Utc::now().timestamp() + self.config.registration_ttl_secs as i64;
+        self.conn
+            .execute(
+                "INSERT INTO owners (peer_id, expires_at) VALUES (?1, ?2)
+                 ON CONFLICT(peer_id) DO UPDATE SET expires_at = excluded.expires_at",
+                params![peer_id, expires_at],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(MailboxResponse::Registered {
+            expires_at,
+            max_messages: self.config.max_messages,
+            max_bytes: self.config.max_bytes,
+        })
+    }
+
+    fn unregister(&mut self, peer: &PeerId) -> Result<MailboxResponse, String> {
+        let peer_id = peer.to_string();
+        self.conn
+            .execute("DELETE FROM mail WHERE recipient = ?1", params![peer_id])
+            .map_err(|e| e.to_string())?;
+        self.conn
+            .execute("DELETE FROM owners WHERE peer_id = ?1", params![peer_id])
+            .map_err(|e| e.to_string())?;
+        Ok(MailboxResponse::Unregistered)
+    }
+
+    fn deposit(
+        &mut self,
+        sender: &PeerId,
+        recipient: &str,
+        envelope: &MessageEnvelope,
+    ) -> Result<MailboxResponse, String> {
+        let recipient: PeerId = recipient.parse().map_err(|e| format!("Invalid recipient: {}", e))?;
+        let recipient = recipient.to_string();
+        if !self.is_owner(&recipient)? {
+            return Err("Recipient has no mailbox here".into());
+        }
+
+        let encoded = serde_json::to_vec(envelope).map_err(|e| e.to_string())?;
+        if encoded.len() > MAX_ENVELOPE_BYTES {
+            return Err("Envelope too large".into());
+        }
+        let (count, bytes): (u32, i64) = self
+            .conn
+            .query_row(
+                "SELECT COUNT(*), COALESCE(SUM(LENGTH(envelope)), 0) FROM mail WHERE recipient = ?1",
+                params![recipient],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .map_err(|e| e.to_string())?;
+        if count >= self.config.max_messages
+            || bytes as u64 + encoded.len() as u64 > self.config.max_bytes
+        {
+            return Err("Recipient's mailbox is full".into());
+        }
+
+        let now = chrono::Utc::now().timestamp();
+        self.conn
+            .execute(
+                "INSERT OR IGNORE INTO mail (recipient, sender, id, deposited_at, expires_at, envelope)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![
+                    recipient,
+                    sender.to_string(),
+                    envelope.id,
+                    now,
+                    now + self.config.ttl_secs as i64,
+                    encoded
+                ],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(MailboxResponse::Stored {
+            id: envelope.id.clone(),
+        })
+    }
+
+    fn fetch(&mut self, peer: &PeerId, limit: u32) -> Result<MailboxResponse, String> {
+        let recipient = peer.to_string();
+        if !self.is_owner(&recipient)? {
+            return Err("Not registered".into());
+        }
+
+        let rows: Vec<(i64, String, i64, Vec<u8>)> = {
+            let mut stmt = self
+                .conn
+                .prepare(
+                    "SELECT seq, sender, deposited_at, envelope FROM mail WHERE recipient = ?1
+                     ORDER BY seq LIMIT ?2",
+                )
+                .map_err(|e| e.to_string())?;
+            stmt.query_map(params![recipient, limit.clamp(1, MAX_FETCH_BATCH)], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?))
+            })
+            .map_err(|e| e.to_string())?
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.to_string())?
+        };
+
+        let mut items = Vec::new();
+        for (seq, sender, deposited_at, envelope) in rows {
+            match serde_json::from_slice(&envelope) {
+                Ok(envelope) => items.push(MailboxItem {
+                    sender,
+                    deposited_at,
+                    envelope,
+                }),
+                // Can never be delivered, and would otherwise be counted as remaining forever
+                Err(e) => {
+                    log::warn!("Dropping unreadable mail {} for {}: {}", seq, recipient, e);
+                    self.conn
+                        .execute("DELETE FROM mail WHERE seq = ?1", params![seq])
+                        .map_err(|e| e.to_string())?;
+                }
+            }
+        }
+
+        let total: u32 = self
+            .conn
+            .query_row(
+                "SELECT COUNT(*) FROM mail WHERE recipient = ?1",
+                params![recipient],
+                |row| row.get(0),
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(MailboxResponse::Mail {
+            remaining: total.saturating_sub(items.len() as u32),
+            items,
+        })
+    }
+
+    fn ack(&mut self, peer: &PeerId, ids: &[String]) -> Result<MailboxResponse, String> {
+        let recipient = peer.to_string();
+        let mut removed = 0;
+        for id in ids {
+            removed += self
+                .conn
+                .execute(
+                    "DELETE FROM mail WHERE recipient = ?1 AND id = ?2",
+                    params![recipient, id],
+                )
+                .map_err(|e| e.to_string())?;
+        }
+        Ok(MailboxResponse::Acked {
+            removed: removed as u32,
+        })
+    }
+
+    /// Owner count and stored envelopes, for logging.
+    pub fn stats(&self) -> Result<(u32, u32), String> {
+        let owners = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM owners", [], |row| row.get(0))
+            .map_err(|e| e.to_string())?;
+        let mail = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM mail", [], |row| row.get(0))
+            .map_err(|e| e.to_string())?;
+        Ok((owners, mail))
+    }
+}
+
+/// Fetched mail that couldn't be opened and was dropped.
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DroppedMail {
+    pub mailbox: String,
+    /// Sender as reported by the mailbox.
+    pub sender: String,
+    pub id: String,
+    pub error: String,
+}
+
+/// What a mailbox event produced for the rest of the node.
+#[derive(Default)]
+pub struct MailboxOutcome {
+    /// Messages and receipts fetched from a mailbox.
+    pub messages: Vec<MessengerEvent>,
+    /// Queued messages that were left in a mailbox.
+    pub changes: Vec<StatusChange>,
+    pub dropped: Vec<DroppedMail>,
+}
+
+/// Client side: keeps us registered with our mailbox nodes, drains them on
+/// reconnect, and leaves messages there when a peer can't be reached directly.
+pub struct MailboxClient {
+    mailboxes: HashSet<PeerId>,
+    deposits: HashMap<OutboundRequestId, (PeerId, String)>,
+    /// Acks in flight, and whether more mail is waiting behind them. The next
+    /// fetch only goes out once the ack is answered, so it can't return the
+    /// items being acked.
+    acks: HashMap<OutboundRequestId, bool>,
+}
+
+impl MailboxClient {
+    pub fn new(mailboxes: Vec<PeerId>) -> Self {
+        Self {
+            mailboxes: mailboxes.into_iter().collect(),
+            deposits: HashMap::new(),
+            acks: HashMap::new(),
+        }
+    }
+
+    pub fn set_mailboxes(&mut self, mailboxes: Vec<PeerId>) {
+        self.mailboxes = mailboxes.into_iter().collect();
+    }
+
+    /// Registers with and fetches from `peer` if it is one of our mailboxes.
+    pub fn on_connected(&mut self, swarm: &mut Swarm<VoidBehaviour>, peer: &PeerId) {
+        if !self.mailboxes.contains(peer) {
+            return;
+        }
+        let mailbox = &mut swarm.behaviour_mut().mailbox;
+        mailbox.send_request(peer, MailboxRequest::Register);
+        mailbox.send_request(peer, MailboxRequest::Fetch { limit: FETCH_BATCH });
+    }
+
+    /// Leaves messages that just failed to go out directly in a connected
+    /// mailbox. Only works once a session with the recipient exists.
+    pub fn deposit(
+        &mut self,
+        store: &mut MessageStore,
+        messenger: &mut Messenger,
+        swarm: &mut Swarm<VoidBehaviour>,
+        changes: &[StatusChange],
+    ) {
+        let Some(mailbox) = self.mailboxes.iter().copied().find(|m| swarm.is_connected(m)) else {
+            return;
+        };
+
+        for change in changes {
+            if change.status != MessageStatus::Queued || change.error.is_none() {
+                continue;
+            }
+            let Ok(recipient) = change.peer_id.parse::<PeerId>() else {
+                continue;
+            };
+            if recipient == mailbox
+                || swarm.is_connected(&recipient)
+                || self.deposits.values().any(|(_, id)| *id == change.id)
+            {
+                continue;
+            }
+
+            let entry = match store.pending_messages(Some(&change.peer_id)) {
+                Ok(entries) => entries.into_iter().find(|e| e.id == change.id),
+                Err(e) => {
+                    eprintln!("Failed to read outbox: {}", e);
+                    None
+                }
+            };
+            let Some(entry) = entry else {
+                continue;
+            };
+            let envelope = serde_json::from_str::<MessageContent>(&entry.content)
+                .map_err(|e| e.to_string())
+                .and_then(|content| messenger.seal(&recipient, entry.id.clone(), entry.sent_at, content));
+            match envelope {
+                Ok(envelope) => {
+                    let request_id = swarm.behaviour_mut().mailbox.send_request(
+                        &mailbox,
+                        MailboxRequest::Deposit {
+                            recipient: change.peer_id.clone(),
+                            envelope,
+                        },
+                    );
+                    self.deposits.insert(request_id, (recipient, entry.id));
+                }
+                // No session yet: stays queued for direct delivery
+                Err(e) => println!("Can't leave {} in a mailbox: {}", entry.id, e),
+            }
+        }
+    }
+
+    pub fn handle_event(
+        &mut self,
+        store: &mut MessageStore,
+        messenger: &mut Messenger,
+        swarm: &mut Swarm<VoidBehaviour>,
+        server: Option<&mut MailboxServer>,
+        event: request_response::Event<MailboxRequest, MailboxResponse>,
+    ) -> MailboxOutcome {
+        let mut outcome = MailboxOutcome::default();
+
+        match event {
+            request_response::Event::Message { peer, message, .. } => match message {
+                Message::Request { request, channel, .. } => {
+                    let response = match server {
+                        Some(server) => server.handle(&peer, request),
+                        None => MailboxResponse::Rejected {
+                            reason: "Not a mailbox node".into(),
+                        },
+                    };
+                    let _ = swarm.behaviour_mut().mailbox.send_response(channel, response);
+                }
+                Message::Response { request_id, response } => match response {
+                    MailboxResponse::Registered { expires_at, .. } => {
+                        println!("Registered with mailbox {} until {}", peer, expires_at);
+                    }
+                    MailboxResponse::Stored { id } => {
+                        if let Some((recipient, _)) = self.deposits.remove(&request_id) {
+                            match store.mark_stored(&recipient.to_string(), &id) {
+                                Ok(true) => outcome.changes.push(StatusChange {
+                                    peer_id: recipient.to_string(),
+                                    id,
+                                    status: MessageStatus::Sent,
+                                    error: None,
+                                    retry_at: None,
+                                }),
+                                Ok(false) => {}
+                                Err(e) => eprintln!("Failed to update outbox: {}", e),
+                            }
+                        }
+                    }
+                    MailboxResponse::Mail { items, remaining } => {
+                        let mut ids = Vec::new();
+                        for item in items {
+                            ids.push(item.envelope.id.clone());
+                            let opened = item
+                                .sender
+                                .parse::<PeerId>()
+                                .map_err(|e| e.to_string())
+                                .and_then(|sender| messenger.open_stored(sender, &item.envelope));
+                            match opened {
+                                Ok(event) => outcome.messages.push(event),
+                                // Acked anyway: it will never open, and would block the mailbox
+                                Err(error) => {
+                                    println!("Dropping undecryptable mail {} from {}: {}", item.envelope.id, item.sender, error);
+                                    outcome.dropped.push(DroppedMail {
+                                        mailbox: peer.to_string(),
+                                        sender: item.sender,
+                                        id: item.envelope.id,
+                                        error,
+                                    });
+                                }
+                            }
+                        }
+                        if !ids.is_empty() {
+                            let request_id = swarm
+                                .behaviour_mut()
+                                .mailbox
+                                .send_request(&peer, MailboxRequest::Ack { ids });
+                            self.acks.insert(request_id, remaining > 0);
+                        }
+                    }
+                    MailboxResponse::Acked { .. } => {
+                        if self.acks.remove(&request_id) == Some(true) {
+                            swarm
+                                .behaviour_mut()
+                                .mailbox
+                                .send_request(&peer, MailboxRequest::Fetch { limit: FETCH_BATCH });
+                        }
+                    }
+                    MailboxResponse::Rejected { reason } => {
+                        self.acks.remove(&request_id);
+                        if let Some((recipient, id)) = self.deposits.remove(&request_id) {
+                            println!("Mailbox {} refused {} for {}: {}", peer, id, recipient, reason);
+                        } else {
+                            println!("Mailbox {} refused request: {}", peer, reason);
+                        }
+                    }
+                    MailboxResponse::Unregistered => {}
+                },
+            },
+            request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+                ..
+            } => {
+                self.deposits.remove(&request_id);
+                self.acks.remove(&request_id);
+                println!("Mailbox request to {} failed: {}", peer, error);
+            }
+            _ => {}
+        }
+
+        outcome
+    }
+}
@@ -0,0 +1,684 @@
+use crate::network::files::FileOffer;
+use crate::network::groups::{GroupMessage, GroupState};
+use crate::network::swarm::{MessageEnvelope, MessageRequest, MessageResponse, VoidBehaviour};
+use crate::security::crypto::{LocalKeys, RatchetSession};
+use crate::storage::vault;
+use libp2p::{
+    PeerId, Swarm,
+    identity::Keypair,
+    request_response::{self, Message, OutboundRequestId},
+};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Associated data of the sealed session file.
+const STATE_AAD: &[u8] = b"void-message-sessions";
+
+/// Typed payload carried inside an encrypted envelope.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum MessageContent {
+    Text { text: String },
+    /// Membership and current key of a group, from its owner.
+    GroupState(GroupState),
+    /// A message to a group, sealed with the group key.
+    GroupMessage(GroupMessage),
+    /// `member` left the group, or was removed by the owner.
+    #[serde(rename_all = "camelCase")]
+    GroupLeave { group_id: String, member: String },
+    /// A file the peer wants to send, with the key its chunks are sealed with.
+    FileOffer(FileOffer),
+}
+
+impl MessageContent {
+    /// Group traffic is handled by `network::groups` rather than shown as a
+    /// conversation message.
+    pub fn is_group(&self) -> bool {
+        matches!(
+            self,
+            MessageContent::GroupState(_) | MessageContent::GroupMessage(_) | MessageContent::GroupLeave { .. }
+        )
+    }
+}
+
+/// What actually gets encrypted; the send time stays hidden from relays.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PlainMessage {
+    sent_at: i64,
+    #[serde(flatten)]
+    body: Body,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+enum Body {
+    Content(MessageContent),
+    /// Read receipt for the listed message ids.
+    Read(Vec<String>),
+}
+
+#[derive(Debug, Clone)]
+pub enum MessengerEvent {
+    Received {
+        peer: PeerId,
+        id: String,
+        sent_at: i64,
+        content: MessageContent,
+    },
+    Delivered {
+        peer: PeerId,
+        id: String,
+    },
+    Read {
+        peer: PeerId,
+        ids: Vec<String>,
+    },
+    Failed {
+        peer: PeerId,
+        id: String,
+        error: String,
+    },
+    /// The peer's session was replaced by a handshake from a different
+    /// identity key, e.g. after a reinstall.
+    KeyChanged {
+        peer: PeerId,
+    },
+}
+
+struct Outgoing {
+    id: String,
+    plain: PlainMessage,
+}
+
+#[derive(Serialize, Deserialize)]
+struct PersistedState {
+    local: LocalKeys,
+    sessions: HashMap<String, RatchetSession>,
+}
+
+/// Owns the local messaging keys and per-peer ratchet sessions, and drives the
+/// `/void/message/1.0.0` protocol for whichever swarm loop it is plugged into.
+pub struct Messenger {
+    node_key: Keypair,
+    local: LocalKeys,
+    sessions: HashMap<PeerId, RatchetSession>,
+    /// Messages waiting for a prekey bundle before a session exists.
+    pending: HashMap<PeerId, Vec<Outgoing>>,
+    bundle_requests: HashMap<OutboundRequestId, PeerId>,
+    in_flight: HashMap<OutboundRequestId, (PeerId, String)>,
+    /// Ids of outgoing read receipts, whose delivery isn't reported.
+    receipts: HashSet<String>,
+    store_path: PathBuf,
+    /// Seals the session file; itself sealed under the passphrase next to it,
+    /// so Argon2 only runs once per launch.
+    state_key: [u8; 32],
+}
+
+pub fn new_message_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
+
+/// `path` with `suffix` appended to its file name.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn write_atomically(path: &Path, bytes: &[u8]) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+    let tmp_path = with_suffix(path, ".tmp");
+    fs::write(&tmp_path, bytes).map_err(|e| e.to_string())?;
+    fs::rename(&tmp_path, path).map_err(|e| e.to_string())
+}
+
+impl Messenger {
+    /// Loads keys and sessions sealed at `store_path`, creating fresh messaging
+    /// keys on first run.
+    pub fn load_or_create(
+        store_path: PathBuf,
+        passphrase: &str,
+        node_key: Keypair,
+    ) -> Result<Self, String> {
+        let key_path = with_suffix(&store_path, ".key");
+        let state_key: Option<[u8; 32]> = if key_path.exists() {
+            let sealed = fs::read(&key_path).map_err(|e| e.to_string())?;
+            let key = vault::open(passphrase, &sealed)
+                .map_err(|_| "Failed to unlock message sessions")?;
+            Some(key.try_into().map_err(|_| "Corrupted session key".to_string())?)
+        } else {
+            None
+        };
+
+        let (local, sessions) = if store_path.exists() {
+            let sealed = fs::read(&store_path).map_err(|e| e.to_string())?;
+            let encoded = match state_key.map(|key| vault::open_with_key(&key, STATE_AAD, &sealed)) {
+                Some(Ok(encoded)) => encoded,
+                // Files from before the separate key were sealed with the passphrase
+                _ => vault::open(passphrase, &sealed).map_err(|_| "Failed to unlock message sessions")?,
+            };
+            let state: PersistedState =
+                serde_json::from_slice(&encoded).map_err(|e| e.to_string())?;
+            let sessions = state
+                .sessions
+                .into_iter()
+                .filter_map(|(peer, session)| Some((peer.parse().ok()?, session)))
+                .collect();
+            (state.local, sessions)
+        } else {
+            (LocalKeys::generate(&node_key)?, HashMap::new())
+        };
+
+        let state_key = match state_key {
+            Some(key) => key,
+            None => {
+                let key: [u8; 32] = rand::random();
+                write_atomically(&key_path, &vault::seal(passphrase, &key)?)?;
+                key
+            }
+        };
+
+        let messenger = Self {
+            node_key,
+            local,
+            sessions,
+            pending: HashMap::new(),
+            bundle_requests: HashMap::new(),
+            in_flight: HashMap::new(),
+            receipts: HashSet::new(),
+            store_path,
+            state_key,
+        };
+        messenger.save()?;
+        Ok(messenger)
+    }
+
+    fn save(&self) -> Result<(), String> {
+        let state = PersistedState {
+            local: self.local.clone(),
+            sessions: self
+                .sessions
+                .iter()
+                .map(|(peer, session)| (peer.to_string(), session.clone()))
+                .collect(),
+        };
+        let encoded = serde_json::to_vec(&state).map_err(|e| e.to_string())?;
+        let sealed = vault::seal_with_key(&self.state_key, STATE_AAD, &encoded)?;
+        write_atomically(&self.store_path, &sealed)
+    }
+
+    pub fn local_identity(&self) -> [u8; 32] {
+        self.local.identity_public()
+    }
+
+    /// Messaging identity key of the peer's current session, if any.
+    pub fn remote_identity(&self, peer: &PeerId) -> Option<[u8; 32]> {
+        self.sessions.get(peer).map(|session| session.remote_identity)
+    }
+
+    /// Queues `content` for `peer`, opening a session first if needed.
+    /// Returns the message id used in delivery events.
+    pub fn send(
+        &mut self,
+        swarm: &mut Swarm<VoidBehaviour>,
+        peer: PeerId,
+        content: MessageContent,
+    ) -> Result<String, String> {
+        let id = new_message_id();
+        self.send_as(swarm, peer, id.clone(), chrono::Utc::now().timestamp(), content)?;
+        Ok(id)
+    }
+
+    /// Like `send`, but with a caller-chosen id and send time, e.g. when
+    /// retrying a message from the outbox.
+    pub fn send_as(
+        &mut self,
+        swarm: &mut Swarm<VoidBehaviour>,
+        peer: PeerId,
+        id: String,
+        sent_at: i64,
+        content: MessageContent,
+    ) -> Result<(), String> {
+        self.enqueue(
+            swarm,
+            peer,
+            Outgoing {
+                id,
+                plain: PlainMessage {
+                    sent_at,
+                    body: Body::Content(content),
+                },
+            },
+        )
+    }
+
+    /// Tells `peer` we've read `ids`. Best effort: not retried if it fails.
+    pub fn send_read_receipt(
+        &mut self,
+        swarm: &mut Swarm<VoidBehaviour>,
+        peer: PeerId,
+        ids: Vec<String>,
+    ) -> Result<(), String> {
+        let id = new_message_id();
+        self.receipts.insert(id.clone());
+        self.enqueue(
+            swarm,
+            peer,
+            Outgoing {
+                id,
+                plain: PlainMessage {
+                    sent_at: chrono::Utc::now().timestamp(),
+                    body: Body::Read(ids),
+                },
+            },
+        )
+    }
+
+    /// Whether `id` is waiting for a session or a delivery receipt.
+    pub fn is_sending(&self, id: &str) -> bool {
+        self.in_flight.values().any(|(_, pending)| pending == id)
+            || self.pending.values().flatten().any(|outgoing| outgoing.id == id)
+    }
+
+    fn enqueue(
+        &mut self,
+        swarm: &mut Swarm<VoidBehaviour>,
+        peer: PeerId,
+        outgoing: Outgoing,
+    ) -> Result<(), String> {
+        if self.sessions.contains_key(&peer) {
+            self.dispatch(swarm, peer, outgoing)?;
+        } else {
+            let queue = self.pending.entry(peer).or_default();
+            queue.push(outgoing);
+            if queue.len() == 1 {
+                let request_id = swarm
+                    .behaviour_mut()
+                    .messaging
+                    .send_request(&peer, MessageRequest::GetPreKeyBundle);
+                self.bundle_requests.insert(request_id, peer);
+            }
+        }
+        Ok(())
+    }
+
+    /// Encrypts `content` for `peer` without sending it, e.g. to leave it in a
+    /// mailbox. Needs an established session.
+    pub fn seal(
+        &mut self,
+        peer: &PeerId,
+        id: String,
+        sent_at: i64,
+        content: MessageContent,
+    ) -> Result<MessageEnvelope, String> {
+        let envelope = self.encrypt(
+            peer,
+            &Outgoing {
+                id,
+                plain: PlainMessage {
+                    sent_at,
+                    body: Body::Content(content),
+                },
+            },
+        )?;
+        self.save()?;
+        Ok(envelope)
+    }
+
+    /// Decrypts an envelope that `peer` left in a mailbox. `peer` is only the
+    /// mailbox's word, so stored mail can open a session but never replace one.
+    pub fn open_stored(&mut self, peer: PeerId, envelope: &MessageEnvelope) -> Result<MessengerEvent, String> {
+        let plain = self.receive(peer, envelope, false, &mut Vec::new())?;
+        self.save()?;
+        Ok(match plain.body {
+            Body::Content(content) => MessengerEvent::Received {
+                peer,
+                id: envelope.id.clone(),
+                sent_at: plain.sent_at,
+                content,
+            },
+            Body::Read(ids) => MessengerEvent::Read { peer, ids },
+        })
+    }
+
+    fn encrypt(&mut self, peer: &PeerId, outgoing: &Outgoing) -> Result<MessageEnvelope, String> {
+        let session = self.sessions.get_mut(peer).ok_or("No session with peer")?;
+        let plaintext = serde_json::to_vec(&outgoing.plain).map_err(|e| e.to_string())?;
+        let (header, ciphertext) = session.encrypt(&plaintext)?;
+
+        Ok(MessageEnvelope {
+            id: outgoing.id.clone(),
+            init: session.pending_init.clone(),
+            header,
+            ciphertext,
+        })
+    }
+
+    fn dispatch(
+        &mut self,
+        swarm: &mut Swarm<VoidBehaviour>,
+        peer: PeerId,
+        outgoing: Outgoing,
+    ) -> Result<(), String> {
+        let envelope = self.encrypt(&peer, &outgoing)?;
+        let request_id = swarm
+            .behaviour_mut()
+            .messaging
+            .send_request(&peer, MessageRequest::Message(envelope));
+        self.in_flight.insert(request_id, (peer, outgoing.id));
+        self.save()
+    }
+
+    fn receive(
+        &mut self,
+        peer: PeerId,
+        envelope: &MessageEnvelope,
+        may_replace: bool,
+        events: &mut Vec<MessengerEvent>,
+    ) -> Result<PlainMessage, String> {
+        let error = match self.sessions.get_mut(&peer) {
+            Some(session) => match session.decrypt(&envelope.header, &envelope.ciphertext) {
+                Ok(plaintext) => return serde_json::from_slice(&plaintext).map_err(|e| e.to_string()),
+                Err(e) => e,
+            },
+            None => "No session with peer".to_string(),
+        };
+
+        // Either a brand new session, or the peer re-initiated. A handshake
+        // only replaces a session if it is signed by the peer and newer, so
+        // replaying or corrupting an old one can't reset it. A new identity
+        // key (the peer reinstalled) is accepted but reported.
+        let init = envelope.init.as_ref().ok_or(error)?;
+        init.verify(&peer)?;
+        let mut key_changed = false;
+        if let Some(current) = self.sessions.get(&peer) {
+            if !may_replace {
+                return Err("Stored mail can't replace a session".into());
+            }
+            if init.created_at <= current.established_at {
+                return Err("Stale handshake".into());
+            }
+            key_changed = init.identity_key != current.remote_identity;
+        }
+        let mut session = RatchetSession::respond(&self.local, init)?;
+        let plaintext = session.decrypt(&envelope.header, &envelope.ciphertext)?;
+        self.sessions.insert(peer, session);
+        if key_changed {
+            events.push(MessengerEvent::KeyChanged { peer });
+        }
+        serde_json::from_slice(&plaintext).map_err(|e| e.to_string())
+    }
+
+    pub fn handle_event(
+        &mut self,
+        swarm: &mut Swarm<VoidBehaviour>,
+        event: request_response::Event<MessageRequest, MessageResponse>,
+    ) -> Vec<MessengerEvent> {
+        let mut events = Vec::new();
+
+        match event {
+            request_response::Event::Message { peer, message, .. } => match message {
+                Message::Request { request, channel, .. } => {
+                    let response = match request {
+                        MessageRequest::GetPreKeyBundle => {
+                            MessageResponse::PreKeyBundle(self.local.bundle(&self.node_key))
+                        }
+                        MessageRequest::Message(envelope) => match self.receive(peer, &envelope, true, &mut events) {
+                            Ok(plain) => {
+                                if let Err(e) = self.save() {
+                                    eprintln!("Failed to persist sessions: {}", e);
+                                }
+                                events.push(match plain.body {
+                                    Body::Content(content) => MessengerEvent::Received {
+                                        peer,
+                                        id: envelope.id.clone(),
+                                        sent_at: plain.sent_at,
+                                        content,
+                                    },
+                                    Body::Read(ids) => MessengerEvent::Read { peer, ids },
+                                });
+                                MessageResponse::Delivered { id: envelope.id }
+                            }
+                            Err(reason) => MessageResponse::Rejected {
+                                id: envelope.id,
+                                reason,
+                            },
+                        },
+                    };
+                    let _ = swarm.behaviour_mut().messaging.send_response(channel, response);
+                }
+                Message::Response {
+                    request_id,
+                    response,
+                } => match response {
+                    MessageResponse::PreKeyBundle(bundle) => {
+                        self.bundle_requests.remove(&request_id);
+                        let session = bundle
+                            .verify(&peer)
+                            .and_then(|_| RatchetSession::initiate(&self.local, &self.node_key, &bundle));
+                        match session {
+                            Ok(session) => {
+                                self.sessions.insert(peer, session);
+                                for outgoing in self.pending.remove(&peer).unwrap_or_default() {
+                                    let id = outgoing.id.clone();
+                                    if let Err(error) = self.dispatch(swarm, peer, outgoing) {
+                                        events.push(MessengerEvent::Failed { peer, id, error });
+                                    }
+                                }
+                            }
+                            Err(error) => events.extend(self.fail_pending(peer, &error)),
+                        }
+                    }
+                    MessageResponse::Delivered { .. } => {
+                        // Trust our own record of the request, not the id the peer echoes
+                        if let Some((peer, id)) = self.in_flight.remove(&request_id) {
+                            events.push(MessengerEvent::Delivered { peer, id });
+                        }
+                    }
+                    MessageResponse::Rejected { reason, .. } => {
+                        // Only for a message we sent, and trusting our own record of it
+                        if let Some((peer, id)) = self.in_flight.remove(&request_id) {
+                            // The peer couldn't decrypt; start over with a fresh handshake next time
+                            self.sessions.remove(&peer);
+                            let _ = self.save();
+                            events.push(MessengerEvent::Failed {
+                                peer,
+                                id,
+                                error: reason,
+                            });
+                        }
+                    }
+                },
+            },
+            request_response::Event::OutboundFailure {
+                peer,
+                request_id,
+                error,
+                ..
+            } => {
+                if self.bundle_requests.remove(&request_id).is_some() {
+                    events.extend(self.fail_pending(peer, &error.to_string()));
+                } else if let Some((peer, id)) = self.in_flight.remove(&request_id) {
+                    events.push(MessengerEvent::Failed {
+                        peer,
+                        id,
+                        error: error.to_string(),
+                    });
+                }
+            }
+            _ => {}
+        }
+
+        // Receipts are fire-and-forget
+        events.retain(|event| match event {
+            MessengerEvent::Delivered { id, .. } | MessengerEvent::Failed { id, .. } => {
+                !self.receipts.remove(id)
+            }
+            _ => true,
+        });
+        events
+    }
+
+    fn fail_pending(&mut self, peer: PeerId, error: &str) -> Vec<MessengerEvent> {
+        self.pending
+            .remove(&peer)
+            .unwrap_or_default()
+            .into_iter()
+            .map(|outgoing| MessengerEvent::Failed {
+                peer,
+                id: outgoing.id,
+                error: error.to_string(),
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn messenger(name: &str) -> (Messenger, PeerId) {
+        let dir = std::env::temp_dir().join(format!("void-messaging-{}-{}", name, new_message_id()));
+        let node_key = Keypair::generate_ed25519();
+        let peer = PeerId::from(node_key.public());
+        let messenger = Messenger::load_or_create(dir.join("messaging.dat"), "test", node_key).unwrap();
+        (messenger, peer)
+    }
+
+    /// Opens a session from `from` to `to` the way a fetched bundle would.
+    fn initiate(from: &mut Messenger, to: &Messenger, to_peer: PeerId) {
+        let bundle = to.local.bundle(&to.node_key);
+        bundle.verify(&to_peer).unwrap();
+        let session = RatchetSession::initiate(&from.local, &from.node_key, &bundle).unwrap();
+        from.sessions.insert(to_peer, session);
+    }
+
+    fn text(messenger: &mut Messenger, peer: PeerId, text: &str) -> MessageEnvelope {
+        let outgoing = Outgoing {
+            id: new_message_id(),
+            plain: PlainMessage {
+                sent_at: 0,
+                body: Body::Content(MessageContent::Text { text: text.into() }),
+            },
+        };
+        messenger.encrypt(&peer, &outgoing).unwrap()
+    }
+
+    fn received(plain: PlainMessage) -> String {
+        match plain.body {
+            Body::Content(MessageContent::Text { text }) => text,
+            _ => panic!("unexpected body"),
+        }
+    }
+
+    #[test]
+    fn replayed_handshake_keeps_session() {
+        let (mut alice, alice_peer) = messenger("alice");
+        let (mut bob, bob_peer) = messenger("bob");
+        initiate(&mut alice, &bob, bob_peer);
+
+        let first = text(&mut alice, bob_peer, "first");
+        assert_eq!(received(bob.receive(alice_peer, &first, true, &mut Vec::new()).unwrap()), "first");
+        let second = text(&mut alice, bob_peer, "second");
+
+        assert!(bob.receive(alice_peer, &first, true, &mut Vec::new()).is_err());
+        let mut corrupted = first.clone();
+        corrupted.ciphertext[0] ^= 1;
+        assert!(bob.receive(alice_peer, &corrupted, true, &mut Vec::new()).is_err());
+
+        // Still the original session
+        assert_eq!(received(bob.receive(alice_peer, &second, true, &mut Vec::new()).unwrap()), "second");
+    }
+
+    #[test]
+    fn handshake_from_other_identity_replaces_session() {
+        let (mut alice, alice_peer) = messenger("alice");
+        let (mut bob, bob_peer) = messenger("bob");
+        initiate(&mut alice, &bob, bob_peer);
+        let hello = text(&mut alice, bob_peer, "hello");
+        bob.receive(alice_peer, &hello, true, &mut Vec::new()).unwrap();
+
+        // Same node identity, but fresh messaging keys
+        let dir = std::env::temp_dir().join(format!("void-messaging-alice2-{}", new_message_id()));
+        let mut reinstalled =
+            Messenger::load_or_create(dir.join("messaging.dat"), "test", alice.node_key.clone()).unwrap();
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        initiate(&mut reinstalled, &bob, bob_peer);
+        let other = text(&mut reinstalled, bob_peer, "other");
+        let mut events = Vec::new();
+        assert_eq!(received(bob.receive(alice_peer, &other, true, &mut events).unwrap()), "other");
+        assert!(matches!(events.as_slice(), [MessengerEvent::KeyChanged { peer }] if *peer == alice_peer));
+        assert_eq!(bob.remote_identity(&alice_peer), Some(reinstalled.local_identity()));
+
+        // The old keys no longer open anything
+        let stale = text(&mut alice, bob_peer, "stale");
+        assert!(bob.receive(alice_peer, &stale, true, &mut Vec::new()).is_err());
+        let next = text(&mut reinstalled, bob_peer, "next");
+        assert_eq!(received(bob.receive(alice_peer, &next, true, &mut Vec::new()).unwrap()), "next");
+    }
+
+    #[test]
+    fn newer_handshake_replaces_session() {
+        let (mut alice, alice_peer) = messenger("alice");
+        let (mut bob, bob_peer) = messenger("bob");
+        initiate(&mut alice, &bob, bob_peer);
+        let hello = text(&mut alice, bob_peer, "hello");
+        bob.receive(alice_peer, &hello, true, &mut Vec::new()).unwrap();
+
+        // Alice lost the session and starts over
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        initiate(&mut alice, &bob, bob_peer);
+        let again = text(&mut alice, bob_peer, "again");
+        assert_eq!(received(bob.receive(alice_peer, &again, true, &mut Vec::new()).unwrap()), "again");
+    }
+
+    #[test]
+    fn sessions_survive_reload() {
+        let (mut alice, alice_peer) = messenger("alice");
+        let (mut bob, bob_peer) = messenger("bob");
+        initiate(&mut alice, &bob, bob_peer);
+        let hello = text(&mut alice, bob_peer, "hello");
+        bob.receive(alice_peer, &hello, true, &mut Vec::new()).unwrap();
+        bob.save().unwrap();
+
+        let node_key = bob.node_key.clone();
+        let mut bob = Messenger::load_or_create(bob.store_path.clone(), "test", node_key).unwrap();
+        let next = text(&mut alice, bob_peer, "next");
+        assert_eq!(received(bob.receive(alice_peer, &next, true, &mut Vec::new()).unwrap()), "next");
+    }
+
+    #[test]
+    fn stored_mail_cannot_forge_or_replace_sessions() {
+        let (mut alice, alice_peer) = messenger("alice");
+        let (mut bob, bob_peer) = messenger("bob");
+        let (mut mallory, mallory_peer) = messenger("mallory");
+        initiate(&mut mallory, &bob, bob_peer);
+
+        // Mallory's handshake, claimed to come from Alice
+        let forged = text(&mut mallory, bob_peer, "forged");
+        assert_eq!(
+            bob.open_stored(alice_peer, &forged).err().as_deref(),
+            Some("Handshake signed by a different peer")
+        );
+        assert!(bob.remote_identity(&alice_peer).is_none());
+        assert!(bob.open_stored(mallory_peer, &forged).is_ok());
+
+        initiate(&mut alice, &bob, bob_peer);
+        let hello = text(&mut alice, bob_peer, "hello");
+        bob.receive(alice_peer, &hello, true, &mut Vec::new()).unwrap();
+
+        // Even a genuine, newer handshake from Alice can't reset the session via a mailbox
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        initiate(&mut alice, &bob, bob_peer);
+        let again = text(&mut alice, bob_peer, "again");
+        assert_eq!(
+            bob.open_stored(alice_peer, &again).err().as_deref(),
+            Some("Stored mail can't replace a session")
+        );
+    }
+}
@@ -1,45 +1,134 @@
+pub mod config;
+pub mod discovery;
+pub mod files;
+pub mod groups;
+pub mod links;
+pub mod mailbox;
+pub mod messaging;
+pub mod outbox;
+pub mod relay_server;
+pub mod rooms;
+pub mod relays;
+pub mod signaling;
 pub mod swarm;
 pub mod utils;
 
-use crate::network::swarm::{SignalingRequest, SignalingResponse, VoidEvent};
-use libp2p::{Multiaddr, PeerId, futures::StreamExt, swarm::SwarmEvent};
+use crate::network::config::NetworkConfig;
+use crate::network::discovery::{LanPeer, LanPeers};
+use crate::network::files::{FileEvent, FileTransfers, LocalFile};
+use crate::network::groups::GroupEvent;
+use crate::network::links::{LinkKind, PeerLinks};
+use crate::network::mailbox::MailboxClient;
+use crate::network::utils::VoidCode;
+use crate::network::messaging::{MessageContent, Messenger, MessengerEvent};
+use crate::network::outbox::StatusChange;
+use crate::network::relays::{EndpointHealth, RelayManager};
+use crate::network::rooms::Rooms;
+use crate::network::swarm::{Signal, SignalingRequest, SignalingResponse, VoidBehaviour, VoidEvent};
+use crate::security::safety::{self, SafetyNumber};
+use crate::storage::contacts::{Contact, KeyPin};
+use crate::storage::db::{self, HistoryState, MessageStore};
+use crate::storage::groups::Group;
+use crate::storage::identity;
+use crate::storage::transfers::FileTransfer;
+use libp2p::{PeerId, Swarm, autonat, futures::StreamExt, identify, mdns, swarm::SwarmEvent};
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tauri::{AppHandle, Emitter, State};
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, Manager, State};
 use tokio::sync::{Mutex, mpsc, oneshot};
+use tokio::task::JoinHandle;
 
 // Command enum to send instructions to the swarm task
 #[derive(Debug)]
 pub enum NetworkCommand {
     Dial(PeerId),
-    DialAddress(Multiaddr),
-    GetIdentity(oneshot::Sender<(PeerId, Vec<Multiaddr>)>),
-    SendSignal(PeerId, String),
+    DialCode(VoidCode),
+    GetVoidCode(u64, oneshot::Sender<Result<String, String>>),
+    SendSignal(PeerId, Signal),
+    SendMessage(PeerId, MessageContent, oneshot::Sender<Result<String, String>>),
+    MarkRead(PeerId, Vec<String>, oneshot::Sender<Result<Vec<String>, String>>),
+    RetryMessage(PeerId, String, oneshot::Sender<Result<(), String>>),
+    UpdateEndpoints(NetworkConfig),
+    GetEndpointHealth(oneshot::Sender<Vec<EndpointHealth>>),
+    GetPeerLinks(oneshot::Sender<HashMap<String, LinkKind>>),
+    GetLanPeers(oneshot::Sender<Vec<LanPeer>>),
+    SetBlocked(PeerId, bool),
+    GetSafetyNumber(PeerId, oneshot::Sender<Result<SafetyNumber, String>>),
+    CreateGroup(String, Vec<PeerId>, oneshot::Sender<Result<Group, String>>),
+    InviteToGroup(String, PeerId, oneshot::Sender<Result<Group, String>>),
+    RemoveFromGroup(String, PeerId, oneshot::Sender<Result<Group, String>>),
+    LeaveGroup(String, oneshot::Sender<Result<(), String>>),
+    SendGroupMessage(String, String, oneshot::Sender<Result<String, String>>),
+    JoinRoom(String, oneshot::Sender<Result<bool, String>>),
+    LeaveRoom(String, oneshot::Sender<Result<bool, String>>),
+    PublishRoom(String, serde_json::Value, oneshot::Sender<Result<String, String>>),
+    GetRoomPeers(String, oneshot::Sender<Result<Vec<String>, String>>),
+    SendFile(PeerId, LocalFile, oneshot::Sender<Result<FileTransfer, String>>),
+    AcceptFile(String, Option<PathBuf>, oneshot::Sender<Result<(), String>>),
+    RejectFile(String, oneshot::Sender<Result<(), String>>),
+    CancelFile(String, oneshot::Sender<Result<(), String>>),
+    Shutdown,
 }
 
 // State managed by Tauri
 pub struct NetworkState {
     pub sender: Arc<Mutex<Option<mpsc::Sender<NetworkCommand>>>>,
+    task: Arc<Mutex<Option<JoinHandle<()>>>>,
 }
 
 impl NetworkState {
     pub fn new() -> Self {
         Self {
             sender: Arc::new(Mutex::new(None)),
+            task: Arc::new(Mutex::new(None)),
         }
     }
 }
 
+/// Sends a command that carries a reply channel to the swarm task and waits
+/// for the answer.
+pub(crate) async fn query<T>(
+    state: &State<'_, NetworkState>,
+    command: impl FnOnce(oneshot::Sender<T>) -> NetworkCommand,
+) -> Result<T, String> {
+    let (tx, rx) = oneshot::channel();
+    let sender_guard = state.sender.lock().await;
+    let sender = sender_guard.as_ref().ok_or("Node not running")?;
+    sender.send(command(tx)).await.map_err(|e| e.to_string())?;
+    rx.await.map_err(|e| e.to_string())
+}
+
+/// Like [`query`], for commands whose reply can fail.
+pub(crate) async fn request<T>(
+    state: &State<'_, NetworkState>,
+    command: impl FnOnce(oneshot::Sender<Result<T, String>>) -> NetworkCommand,
+) -> Result<T, String> {
+    query(state, command).await?
+}
+
 #[tauri::command]
 pub async fn send_signal(
     peer_id: String,
     payload: String,
     state: State<'_, NetworkState>,
+) -> Result<(), String> {
+    let signal = signaling::from_frontend_payload(&payload)?;
+    send_typed_signal(peer_id, signal, state).await
+}
+
+#[tauri::command]
+pub async fn send_typed_signal(
+    peer_id: String,
+    signal: Signal,
+    state: State<'_, NetworkState>,
 ) -> Result<(), String> {
     let peer_id = peer_id.parse::<PeerId>().map_err(|e| e.to_string())?;
     let sender_guard = state.sender.lock().await;
 
     if let Some(tx) = sender_guard.as_ref() {
-        tx.send(NetworkCommand::SendSignal(peer_id, payload))
+        tx.send(NetworkCommand::SendSignal(peer_id, signal))
             .await
             .map_err(|e| e.to_string())?;
         Ok(())
@@ -49,45 +138,117 @@ pub async fn send_signal(
 }
 
 #[tauri::command]
-pub async fn start_node(state: State<'_, NetworkState>, app: AppHandle) -> Result<String, String> {
+pub async fn start_node(
+    state: State<'_, NetworkState>,
+    history: State<'_, HistoryState>,
+    app: AppHandle,
+    passphrase: Option<String>,
+) -> Result<String, String> {
+    launch(&state, &history, app, passphrase).await
+}
+
+#[tauri::command]
+pub async fn stop_node(
+    state: State<'_, NetworkState>,
+    history: State<'_, HistoryState>,
+) -> Result<String, String> {
+    if shutdown(&state, &history).await? {
+        Ok("Node stopped".into())
+    } else {
+        Ok("Node not running".into())
+    }
+}
+
+/// Stops the node if it is running and starts it again, e.g. after the
+/// identity or passphrase changed.
+#[tauri::command]
+pub async fn restart_node(
+    state: State<'_, NetworkState>,
+    history: State<'_, HistoryState>,
+    app: AppHandle,
+    passphrase: Option<String>,
+) -> Result<String, String> {
+    shutdown(&state, &history).await?;
+    launch(&state, &history, app, passphrase).await
+}
+
+/// Asks the swarm task to exit and waits for it. Returns false if the node
+/// wasn't running.
+async fn shutdown(state: &NetworkState, history: &HistoryState) -> Result<bool, String> {
+    let Some(sender) = state.sender.lock().await.take() else {
+        return Ok(false);
+    };
+    // The task may already be gone if the swarm failed to build
+    let _ = sender.send(NetworkCommand::Shutdown).await;
+
+    if let Some(mut task) = state.task.lock().await.take() {
+        if tokio::time::timeout(Duration::from_secs(5), &mut task).await.is_err() {
+            println!("Swarm task did not stop in time, aborting");
+            task.abort();
+        }
+    }
+
+    *history.store.lock().map_err(|e| e.to_string())? = None;
+    Ok(true)
+}
+
+async fn launch(
+    state: &NetworkState,
+    history: &HistoryState,
+    app: AppHandle,
+    passphrase: Option<String>,
+) -> Result<String, String> {
     let mut sender_guard = state.sender.lock().await;
     if sender_guard.is_some() {
         return Ok("Node already running".into());
     }
 
+    let passphrase = identity::require_passphrase(passphrase)?;
+    let local_key = identity::load_or_create(&identity::identity_path(&app)?, &passphrase)?;
+    let sessions_path = app
+        .path()
+        .app_data_dir()
+        .map_err(|e| e.to_string())?
+        .join("messaging.dat");
+    let mut messenger = Messenger::load_or_create(sessions_path, &passphrase, local_key.clone())?;
+    let node_key = local_key.clone();
+
+    let store = MessageStore::open(&db::db_path(&app)?, &passphrase)?;
+    let contacts = store.contacts()?;
+    *history.store.lock().map_err(|e| e.to_string())? = Some(store);
+    let history = history.store.clone();
+    let network_config = NetworkConfig::load(&config::config_path(&app)?)?;
+    let mut relays = RelayManager::new(&network_config);
+    let mut mailbox = MailboxClient::new(network_config.mailbox_peers());
+    let download_dir = match app.path().download_dir() {
+        Ok(dir) => dir,
+        Err(_) => app.path().app_data_dir().map_err(|e| e.to_string())?.join("downloads"),
+    };
+    let mut files = FileTransfers::new(download_dir);
+
     let (tx, mut rx) = mpsc::channel(32);
     *sender_guard = Some(tx);
 
     // Spawn the swarm task
-    tokio::spawn(async move {
-        match swarm::build_swarm().await {
+    let task = tokio::spawn(async move {
+        match swarm::build_swarm(local_key, false).await {
             Ok(mut swarm) => {
                 println!("Swarm initialized successfully");
 
-                // Bootnodes (Relays)
-                let bootnodes = [
-                    "/dnsaddr/bootstrap.libp2p.io/p2p/QmNnooDu7bfjPFoTZYxMNLWUQJyrVwtbZg5gBMjTezGAJN",
-                    "/dnsaddr/bootstrap.libp2p.io/p2p/QmQCU2EcMqAqQPR2i9bChDtGNJchTeq5s0GNHw5zXIov6U",
-                    "/dnsaddr/bootstrap.libp2p.io/p2p/QmbLHAnMoJPWSCR5Zhtx6BHJX9CkJv68846kJcCPaQFjNA",
-                    "/dnsaddr/bootstrap.libp2p.io/p2p/QmcZf59bWwK5XFi76CZX8cbJ4BhTzzA3gU1Ubuu79rfVP3",
-                ];
-
-                for peer in bootnodes {
-                    if let Ok(addr) = peer.parse::<Multiaddr>() {
-                        println!("Dialing bootnode: {}", addr);
-                        if let Err(e) = swarm.dial(addr) {
-                            println!("Failed to dial bootnode: {}", e);
-                        }
+                // Direct listeners on ephemeral ports, QUIC alongside TCP
+                for addr in ["/ip4/0.0.0.0/tcp/0", "/ip4/0.0.0.0/udp/0/quic-v1"] {
+                    if let Err(e) = swarm.listen_on(addr.parse().expect("valid multiaddr")) {
+                        println!("Failed to listen on {}: {}", addr, e);
                     }
                 }
 
-                // Listen on p2p-circuit (Relay)
-                if let Ok(addr) = "/p2p-circuit".parse::<Multiaddr>() {
-                     println!("Attempting to listen on relay...");
-                     if let Err(e) = swarm.listen_on(addr) {
-                         println!("Failed to listen on relay: {}", e);
-                     }
-                }
+                // Relays & bootstrap nodes from the network config
+                relays.dial_due(&mut swarm);
+                dial_contacts(&mut swarm, &contacts);
+                let mut reconnect = tokio::time::interval(Duration::from_secs(5));
+                let mut links = PeerLinks::new();
+                let mut lan_peers = LanPeers::new();
+                let mut rooms = Rooms::new();
 
                 // Main Event Loop
                 loop {
@@ -101,72 +262,628 @@ pub async fn start_node(state: State<'_, NetworkState>, app: AppHandle) -> Resul
                                         println!("Failed to dial: {}", e);
                                     }
                                 }
-                                NetworkCommand::DialAddress(addr) => {
-                                    println!("Dialing address: {}", addr);
-                                    if let Err(e) = swarm.dial(addr) {
+                                NetworkCommand::DialCode(code) => {
+                                    println!("Dialing {:?} at {:?}", code.peer_id, code.addresses);
+                                    if let Err(e) = swarm.dial(code.dial_opts()) {
                                         println!("Failed to dial: {}", e);
                                     }
                                 }
-                                NetworkCommand::GetIdentity(reply_tx) => {
-                                    let peer_id = *swarm.local_peer_id();
-                                    let mut addrs: Vec<Multiaddr> = swarm.external_addresses().map(|a| a.clone()).collect();
-                                    if addrs.is_empty() {
-                                        addrs = swarm.listeners().map(|a| a.clone()).collect();
+                                NetworkCommand::GetVoidCode(ttl_secs, reply_tx) => {
+                                    let addrs = utils::code_addresses(
+                                        swarm.listeners().chain(swarm.external_addresses()),
+                                    );
+                                    let _ = reply_tx.send(utils::generate_void_code(&node_key, &addrs, ttl_secs));
+                                }
+                                NetworkCommand::SendSignal(peer_id, signal) => {
+                                    swarm.behaviour_mut().signaling.send_request(&peer_id, SignalingRequest::new(signal));
+                                }
+                                NetworkCommand::UpdateEndpoints(config) => {
+                                    relays.update(&mut swarm, &config);
+                                    mailbox.set_mailboxes(config.mailbox_peers());
+                                    relays.dial_due(&mut swarm);
+                                }
+                                NetworkCommand::GetEndpointHealth(reply_tx) => {
+                                    let _ = reply_tx.send(relays.health());
+                                }
+                                NetworkCommand::GetPeerLinks(reply_tx) => {
+                                    let _ = reply_tx.send(links.snapshot());
+                                }
+                                NetworkCommand::GetLanPeers(reply_tx) => {
+                                    let _ = reply_tx.send(lan_peers.snapshot());
+                                }
+                                NetworkCommand::SetBlocked(peer_id, blocked) => {
+                                    if blocked {
+                                        swarm.behaviour_mut().blocked.block_peer(peer_id);
+                                    } else {
+                                        swarm.behaviour_mut().blocked.unblock_peer(peer_id);
                                     }
-                                    let _ = reply_tx.send((peer_id, addrs));
                                 }
-                                NetworkCommand::SendSignal(peer_id, payload) => {
-                                    swarm.behaviour_mut().signaling.send_request(&peer_id, SignalingRequest(payload));
+                                NetworkCommand::GetSafetyNumber(peer_id, reply_tx) => {
+                                    let local_peer = *swarm.local_peer_id();
+                                    let _ = reply_tx.send(safety_number_with(&history, &messenger, &local_peer, &peer_id));
+                                }
+                                NetworkCommand::Shutdown => {
+                                    println!("Shutting down node");
+                                    break;
+                                }
+                                NetworkCommand::SendMessage(peer_id, content, reply_tx) => {
+                                    let result = with_history(&history, |store| {
+                                        outbox::send(store, &mut messenger, &mut swarm, peer_id, content)
+                                    })
+                                    .map(|(id, changes)| {
+                                        emit_status(&app, changes);
+                                        id
+                                    });
+                                    let _ = reply_tx.send(result);
+                                }
+                                NetworkCommand::MarkRead(peer_id, ids, reply_tx) => {
+                                    let _ = reply_tx.send(with_history(&history, |store| {
+                                        outbox::mark_read(store, &mut messenger, &mut swarm, peer_id, &ids)
+                                    }));
+                                }
+                                NetworkCommand::RetryMessage(peer_id, id, reply_tx) => {
+                                    let result = with_history(&history, |store| {
+                                        outbox::retry(store, &mut messenger, &mut swarm, peer_id, &id)
+                                    })
+                                    .map(|changes| emit_status(&app, changes));
+                                    let _ = reply_tx.send(result);
+                                }
+                                NetworkCommand::CreateGroup(name, members, reply_tx) => {
+                                    let result = with_history(&history, |store| {
+                                        groups::create(store, &mut messenger, &mut swarm, &name, members)
+                                    });
+                                    let _ = reply_tx.send(result.map(|(group, changes)| {
+                                        emit_status(&app, changes);
+                                        group
+                                    }));
+                                }
+                                NetworkCommand::InviteToGroup(group_id, peer_id, reply_tx) => {
+                                    let result = with_history(&history, |store| {
+                                        groups::invite(store, &mut messenger, &mut swarm, &group_id, peer_id)
+                                    });
+                                    let _ = reply_tx.send(result.map(|(group, changes)| {
+                                        emit_status(&app, changes);
+                                        group
+                                    }));
+                                }
+                                NetworkCommand::RemoveFromGroup(group_id, peer_id, reply_tx) => {
+                                    let result = with_history(&history, |store| {
+                                        groups::remove(store, &mut messenger, &mut swarm, &group_id, peer_id)
+                                    });
+                                    let _ = reply_tx.send(result.map(|(group, changes)| {
+                                        emit_status(&app, changes);
+                                        group
+                                    }));
+                                }
+                                NetworkCommand::LeaveGroup(group_id, reply_tx) => {
+                                    let result = with_history(&history, |store| {
+                                        groups::leave(store, &mut messenger, &mut swarm, &group_id)
+                                    })
+                                    .map(|changes| emit_status(&app, changes));
+                                    let _ = reply_tx.send(result);
+                                }
+                                NetworkCommand::JoinRoom(room, reply_tx) => {
+                                    let _ = reply_tx.send(rooms.join(&mut swarm, &room));
+                                }
+                                NetworkCommand::LeaveRoom(room, reply_tx) => {
+                                    let _ = reply_tx.send(rooms.leave(&mut swarm, &room));
+                                }
+                                NetworkCommand::PublishRoom(room, payload, reply_tx) => {
+                                    let _ = reply_tx.send(rooms.publish(&mut swarm, &room, payload));
+                                }
+                                NetworkCommand::GetRoomPeers(room, reply_tx) => {
+                                    let peers = rooms.peers(&swarm, &room).iter().map(|p| p.to_string()).collect();
+                                    let _ = reply_tx.send(Ok(peers));
+                                }
+                                NetworkCommand::SendFile(peer_id, file, reply_tx) => {
+                                    let result = with_history(&history, |store| {
+                                        files.offer(store, &mut messenger, &mut swarm, peer_id, file)
+                                    });
+                                    let _ = reply_tx.send(result.map(|(transfer, changes)| {
+                                        emit_status(&app, changes);
+                                        emit_file_events(&app, vec![FileEvent::Progress { transfer: transfer.clone() }]);
+                                        transfer
+                                    }));
+                                }
+                                NetworkCommand::AcceptFile(id, dir, reply_tx) => {
+                                    let result = with_history(&history, |store| files.accept(store, &id, dir))
+                                        .map(|events| emit_file_events(&app, events));
+                                    let _ = reply_tx.send(result);
+                                }
+                                NetworkCommand::RejectFile(id, reply_tx) => {
+                                    let result = with_history(&history, |store| files.reject(store, &mut swarm, &id))
+                                        .map(|events| emit_file_events(&app, events));
+                                    let _ = reply_tx.send(result);
+                                }
+                                NetworkCommand::CancelFile(id, reply_tx) => {
+                                    let result = with_history(&history, |store| files.cancel(store, &mut swarm, &id))
+                                        .map(|events| emit_file_events(&app, events));
+                                    let _ = reply_tx.send(result);
+                                }
+                                NetworkCommand::SendGroupMessage(group_id, text, reply_tx) => {
+                                    let result = with_history(&history, |store| {
+                                        groups::send(store, &mut messenger, &mut swarm, &group_id, text)
+                                    });
+                                    let _ = reply_tx.send(result.map(|(id, changes)| {
+                                        emit_status(&app, changes);
+                                        id
+                                    }));
                                 }
                             }
                         }
 
+                        // A transfer's file read, write or check finished off the swarm task
+                        Some(done) = files.next_disk() => {
+                            if let Ok(events) = with_history(&history, |store| {
+                                Ok(files.on_disk(store, &mut swarm, done))
+                            }) {
+                                emit_file_events(&app, events);
+                            }
+                        }
+                        // Retry relays / bootstrap nodes and queued messages whose backoff expired
+                        _ = reconnect.tick() => {
+                            relays.dial_due(&mut swarm);
+                            if let Ok(changes) = with_history(&history, |store| {
+                                let changes = outbox::retry_due(store, &mut messenger, &mut swarm);
+                                mailbox.deposit(store, &mut messenger, &mut swarm, &changes);
+                                Ok(changes)
+                            }) {
+                                emit_status(&app, changes);
+                            }
+                        }
+
                         // Handle Swarm Events
                         event = swarm.select_next_some() => {
                             match event {
                                 SwarmEvent::NewListenAddr { address, .. } => {
                                     println!("Listening on {:?}", address);
-                                    let _ = app.emit("network-event", format!("Listening on {:?}", address));
+                                    let _ = app.emit("network-event", serde_json::json!({
+                                        "type": "listening",
+                                        "address": address.to_string()
+                                    }));
+                                }
+                                SwarmEvent::ConnectionEstablished { peer_id, connection_id, endpoint, .. } => {
+                                    relays.on_connected(&mut swarm, &peer_id);
+                                    mailbox.on_connected(&mut swarm, &peer_id);
+                                    if let Some(link) = links.on_established(peer_id, connection_id, &endpoint) {
+                                        emit_peer_link(&app, &peer_id, Some(link));
+                                    }
+                                    if let Ok((changes, events)) = with_history(&history, |store| {
+                                        let changes = outbox::flush(store, &mut messenger, &mut swarm, &peer_id);
+                                        Ok((changes, files.on_connected(store, &peer_id)))
+                                    }) {
+                                        emit_status(&app, changes);
+                                        emit_file_events(&app, events);
+                                    }
+                                }
+                                SwarmEvent::ConnectionClosed { peer_id, connection_id, num_established, .. } => {
+                                    if num_established == 0 {
+                                        relays.on_disconnected(&mut swarm, &peer_id);
+                                    }
+                                    if let Some(link) = links.on_closed(peer_id, connection_id) {
+                                        emit_peer_link(&app, &peer_id, link);
+                                    }
+                                }
+                                SwarmEvent::OutgoingConnectionError { peer_id: Some(peer_id), error, .. } => {
+                                    relays.on_dial_failure(&peer_id, error.to_string());
+                                }
+                                SwarmEvent::ListenerClosed { listener_id, reason, .. } => {
+                                    relays.on_listener_closed(
+                                        &mut swarm,
+                                        listener_id,
+                                        reason.map_err(|e| e.to_string()),
+                                    );
                                 }
                                 SwarmEvent::Behaviour(VoidEvent::RelayClient(event)) => {
                                      println!("Relay Event: {:?}", event);
                                 }
                                 SwarmEvent::Behaviour(VoidEvent::Identify(event)) => {
                                      println!("Identify Event: {:?}", event);
+                                     if let identify::Event::Received { peer_id, info, .. } = event {
+                                         record_contact_seen(&history, &peer_id, &info.listen_addrs);
+                                         if let Some(peer) = lan_peers.on_identified(&peer_id, &info.protocol_version) {
+                                             println!("LAN peer discovered: {}", peer.peer_id);
+                                             let _ = app.emit("peer-discovered", peer);
+                                         }
+                                     }
+                                }
+                                SwarmEvent::Behaviour(VoidEvent::Mdns(mdns::Event::Discovered(list))) => {
+                                    for (peer_id, addr) in &list {
+                                        swarm.add_peer_address(*peer_id, addr.clone());
+                                    }
+                                    // Dial so identify can confirm it's a VOID node
+                                    for peer_id in lan_peers.on_discovered(list) {
+                                        if !swarm.is_connected(&peer_id) {
+                                            let _ = swarm.dial(peer_id);
+                                        }
+                                    }
+                                }
+                                SwarmEvent::Behaviour(VoidEvent::Mdns(mdns::Event::Expired(list))) => {
+                                    for peer_id in lan_peers.on_expired(list) {
+                                        let _ = app.emit("peer-expired", serde_json::json!({
+                                            "peerId": peer_id.to_string()
+                                        }));
+                                    }
+                                }
+                                SwarmEvent::Behaviour(VoidEvent::Dcutr(event)) => {
+                                    println!("Hole punch with {}: {:?}", event.remote_peer_id, event.result);
+                                    let _ = app.emit("network-event", serde_json::json!({
+                                        "type": "holePunch",
+                                        "peerId": event.remote_peer_id.to_string(),
+                                        "success": event.result.is_ok(),
+                                        "error": event.result.err().map(|e| e.to_string())
+                                    }));
+                                }
+                                SwarmEvent::Behaviour(VoidEvent::Autonat(autonat::Event::StatusChanged { old, new })) => {
+                                    println!("NAT status changed: {:?} -> {:?}", old, new);
+                                    let (status, address) = match new {
+                                        autonat::NatStatus::Public(addr) => ("public", Some(addr.to_string())),
+                                        autonat::NatStatus::Private => ("private", None),
+                                        autonat::NatStatus::Unknown => ("unknown", None),
+                                    };
+                                    let _ = app.emit("network-event", serde_json::json!({
+                                        "type": "natStatus",
+                                        "status": status,
+                                        "address": address
+                                    }));
                                 }
                                 SwarmEvent::Behaviour(VoidEvent::Signaling(event)) => {
                                     match event {
                                         libp2p::request_response::Event::Message { peer, message, .. } => {
                                             match message {
                                                 libp2p::request_response::Message::Request { request, channel, .. } => {
-                                                    println!("Received Signal from {}: {}", peer, request.0);
-                                                    let _ = app.emit("signal-event", serde_json::json!({
-                                                        "peerId": peer.to_string(),
-                                                        "payload": request.0
-                                                    }));
-                                                    let _ = swarm.behaviour_mut().signaling.send_response(channel, SignalingResponse("ACK".into()));
+                                                    let response = match signaling::validate(&request) {
+                                                        Ok(()) => {
+                                                            route_signal(&app, peer, request.signal);
+                                                            SignalingResponse::Ack
+                                                        }
+                                                        Err(rejection) => {
+                                                            println!("Rejected Signal from {}: {:?}", peer, rejection);
+                                                            rejection
+                                                        }
+                                                    };
+                                                    let _ = swarm.behaviour_mut().signaling.send_response(channel, response);
+                                                }
+                                                libp2p::request_response::Message::Response { response, .. } => {
+                                                    if let SignalingResponse::Rejected { code, reason } = response {
+                                                        let _ = app.emit("signal-error", serde_json::json!({
+                                                            "peerId": peer.to_string(),
+                                                            "code": code,
+                                                            "reason": reason
+                                                        }));
+                                                    }
                                                 }
-                                                _ => {}
                                             }
                                         }
+                                        libp2p::request_response::Event::OutboundFailure { peer, error, .. } => {
+                                            let _ = app.emit("signal-error", serde_json::json!({
+                                                "peerId": peer.to_string(),
+                                                "code": "unreachable",
+                                                "reason": error.to_string()
+                                            }));
+                                        }
                                         _ => {}
                                     }
                                 }
+                                SwarmEvent::Behaviour(VoidEvent::Messaging(event)) => {
+                                    for event in messenger.handle_event(&mut swarm, event) {
+                                        handle_messenger_event(&app, &history, &mut messenger, &mut swarm, &mut mailbox, &mut files, event);
+                                    }
+                                }
+                                SwarmEvent::Behaviour(VoidEvent::Files(event)) => {
+                                    if let Ok(events) = with_history(&history, |store| {
+                                        Ok(files.handle_event(store, &mut swarm, event))
+                                    }) {
+                                        emit_file_events(&app, events);
+                                    }
+                                }
+                                SwarmEvent::Behaviour(VoidEvent::Gossipsub(event)) => {
+                                    if let Some(event) = rooms.handle_event(event) {
+                                        let _ = app.emit("room-event", event);
+                                    }
+                                }
+                                SwarmEvent::Behaviour(VoidEvent::Mailbox(event)) => {
+                                    match with_history(&history, |store| {
+                                        Ok(mailbox.handle_event(store, &mut messenger, &mut swarm, None, event))
+                                    }) {
+                                        Ok(outcome) => {
+                                            emit_status(&app, outcome.changes);
+                                            for dropped in outcome.dropped {
+                                                let _ = app.emit("network-event", serde_json::json!({
+                                                    "type": "mailDropped",
+                                                    "mail": dropped
+                                                }));
+                                            }
+                                            for event in outcome.messages {
+                                                handle_messenger_event(&app, &history, &mut messenger, &mut swarm, &mut mailbox, &mut files, event);
+                                            }
+                                        }
+                                        Err(e) => eprintln!("Failed to handle mailbox event: {}", e),
+                                    }
+                                }
                                 _ => {}
                             }
                         }
                     }
                 }
+
+                let _ = app.emit("network-event", serde_json::json!({ "type": "stopped" }));
             }
             Err(e) => {
                 eprintln!("Failed to build swarm: {}", e);
             }
         }
     });
+    *state.task.lock().await = Some(task);
 
     Ok("Node started".into())
 }
 
+/// Records, pins and forwards one messenger event, whether it arrived
+/// directly or was fetched from a mailbox.
+fn handle_messenger_event(
+    app: &AppHandle,
+    history: &std::sync::Mutex<Option<MessageStore>>,
+    messenger: &mut Messenger,
+    swarm: &mut Swarm<VoidBehaviour>,
+    mailbox: &mut MailboxClient,
+    files: &mut FileTransfers,
+    event: MessengerEvent,
+) {
+    match &event {
+        MessengerEvent::Received { peer, content: MessageContent::FileOffer(offer), .. } => {
+            pin_contact_key(app, history, messenger, peer);
+            match with_history(history, |store| files.on_offer(store, swarm, peer, offer)) {
+                Ok(events) => emit_file_events(app, events),
+                Err(e) => eprintln!("Ignoring file offer from {}: {}", peer, e),
+            }
+            return;
+        }
+        MessengerEvent::Received { peer, sent_at, content, .. } if content.is_group() => {
+            pin_contact_key(app, history, messenger, peer);
+            if let Ok(outcome) = with_history(history, |store| {
+                let outcome = groups::on_received(store, messenger, swarm, peer, *sent_at, content);
+                mailbox.deposit(store, messenger, swarm, &outcome.changes);
+                Ok(outcome)
+            }) {
+                emit_status(app, outcome.changes);
+                emit_group_events(app, outcome.events);
+            }
+            return;
+        }
+        MessengerEvent::Received { peer, id, sent_at, content } => {
+            record_message(history, id, peer, content, false, *sent_at);
+            pin_contact_key(app, history, messenger, peer);
+        }
+        MessengerEvent::Delivered { peer, .. } | MessengerEvent::KeyChanged { peer } => {
+            pin_contact_key(app, history, messenger, peer);
+        }
+        MessengerEvent::Read { .. } | MessengerEvent::Failed { .. } => {}
+    }
+    if let Ok(changes) = with_history(history, |store| {
+        let changes = outbox::on_event(store, &event);
+        // Couldn't reach the peer directly; try its mailbox
+        mailbox.deposit(store, messenger, swarm, &changes);
+        Ok(changes)
+    }) {
+        emit_status(app, changes);
+    }
+    emit_messenger_event(app, event);
+}
+
+fn emit_peer_link(app: &AppHandle, peer: &PeerId, link: Option<LinkKind>) {
+    let _ = app.emit("network-event", serde_json::json!({
+        "type": "peerLink",
+        "peerId": peer.to_string(),
+        // null once the last connection to the peer is gone
+        "link": link
+    }));
+}
+
+fn record_message(
+    history: &std::sync::Mutex<Option<MessageStore>>,
+    id: &str,
+    peer: &PeerId,
+    content: &MessageContent,
+    is_sent: bool,
+    timestamp: i64,
+) {
+    let MessageContent::Text { text } = content else {
+        return;
+    };
+    if let Ok(mut guard) = history.lock() {
+        if let Some(store) = guard.as_mut() {
+            if let Err(e) = store.insert(id, &peer.to_string(), text, is_sent, timestamp) {
+                eprintln!("Failed to store message: {}", e);
+            }
+        }
+    }
+}
+
+/// Runs `f` against the open message store.
+fn with_history<T>(
+    history: &std::sync::Mutex<Option<MessageStore>>,
+    f: impl FnOnce(&mut MessageStore) -> Result<T, String>,
+) -> Result<T, String> {
+    let mut guard = history.lock().map_err(|e| e.to_string())?;
+    let store = guard.as_mut().ok_or("Message store is locked")?;
+    f(store)
+}
+
+/// Blocks blocked contacts and dials the rest at their last-seen addresses.
+pub fn dial_contacts(swarm: &mut Swarm<VoidBehaviour>, contacts: &[Contact]) {
+    for contact in contacts {
+        let Ok(peer_id) = contact.peer_id.parse::<PeerId>() else {
+            continue;
+        };
+        if contact.blocked {
+            swarm.behaviour_mut().blocked.block_peer(peer_id);
+            continue;
+        }
+        for addr in contact.addresses.iter().filter_map(|a| a.parse().ok()) {
+            swarm.add_peer_address(peer_id, addr);
+        }
+        if !contact.addresses.is_empty() {
+            println!("Dialing contact {} ({})", contact.nickname, peer_id);
+            let _ = swarm.dial(peer_id);
+        }
+    }
+}
+
+/// Remembers the addresses a contact advertised, so it can be dialed on the
+/// next start. Shared by the GUI and CLI event loops.
+pub(crate) fn record_seen(store: &mut MessageStore, peer: &PeerId, addrs: &[libp2p::Multiaddr]) -> Result<(), String> {
+    let addresses: Vec<String> = utils::code_addresses(addrs).iter().map(|a| a.to_string()).collect();
+    store.record_seen(&peer.to_string(), &addresses)
+}
+
+/// Pins the messaging identity key of the peer's session if it is a contact
+/// (trust on first use). Shared by the GUI and CLI event loops.
+pub(crate) fn pin_session_key(store: &mut MessageStore, messenger: &Messenger, peer: &PeerId) -> Result<KeyPin, String> {
+    match messenger.remote_identity(peer) {
+        Some(identity_key) => store.pin_identity_key(&peer.to_string(), &identity_key),
+        // No session yet, so nothing to pin
+        None => Ok(KeyPin::Unchanged),
+    }
+}
+
+fn record_contact_seen(history: &std::sync::Mutex<Option<MessageStore>>, peer: &PeerId, addrs: &[libp2p::Multiaddr]) {
+    if let Err(e) = with_history(history, |store| record_seen(store, peer, addrs)) {
+        eprintln!("Failed to update contact: {}", e);
+    }
+}
+
+/// Pins the peer's messaging identity key if it is a contact, warning the
+/// frontend when it differs from the pinned one.
+fn pin_contact_key(
+    app: &AppHandle,
+    history: &std::sync::Mutex<Option<MessageStore>>,
+    messenger: &Messenger,
+    peer: &PeerId,
+) {
+    if let Ok(mut guard) = history.lock() {
+        if let Some(store) = guard.as_mut() {
+            match pin_session_key(store, messenger, peer) {
+                Ok(KeyPin::Changed { was_verified }) => {
+                    println!("Identity key of contact {} changed", peer);
+                    let _ = app.emit("contact-key-changed", serde_json::json!({
+                        "peerId": peer.to_string(),
+                        "wasVerified": was_verified
+                    }));
+                }
+                Ok(_) => {}
+                Err(e) => eprintln!("Failed to pin contact key: {}", e),
+            }
+        }
+    }
+}
+
+/// Safety number for `peer`, using the live session key or else the pinned one.
+fn safety_number_with(
+    history: &std::sync::Mutex<Option<MessageStore>>,
+    messenger: &Messenger,
+    local_peer: &PeerId,
+    peer: &PeerId,
+) -> Result<SafetyNumber, String> {
+    let remote_key = match messenger.remote_identity(peer) {
+        Some(key) => key,
+        None => {
+            let guard = history.lock().map_err(|e| e.to_string())?;
+            let store = guard.as_ref().ok_or("Message store is locked")?;
+            store
+                .pinned_identity_key(&peer.to_string())?
+                .ok_or("No session with this peer yet; exchange a message first")?
+        }
+    };
+    Ok(safety::safety_number(&messenger.local_identity(), local_peer, &remote_key, peer))
+}
+
+/// Dispatches a validated signal to the frontend listener that owns it.
+fn route_signal(app: &AppHandle, peer: PeerId, signal: Signal) {
+    println!("Received Signal from {}: {:?}", peer, signal);
+    let event = match &signal {
+        Signal::Offer { .. }
+        | Signal::Answer { .. }
+        | Signal::IceCandidate { .. }
+        | Signal::AppData { .. } => "signal-event",
+        Signal::Chat { .. } => "chat-event",
+        Signal::Receipt { .. } => "receipt-event",
+        Signal::Error { .. } => "signal-error",
+    };
+    let _ = app.emit(event, serde_json::json!({
+        "peerId": peer.to_string(),
+        // Legacy JSON string that existing WebRTC / SyncPlayer / StickyBoard listeners parse
+        "payload": signaling::to_frontend_payload(&signal),
+        "signal": signal
+    }));
+}
+
+fn emit_messenger_event(app: &AppHandle, event: MessengerEvent) {
+    // Delivery, read and failure updates come from the outbox as status changes
+    if let MessengerEvent::Received {
+        peer,
+        id,
+        sent_at,
+        content,
+    } = event
+    {
+        let _ = app.emit("message-event", serde_json::json!({
+            "peerId": peer.to_string(),
+            "id": id,
+            "sentAt": sent_at,
+            "content": content
+        }));
+    }
+}
+
+fn emit_group_events(app: &AppHandle, events: Vec<GroupEvent>) {
+    for event in events {
+        let _ = app.emit("group-event", event);
+    }
+}
+
+fn emit_file_events(app: &AppHandle, events: Vec<FileEvent>) {
+    for event in events {
+        let _ = app.emit("file-event", event);
+    }
+}
+
+fn emit_status(app: &AppHandle, changes: Vec<StatusChange>) {
+    for change in changes {
+        let _ = app.emit("message-status", change);
+    }
+}
+
+/// Sends an end-to-end encrypted text message and returns its id. The message
+/// waits in the outbox until the peer is reachable; progress is reported via
+/// `message-status` events.
+#[tauri::command]
+pub async fn send_message(
+    peer_id: String,
+    text: String,
+    state: State<'_, NetworkState>,
+) -> Result<String, String> {
+    let peer_id = peer_id.parse::<PeerId>().map_err(|e| e.to_string())?;
+    request(&state, |tx| {
+        NetworkCommand::SendMessage(peer_id, MessageContent::Text { text }, tx)
+    })
+    .await
+}
+
+/// Current link kind (relayed / direct) of every connected peer.
+#[tauri::command]
+pub async fn get_peer_links(
+    state: State<'_, NetworkState>,
+) -> Result<HashMap<String, LinkKind>, String> {
+    query(&state, NetworkCommand::GetPeerLinks).await
+}
+
+/// VOID nodes currently visible on the local network.
+#[tauri::command]
+pub async fn get_lan_peers(state: State<'_, NetworkState>) -> Result<Vec<LanPeer>, String> {
+    query(&state, NetworkCommand::GetLanPeers).await
+}
+
 #[tauri::command]
 pub async fn dial_peer(peer_id: String, state: State<'_, NetworkState>) -> Result<(), String> {
     let peer_id = peer_id.parse::<PeerId>().map_err(|e| e.to_string())?;
@@ -184,11 +901,11 @@ pub async fn dial_peer(peer_id: String, state: State<'_, NetworkState>) -> Resul
 
 #[tauri::command]
 pub async fn connect_via_code(code: String, state: State<'_, NetworkState>) -> Result<(), String> {
-    let multiaddr = utils::parse_void_code(&code)?;
+    let code = utils::parse_void_code(&code)?;
     let sender_guard = state.sender.lock().await;
 
     if let Some(tx) = sender_guard.as_ref() {
-        tx.send(NetworkCommand::DialAddress(multiaddr))
+        tx.send(NetworkCommand::DialCode(code))
             .await
             .map_err(|e| e.to_string())?;
         Ok(())
@@ -197,33 +914,32 @@ pub async fn connect_via_code(code: String, state: State<'_, NetworkState>) -> R
     }
 }
 
+/// Signed code listing our relay and direct addresses, valid for `ttl_secs`
+/// (24h by default).
 #[tauri::command]
-pub async fn get_my_void_code(state: State<'_, NetworkState>) -> Result<String, String> {
-    let (tx, rx) = oneshot::channel();
-    let sender_guard = state.sender.lock().await;
-
-    if let Some(sender) = sender_guard.as_ref() {
-        sender
-            .send(NetworkCommand::GetIdentity(tx))
-            .await
-            .map_err(|e| e.to_string())?;
-
-        let (peer_id, addrs) = rx.await.map_err(|e| e.to_string())?;
-
-        // Prioritize relay address (p2p-circuit)
-        for addr in &addrs {
-             if addr.to_string().contains("p2p-circuit") {
-                 return Ok(utils::generate_void_code(addr));
-             }
-        }
+pub async fn get_my_void_code(
+    ttl_secs: Option<u64>,
+    state: State<'_, NetworkState>,
+) -> Result<String, String> {
+    let ttl_secs = ttl_secs.unwrap_or(utils::DEFAULT_CODE_TTL_SECS);
+    request(&state, |tx| NetworkCommand::GetVoidCode(ttl_secs, tx)).await
+}
 
-        // Fallback to first available address
-        if let Some(addr) = addrs.first() {
-            return Ok(utils::generate_void_code(addr));
-        }
+/// Marks received messages as read and tells the sender. Returns the ids that
+/// weren't read before.
+#[tauri::command]
+pub async fn mark_messages_read(
+    peer_id: String,
+    ids: Vec<String>,
+    state: State<'_, NetworkState>,
+) -> Result<Vec<String>, String> {
+    let peer_id = peer_id.parse::<PeerId>().map_err(|e| e.to_string())?;
+    request(&state, |tx| NetworkCommand::MarkRead(peer_id, ids, tx)).await
+}
 
-        Err("No listen address found yet".into())
-    } else {
-        Err("Node not running".into())
-    }
+/// Puts a failed message back in the outbox and tries to send it again.
+#[tauri::command]
+pub async fn retry_message(peer_id: String, id: String, state: State<'_, NetworkState>) -> Result<(), String> {
+    let peer_id = peer_id.parse::<PeerId>().map_err(|e| e.to_string())?;
+    request(&state, |tx| NetworkCommand::RetryMessage(peer_id, id, tx)).await
 }
@@ -0,0 +1,231 @@
+// Drives the persistent outbox: queues outgoing messages, resends them when
+// the peer reconnects or their backoff expires, and turns messenger events into
+// status changes for the UI.
+use crate::network::messaging::{self, MessageContent, Messenger, MessengerEvent};
+use crate::network::swarm::VoidBehaviour;
+use crate::storage::db::MessageStore;
+use crate::storage::outbox::{MessageStatus, OutboxEntry};
+use libp2p::{PeerId, Swarm};
+use serde::Serialize;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusChange {
+    pub peer_id: String,
+    pub id: String,
+    pub status: MessageStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// When the next attempt is due, for messages still queued.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub retry_at: Option<i64>,
+}
+
+impl StatusChange {
+    fn new(peer_id: impl ToString, id: impl ToString, status: MessageStatus) -> Self {
+        Self {
+            peer_id: peer_id.to_string(),
+            id: id.to_string(),
+            status,
+            error: None,
+            retry_at: None,
+        }
+    }
+}
+
+/// Stores `content` in the history and outbox, then tries to send it.
+pub fn send(
+    store: &mut MessageStore,
+    messenger: &mut Messenger,
+    swarm: &mut Swarm<VoidBehaviour>,
+    peer: PeerId,
+    content: MessageContent,
+) -> Result<(String, Vec<StatusChange>), String> {
+    let id = messaging::new_message_id();
+    let sent_at = chrono::Utc::now().timestamp();
+    let MessageContent::Text { text } = &content else {
+        return Err("Only text messages have a history entry".into());
+    };
+    let encoded = serde_json::to_string(&content).map_err(|e| e.to_string())?;
+    store.queue_message(&id, &peer.to_string(), text, &encoded, sent_at)?;
+
+    let mut changes = vec![StatusChange::new(peer, &id, MessageStatus::Queued)];
+    let entry = OutboxEntry {
+        id: id.clone(),
+        peer_id: peer.to_string(),
+        sent_at,
+        content: encoded,
+    };
+    changes.extend(dispatch(store, messenger, swarm, entry));
+    Ok((id, changes))
+}
+
+/// Queues protocol traffic without a history entry (group updates, fan-out
+/// copies of group messages) and tries to send it.
+pub fn send_control(
+    store: &mut MessageStore,
+    messenger: &mut Messenger,
+    swarm: &mut Swarm<VoidBehaviour>,
+    peer: PeerId,
+    content: &MessageContent,
+) -> Result<Vec<StatusChange>, String> {
+    let id = messaging::new_message_id();
+    let sent_at = chrono::Utc::now().timestamp();
+    let encoded = serde_json::to_string(content).map_err(|e| e.to_string())?;
+    store.queue_control(&id, &peer.to_string(), &encoded, sent_at)?;
+
+    let entry = OutboxEntry {
+        id,
+        peer_id: peer.to_string(),
+        sent_at,
+        content: encoded,
+    };
+    Ok(dispatch(store, messenger, swarm, entry).into_iter().collect())
+}
+
+/// Resends everything queued for `peer`, ignoring backoff. Called when a
+/// connection to it comes up.
+pub fn flush(
+    store: &mut MessageStore,
+    messenger: &mut Messenger,
+    swarm: &mut Swarm<VoidBehaviour>,
+    peer: &PeerId,
+) -> Vec<StatusChange> {
+    resend(store, messenger, swarm, Some(&peer.to_string()))
+}
+
+/// Resends messages whose backoff expired. Dialing happens implicitly.
+pub fn retry_due(
+    store: &mut MessageStore,
+    messenger: &mut Messenger,
+    swarm: &mut Swarm<VoidBehaviour>,
+) -> Vec<StatusChange> {
+    resend(store, messenger, swarm, None)
+}
+
+/// Puts a failed message to `peer` back in the queue and tries it again.
+pub fn retry(
+    store: &mut MessageStore,
+    messenger: &mut Messenger,
+    swarm: &mut Swarm<VoidBehaviour>,
+    peer: PeerId,
+    id: &str,
+) -> Result<Vec<StatusChange>, String> {
+    let peer_id = peer.to_string();
+    if !store.requeue_message(&peer_id, id)? {
+        return Err("Message is not in the outbox".into());
+    }
+    let mut changes = vec![StatusChange::new(&peer_id, id, MessageStatus::Queued)];
+    changes.extend(resend(store, messenger, swarm, Some(&peer_id)));
+    Ok(changes)
+}
+
+/// Marks messages from `peer` as read and sends a read receipt.
+pub fn mark_read(
+    store: &mut MessageStore,
+    messenger: &mut Messenger,
+    swarm: &mut Swarm<VoidBehaviour>,
+    peer: PeerId,
+    ids: &[String],
+) -> Result<Vec<String>, String> {
+    let changed = store.mark_read(&peer.to_string(), ids, false)?;
+    if !changed.is_empty() {
+        messenger.send_read_receipt(swarm, peer, changed.clone())?;
+    }
+    Ok(changed)
+}
+
+/// Updates the outbox for a messenger event and reports what changed.
+pub fn on_event(store: &mut MessageStore, event: &MessengerEvent) -> Vec<StatusChange> {
+    let result = match event {
+        MessengerEvent::Received { .. } | MessengerEvent::KeyChanged { .. } => Ok(Vec::new()),
+        MessengerEvent::Delivered { peer, id } => store.mark_delivered(&peer.to_string(), id).map(|updated| {
+            if updated {
+                vec![StatusChange::new(peer, id, MessageStatus::Delivered)]
+            } else {
+                Vec::new()
+            }
+        }),
+        MessengerEvent::Read { peer, ids } => store.mark_read(&peer.to_string(), ids, true).map(|ids| {
+            ids.into_iter()
+                .map(|id| StatusChange::new(peer, id, MessageStatus::Read))
+                .collect()
+        }),
+        MessengerEvent::Failed { peer, id, error } => failed(store, &peer.to_string(), id, error)
+            .map(|change| change.into_iter().collect()),
+    };
+    result.unwrap_or_else(|e| {
+        eprintln!("Failed to update outbox: {}", e);
+        Vec::new()
+    })
+}
+
+fn resend(
+    store: &mut MessageStore,
+    messenger: &mut Messenger,
+    swarm: &mut Swarm<VoidBehaviour>,
+    peer_id: Option<&str>,
+) -> Vec<StatusChange> {
+    let entries = match store.pending_messages(peer_id) {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Failed to read outbox: {}", e);
+            return Vec::new();
+        }
+    };
+    let mut changes = Vec::new();
+    for entry in entries {
+        if !messenger.is_sending(&entry.id) {
+            changes.extend(dispatch(store, messenger, swarm, entry));
+        }
+    }
+    changes
+}
+
+fn dispatch(
+    store: &mut MessageStore,
+    messenger: &mut Messenger,
+    swarm: &mut Swarm<VoidBehaviour>,
+    entry: OutboxEntry,
+) -> Option<StatusChange> {
+    let result = entry
+        .peer_id
+        .parse::<PeerId>()
+        .map_err(|e| e.to_string())
+        .and_then(|peer| {
+            let content: MessageContent =
+                serde_json::from_str(&entry.content).map_err(|e| e.to_string())?;
+            messenger.send_as(swarm, peer, entry.id.clone(), entry.sent_at, content)?;
+            Ok(peer)
+        });
+
+    match result {
+        // Only counts as sent once it's on a live connection
+        Ok(peer) if swarm.is_connected(&peer) => match store.mark_sent(&entry.peer_id, &entry.id) {
+            Ok(true) => Some(StatusChange::new(peer, &entry.id, MessageStatus::Sent)),
+            Ok(false) => None,
+            Err(e) => {
+                eprintln!("Failed to update outbox: {}", e);
+                None
+            }
+        },
+        Ok(_) => None,
+        Err(error) => failed(store, &entry.peer_id, &entry.id, &error).unwrap_or_else(|e| {
+            eprintln!("Failed to update outbox: {}", e);
+            None
+        }),
+    }
+}
+
+fn failed(
+    store: &mut MessageStore,
+    peer_id: &str,
+    id: &str,
+    error: &str,
+) -> Result<Option<StatusChange>, String> {
+    Ok(store.schedule_retry(peer_id, id, error)?.map(|(status, retry_at)| StatusChange {
+        error: Some(error.to_string()),
+        retry_at,
+        ..StatusChange::new(peer_id, id, status)
+    }))
+}
@@ -0,0 +1,337 @@
+use crate::network::mailbox::{MailboxConfig, MailboxServer};
+use crate::network::swarm::{RelayServerEvent, build_relay_swarm};
+use crate::storage::identity;
+use clap::Parser;
+use libp2p::{Multiaddr, PeerId, futures::StreamExt, relay, request_response, swarm::SwarmEvent};
+use std::error::Error;
+use std::net::SocketAddr;
+use std::num::NonZeroU32;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpListener;
+
+#[derive(Parser, Debug)]
+#[command(name = "void-relay", version, about = "Headless VOID circuit relay")]
+pub struct RelayArgs {
+    /// TCP and QUIC port to listen on (WebSocket listens on port + 1)
+    #[arg(long, default_value_t = 4001)]
+    pub port: u16,
+
+    /// Additional listen multiaddrs (overrides the defaults derived from --port)
+    #[arg(long = "listen")]
+    pub listen: Vec<String>,
+
+    /// Publicly reachable address to advertise (repeatable)
+    #[arg(long = "external")]
+    pub external: Vec<String>,
+
+    /// Path to the encrypted relay identity
+    #[arg(long, default_value = "void-relay.key")]
+    pub identity: PathBuf,
+
+    /// Only accept connections from these PeerIds (repeatable). Open relay if empty.
+    #[arg(long = "allow")]
+    pub allow: Vec<String>,
+
+    #[arg(long, default_value_t = 128)]
+    pub max_reservations: usize,
+
+    #[arg(long, default_value_t = 4)]
+    pub max_reservations_per_peer: usize,
+
+    #[arg(long, default_value_t = 64)]
+    pub max_circuits: usize,
+
+    #[arg(long, default_value_t = 8)]
+    pub max_circuits_per_peer: usize,
+
+    /// Reservation requests allowed per peer per minute
+    #[arg(long, default_value_t = 30)]
+    pub reservation_rate: u32,
+
+    /// Circuit requests allowed per source peer per minute
+    #[arg(long, default_value_t = 60)]
+    pub circuit_rate: u32,
+
+    /// Prometheus metrics endpoint; pass an empty string to disable
+    #[arg(long, default_value = "127.0.0.1:9464")]
+    pub metrics: String,
+
+    /// Also act as a store-and-forward mailbox, keeping mail in this database
+    #[arg(long)]
+    pub mailbox: Option<PathBuf>,
+
+    /// Hours undelivered mail is kept
+    #[arg(long, default_value_t = 168)]
+    pub mailbox_ttl_hours: u64,
+
+    /// Messages held per registered peer
+    #[arg(long, default_value_t = 500)]
+    pub mailbox_max_messages: u32,
+
+    /// Megabytes held per registered peer
+    #[arg(long, default_value_t = 10)]
+    pub mailbox_max_mb: u64,
+}
+
+impl RelayArgs {
+    fn relay_config(&self) -> Result<relay::Config, String> {
+        let reservation_rate =
+            NonZeroU32::new(self.reservation_rate).ok_or("--reservation-rate must be > 0")?;
+        let circuit_rate = NonZeroU32::new(self.circuit_rate).ok_or("--circuit-rate must be > 0")?;
+
+        Ok(relay::Config {
+            max_reservations: self.max_reservations,
+            max_reservations_per_peer: self.max_reservations_per_peer,
+            max_circuits: self.max_circuits,
+            max_circuits_per_peer: self.max_circuits_per_peer,
+            ..Default::default()
+        }
+        .reservation_rate_per_peer(reservation_rate, Duration::from_secs(60))
+        .circuit_src_per_peer(circuit_rate, Duration::from_secs(60)))
+    }
+
+    fn mailbox_config(&self) -> MailboxConfig {
+        MailboxConfig {
+            ttl_secs: self.mailbox_ttl_hours.saturating_mul(60 * 60),
+            max_messages: self.mailbox_max_messages,
+            max_bytes: self.mailbox_max_mb.saturating_mul(1024 * 1024),
+            ..Default::default()
+        }
+    }
+
+    fn allowed_peers(&self) -> Result<Option<Vec<PeerId>>, String> {
+        if self.allow.is_empty() {
+            return Ok(None);
+        }
+        self.allow
+            .iter()
+            .map(|p| p.parse::<PeerId>().map_err(|e| format!("Invalid --allow {}: {}", p, e)))
+            .collect::<Result<Vec<_>, _>>()
+            .map(Some)
+    }
+
+    fn listen_addrs(&self) -> Result<Vec<Multiaddr>, String> {
+        let addrs = if self.listen.is_empty() {
+            vec![
+                format!("/ip4/0.0.0.0/tcp/{}", self.port),
+                format!("/ip4/0.0.0.0/udp/{}/quic-v1", self.port),
+                format!("/ip4/0.0.0.0/tcp/{}/ws", self.port.saturating_add(1)),
+            ]
+        } else {
+            self.listen.clone()
+        };
+        addrs
+            .iter()
+            .map(|a| a.parse().map_err(|e| format!("Invalid listen address {}: {}", a, e)))
+            .collect()
+    }
+}
+
+/// Counters exposed on the metrics endpoint in Prometheus text format.
+#[derive(Default)]
+pub struct RelayMetrics {
+    connections_active: AtomicU64,
+    reservations_accepted: AtomicU64,
+    reservations_denied: AtomicU64,
+    reservations_timed_out: AtomicU64,
+    circuits_accepted: AtomicU64,
+    circuits_denied: AtomicU64,
+    circuits_active: AtomicU64,
+}
+
+impl RelayMetrics {
+    fn render(&self) -> String {
+        let metrics = [
+            ("void_relay_connections_active", "gauge", &self.connections_active),
+            ("void_relay_reservations_accepted_total", "counter", &self.reservations_accepted),
+            ("void_relay_reservations_denied_total", "counter", &self.reservations_denied),
+            ("void_relay_reservations_timed_out_total", "counter", &self.reservations_timed_out),
+            ("void_relay_circuits_accepted_total", "counter", &self.circuits_accepted),
+            ("void_relay_circuits_denied_total", "counter", &self.circuits_denied),
+            ("void_relay_circuits_active", "gauge", &self.circuits_active),
+        ];
+
+        let mut out = String::new();
+        for (name, kind, value) in metrics {
+            out.push_str(&format!(
+                "# TYPE {} {}\n{} {}\n",
+                name,
+                kind,
+                name,
+                value.load(Ordering::Relaxed)
+            ));
+        }
+        out
+    }
+
+    fn record(&self, event: &relay::Event) {
+        match event {
+            relay::Event::ReservationReqAccepted { .. } => {
+                self.reservations_accepted.fetch_add(1, Ordering::Relaxed);
+            }
+            relay::Event::ReservationReqDenied { .. } => {
+                self.reservations_denied.fetch_add(1, Ordering::Relaxed);
+            }
+            relay::Event::ReservationTimedOut { .. } => {
+                self.reservations_timed_out.fetch_add(1, Ordering::Relaxed);
+            }
+            relay::Event::CircuitReqAccepted { .. } => {
+                self.circuits_accepted.fetch_add(1, Ordering::Relaxed);
+                self.circuits_active.fetch_add(1, Ordering::Relaxed);
+            }
+            relay::Event::CircuitReqDenied { .. } => {
+                self.circuits_denied.fetch_add(1, Ordering::Relaxed);
+            }
+            relay::Event::CircuitClosed { .. } => {
+                let _ = self.circuits_active.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| {
+                    Some(v.saturating_sub(1))
+                });
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Minimal HTTP server answering `GET /metrics`.
+async fn serve_metrics(addr: SocketAddr, metrics: Arc<RelayMetrics>) -> std::io::Result<()> {
+    let listener = TcpListener::bind(addr).await?;
+    log::info!("Metrics on http://{}/metrics", addr);
+
+    loop {
+        let (mut socket, _) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let n = socket.read(&mut buf).await.unwrap_or(0);
+            let request = String::from_utf8_lossy(&buf[..n]);
+
+            let (status, body) = if request.starts_with("GET /metrics") {
+                ("200 OK", metrics.render())
+            } else {
+                ("404 Not Found", "not found\n".to_string())
+            };
+            let response = format!(
+                "HTTP/1.1 {}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                status,
+                body.len(),
+                body
+            );
+            let _ = socket.write_all(response.as_bytes()).await;
+        });
+    }
+}
+
+pub async fn run_relay(args: RelayArgs) -> Result<(), Box<dyn Error>> {
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
+
+    let passphrase = std::env::var("VOID_IDENTITY_PASSPHRASE")
+        .unwrap_or_else(|_| identity::DEFAULT_PASSPHRASE.into());
+    let local_key = identity::load_or_create(&args.identity, &passphrase)?;
+
+    let allowed = args.allowed_peers()?;
+    match &allowed {
+        Some(peers) => log::info!("Allow-list enabled ({} peers)", peers.len()),
+        None => log::warn!("No --allow given: relay is open to any peer"),
+    }
+
+    let mut mailbox = match &args.mailbox {
+        Some(path) => {
+            let server = MailboxServer::open(path, args.mailbox_config())?;
+            log::info!("Mailbox enabled: {}", path.display());
+            Some(server)
+        }
+        None => None,
+    };
+
+    let mut swarm = build_relay_swarm(local_key, args.relay_config()?, allowed, mailbox.is_some())
+        .await
+        .map_err(|e| format!("Failed to build relay swarm: {}", e))?;
+
+    for addr in args.listen_addrs()? {
+        swarm.listen_on(addr)?;
+    }
+    for addr in &args.external {
+        let addr: Multiaddr = addr.parse()?;
+        swarm.add_external_address(addr);
+    }
+
+    let metrics = Arc::new(RelayMetrics::default());
+    if !args.metrics.is_empty() {
+        let addr: SocketAddr = args.metrics.parse()?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(e) = serve_metrics(addr, metrics).await {
+                log::error!("Metrics server stopped: {}", e);
+            }
+        });
+    }
+
+    let mut purge = tokio::time::interval(Duration::from_secs(10 * 60));
+    let shutdown = tokio::signal::ctrl_c();
+    tokio::pin!(shutdown);
+
+    loop {
+        tokio::select! {
+            event = swarm.select_next_some() => match event {
+                SwarmEvent::NewListenAddr { address, .. } => {
+                    log::info!("Listening on {}/p2p/{}", address, swarm.local_peer_id());
+                }
+                SwarmEvent::ConnectionEstablished { peer_id, num_established, .. } => {
+                    log::debug!("Connected: {}", peer_id);
+                    if num_established.get() == 1 {
+                        metrics.connections_active.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                SwarmEvent::ConnectionClosed { peer_id, num_established: 0, .. } => {
+                    log::debug!("Disconnected: {}", peer_id);
+                    let _ = metrics.connections_active.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |v| {
+                        Some(v.saturating_sub(1))
+                    });
+                }
+                SwarmEvent::Behaviour(RelayServerEvent::Relay(event)) => {
+                    log::info!("{:?}", event);
+                    metrics.record(&event);
+                }
+                SwarmEvent::Behaviour(RelayServerEvent::Identify(event)) => {
+                    log::debug!("Identify Event: {:?}", event);
+                }
+                SwarmEvent::Behaviour(RelayServerEvent::Mailbox(request_response::Event::Message {
+                    peer,
+                    message: request_response::Message::Request { request, channel, .. },
+                    ..
+                })) => {
+                    if let (Some(server), Some(behaviour)) = (&mut mailbox, swarm.behaviour_mut().mailbox.as_mut()) {
+                        let response = server.handle(&peer, request);
+                        let _ = behaviour.send_response(channel, response);
+                    }
+                }
+                SwarmEvent::Behaviour(RelayServerEvent::Mailbox(event)) => {
+                    log::debug!("Mailbox Event: {:?}", event);
+                }
+                _ => {}
+            },
+            _ = purge.tick(), if mailbox.is_some() => {
+                if let Some(server) = &mut mailbox {
+                    match server.purge_expired() {
+                        Ok(purged) => {
+                            if let Ok((owners, mail)) = server.stats() {
+                                log::info!("Mailbox: purged {}, holding {} messages for {} peers", purged, mail, owners);
+                            }
+                        }
+                        Err(e) => log::warn!("Mailbox purge failed: {}", e),
+                    }
+                }
+            }
+            _ = &mut shutdown => {
+                log::info!("Shutting down relay");
+                break;
+            }
+        }
+    }
+
+    Ok(())
+}
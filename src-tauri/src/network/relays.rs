@@ -0,0 +1,285 @@
+use crate::network::config::{BackoffConfig, Endpoint, EndpointKind, NetworkConfig};
+use crate::network::swarm::VoidBehaviour;
+use libp2p::{Multiaddr, PeerId, Swarm, core::transport::ListenerId, multiaddr::Protocol};
+use serde::Serialize;
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum LinkState {
+    Idle,
+    Dialing,
+    Connected,
+    BackingOff,
+}
+
+struct Tracked {
+    endpoint: Endpoint,
+    peer_id: PeerId,
+    addr: Multiaddr,
+    state: LinkState,
+    failures: u32,
+    next_attempt: Instant,
+    last_connected: Option<i64>,
+    last_error: Option<String>,
+    reservation: Option<ListenerId>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct EndpointHealth {
+    pub address: String,
+    pub kind: EndpointKind,
+    pub priority: u32,
+    pub state: LinkState,
+    pub failures: u32,
+    pub last_connected: Option<i64>,
+    pub last_error: Option<String>,
+    pub reserved: bool,
+    pub retry_in_secs: Option<u64>,
+}
+
+/// Keeps the node connected to its configured relays and bootstrap nodes,
+/// backing off on failures and moving relay reservations down the priority
+/// list when a relay goes away.
+pub struct RelayManager {
+    tracked: Vec<Tracked>,
+    max_reservations: usize,
+    backoff: BackoffConfig,
+}
+
+impl RelayManager {
+    pub fn new(config: &NetworkConfig) -> Self {
+        let mut manager = Self {
+            tracked: Vec::new(),
+            max_reservations: config.max_reservations,
+            backoff: config.backoff.clone(),
+        };
+        manager.apply(config);
+        manager
+    }
+
+    /// Applies a new config, keeping health history for endpoints that didn't
+    /// change. Reservations on endpoints that were removed, disabled or no
+    /// longer fit under `max_reservations` are released.
+    pub fn update(&mut self, swarm: &mut Swarm<VoidBehaviour>, config: &NetworkConfig) {
+        for dropped in self.apply(config) {
+            if let Some(listener) = dropped.reservation {
+                swarm.remove_listener(listener);
+            }
+        }
+
+        let mut active = 0;
+        for tracked in &mut self.tracked {
+            if tracked.reservation.is_none() {
+                continue;
+            }
+            if active < self.max_reservations {
+                active += 1;
+            } else if let Some(listener) = tracked.reservation.take() {
+                swarm.remove_listener(listener);
+            }
+        }
+        self.ensure_reservations(swarm);
+    }
+
+    /// Replaces the tracked endpoints, returning the ones no longer configured.
+    fn apply(&mut self, config: &NetworkConfig) -> Vec<Tracked> {
+        self.max_reservations = config.max_reservations;
+        self.backoff = config.backoff.clone();
+
+        let mut previous = std::mem::take(&mut self.tracked);
+        for endpoint in config.endpoints.iter().filter(|e| e.enabled) {
+            let (peer_id, addr) = match endpoint.parse() {
+                Ok(parsed) => parsed,
+                Err(e) => {
+                    println!("Skipping endpoint: {}", e);
+                    continue;
+                }
+            };
+
+            if let Some(pos) = previous.iter().position(|t| t.endpoint.address == endpoint.address) {
+                let mut tracked = previous.remove(pos);
+                tracked.endpoint = endpoint.clone();
+                self.tracked.push(tracked);
+            } else {
+                self.tracked.push(Tracked {
+                    endpoint: endpoint.clone(),
+                    peer_id,
+                    addr,
+                    state: LinkState::Idle,
+                    failures: 0,
+                    next_attempt: Instant::now(),
+                    last_connected: None,
+                    last_error: None,
+                    reservation: None,
+                });
+            }
+        }
+
+        // Relays first, then by configured priority, then by how reliable they've been
+        self.tracked.sort_by_key(|t| {
+            (
+                t.endpoint.kind != EndpointKind::Relay,
+                t.endpoint.priority,
+                t.failures,
+            )
+        });
+        previous
+    }
+
+    /// Dials every endpoint that is idle or whose backoff has expired.
+    pub fn dial_due(&mut self, swarm: &mut Swarm<VoidBehaviour>) {
+        let now = Instant::now();
+        for tracked in &mut self.tracked {
+            let due = match tracked.state {
+                LinkState::Idle => true,
+                LinkState::BackingOff => now >= tracked.next_attempt,
+                LinkState::Dialing | LinkState::Connected => false,
+            };
+            if !due {
+                continue;
+            }
+
+            println!("Dialing {:?} endpoint: {}", tracked.endpoint.kind, tracked.addr);
+            match swarm.dial(tracked.addr.clone()) {
+                Ok(()) => tracked.state = LinkState::Dialing,
+                Err(e) => record_failure(tracked, &self.backoff, e.to_string()),
+            }
+        }
+
+        // Retry reservations whose backoff has expired
+        self.ensure_reservations(swarm);
+    }
+
+    pub fn on_connected(&mut self, swarm: &mut Swarm<VoidBehaviour>, peer_id: &PeerId) {
+        let Some(tracked) = self.tracked.iter_mut().find(|t| t.peer_id == *peer_id) else {
+            return;
+        };
+        // Further connections to a relay that is already up don't clear a
+        // reservation backoff
+        if tracked.state != LinkState::Connected {
+            tracked.failures = 0;
+            tracked.last_error = None;
+        }
+        tracked.state = LinkState::Connected;
+        tracked.last_connected = Some(chrono::Utc::now().timestamp());
+        self.ensure_reservations(swarm);
+    }
+
+    pub fn on_dial_failure(&mut self, peer_id: &PeerId, error: String) {
+        let backoff = self.backoff.clone();
+        if let Some(tracked) = self
+            .tracked
+            .iter_mut()
+            .find(|t| t.peer_id == *peer_id && t.state != LinkState::Connected)
+        {
+            println!("Endpoint {} unreachable: {}", tracked.addr, error);
+            record_failure(tracked, &backoff, error);
+        }
+    }
+
+    /// Called once the last connection to `peer_id` has closed.
+    pub fn on_disconnected(&mut self, swarm: &mut Swarm<VoidBehaviour>, peer_id: &PeerId) {
+        let backoff = self.backoff.clone();
+        let Some(tracked) = self.tracked.iter_mut().find(|t| t.peer_id == *peer_id) else {
+            return;
+        };
+        if let Some(listener) = tracked.reservation.take() {
+            swarm.remove_listener(listener);
+        }
+        record_failure(tracked, &backoff, "Connection closed".into());
+
+        // Fall back to the next relay in line
+        self.ensure_reservations(swarm);
+    }
+
+    /// Called when a listener closes. If it was a reservation the relay
+    /// refused or dropped, that relay is skipped until its backoff expires, so
+    /// a relay that keeps refusing isn't asked again in a tight loop.
+    pub fn on_listener_closed(
+        &mut self,
+        swarm: &mut Swarm<VoidBehaviour>,
+        listener_id: ListenerId,
+        reason: Result<(), String>,
+    ) {
+        let backoff = self.backoff.clone();
+        if let Some(tracked) = self
+            .tracked
+            .iter_mut()
+            .find(|t| t.reservation == Some(listener_id))
+        {
+            tracked.reservation = None;
+            let error = reason.err().unwrap_or_else(|| "Reservation closed".into());
+            println!("Relay reservation on {} closed: {}", tracked.addr, error);
+            record_reservation_failure(tracked, &backoff, error);
+            self.ensure_reservations(swarm);
+        }
+    }
+
+    fn ensure_reservations(&mut self, swarm: &mut Swarm<VoidBehaviour>) {
+        let now = Instant::now();
+        let mut active = self.tracked.iter().filter(|t| t.reservation.is_some()).count();
+        for tracked in &mut self.tracked {
+            if active >= self.max_reservations {
+                break;
+            }
+            if tracked.endpoint.kind != EndpointKind::Relay
+                || tracked.state != LinkState::Connected
+                || tracked.reservation.is_some()
+                || now < tracked.next_attempt
+            {
+                continue;
+            }
+
+            let circuit = tracked.addr.clone().with(Protocol::P2pCircuit);
+            println!("Requesting relay reservation on {}", circuit);
+            match swarm.listen_on(circuit) {
+                Ok(listener) => {
+                    tracked.reservation = Some(listener);
+                    active += 1;
+                }
+                Err(e) => record_reservation_failure(tracked, &self.backoff, e.to_string()),
+            }
+        }
+    }
+
+    pub fn health(&self) -> Vec<EndpointHealth> {
+        let now = Instant::now();
+        self.tracked
+            .iter()
+            .map(|t| EndpointHealth {
+                address: t.endpoint.address.clone(),
+                kind: t.endpoint.kind,
+                priority: t.endpoint.priority,
+                state: t.state,
+                failures: t.failures,
+                last_connected: t.last_connected,
+                last_error: t.last_error.clone(),
+                reserved: t.reservation.is_some(),
+                retry_in_secs: (t.next_attempt > now)
+                    .then(|| t.next_attempt.saturating_duration_since(now).as_secs()),
+            })
+            .collect()
+    }
+}
+
+fn record_failure(tracked: &mut Tracked, backoff: &BackoffConfig, error: String) {
+    tracked.state = LinkState::BackingOff;
+    record_reservation_failure(tracked, backoff, error);
+}
+
+/// Backs off like a failed dial, but keeps the connection: only new
+/// reservations wait for `next_attempt`.
+fn record_reservation_failure(tracked: &mut Tracked, backoff: &BackoffConfig, error: String) {
+    tracked.failures = tracked.failures.saturating_add(1);
+    tracked.last_error = Some(error);
+
+    // Exponential backoff: initial, 2x, 4x ... capped at max
+    let exponent = tracked.failures.saturating_sub(1).min(16);
+    let delay = backoff
+        .initial_secs
+        .saturating_mul(1u64 << exponent)
+        .min(backoff.max_secs);
+    tracked.next_attempt = Instant::now() + Duration::from_secs(delay);
+}
@@ -0,0 +1,183 @@
+// Rooms
+//
+// Topic channels for state every participant shares, like StickyBoard notes or
+// SyncPlayer position, instead of sending the same signal to each peer in turn.
+// A room is a gossipsub topic; messages are signed by the publishing node and
+// deduplicated by content. Room traffic is not end-to-end encrypted, so anyone
+// who knows the room name can read along; use groups for private conversation.
+use crate::network::swarm::{MAX_ROOM_MESSAGE, VoidBehaviour};
+use crate::network::{NetworkCommand, NetworkState, request};
+use libp2p::{PeerId, Swarm, gossipsub};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use tauri::State;
+
+const TOPIC_PREFIX: &str = "void/room/";
+const MAX_ROOM_NAME: usize = 128;
+
+/// What actually goes over the topic.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RoomMessage {
+    sent_at: i64,
+    payload: serde_json::Value,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum RoomEvent {
+    #[serde(rename_all = "camelCase")]
+    Message {
+        room: String,
+        peer_id: String,
+        message_id: String,
+        sent_at: i64,
+        payload: serde_json::Value,
+    },
+    #[serde(rename_all = "camelCase")]
+    PeerJoined { room: String, peer_id: String },
+    #[serde(rename_all = "camelCase")]
+    PeerLeft { room: String, peer_id: String },
+}
+
+/// Rooms this node has joined, by topic.
+pub struct Rooms {
+    joined: HashMap<gossipsub::TopicHash, String>,
+}
+
+fn topic(room: &str) -> Result<gossipsub::IdentTopic, String> {
+    if room.is_empty() || room.len() > MAX_ROOM_NAME {
+        return Err(format!("Room names must be 1-{} characters", MAX_ROOM_NAME));
+    }
+    Ok(gossipsub::IdentTopic::new(format!("{}{}", TOPIC_PREFIX, room)))
+}
+
+impl Rooms {
+    pub fn new() -> Self {
+        Self {
+            joined: HashMap::new(),
+        }
+    }
+
+    /// Subscribes to `room`. Returns false if already joined.
+    pub fn join(&mut self, swarm: &mut Swarm<VoidBehaviour>, room: &str) -> Result<bool, String> {
+        let topic = topic(room)?;
+        let subscribed = swarm
+            .behaviour_mut()
+            .gossipsub
+            .subscribe(&topic)
+            .map_err(|e| e.to_string())?;
+        self.joined.insert(topic.hash(), room.to_string());
+        Ok(subscribed)
+    }
+
+    /// Unsubscribes from `room`. Returns false if it wasn't joined.
+    pub fn leave(&mut self, swarm: &mut Swarm<VoidBehaviour>, room: &str) -> Result<bool, String> {
+        let topic = topic(room)?;
+        self.joined.remove(&topic.hash());
+        Ok(swarm.behaviour_mut().gossipsub.unsubscribe(&topic))
+    }
+
+    /// Publishes `payload` to a joined room and returns the message id.
+    pub fn publish(
+        &mut self,
+        swarm: &mut Swarm<VoidBehaviour>,
+        room: &str,
+        payload: serde_json::Value,
+    ) -> Result<String, String> {
+        let topic = topic(room)?;
+        if !self.joined.contains_key(&topic.hash()) {
+            return Err("Join the room before publishing to it".into());
+        }
+        let data = serde_json::to_vec(&RoomMessage {
+            sent_at: chrono::Utc::now().timestamp_millis(),
+            payload,
+        })
+        .map_err(|e| e.to_string())?;
+        if data.len() > MAX_ROOM_MESSAGE {
+            return Err("Room message too large".into());
+        }
+        swarm
+            .behaviour_mut()
+            .gossipsub
+            .publish(topic, data)
+            .map(|id| id.to_string())
+            .map_err(|e| e.to_string())
+    }
+
+    pub fn handle_event(&self, event: gossipsub::Event) -> Option<RoomEvent> {
+        match event {
+            gossipsub::Event::Message {
+                propagation_source,
+                message_id,
+                message,
+            } => {
+                let room = self.joined.get(&message.topic)?.clone();
+                let Ok(decoded) = serde_json::from_slice::<RoomMessage>(&message.data) else {
+                    println!("Dropping malformed room message from {}", propagation_source);
+                    return None;
+                };
+                // Strict validation means every message carries its signed source
+                let peer_id = message.source.unwrap_or(propagation_source);
+                Some(RoomEvent::Message {
+                    room,
+                    peer_id: peer_id.to_string(),
+                    message_id: message_id.to_string(),
+                    sent_at: decoded.sent_at,
+                    payload: decoded.payload,
+                })
+            }
+            gossipsub::Event::Subscribed { peer_id, topic } => Some(RoomEvent::PeerJoined {
+                room: self.joined.get(&topic)?.clone(),
+                peer_id: peer_id.to_string(),
+            }),
+            gossipsub::Event::Unsubscribed { peer_id, topic } => Some(RoomEvent::PeerLeft {
+                room: self.joined.get(&topic)?.clone(),
+                peer_id: peer_id.to_string(),
+            }),
+            _ => None,
+        }
+    }
+
+    /// Peers we know to be in `room`.
+    pub fn peers(&self, swarm: &Swarm<VoidBehaviour>, room: &str) -> Vec<PeerId> {
+        let Ok(topic) = topic(room) else {
+            return Vec::new();
+        };
+        let hash = topic.hash();
+        swarm
+            .behaviour()
+            .gossipsub
+            .all_peers()
+            .filter(|(_, topics)| topics.contains(&&hash))
+            .map(|(peer, _)| *peer)
+            .collect()
+    }
+}
+
+#[tauri::command]
+pub async fn join_room(room: String, state: State<'_, NetworkState>) -> Result<bool, String> {
+    request(&state, |tx| NetworkCommand::JoinRoom(room, tx)).await
+}
+
+#[tauri::command]
+pub async fn leave_room(room: String, state: State<'_, NetworkState>) -> Result<bool, String> {
+    request(&state, |tx| NetworkCommand::LeaveRoom(room, tx)).await
+}
+
+/// Publishes a JSON payload to everyone in the room. Delivered to the others
+/// as `room-event` messages; our own messages aren't echoed back.
+#[tauri::command]
+pub async fn publish_room(
+    room: String,
+    payload: serde_json::Value,
+    state: State<'_, NetworkState>,
+) -> Result<String, String> {
+    request(&state, |tx| NetworkCommand::PublishRoom(room, payload, tx)).await
+}
+
+/// PeerIds currently subscribed to a joined room.
+#[tauri::command]
+pub async fn get_room_peers(room: String, state: State<'_, NetworkState>) -> Result<Vec<String>, String> {
+    request(&state, |tx| NetworkCommand::GetRoomPeers(room, tx)).await
+}
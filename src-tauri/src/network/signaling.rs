@@ -0,0 +1,121 @@
+use crate::network::swarm::{
+    SIGNALING_VERSION, Signal, SignalErrorCode, SignalingRequest, SignalingResponse,
+};
+use serde_json::{Value, json};
+
+/// Largest SDP / app payload accepted over signaling.
+const MAX_SIGNAL_LEN: usize = 64 * 1024;
+
+/// Checks an incoming request before it is routed, returning the rejection to
+/// send back if it can't be accepted.
+pub fn validate(request: &SignalingRequest) -> Result<(), SignalingResponse> {
+    if request.version != SIGNALING_VERSION {
+        return Err(reject(
+            SignalErrorCode::UnsupportedVersion,
+            format!("Unsupported signaling version {}", request.version),
+        ));
+    }
+
+    let (field, len) = match &request.signal {
+        Signal::Offer { sdp } | Signal::Answer { sdp } => ("sdp", sdp.len()),
+        Signal::IceCandidate { candidate, .. } => ("candidate", candidate.len()),
+        Signal::AppData { app, data } => {
+            if app.is_empty() {
+                return Err(reject(SignalErrorCode::Malformed, "Missing app name".into()));
+            }
+            ("data", data.len())
+        }
+        Signal::Chat { text, .. } => ("text", text.len()),
+        Signal::Receipt { .. } | Signal::Error { .. } => return Ok(()),
+    };
+
+    if len == 0 && field == "sdp" {
+        return Err(reject(SignalErrorCode::Malformed, "Empty sdp".into()));
+    }
+    if len > MAX_SIGNAL_LEN {
+        return Err(reject(
+            SignalErrorCode::TooLarge,
+            format!("{} exceeds {} bytes", field, MAX_SIGNAL_LEN),
+        ));
+    }
+    Ok(())
+}
+
+fn reject(code: SignalErrorCode, reason: String) -> SignalingResponse {
+    SignalingResponse::Rejected { code, reason }
+}
+
+/// Maps the JSON strings the frontend passes to `send_signal` onto a typed
+/// signal. WebRTC messages keep their meaning; anything else is treated as
+/// app data keyed by its `type` field.
+pub fn from_frontend_payload(payload: &str) -> Result<Signal, String> {
+    let value: Value = serde_json::from_str(payload).map_err(|e| format!("Invalid signal JSON: {}", e))?;
+    let kind = value
+        .get("type")
+        .and_then(Value::as_str)
+        .ok_or("Signal is missing a type")?;
+
+    let description_sdp = || {
+        value
+            .get("sdp")
+            .and_then(|sdp| sdp.get("sdp").or(Some(sdp)))
+            .and_then(Value::as_str)
+            .map(str::to_string)
+            .ok_or_else(|| format!("{} is missing sdp", kind))
+    };
+
+    match kind {
+        "offer" => Ok(Signal::Offer {
+            sdp: description_sdp()?,
+        }),
+        "answer" => Ok(Signal::Answer {
+            sdp: description_sdp()?,
+        }),
+        "candidate" => {
+            let candidate = value.get("candidate").ok_or("candidate is missing")?;
+            Ok(Signal::IceCandidate {
+                candidate: candidate
+                    .get("candidate")
+                    .and_then(Value::as_str)
+                    .unwrap_or_default()
+                    .to_string(),
+                sdp_mid: candidate
+                    .get("sdpMid")
+                    .and_then(Value::as_str)
+                    .map(str::to_string),
+                sdp_m_line_index: candidate
+                    .get("sdpMLineIndex")
+                    .and_then(Value::as_u64)
+                    .map(|i| i as u16),
+            })
+        }
+        app => Ok(Signal::AppData {
+            app: app.to_string(),
+            data: payload.to_string(),
+        }),
+    }
+}
+
+/// Inverse of [`from_frontend_payload`], used so existing listeners of
+/// `signal-event` keep receiving the JSON shape they parse today.
+pub fn to_frontend_payload(signal: &Signal) -> String {
+    let value = match signal {
+        Signal::Offer { sdp } => json!({ "type": "offer", "sdp": { "type": "offer", "sdp": sdp } }),
+        Signal::Answer { sdp } => json!({ "type": "answer", "sdp": { "type": "answer", "sdp": sdp } }),
+        Signal::IceCandidate {
+            candidate,
+            sdp_mid,
+            sdp_m_line_index,
+        } => json!({
+            "type": "candidate",
+            "candidate": {
+                "candidate": candidate,
+                "sdpMid": sdp_mid,
+                "sdpMLineIndex": sdp_m_line_index
+            }
+        }),
+        Signal::AppData { data, .. } => return data.clone(),
+        other => serde_json::to_value(other).unwrap_or(Value::Null),
+    };
+    value.to_string()
+}
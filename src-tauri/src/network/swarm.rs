@@ -1,34 +1,218 @@
+use crate::security::crypto::{PreKeyBundle, RatchetHeader, X3dhInit};
 use anyhow::Result;
 use libp2p::{
-    PeerId, SwarmBuilder, identify, noise, ping, relay,
+    PeerId, SwarmBuilder, allow_block_list, autonat, dcutr, gossipsub, identify, identity::Keypair, mdns, noise, ping, relay,
     request_response::{self, ProtocolSupport},
     tcp, yamux, websocket, dns,
-    swarm::NetworkBehaviour,
+    swarm::{NetworkBehaviour, behaviour::toggle::Toggle},
 };
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::time::Duration;
 
+/// Identify protocol version advertised by VOID nodes.
+pub const PROTOCOL_VERSION: &str = "void/1.0.1";
+
+/// Current `/void/signaling/1.0.0` envelope version.
+pub const SIGNALING_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SignalingRequest {
+    pub version: u16,
+    pub signal: Signal,
+}
+
+impl SignalingRequest {
+    pub fn new(signal: Signal) -> Self {
+        Self {
+            version: SIGNALING_VERSION,
+            signal,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Signal {
+    Offer {
+        sdp: String,
+    },
+    Answer {
+        sdp: String,
+    },
+    #[serde(rename_all = "camelCase")]
+    IceCandidate {
+        candidate: String,
+        sdp_mid: Option<String>,
+        sdp_m_line_index: Option<u16>,
+    },
+    /// Application traffic such as SyncPlayer or StickyBoard state. `data` is
+    /// opaque JSON owned by the named app.
+    AppData {
+        app: String,
+        data: String,
+    },
+    Chat {
+        id: String,
+        text: String,
+    },
+    Receipt {
+        id: String,
+        status: ReceiptStatus,
+    },
+    Error {
+        code: SignalErrorCode,
+        message: String,
+    },
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ReceiptStatus {
+    Delivered,
+    Read,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SignalErrorCode {
+    UnsupportedVersion,
+    Malformed,
+    TooLarge,
+    Unexpected,
+    Internal,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub struct SignalingRequest(pub String);
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum SignalingResponse {
+    Ack,
+    Rejected {
+        code: SignalErrorCode,
+        reason: String,
+    },
+}
 
+/// End-to-end encrypted direct messages on `/void/message/1.0.0`.
 #[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
-pub struct SignalingResponse(pub String);
+pub enum MessageRequest {
+    GetPreKeyBundle,
+    Message(MessageEnvelope),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MessageEnvelope {
+    pub id: String,
+    /// Present until the recipient has replied, so it can complete X3DH.
+    pub init: Option<X3dhInit>,
+    pub header: RatchetHeader,
+    pub ciphertext: Vec<u8>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MessageResponse {
+    PreKeyBundle(PreKeyBundle),
+    Delivered { id: String },
+    Rejected { id: String, reason: String },
+}
+
+/// Store-and-forward on `/void/mailbox/1.0.0`. Requests act on behalf of the
+/// authenticated connection peer, so only an owner can fetch its own mail.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MailboxRequest {
+    /// Ask the node to hold mail for us (also renews the registration).
+    Register,
+    Unregister,
+    /// Leave an envelope for a registered peer.
+    Deposit {
+        recipient: String,
+        envelope: MessageEnvelope,
+    },
+    Fetch { limit: u32 },
+    /// Fetched envelopes that can be deleted.
+    Ack { ids: Vec<String> },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct MailboxItem {
+    pub sender: String,
+    pub deposited_at: i64,
+    pub envelope: MessageEnvelope,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MailboxResponse {
+    Registered {
+        expires_at: i64,
+        max_messages: u32,
+        max_bytes: u64,
+    },
+    Unregistered,
+    Stored { id: String },
+    Mail { items: Vec<MailboxItem>, remaining: u32 },
+    Acked { removed: u32 },
+    Rejected { reason: String },
+}
+
+/// Encrypted file chunks on `/void/file/1.0.0`. The offer itself, with the
+/// transfer key, travels over the message channel; here the receiver pulls
+/// chunks and both sides report how the transfer ended.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileRequest {
+    Chunk { transfer_id: String, index: u64 },
+    /// Receiver declined the offer or gave up on it.
+    Reject { transfer_id: String, reason: String },
+    /// Receiver has the whole file and its hash matched.
+    Complete { transfer_id: String },
+    /// Sender withdrew the file.
+    Cancel { transfer_id: String },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum FileResponse {
+    /// Chunk sealed with the transfer key: [Nonce (24 bytes)] [Ciphertext].
+    Chunk {
+        transfer_id: String,
+        index: u64,
+        data: Vec<u8>,
+    },
+    Ack,
+    Rejected { reason: String },
+}
 
 #[derive(NetworkBehaviour)]
 #[behaviour(out_event = "VoidEvent")]
 pub struct VoidBehaviour {
     pub relay_client: relay::client::Behaviour,
+    pub dcutr: dcutr::Behaviour,
+    pub autonat: autonat::Behaviour,
     pub identify: identify::Behaviour,
     pub ping: ping::Behaviour,
+    /// LAN discovery; disabled if the mDNS socket couldn't be opened.
+    pub mdns: Toggle<mdns::tokio::Behaviour>,
+    /// Peers the user blocked in the contact roster.
+    pub blocked: allow_block_list::Behaviour<allow_block_list::BlockedPeers>,
     pub signaling: request_response::cbor::Behaviour<SignalingRequest, SignalingResponse>,
+    pub messaging: request_response::cbor::Behaviour<MessageRequest, MessageResponse>,
+    pub mailbox: request_response::cbor::Behaviour<MailboxRequest, MailboxResponse>,
+    pub files: request_response::cbor::Behaviour<FileRequest, FileResponse>,
+    /// Topic channels shared by everyone in a room.
+    pub gossipsub: gossipsub::Behaviour,
 }
 
 #[derive(Debug)]
 pub enum VoidEvent {
     RelayClient(relay::client::Event),
+    Dcutr(dcutr::Event),
+    Autonat(autonat::Event),
     Identify(identify::Event),
     Ping(ping::Event),
+    Mdns(mdns::Event),
     Signaling(request_response::Event<SignalingRequest, SignalingResponse>),
+    Messaging(request_response::Event<MessageRequest, MessageResponse>),
+    Mailbox(request_response::Event<MailboxRequest, MailboxResponse>),
+    Files(request_response::Event<FileRequest, FileResponse>),
+    Gossipsub(gossipsub::Event),
 }
 
 impl From<relay::client::Event> for VoidEvent {
@@ -37,6 +221,18 @@ impl From<relay::client::Event> for VoidEvent {
     }
 }
 
+impl From<dcutr::Event> for VoidEvent {
+    fn from(event: dcutr::Event) -> Self {
+        VoidEvent::Dcutr(event)
+    }
+}
+
+impl From<autonat::Event> for VoidEvent {
+    fn from(event: autonat::Event) -> Self {
+        VoidEvent::Autonat(event)
+    }
+}
+
 impl From<identify::Event> for VoidEvent {
     fn from(event: identify::Event) -> Self {
         VoidEvent::Identify(event)
@@ -49,14 +245,87 @@ impl From<ping::Event> for VoidEvent {
     }
 }
 
+impl From<mdns::Event> for VoidEvent {
+    fn from(event: mdns::Event) -> Self {
+        VoidEvent::Mdns(event)
+    }
+}
+
+impl From<std::convert::Infallible> for VoidEvent {
+    fn from(event: std::convert::Infallible) -> Self {
+        match event {}
+    }
+}
+
 impl From<request_response::Event<SignalingRequest, SignalingResponse>> for VoidEvent {
     fn from(event: request_response::Event<SignalingRequest, SignalingResponse>) -> Self {
         VoidEvent::Signaling(event)
     }
 }
 
-pub async fn build_swarm() -> Result<libp2p::Swarm<VoidBehaviour>> {
-    let local_key = libp2p::identity::Keypair::generate_ed25519();
+impl From<request_response::Event<MessageRequest, MessageResponse>> for VoidEvent {
+    fn from(event: request_response::Event<MessageRequest, MessageResponse>) -> Self {
+        VoidEvent::Messaging(event)
+    }
+}
+
+impl From<request_response::Event<MailboxRequest, MailboxResponse>> for VoidEvent {
+    fn from(event: request_response::Event<MailboxRequest, MailboxResponse>) -> Self {
+        VoidEvent::Mailbox(event)
+    }
+}
+
+impl From<request_response::Event<FileRequest, FileResponse>> for VoidEvent {
+    fn from(event: request_response::Event<FileRequest, FileResponse>) -> Self {
+        VoidEvent::Files(event)
+    }
+}
+
+impl From<gossipsub::Event> for VoidEvent {
+    fn from(event: gossipsub::Event) -> Self {
+        VoidEvent::Gossipsub(event)
+    }
+}
+
+fn mailbox_behaviour(serve: bool) -> request_response::cbor::Behaviour<MailboxRequest, MailboxResponse> {
+    let support = if serve {
+        ProtocolSupport::Full
+    } else {
+        ProtocolSupport::Outbound
+    };
+    request_response::cbor::Behaviour::new(
+        [(libp2p::StreamProtocol::new("/void/mailbox/1.0.0"), support)],
+        request_response::Config::default(),
+    )
+}
+
+/// Largest room message accepted or published.
+pub const MAX_ROOM_MESSAGE: usize = 64 * 1024;
+
+/// Gossipsub for rooms: every message is signed by its publisher and checked
+/// strictly, and ids are content hashes so the same update relayed along
+/// several paths is only delivered once.
+fn gossipsub_behaviour(key: &Keypair) -> Result<gossipsub::Behaviour, String> {
+    let config = gossipsub::ConfigBuilder::default()
+        .heartbeat_interval(Duration::from_secs(1))
+        .validation_mode(gossipsub::ValidationMode::Strict)
+        .max_transmit_size(MAX_ROOM_MESSAGE)
+        .message_id_fn(|message: &gossipsub::Message| {
+            let mut hasher = Sha256::new();
+            if let Some(source) = &message.source {
+                hasher.update(source.to_bytes());
+            }
+            hasher.update(&message.data);
+            gossipsub::MessageId::from(hasher.finalize().to_vec())
+        })
+        .build()
+        .map_err(|e| e.to_string())?;
+    gossipsub::Behaviour::new(gossipsub::MessageAuthenticity::Signed(key.clone()), config).map_err(|e| e.to_string())
+}
+
+/// Builds the swarm of a regular node. With `serve_mailbox` it also accepts
+/// mailbox requests from other peers.
+pub async fn build_swarm(local_key: Keypair, serve_mailbox: bool) -> Result<libp2p::Swarm<VoidBehaviour>> {
     let local_peer_id = PeerId::from(local_key.public());
 
     println!("Local PeerID: {}", local_peer_id);
@@ -68,6 +337,7 @@ pub async fn build_swarm() -> Result<libp2p::Swarm<VoidBehaviour>> {
             noise::Config::new,
             yamux::Config::default,
         )?
+        .with_quic()
         .with_websocket(
             websocket::Config::default(),
             noise::Config::new,
@@ -77,15 +347,30 @@ pub async fn build_swarm() -> Result<libp2p::Swarm<VoidBehaviour>> {
         .with_dns()?
         .with_relay_client(noise::Config::new, yamux::Config::default)?
         .with_behaviour(|key, relay_client| {
+            // Hole punching: upgrade relayed connections to direct ones
+            let dcutr = dcutr::Behaviour::new(key.public().to_peer_id());
+
+            // AutoNAT: learn whether we are publicly reachable
+            let autonat = autonat::Behaviour::new(key.public().to_peer_id(), autonat::Config::default());
+
             // Identify
             let identify = identify::Behaviour::new(identify::Config::new(
-                "void/1.0.1".to_string(), // Updated version
+                PROTOCOL_VERSION.to_string(),
                 key.public(),
             ));
 
             // Ping
             let ping = ping::Behaviour::new(ping::Config::new());
 
+            // mDNS: find other nodes on the LAN without any relay
+            let mdns = match mdns::tokio::Behaviour::new(mdns::Config::default(), key.public().to_peer_id()) {
+                Ok(mdns) => Some(mdns),
+                Err(e) => {
+                    println!("mDNS unavailable: {}", e);
+                    None
+                }
+            };
+
             // Signaling (Request-Response)
             let signaling = request_response::cbor::Behaviour::new(
                 [(
@@ -95,11 +380,39 @@ pub async fn build_swarm() -> Result<libp2p::Swarm<VoidBehaviour>> {
                 request_response::Config::default(),
             );
 
+            // Encrypted direct messages (Request-Response)
+            let messaging = request_response::cbor::Behaviour::new(
+                [(
+                    libp2p::StreamProtocol::new("/void/message/1.0.0"),
+                    ProtocolSupport::Full,
+                )],
+                request_response::Config::default(),
+            );
+
+            // Encrypted file transfers (Request-Response)
+            let files = request_response::cbor::Behaviour::new(
+                [(
+                    libp2p::StreamProtocol::new("/void/file/1.0.0"),
+                    ProtocolSupport::Full,
+                )],
+                request_response::Config::default().with_request_timeout(Duration::from_secs(30)),
+            );
+
+            let gossipsub = gossipsub_behaviour(key)?;
+
             Ok(VoidBehaviour {
                 relay_client,
+                dcutr,
+                autonat,
                 identify,
                 ping,
+                mdns: mdns.into(),
+                blocked: allow_block_list::Behaviour::default(),
                 signaling,
+                messaging,
+                mailbox: mailbox_behaviour(serve_mailbox),
+                files,
+                gossipsub,
             })
         })?
         .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(60)))
@@ -107,3 +420,110 @@ pub async fn build_swarm() -> Result<libp2p::Swarm<VoidBehaviour>> {
 
     Ok(swarm)
 }
+
+/// Behaviour of the headless `void-relay` server.
+#[derive(NetworkBehaviour)]
+#[behaviour(out_event = "RelayServerEvent")]
+pub struct RelayServerBehaviour {
+    pub relay: relay::Behaviour,
+    pub identify: identify::Behaviour,
+    pub ping: ping::Behaviour,
+    /// Only present when the relay is restricted to an allow-list.
+    pub allow_list: Toggle<allow_block_list::Behaviour<allow_block_list::AllowedPeers>>,
+    /// Only present when the relay also runs a mailbox.
+    pub mailbox: Toggle<request_response::cbor::Behaviour<MailboxRequest, MailboxResponse>>,
+}
+
+#[derive(Debug)]
+pub enum RelayServerEvent {
+    Relay(relay::Event),
+    Identify(identify::Event),
+    Ping(ping::Event),
+    Mailbox(request_response::Event<MailboxRequest, MailboxResponse>),
+}
+
+impl From<relay::Event> for RelayServerEvent {
+    fn from(event: relay::Event) -> Self {
+        RelayServerEvent::Relay(event)
+    }
+}
+
+impl From<identify::Event> for RelayServerEvent {
+    fn from(event: identify::Event) -> Self {
+        RelayServerEvent::Identify(event)
+    }
+}
+
+impl From<ping::Event> for RelayServerEvent {
+    fn from(event: ping::Event) -> Self {
+        RelayServerEvent::Ping(event)
+    }
+}
+
+impl From<request_response::Event<MailboxRequest, MailboxResponse>> for RelayServerEvent {
+    fn from(event: request_response::Event<MailboxRequest, MailboxResponse>) -> Self {
+        RelayServerEvent::Mailbox(event)
+    }
+}
+
+impl From<std::convert::Infallible> for RelayServerEvent {
+    fn from(event: std::convert::Infallible) -> Self {
+        match event {}
+    }
+}
+
+pub async fn build_relay_swarm(
+    local_key: Keypair,
+    relay_config: relay::Config,
+    allowed_peers: Option<Vec<PeerId>>,
+    serve_mailbox: bool,
+) -> Result<libp2p::Swarm<RelayServerBehaviour>> {
+    println!("Relay PeerID: {}", PeerId::from(local_key.public()));
+
+    let swarm = SwarmBuilder::with_existing_identity(local_key)
+        .with_tokio()
+        .with_tcp(
+            tcp::Config::default(),
+            noise::Config::new,
+            yamux::Config::default,
+        )?
+        .with_quic()
+        .with_websocket(
+            websocket::Config::default(),
+            noise::Config::new,
+            yamux::Config::default,
+        )
+        .await?
+        .with_dns()?
+        .with_behaviour(|key| {
+            let relay = relay::Behaviour::new(key.public().to_peer_id(), relay_config);
+
+            let identify = identify::Behaviour::new(
+                identify::Config::new(PROTOCOL_VERSION.to_string(), key.public())
+                    .with_agent_version(format!("void-relay/{}", env!("CARGO_PKG_VERSION"))),
+            );
+
+            let ping = ping::Behaviour::new(ping::Config::new());
+
+            let allow_list = allowed_peers.map(|peers| {
+                let mut allow_list = allow_block_list::Behaviour::default();
+                for peer in peers {
+                    allow_list.allow_peer(peer);
+                }
+                allow_list
+            });
+
+            Ok(RelayServerBehaviour {
+                relay,
+                identify,
+                ping,
+                allow_list: Toggle::from(allow_list),
+                mailbox: Toggle::from(serve_mailbox.then(|| mailbox_behaviour(true))),
+            })
+        })?
+        // Relayed circuits can sit idle between signaling bursts
+        .with_swarm_config(|c| c.with_idle_connection_timeout(Duration::from_secs(120)))
+        .build();
+
+    Ok(swarm)
+}
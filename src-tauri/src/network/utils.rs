@@ -1,16 +1,186 @@
-use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
-use libp2p::Multiaddr;
+use base64::{
+    Engine as _,
+    engine::general_purpose::{STANDARD as BASE64, URL_SAFE_NO_PAD as BASE64_URL},
+};
+use libp2p::{
+    Multiaddr, PeerId,
+    identity::{Keypair, PublicKey},
+    multiaddr::Protocol,
+    swarm::dial_opts::DialOpts,
+};
+use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 
-pub fn generate_void_code(multiaddr: &Multiaddr) -> String {
-    let s = multiaddr.to_string();
-    let encoded = BASE64.encode(s);
-    format!("void://{}", encoded)
+pub const DEFAULT_CODE_TTL_SECS: u64 = 24 * 60 * 60;
+const MAX_CODE_ADDRESSES: usize = 8;
+const CODE_V2_PREFIX: &str = "void://v2.";
+const CODE_SIGNING_DOMAIN: &[u8] = b"void-code-v2:";
+/// Tolerated clock difference for codes created "in the future".
+const MAX_CLOCK_SKEW_SECS: i64 = 300;
+
+/// A decoded VOID code. Legacy v1 codes carry a single address and no
+/// signature, so `peer_id` and `expires_at` may be missing.
+#[derive(Debug, Clone)]
+pub struct VoidCode {
+    pub peer_id: Option<PeerId>,
+    pub addresses: Vec<Multiaddr>,
+    pub expires_at: Option<i64>,
+}
+
+impl VoidCode {
+    pub fn dial_opts(&self) -> DialOpts {
+        match self.peer_id {
+            Some(peer_id) => DialOpts::peer_id(peer_id)
+                .addresses(self.addresses.clone())
+                .build(),
+            None => DialOpts::unknown_peer_id()
+                .address(self.addresses[0].clone())
+                .build(),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CodePayload {
+    peer_id: String,
+    /// Protobuf-encoded public key, base64.
+    public_key: String,
+    addresses: Vec<String>,
+    created_at: i64,
+    ttl_secs: u64,
+}
+
+/// Picks the addresses worth sharing: relay circuits first, loopback dropped.
+pub fn code_addresses<'a>(addrs: impl IntoIterator<Item = &'a Multiaddr>) -> Vec<Multiaddr> {
+    let mut relayed = Vec::new();
+    let mut direct = Vec::new();
+    for addr in addrs {
+        let loopback = addr.iter().any(|p| match p {
+            Protocol::Ip4(ip) => ip.is_loopback(),
+            Protocol::Ip6(ip) => ip.is_loopback(),
+            _ => false,
+        });
+        if loopback || relayed.contains(addr) || direct.contains(addr) {
+            continue;
+        }
+        if addr.iter().any(|p| matches!(p, Protocol::P2pCircuit)) {
+            relayed.push(addr.clone());
+        } else {
+            direct.push(addr.clone());
+        }
+    }
+    relayed.extend(direct);
+    relayed.truncate(MAX_CODE_ADDRESSES);
+    relayed
+}
+
+/// Builds a v2 code: the peer's addresses plus an expiry, signed by the node identity.
+pub fn generate_void_code(
+    keypair: &Keypair,
+    addresses: &[Multiaddr],
+    ttl_secs: u64,
+) -> Result<String, String> {
+    if addresses.is_empty() {
+        return Err("No listen address found yet".into());
+    }
+
+    let payload = CodePayload {
+        peer_id: keypair.public().to_peer_id().to_string(),
+        public_key: BASE64.encode(keypair.public().encode_protobuf()),
+        addresses: addresses.iter().map(|a| a.to_string()).collect(),
+        created_at: chrono::Utc::now().timestamp(),
+        ttl_secs,
+    };
+    let payload = serde_json::to_vec(&payload).map_err(|e| e.to_string())?;
+    let signature = keypair
+        .sign(&[CODE_SIGNING_DOMAIN, &payload].concat())
+        .map_err(|e| format!("Failed to sign VOID code: {}", e))?;
+
+    Ok(format!(
+        "{}{}.{}",
+        CODE_V2_PREFIX,
+        BASE64_URL.encode(payload),
+        BASE64_URL.encode(signature)
+    ))
 }
 
-pub fn parse_void_code(code: &str) -> Result<Multiaddr, String> {
+/// Parses a v2 code (verifying signature and expiry) or a legacy v1 code.
+pub fn parse_void_code(code: &str) -> Result<VoidCode, String> {
     let code = code.trim();
-    
+
+    if let Some(body) = code.strip_prefix(CODE_V2_PREFIX) {
+        return parse_v2(body);
+    }
+
+    let addr = parse_v1(code)?;
+    let peer_id = match addr.iter().last() {
+        Some(Protocol::P2p(peer_id)) => Some(peer_id),
+        _ => None,
+    };
+    Ok(VoidCode {
+        peer_id,
+        addresses: vec![addr],
+        expires_at: None,
+    })
+}
+
+fn parse_v2(body: &str) -> Result<VoidCode, String> {
+    let (payload_b64, signature_b64) = body
+        .split_once('.')
+        .ok_or("Malformed VOID code")?;
+    let payload_bytes = BASE64_URL
+        .decode(payload_b64)
+        .map_err(|e| format!("Base64 decode error: {}", e))?;
+    let signature = BASE64_URL
+        .decode(signature_b64)
+        .map_err(|e| format!("Base64 decode error: {}", e))?;
+
+    let payload: CodePayload =
+        serde_json::from_slice(&payload_bytes).map_err(|e| format!("Malformed VOID code: {}", e))?;
+
+    let public_key = BASE64
+        .decode(&payload.public_key)
+        .map_err(|e| format!("Base64 decode error: {}", e))?;
+    let public_key = PublicKey::try_decode_protobuf(&public_key)
+        .map_err(|e| format!("Invalid public key: {}", e))?;
+    if !public_key.verify(&[CODE_SIGNING_DOMAIN, &payload_bytes].concat(), &signature) {
+        return Err("VOID code signature is invalid".into());
+    }
+
+    let peer_id = PeerId::from_str(&payload.peer_id).map_err(|e| format!("Invalid PeerId: {}", e))?;
+    if public_key.to_peer_id() != peer_id {
+        return Err("VOID code was not signed by its peer".into());
+    }
+
+    let now = chrono::Utc::now().timestamp();
+    let expires_at = payload
+        .created_at
+        .saturating_add(i64::try_from(payload.ttl_secs).unwrap_or(i64::MAX));
+    if payload.created_at > now + MAX_CLOCK_SKEW_SECS {
+        return Err("VOID code was created in the future".into());
+    }
+    if now > expires_at {
+        return Err("VOID code has expired".into());
+    }
+
+    let addresses = payload
+        .addresses
+        .iter()
+        .map(|a| Multiaddr::from_str(a).map_err(|e| format!("Invalid Multiaddr: {}", e)))
+        .collect::<Result<Vec<_>, _>>()?;
+    if addresses.is_empty() {
+        return Err("VOID code has no addresses".into());
+    }
+
+    Ok(VoidCode {
+        peer_id: Some(peer_id),
+        addresses,
+        expires_at: Some(expires_at),
+    })
+}
+
+fn parse_v1(code: &str) -> Result<Multiaddr, String> {
     if !code.starts_with("void://") {
         return Err("Invalid protocol prefix".to_string());
     }
@@ -24,3 +194,115 @@ pub fn parse_void_code(code: &str) -> Result<Multiaddr, String> {
 
     Multiaddr::from_str(&decoded_str).map_err(|e| format!("Invalid Multiaddr: {}", e))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn addr() -> Multiaddr {
+        "/ip4/203.0.113.7/udp/4001/quic-v1".parse().unwrap()
+    }
+
+    fn payload(keypair: &Keypair, created_at: i64, ttl_secs: u64) -> CodePayload {
+        CodePayload {
+            peer_id: keypair.public().to_peer_id().to_string(),
+            public_key: BASE64.encode(keypair.public().encode_protobuf()),
+            addresses: vec![addr().to_string()],
+            created_at,
+            ttl_secs,
+        }
+    }
+
+    /// Encodes `payload` signed by `keypair`, like `generate_void_code` does.
+    fn encode(keypair: &Keypair, payload: &CodePayload) -> String {
+        let payload = serde_json::to_vec(payload).unwrap();
+        let signature = keypair.sign(&[CODE_SIGNING_DOMAIN, &payload].concat()).unwrap();
+        format!(
+            "{}{}.{}",
+            CODE_V2_PREFIX,
+            BASE64_URL.encode(payload),
+            BASE64_URL.encode(signature)
+        )
+    }
+
+    #[test]
+    fn v2_round_trip() {
+        let keypair = Keypair::generate_ed25519();
+        let code = generate_void_code(&keypair, &[addr()], DEFAULT_CODE_TTL_SECS).unwrap();
+
+        let parsed = parse_void_code(&code).unwrap();
+        assert_eq!(parsed.peer_id, Some(keypair.public().to_peer_id()));
+        assert_eq!(parsed.addresses, vec![addr()]);
+        assert!(parsed.expires_at.unwrap() > chrono::Utc::now().timestamp());
+    }
+
+    #[test]
+    fn v2_rejects_tampered_payload() {
+        let keypair = Keypair::generate_ed25519();
+        let now = chrono::Utc::now().timestamp();
+        let code = encode(&keypair, &payload(&keypair, now, 60));
+
+        let body = code.strip_prefix(CODE_V2_PREFIX).unwrap();
+        let (payload_b64, signature_b64) = body.split_once('.').unwrap();
+        let mut tampered: CodePayload =
+            serde_json::from_slice(&BASE64_URL.decode(payload_b64).unwrap()).unwrap();
+        tampered.addresses = vec!["/ip4/198.51.100.1/tcp/4001".into()];
+        let tampered = format!(
+            "{}{}.{}",
+            CODE_V2_PREFIX,
+            BASE64_URL.encode(serde_json::to_vec(&tampered).unwrap()),
+            signature_b64
+        );
+
+        assert_eq!(
+            parse_void_code(&tampered).unwrap_err(),
+            "VOID code signature is invalid"
+        );
+    }
+
+    #[test]
+    fn v2_rejects_peer_id_mismatch() {
+        let keypair = Keypair::generate_ed25519();
+        let other = Keypair::generate_ed25519();
+        let mut payload = payload(&keypair, chrono::Utc::now().timestamp(), 60);
+        payload.peer_id = other.public().to_peer_id().to_string();
+
+        assert_eq!(
+            parse_void_code(&encode(&keypair, &payload)).unwrap_err(),
+            "VOID code was not signed by its peer"
+        );
+    }
+
+    #[test]
+    fn v2_rejects_expired_and_future_codes() {
+        let keypair = Keypair::generate_ed25519();
+        let now = chrono::Utc::now().timestamp();
+
+        let expired = encode(&keypair, &payload(&keypair, now - 120, 60));
+        assert_eq!(parse_void_code(&expired).unwrap_err(), "VOID code has expired");
+
+        let future = encode(&keypair, &payload(&keypair, now + MAX_CLOCK_SKEW_SECS + 60, 600));
+        assert_eq!(
+            parse_void_code(&future).unwrap_err(),
+            "VOID code was created in the future"
+        );
+
+        // Small clock differences are tolerated
+        let skewed = encode(&keypair, &payload(&keypair, now + 10, 600));
+        assert!(parse_void_code(&skewed).is_ok());
+    }
+
+    #[test]
+    fn falls_back_to_v1() {
+        let peer_id = Keypair::generate_ed25519().public().to_peer_id();
+        let addr = addr().with(Protocol::P2p(peer_id));
+        let code = format!("void://{}", BASE64.encode(addr.to_string()));
+
+        let parsed = parse_void_code(&code).unwrap();
+        assert_eq!(parsed.peer_id, Some(peer_id));
+        assert_eq!(parsed.addresses, vec![addr]);
+        assert_eq!(parsed.expires_at, None);
+
+        assert!(parse_void_code("void://not-base64!").is_err());
+    }
+}
@@ -1 +1,606 @@
 // Crypto implementation
+//
+// X3DH key agreement (https://signal.org/docs/specifications/x3dh/) followed by a
+// Double Ratchet session (https://signal.org/docs/specifications/doubleratchet/)
+// per peer. One-time prekeys are not used and the signed prekey is never
+// rotated, so the first messages of a session are only as safe as the signed
+// prekey: whoever later obtains it can read them. Forward secrecy starts once
+// the responder has replied and the ratchet has stepped past the prekey.
+use chacha20poly1305::{
+    XChaCha20Poly1305, XNonce,
+    aead::{Aead, KeyInit, OsRng, Payload},
+};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use libp2p::{PeerId, identity::Keypair};
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+const X3DH_INFO: &[u8] = b"VOID_X3DH_v1";
+const RATCHET_INFO: &[u8] = b"VOID_RATCHET_v1";
+const MESSAGE_KEY_INFO: &[u8] = b"VOID_MESSAGE_KEY_v1";
+const PREKEY_SIG_CONTEXT: &[u8] = b"void-signed-prekey:";
+const INIT_SIG_CONTEXT: &[u8] = b"void-x3dh-init:";
+
+/// Upper bound on message keys kept for out-of-order delivery, per session.
+const MAX_SKIP: u32 = 1000;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Long-term messaging keys of the local node.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct LocalKeys {
+    identity_secret: [u8; 32],
+    signed_prekey_secret: [u8; 32],
+    signed_prekey_signature: Vec<u8>,
+}
+
+impl LocalKeys {
+    /// Generates an X25519 identity key and a signed prekey, signing the pair
+    /// with the libp2p node identity so peers can bind them to our PeerId.
+    pub fn generate(node_key: &Keypair) -> Result<Self, String> {
+        let identity_secret = StaticSecret::random_from_rng(OsRng).to_bytes();
+        let signed_prekey_secret = StaticSecret::random_from_rng(OsRng).to_bytes();
+
+        let identity_public = PublicKey::from(&StaticSecret::from(identity_secret));
+        let prekey_public = PublicKey::from(&StaticSecret::from(signed_prekey_secret));
+        let signed_prekey_signature = node_key
+            .sign(&prekey_signing_payload(identity_public.as_bytes(), prekey_public.as_bytes()))
+            .map_err(|e| e.to_string())?;
+
+        Ok(Self {
+            identity_secret,
+            signed_prekey_secret,
+            signed_prekey_signature,
+        })
+    }
+
+    pub fn identity_public(&self) -> [u8; 32] {
+        PublicKey::from(&StaticSecret::from(self.identity_secret)).to_bytes()
+    }
+
+    pub fn bundle(&self, node_key: &Keypair) -> PreKeyBundle {
+        PreKeyBundle {
+            identity_key: self.identity_public(),
+            signed_prekey: PublicKey::from(&StaticSecret::from(self.signed_prekey_secret))
+                .to_bytes(),
+            signature: self.signed_prekey_signature.clone(),
+            signing_key: node_key.public().encode_protobuf(),
+        }
+    }
+}
+
+/// Public half of [`LocalKeys`], handed to peers that want to open a session.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PreKeyBundle {
+    pub identity_key: [u8; 32],
+    pub signed_prekey: [u8; 32],
+    pub signature: Vec<u8>,
+    /// Protobuf-encoded libp2p public key that produced `signature`.
+    pub signing_key: Vec<u8>,
+}
+
+impl PreKeyBundle {
+    /// Checks that the bundle was signed by the node identity behind `peer`.
+    pub fn verify(&self, peer: &PeerId) -> Result<(), String> {
+        let signing_key = libp2p::identity::PublicKey::try_decode_protobuf(&self.signing_key)
+            .map_err(|e| e.to_string())?;
+        if PeerId::from(signing_key.clone()) != *peer {
+            return Err("Prekey bundle signed by a different peer".into());
+        }
+        let payload = prekey_signing_payload(&self.identity_key, &self.signed_prekey);
+        if !signing_key.verify(&payload, &self.signature) {
+            return Err("Invalid prekey signature".into());
+        }
+        Ok(())
+    }
+}
+
+/// Sent alongside messages until the responder has answered, so it can
+/// complete its side of the key agreement. Signed by the initiator's node
+/// identity, so whoever relays it (e.g. a mailbox) can't open a session in
+/// someone else's name.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct X3dhInit {
+    pub identity_key: [u8; 32],
+    pub ephemeral_key: [u8; 32],
+    /// When the initiator started the session, in milliseconds. Bound into the
+    /// associated data, so a replayed handshake can't pass as a newer one.
+    pub created_at: i64,
+    pub signature: Vec<u8>,
+    /// Protobuf-encoded libp2p public key that produced `signature`.
+    pub signing_key: Vec<u8>,
+}
+
+impl X3dhInit {
+    /// Checks that the handshake was signed by the node identity behind `peer`.
+    pub fn verify(&self, peer: &PeerId) -> Result<(), String> {
+        let signing_key = libp2p::identity::PublicKey::try_decode_protobuf(&self.signing_key)
+            .map_err(|e| e.to_string())?;
+        if PeerId::from(signing_key.clone()) != *peer {
+            return Err("Handshake signed by a different peer".into());
+        }
+        let payload = init_signing_payload(&self.identity_key, &self.ephemeral_key, self.created_at);
+        if !signing_key.verify(&payload, &self.signature) {
+            return Err("Invalid handshake signature".into());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RatchetHeader {
+    pub dh: [u8; 32],
+    pub pn: u32,
+    pub n: u32,
+}
+
+impl RatchetHeader {
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(40);
+        out.extend_from_slice(&self.dh);
+        out.extend_from_slice(&self.pn.to_be_bytes());
+        out.extend_from_slice(&self.n.to_be_bytes());
+        out
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+struct SkippedKey {
+    dh: [u8; 32],
+    n: u32,
+    message_key: [u8; 32],
+}
+
+/// Double Ratchet state for a single peer.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RatchetSession {
+    /// Remote X3DH identity key this session was agreed with.
+    pub remote_identity: [u8; 32],
+    /// Set on the initiator until the first reply arrives.
+    pub pending_init: Option<X3dhInit>,
+    /// `created_at` of the handshake that opened this session.
+    pub established_at: i64,
+    associated_data: Vec<u8>,
+    dh_self: [u8; 32],
+    dh_remote: Option<[u8; 32]>,
+    root_key: [u8; 32],
+    chain_send: Option<[u8; 32]>,
+    chain_recv: Option<[u8; 32]>,
+    n_send: u32,
+    n_recv: u32,
+    prev_chain_len: u32,
+    skipped: Vec<SkippedKey>,
+}
+
+impl RatchetSession {
+    /// Runs X3DH as the initiator against a verified `bundle`, signing the
+    /// handshake with `node_key`.
+    pub fn initiate(local: &LocalKeys, node_key: &Keypair, bundle: &PreKeyBundle) -> Result<Self, String> {
+        let identity = StaticSecret::from(local.identity_secret);
+        let ephemeral = StaticSecret::random_from_rng(OsRng);
+        let remote_identity = PublicKey::from(bundle.identity_key);
+        let remote_prekey = PublicKey::from(bundle.signed_prekey);
+
+        let dh1 = identity.diffie_hellman(&remote_prekey);
+        let dh2 = ephemeral.diffie_hellman(&remote_identity);
+        let dh3 = ephemeral.diffie_hellman(&remote_prekey);
+        let shared = x3dh_kdf(&[dh1.as_bytes(), dh2.as_bytes(), dh3.as_bytes()])?;
+
+        let identity_key = local.identity_public();
+        let ephemeral_key = PublicKey::from(&ephemeral).to_bytes();
+        let created_at = chrono::Utc::now().timestamp_millis();
+        let signature = node_key
+            .sign(&init_signing_payload(&identity_key, &ephemeral_key, created_at))
+            .map_err(|e| e.to_string())?;
+        let init = X3dhInit {
+            identity_key,
+            ephemeral_key,
+            created_at,
+            signature,
+            signing_key: node_key.public().encode_protobuf(),
+        };
+
+        // First ratchet step against the responder's signed prekey
+        let dh_self = StaticSecret::random_from_rng(OsRng);
+        let (root_key, chain_send) =
+            kdf_rk(&shared, dh_self.diffie_hellman(&remote_prekey).as_bytes())?;
+
+        Ok(Self {
+            remote_identity: bundle.identity_key,
+            associated_data: associated_data(&init.identity_key, &bundle.identity_key, init.created_at),
+            established_at: init.created_at,
+            pending_init: Some(init),
+            dh_self: dh_self.to_bytes(),
+            dh_remote: Some(bundle.signed_prekey),
+            root_key,
+            chain_send: Some(chain_send),
+            chain_recv: None,
+            n_send: 0,
+            n_recv: 0,
+            prev_chain_len: 0,
+            skipped: Vec::new(),
+        })
+    }
+
+    /// Completes X3DH as the responder from the initiator's first message.
+    pub fn respond(local: &LocalKeys, init: &X3dhInit) -> Result<Self, String> {
+        let identity = StaticSecret::from(local.identity_secret);
+        let prekey = StaticSecret::from(local.signed_prekey_secret);
+        let remote_identity = PublicKey::from(init.identity_key);
+        let remote_ephemeral = PublicKey::from(init.ephemeral_key);
+
+        let dh1 = prekey.diffie_hellman(&remote_identity);
+        let dh2 = identity.diffie_hellman(&remote_ephemeral);
+        let dh3 = prekey.diffie_hellman(&remote_ephemeral);
+        let shared = x3dh_kdf(&[dh1.as_bytes(), dh2.as_bytes(), dh3.as_bytes()])?;
+
+        Ok(Self {
+            remote_identity: init.identity_key,
+            associated_data: associated_data(&init.identity_key, &local.identity_public(), init.created_at),
+            pending_init: None,
+            established_at: init.created_at,
+            dh_self: local.signed_prekey_secret,
+            dh_remote: None,
+            root_key: shared,
+            chain_send: None,
+            chain_recv: None,
+            n_send: 0,
+            n_recv: 0,
+            prev_chain_len: 0,
+            skipped: Vec::new(),
+        })
+    }
+
+    pub fn encrypt(&mut self, plaintext: &[u8]) -> Result<(RatchetHeader, Vec<u8>), String> {
+        let chain = self.chain_send.ok_or("Session cannot send before receiving")?;
+        let (next_chain, message_key) = kdf_ck(&chain)?;
+        self.chain_send = Some(next_chain);
+
+        let header = RatchetHeader {
+            dh: PublicKey::from(&StaticSecret::from(self.dh_self)).to_bytes(),
+            pn: self.prev_chain_len,
+            n: self.n_send,
+        };
+        self.n_send += 1;
+
+        let ciphertext = seal_message(&message_key, &self.aad(&header), plaintext)?;
+        Ok((header, ciphertext))
+    }
+
+    /// Decrypts a message. State is only committed if authentication succeeds,
+    /// so a forged message can't desynchronise the session.
+    pub fn decrypt(&mut self, header: &RatchetHeader, ciphertext: &[u8]) -> Result<Vec<u8>, String> {
+        if let Some(pos) = self
+            .skipped
+            .iter()
+            .position(|k| k.dh == header.dh && k.n == header.n)
+        {
+            let plaintext =
+                open_message(&self.skipped[pos].message_key, &self.aad(header), ciphertext)?;
+            self.skipped.remove(pos);
+            return Ok(plaintext);
+        }
+
+        let mut next = self.clone();
+        if next.dh_remote != Some(header.dh) {
+            next.skip_message_keys(header.pn)?;
+            next.dh_ratchet(header)?;
+        }
+        next.skip_message_keys(header.n)?;
+
+        let chain = next.chain_recv.ok_or("No receiving chain")?;
+        let (next_chain, message_key) = kdf_ck(&chain)?;
+        next.chain_recv = Some(next_chain);
+        next.n_recv += 1;
+
+        let plaintext = open_message(&message_key, &next.aad(header), ciphertext)?;
+        next.pending_init = None;
+        *self = next;
+        Ok(plaintext)
+    }
+
+    fn aad(&self, header: &RatchetHeader) -> Vec<u8> {
+        let mut aad = self.associated_data.clone();
+        aad.extend_from_slice(&header.encode());
+        aad
+    }
+
+    fn skip_message_keys(&mut self, until: u32) -> Result<(), String> {
+        let Some(mut chain) = self.chain_recv else {
+            return Ok(());
+        };
+        if until.saturating_sub(self.n_recv) > MAX_SKIP {
+            return Err("Too many skipped messages".into());
+        }
+        let dh = self.dh_remote.ok_or("No remote ratchet key")?;
+        while self.n_recv < until {
+            let (next_chain, message_key) = kdf_ck(&chain)?;
+            self.skipped.push(SkippedKey {
+                dh,
+                n: self.n_recv,
+                message_key,
+            });
+            chain = next_chain;
+            self.n_recv += 1;
+        }
+        self.chain_recv = Some(chain);
+
+        // Drop the oldest keys rather than growing without bound
+        if self.skipped.len() > MAX_SKIP as usize {
+            let excess = self.skipped.len() - MAX_SKIP as usize;
+            self.skipped.drain(..excess);
+        }
+        Ok(())
+    }
+
+    fn dh_ratchet(&mut self, header: &RatchetHeader) -> Result<(), String> {
+        self.prev_chain_len = self.n_send;
+        self.n_send = 0;
+        self.n_recv = 0;
+        self.dh_remote = Some(header.dh);
+
+        let remote = PublicKey::from(header.dh);
+        let (root_key, chain_recv) = kdf_rk(
+            &self.root_key,
+            StaticSecret::from(self.dh_self).diffie_hellman(&remote).as_bytes(),
+        )?;
+        self.chain_recv = Some(chain_recv);
+
+        let dh_self = StaticSecret::random_from_rng(OsRng);
+        let (root_key, chain_send) =
+            kdf_rk(&root_key, dh_self.diffie_hellman(&remote).as_bytes())?;
+        self.dh_self = dh_self.to_bytes();
+        self.root_key = root_key;
+        self.chain_send = Some(chain_send);
+        Ok(())
+    }
+}
+
+fn prekey_signing_payload(identity_key: &[u8; 32], signed_prekey: &[u8; 32]) -> Vec<u8> {
+    let mut payload = PREKEY_SIG_CONTEXT.to_vec();
+    payload.extend_from_slice(identity_key);
+    payload.extend_from_slice(signed_prekey);
+    payload
+}
+
+fn init_signing_payload(identity_key: &[u8; 32], ephemeral_key: &[u8; 32], created_at: i64) -> Vec<u8> {
+    let mut payload = INIT_SIG_CONTEXT.to_vec();
+    payload.extend_from_slice(identity_key);
+    payload.extend_from_slice(ephemeral_key);
+    payload.extend_from_slice(&created_at.to_be_bytes());
+    payload
+}
+
+fn associated_data(initiator: &[u8; 32], responder: &[u8; 32], created_at: i64) -> Vec<u8> {
+    let mut ad = Vec::with_capacity(72);
+    ad.extend_from_slice(initiator);
+    ad.extend_from_slice(responder);
+    ad.extend_from_slice(&created_at.to_be_bytes());
+    ad
+}
+
+fn x3dh_kdf(dhs: &[&[u8; 32]]) -> Result<[u8; 32], String> {
+    // 32 0xFF bytes as required by the X3DH spec for X25519
+    let mut ikm = vec![0xFFu8; 32];
+    for dh in dhs {
+        ikm.extend_from_slice(*dh);
+    }
+    let hk = Hkdf::<Sha256>::new(Some(&[0u8; 32]), &ikm);
+    let mut out = [0u8; 32];
+    hk.expand(X3DH_INFO, &mut out).map_err(|e| e.to_string())?;
+    Ok(out)
+}
+
+fn kdf_rk(root_key: &[u8; 32], dh_out: &[u8; 32]) -> Result<([u8; 32], [u8; 32]), String> {
+    let hk = Hkdf::<Sha256>::new(Some(root_key), dh_out);
+    let mut okm = [0u8; 64];
+    hk.expand(RATCHET_INFO, &mut okm).map_err(|e| e.to_string())?;
+
+    let mut root = [0u8; 32];
+    let mut chain = [0u8; 32];
+    root.copy_from_slice(&okm[..32]);
+    chain.copy_from_slice(&okm[32..]);
+    Ok((root, chain))
+}
+
+fn kdf_ck(chain_key: &[u8; 32]) -> Result<([u8; 32], [u8; 32]), String> {
+    let step = |constant: u8| -> Result<[u8; 32], String> {
+        let mut mac = HmacSha256::new_from_slice(chain_key).map_err(|e| e.to_string())?;
+        mac.update(&[constant]);
+        Ok(mac.finalize().into_bytes().into())
+    };
+    Ok((step(0x02)?, step(0x01)?))
+}
+
+/// Expands a single-use message key into a cipher key and nonce.
+fn message_cipher(message_key: &[u8; 32]) -> Result<(XChaCha20Poly1305, [u8; 24]), String> {
+    let hk = Hkdf::<Sha256>::new(None, message_key);
+    let mut okm = [0u8; 56];
+    hk.expand(MESSAGE_KEY_INFO, &mut okm).map_err(|e| e.to_string())?;
+
+    let cipher = XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(&okm[..32]));
+    let mut nonce = [0u8; 24];
+    nonce.copy_from_slice(&okm[32..]);
+    Ok((cipher, nonce))
+}
+
+fn seal_message(message_key: &[u8; 32], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, String> {
+    let (cipher, nonce) = message_cipher(message_key)?;
+    cipher
+        .encrypt(XNonce::from_slice(&nonce), Payload { msg: plaintext, aad })
+        .map_err(|e| e.to_string())
+}
+
+fn open_message(message_key: &[u8; 32], aad: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, String> {
+    let (cipher, nonce) = message_cipher(message_key)?;
+    cipher
+        .decrypt(XNonce::from_slice(&nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| "Message authentication failed".into())
+}
+
+/// Fresh random key for a group epoch.
+pub fn generate_group_key() -> [u8; 32] {
+    let mut key = [0u8; 32];
+    OsRng.fill_bytes(&mut key);
+    key
+}
+
+/// Seals a group message with the shared group key. The key is reused for a
+/// whole epoch, so each message gets a random nonce.
+pub fn seal_group(group_key: &[u8; 32], aad: &[u8], plaintext: &[u8]) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let cipher = XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(group_key));
+    let mut nonce = [0u8; 24];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = cipher
+        .encrypt(XNonce::from_slice(&nonce), Payload { msg: plaintext, aad })
+        .map_err(|e| e.to_string())?;
+    Ok((nonce.to_vec(), ciphertext))
+}
+
+pub fn open_group(group_key: &[u8; 32], aad: &[u8], nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, String> {
+    if nonce.len() != 24 {
+        return Err("Invalid nonce".into());
+    }
+    let cipher = XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(group_key));
+    cipher
+        .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| "Message authentication failed".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An initiator session and the responder session it opens with its first message.
+    fn pair() -> (RatchetSession, RatchetSession, RatchetHeader, Vec<u8>) {
+        let alice_node = Keypair::generate_ed25519();
+        let bob_node = Keypair::generate_ed25519();
+        let alice_keys = LocalKeys::generate(&alice_node).unwrap();
+        let bob_keys = LocalKeys::generate(&bob_node).unwrap();
+
+        let bundle = bob_keys.bundle(&bob_node);
+        bundle.verify(&PeerId::from(bob_node.public())).unwrap();
+        let mut alice = RatchetSession::initiate(&alice_keys, &alice_node, &bundle).unwrap();
+        let (header, ciphertext) = alice.encrypt(b"hello").unwrap();
+
+        let init = alice.pending_init.clone().unwrap();
+        init.verify(&PeerId::from(alice_node.public())).unwrap();
+        let mut bob = RatchetSession::respond(&bob_keys, &init).unwrap();
+        assert_eq!(bob.decrypt(&header, &ciphertext).unwrap(), b"hello");
+        (alice, bob, header, ciphertext)
+    }
+
+    #[test]
+    fn round_trip_in_order() {
+        let (mut alice, mut bob, _, _) = pair();
+
+        for i in 0..3 {
+            let text = format!("bob {}", i);
+            let (header, ciphertext) = bob.encrypt(text.as_bytes()).unwrap();
+            assert_eq!(alice.decrypt(&header, &ciphertext).unwrap(), text.as_bytes());
+        }
+        assert!(alice.pending_init.is_none());
+
+        for i in 0..3 {
+            let text = format!("alice {}", i);
+            let (header, ciphertext) = alice.encrypt(text.as_bytes()).unwrap();
+            assert_eq!(bob.decrypt(&header, &ciphertext).unwrap(), text.as_bytes());
+        }
+    }
+
+    #[test]
+    fn out_of_order_within_max_skip() {
+        let (mut alice, mut bob, _, _) = pair();
+
+        let sent: Vec<_> = (0..5u8).map(|i| alice.encrypt(&[i]).unwrap()).collect();
+        for i in [4, 0, 3, 1, 2] {
+            let (header, ciphertext) = &sent[i];
+            assert_eq!(bob.decrypt(header, ciphertext).unwrap(), [i as u8]);
+        }
+        assert!(bob.skipped.is_empty());
+    }
+
+    #[test]
+    fn rejects_gap_beyond_max_skip() {
+        let (mut alice, mut bob, _, _) = pair();
+
+        for _ in 0..=MAX_SKIP + 1 {
+            alice.encrypt(b"dropped").unwrap();
+        }
+        let (header, ciphertext) = alice.encrypt(b"too far").unwrap();
+        assert!(bob.decrypt(&header, &ciphertext).is_err());
+
+        // The failed attempt left the session usable
+        let (header, ciphertext) = bob.encrypt(b"still here").unwrap();
+        assert_eq!(alice.decrypt(&header, &ciphertext).unwrap(), b"still here");
+    }
+
+    #[test]
+    fn rejects_tampered_header_and_ciphertext() {
+        let (mut alice, mut bob, _, _) = pair();
+        let (header, ciphertext) = alice.encrypt(b"secret").unwrap();
+
+        let mut bad_header = header.clone();
+        bad_header.pn += 1;
+        assert!(bob.decrypt(&bad_header, &ciphertext).is_err());
+
+        let mut bad_ciphertext = ciphertext.clone();
+        bad_ciphertext[0] ^= 1;
+        assert!(bob.decrypt(&header, &bad_ciphertext).is_err());
+
+        assert_eq!(bob.decrypt(&header, &ciphertext).unwrap(), b"secret");
+    }
+
+    #[test]
+    fn rejects_replay() {
+        let (mut alice, mut bob, first_header, first_ciphertext) = pair();
+        assert!(bob.decrypt(&first_header, &first_ciphertext).is_err());
+
+        let skipped = alice.encrypt(b"late").unwrap();
+        let (header, ciphertext) = alice.encrypt(b"once").unwrap();
+        assert_eq!(bob.decrypt(&header, &ciphertext).unwrap(), b"once");
+        assert!(bob.decrypt(&header, &ciphertext).is_err());
+
+        // Keys kept for skipped messages are single-use as well
+        assert_eq!(bob.decrypt(&skipped.0, &skipped.1).unwrap(), b"late");
+        assert!(bob.decrypt(&skipped.0, &skipped.1).is_err());
+    }
+
+    #[test]
+    fn handshake_time_is_authenticated() {
+        let alice_node = Keypair::generate_ed25519();
+        let bob_node = Keypair::generate_ed25519();
+        let alice_keys = LocalKeys::generate(&alice_node).unwrap();
+        let bob_keys = LocalKeys::generate(&bob_node).unwrap();
+        let mut alice =
+            RatchetSession::initiate(&alice_keys, &alice_node, &bob_keys.bundle(&bob_node)).unwrap();
+        let (header, ciphertext) = alice.encrypt(b"hello").unwrap();
+
+        let mut init = alice.pending_init.clone().unwrap();
+        init.created_at += 1;
+        let mut bob = RatchetSession::respond(&bob_keys, &init).unwrap();
+        assert!(bob.decrypt(&header, &ciphertext).is_err());
+    }
+
+    #[test]
+    fn handshake_is_bound_to_the_initiator() {
+        let alice_node = Keypair::generate_ed25519();
+        let bob_node = Keypair::generate_ed25519();
+        let alice_keys = LocalKeys::generate(&alice_node).unwrap();
+        let bob_keys = LocalKeys::generate(&bob_node).unwrap();
+        let alice = RatchetSession::initiate(&alice_keys, &alice_node, &bob_keys.bundle(&bob_node)).unwrap();
+        let init = alice.pending_init.clone().unwrap();
+
+        let alice_peer = PeerId::from(alice_node.public());
+        let bob_peer = PeerId::from(bob_node.public());
+        assert!(init.verify(&alice_peer).is_ok());
+        assert!(init.verify(&bob_peer).is_err());
+
+        let mut forged = init.clone();
+        forged.identity_key = bob_keys.identity_public();
+        assert!(forged.verify(&alice_peer).is_err());
+    }
+}
@@ -1 +1,3 @@
 pub mod crypto;
+pub mod pin_strength;
+pub mod safety;
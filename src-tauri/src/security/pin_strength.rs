@@ -0,0 +1,123 @@
+// PIN strength
+//
+// The vault PIN guards the master key against offline guessing, where only the
+// Argon2 cost slows an attacker down. PINs that fall to the first guesses of
+// any cracker (short, common, one character or a short pattern repeated, a
+// straight run) are rejected. The rest get a rough entropy estimate from
+// length and character classes, which overstates PINs people pick but is
+// enough to tell a 6-digit PIN from a passphrase.
+use serde::Serialize;
+
+const MIN_PIN_LEN: usize = 6;
+const WEAK_BITS: f64 = 28.0;
+const STRONG_BITS: f64 = 50.0;
+
+const COMMON_PINS: &[&str] = &[
+    "password", "passw0rd", "password1", "qwerty", "qwerty123", "qwertyuiop", "azerty", "asdfgh", "zxcvbn",
+    "letmein", "iloveyou", "welcome", "monkey", "dragon", "admin", "abc123", "1q2w3e", "1q2w3e4r", "1qaz2wsx",
+    "zaq12wsx", "123123", "112233", "123321", "121212", "131313", "696969", "159753", "159357", "147258",
+    "147258369", "789456", "789456123", "102030", "123654", "111222", "520520",
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum PinRating {
+    Rejected,
+    Weak,
+    Fair,
+    Strong,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PinStrength {
+    pub rating: PinRating,
+    pub entropy_bits: f64,
+    /// Why the PIN is rejected, if it is.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub problem: Option<String>,
+}
+
+pub fn assess(pin: &str) -> PinStrength {
+    let chars: Vec<char> = pin.chars().collect();
+    let entropy_bits = entropy_bits(&chars);
+    let problem = problem(pin, &chars);
+    let rating = if problem.is_some() {
+        PinRating::Rejected
+    } else if entropy_bits < WEAK_BITS {
+        PinRating::Weak
+    } else if entropy_bits < STRONG_BITS {
+        PinRating::Fair
+    } else {
+        PinRating::Strong
+    };
+    PinStrength {
+        rating,
+        entropy_bits,
+        problem,
+    }
+}
+
+/// Fails for PINs that are trivially guessed.
+pub fn check(pin: &str) -> Result<(), String> {
+    match assess(pin).problem {
+        Some(problem) => Err(problem),
+        None => Ok(()),
+    }
+}
+
+fn problem(pin: &str, chars: &[char]) -> Option<String> {
+    if chars.len() < MIN_PIN_LEN {
+        return Some(format!("PINs need at least {} characters", MIN_PIN_LEN));
+    }
+    if COMMON_PINS.contains(&pin.to_lowercase().as_str()) {
+        return Some("This is one of the most common PINs".into());
+    }
+    if is_repeated(chars) {
+        return Some("PINs can't be one character or a short pattern repeated".into());
+    }
+    if is_run(chars) {
+        return Some("PINs can't be a straight run like 123456 or abcdef".into());
+    }
+    None
+}
+
+/// "111111", "121212", "123123", "1231231"...
+fn is_repeated(chars: &[char]) -> bool {
+    (1..=chars.len() / 2).any(|period| (period..chars.len()).all(|i| chars[i] == chars[i % period]))
+}
+
+/// Consecutive characters all one apart, up or down.
+fn is_run(chars: &[char]) -> bool {
+    [1i64, -1].iter().any(|step| {
+        chars
+            .windows(2)
+            .all(|pair| pair[1] as i64 - pair[0] as i64 == *step)
+    })
+}
+
+fn entropy_bits(chars: &[char]) -> f64 {
+    let mut pool = 0;
+    if chars.iter().any(|c| c.is_ascii_digit()) {
+        pool += 10;
+    }
+    if chars.iter().any(|c| c.is_ascii_lowercase()) {
+        pool += 26;
+    }
+    if chars.iter().any(|c| c.is_ascii_uppercase()) {
+        pool += 26;
+    }
+    if chars.iter().any(|c| !c.is_ascii_alphanumeric()) {
+        pool += 33;
+    }
+    if pool == 0 {
+        return 0.0;
+    }
+    chars.len() as f64 * (pool as f64).log2()
+}
+
+/// Rates a PIN before it is set, so the UI can explain a rejection.
+#[tauri::command]
+pub async fn check_vault_pin(pin: String) -> Result<PinStrength, String> {
+    Ok(assess(&pin))
+}
@@ -0,0 +1,145 @@
+// Safety numbers
+//
+// Same construction as Signal's numeric fingerprints: each side's messaging
+// identity key and PeerId are hashed 5200 times with SHA-512, the first 30
+// bytes become six 5-digit groups, and the two 30-digit halves are sorted so
+// both users see the same 60 digits. The QR payload carries the raw
+// fingerprints so a scan can be checked without reading digits aloud.
+use crate::network::{NetworkCommand, NetworkState};
+use crate::storage::contacts::Verification;
+use crate::storage::db::HistoryState;
+use base64::{Engine as _, engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL};
+use libp2p::PeerId;
+use serde::Serialize;
+use sha2::{Digest, Sha512};
+use tauri::State;
+
+const FINGERPRINT_VERSION: u16 = 0;
+const ITERATIONS: usize = 5200;
+const FINGERPRINT_LEN: usize = 30;
+const QR_PREFIX: &str = "void-safety:1:";
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SafetyNumber {
+    /// 60 digits in 12 groups of 5, identical on both devices.
+    pub digits: String,
+    /// Payload to render as a QR code for the other side to scan.
+    pub qr_payload: String,
+}
+
+fn fingerprint(identity_key: &[u8; 32], peer: &PeerId) -> [u8; FINGERPRINT_LEN] {
+    let peer = peer.to_bytes();
+    let mut hash = {
+        let mut hasher = Sha512::new();
+        hasher.update(FINGERPRINT_VERSION.to_be_bytes());
+        hasher.update(identity_key);
+        hasher.update(&peer);
+        hasher.finalize()
+    };
+    for _ in 1..ITERATIONS {
+        let mut hasher = Sha512::new();
+        hasher.update(hash);
+        hasher.update(identity_key);
+        hash = hasher.finalize();
+    }
+
+    let mut out = [0u8; FINGERPRINT_LEN];
+    out.copy_from_slice(&hash[..FINGERPRINT_LEN]);
+    out
+}
+
+fn digits(fingerprint: &[u8; FINGERPRINT_LEN]) -> String {
+    fingerprint
+        .chunks(5)
+        .map(|chunk| {
+            let value = chunk.iter().fold(0u64, |acc, b| (acc << 8) | u64::from(*b));
+            format!("{:05}", value % 100_000)
+        })
+        .collect()
+}
+
+/// Derives the safety number shared by `local` and `remote`.
+pub fn safety_number(
+    local_key: &[u8; 32],
+    local_peer: &PeerId,
+    remote_key: &[u8; 32],
+    remote_peer: &PeerId,
+) -> SafetyNumber {
+    let local = fingerprint(local_key, local_peer);
+    let remote = fingerprint(remote_key, remote_peer);
+
+    let mut halves = [digits(&local), digits(&remote)];
+    halves.sort();
+    let joined = halves.concat();
+    let digits = joined
+        .as_bytes()
+        .chunks(5)
+        .map(|group| std::str::from_utf8(group).unwrap_or_default())
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    SafetyNumber {
+        digits,
+        qr_payload: format!(
+            "{}{}.{}",
+            QR_PREFIX,
+            BASE64_URL.encode(local),
+            BASE64_URL.encode(remote)
+        ),
+    }
+}
+
+/// Checks a payload scanned from the other device against our own view of
+/// the keys. The scanned "local" side must be our "remote" side and vice versa.
+pub fn verify_qr_payload(scanned: &str, ours: &SafetyNumber) -> Result<bool, String> {
+    let parse = |payload: &str| -> Result<(Vec<u8>, Vec<u8>), String> {
+        let body = payload
+            .trim()
+            .strip_prefix(QR_PREFIX)
+            .ok_or("Not a VOID safety code")?;
+        let (first, second) = body.split_once('.').ok_or("Malformed safety code")?;
+        let decode = |s: &str| BASE64_URL.decode(s).map_err(|e| format!("Malformed safety code: {}", e));
+        Ok((decode(first)?, decode(second)?))
+    };
+
+    let (their_local, their_remote) = parse(scanned)?;
+    let (our_local, our_remote) = parse(&ours.qr_payload)?;
+    Ok(their_local == our_remote && their_remote == our_local)
+}
+
+async fn fetch(network: &State<'_, NetworkState>, peer: PeerId) -> Result<SafetyNumber, String> {
+    crate::network::request(network, |tx| NetworkCommand::GetSafetyNumber(peer, tx)).await
+}
+
+#[tauri::command]
+pub async fn get_safety_number(
+    peer_id: String,
+    network: State<'_, NetworkState>,
+) -> Result<SafetyNumber, String> {
+    let peer = peer_id.parse().map_err(|e| format!("Invalid PeerId: {}", e))?;
+    fetch(&network, peer).await
+}
+
+/// Compares a scanned QR payload with our safety number and marks the contact
+/// verified if they match.
+#[tauri::command]
+pub async fn verify_safety_qr(
+    peer_id: String,
+    payload: String,
+    network: State<'_, NetworkState>,
+    history: State<'_, HistoryState>,
+) -> Result<bool, String> {
+    let peer = peer_id.parse().map_err(|e| format!("Invalid PeerId: {}", e))?;
+    let ours = fetch(&network, peer).await?;
+    if !verify_qr_payload(&payload, &ours)? {
+        return Ok(false);
+    }
+
+    let mut guard = history.store.lock().map_err(|e| e.to_string())?;
+    let store = guard.as_mut().ok_or("Message store is locked; start the node first")?;
+    if !store.set_contact_verification(&peer_id, Verification::Verified)? {
+        return Err("Add the peer as a contact before verifying it".into());
+    }
+    Ok(true)
+}
@@ -0,0 +1,359 @@
+// Contact roster
+//
+// Lives in the same SQLite database as the message history. The messaging
+// identity key of a contact is pinned the first time a session with it is
+// established (trust on first use) and can then be marked verified out of band.
+use crate::network::{NetworkCommand, NetworkState, utils};
+use crate::storage::db::{HistoryState, MessageStore, with_store};
+use libp2p::PeerId;
+use rusqlite::{OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+use tauri::State;
+
+/// Last-seen addresses kept per contact.
+const MAX_ADDRESSES: usize = 8;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum Verification {
+    Unverified,
+    /// The user compared keys with the contact out of band.
+    Verified,
+    /// The contact's identity key changed since it was pinned; needs re-verifying.
+    KeyChanged,
+}
+
+impl Verification {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Verification::Unverified => "unverified",
+            Verification::Verified => "verified",
+            Verification::KeyChanged => "key_changed",
+        }
+    }
+
+    fn parse(value: &str) -> Self {
+        match value {
+            "verified" => Verification::Verified,
+            "key_changed" => Verification::KeyChanged,
+            _ => Verification::Unverified,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Contact {
+    pub peer_id: String,
+    pub nickname: String,
+    /// Hex-encoded messaging identity key pinned from the first session.
+    pub identity_key: Option<String>,
+    pub verification: Verification,
+    pub blocked: bool,
+    pub addresses: Vec<String>,
+    pub last_seen: Option<i64>,
+    pub added_at: i64,
+}
+
+/// Outcome of comparing a session's identity key with the pinned one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyPin {
+    NotAContact,
+    Pinned,
+    Unchanged,
+    /// A different key was seen. The new key is pinned and the contact is
+    /// flagged `KeyChanged` until the user verifies it again.
+    Changed { was_verified: bool },
+}
+
+const CONTACT_COLUMNS: &str =
+    "peer_id, nickname, identity_key, verification, blocked, addresses, last_seen, added_at";
+
+fn read_contact(row: &rusqlite::Row<'_>) -> rusqlite::Result<Contact> {
+    let identity_key: Option<Vec<u8>> = row.get(2)?;
+    let verification: String = row.get(3)?;
+    let addresses: String = row.get(5)?;
+    Ok(Contact {
+        peer_id: row.get(0)?,
+        nickname: row.get(1)?,
+        identity_key: identity_key.map(|key| to_hex(&key)),
+        verification: Verification::parse(&verification),
+        blocked: row.get(4)?,
+        addresses: serde_json::from_str(&addresses).unwrap_or_default(),
+        last_seen: row.get(6)?,
+        added_at: row.get(7)?,
+    })
+}
+
+pub(crate) fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl MessageStore {
+    pub fn contacts(&self) -> Result<Vec<Contact>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!(
+                "SELECT {} FROM contacts ORDER BY nickname COLLATE NOCASE",
+                CONTACT_COLUMNS
+            ))
+            .map_err(|e| e.to_string())?;
+        let contacts = stmt
+            .query_map([], read_contact)
+            .map_err(|e| e.to_string())?
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.to_string())?;
+        Ok(contacts)
+    }
+
+    pub fn contact(&self, peer_id: &str) -> Result<Option<Contact>, String> {
+        self.conn
+            .query_row(
+                &format!("SELECT {} FROM contacts WHERE peer_id = ?1", CONTACT_COLUMNS),
+                params![peer_id],
+                read_contact,
+            )
+            .optional()
+            .map_err(|e| e.to_string())
+    }
+
+    /// Adds a contact, or renames it and merges `addresses` if it already exists.
+    pub fn add_contact(
+        &mut self,
+        peer_id: &str,
+        nickname: &str,
+        addresses: &[String],
+    ) -> Result<Contact, String> {
+        let mut merged = self
+            .contact(peer_id)?
+            .map(|c| c.addresses)
+            .unwrap_or_default();
+        for addr in addresses.iter().rev() {
+            merged.retain(|a| a != addr);
+            merged.insert(0, addr.clone());
+        }
+        merged.truncate(MAX_ADDRESSES);
+        let merged = serde_json::to_string(&merged).map_err(|e| e.to_string())?;
+
+        self.conn
+            .execute(
+                "INSERT INTO contacts (peer_id, nickname, addresses, added_at) VALUES (?1, ?2, ?3, ?4)
+                 ON CONFLICT(peer_id) DO UPDATE SET nickname = excluded.nickname, addresses = excluded.addresses",
+                params![peer_id, nickname, merged, chrono::Utc::now().timestamp()],
+            )
+            .map_err(|e| e.to_string())?;
+        self.contact(peer_id)?.ok_or_else(|| "Contact vanished".to_string())
+    }
+
+    pub fn remove_contact(&mut self, peer_id: &str) -> Result<bool, String> {
+        let deleted = self
+            .conn
+            .execute("DELETE FROM contacts WHERE peer_id = ?1", params![peer_id])
+            .map_err(|e| e.to_string())?;
+        Ok(deleted > 0)
+    }
+
+    pub fn rename_contact(&mut self, peer_id: &str, nickname: &str) -> Result<bool, String> {
+        let updated = self
+            .conn
+            .execute(
+                "UPDATE contacts SET nickname = ?2 WHERE peer_id = ?1",
+                params![peer_id, nickname],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(updated > 0)
+    }
+
+    pub fn set_contact_blocked(&mut self, peer_id: &str, blocked: bool) -> Result<bool, String> {
+        let updated = self
+            .conn
+            .execute(
+                "UPDATE contacts SET blocked = ?2 WHERE peer_id = ?1",
+                params![peer_id, blocked],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(updated > 0)
+    }
+
+    pub fn set_contact_verification(
+        &mut self,
+        peer_id: &str,
+        verification: Verification,
+    ) -> Result<bool, String> {
+        let updated = self
+            .conn
+            .execute(
+                "UPDATE contacts SET verification = ?2 WHERE peer_id = ?1",
+                params![peer_id, verification.as_str()],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(updated > 0)
+    }
+
+    /// Updates last-seen time and addresses of a contact. No-op for strangers.
+    pub fn record_seen(&mut self, peer_id: &str, addresses: &[String]) -> Result<(), String> {
+        let Some(contact) = self.contact(peer_id)? else {
+            return Ok(());
+        };
+        let mut merged = addresses.to_vec();
+        for addr in contact.addresses {
+            if !merged.contains(&addr) {
+                merged.push(addr);
+            }
+        }
+        merged.truncate(MAX_ADDRESSES);
+        self.conn
+            .execute(
+                "UPDATE contacts SET last_seen = ?2, addresses = ?3 WHERE peer_id = ?1",
+                params![
+                    peer_id,
+                    chrono::Utc::now().timestamp(),
+                    serde_json::to_string(&merged).map_err(|e| e.to_string())?
+                ],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Pins `identity_key` for a contact on first use and reports whether it
+    /// differs from the key pinned earlier.
+    pub fn pin_identity_key(&mut self, peer_id: &str, identity_key: &[u8; 32]) -> Result<KeyPin, String> {
+        let pinned: Option<(Option<Vec<u8>>, String)> = self
+            .conn
+            .query_row(
+                "SELECT identity_key, verification FROM contacts WHERE peer_id = ?1",
+                params![peer_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .map_err(|e| e.to_string())?;
+
+        let pin = match pinned {
+            None => return Ok(KeyPin::NotAContact),
+            Some((Some(key), _)) if key == identity_key => return Ok(KeyPin::Unchanged),
+            Some((Some(_), verification)) => KeyPin::Changed {
+                was_verified: Verification::parse(&verification) == Verification::Verified,
+            },
+            Some((None, _)) => KeyPin::Pinned,
+        };
+
+        let verification = match pin {
+            KeyPin::Changed { .. } => Verification::KeyChanged,
+            _ => Verification::Unverified,
+        };
+        self.conn
+            .execute(
+                "UPDATE contacts SET identity_key = ?2, verification = ?3 WHERE peer_id = ?1",
+                params![peer_id, identity_key.as_slice(), verification.as_str()],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(pin)
+    }
+
+    pub fn pinned_identity_key(&self, peer_id: &str) -> Result<Option<[u8; 32]>, String> {
+        let key: Option<Option<Vec<u8>>> = self
+            .conn
+            .query_row(
+                "SELECT identity_key FROM contacts WHERE peer_id = ?1",
+                params![peer_id],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| e.to_string())?;
+        Ok(key.flatten().and_then(|key| key.try_into().ok()))
+    }
+}
+
+fn parse_peer(peer_id: &str) -> Result<PeerId, String> {
+    peer_id.parse().map_err(|e| format!("Invalid PeerId: {}", e))
+}
+
+/// Forwards `command` to the running node, if any.
+async fn notify_node(network: &State<'_, NetworkState>, command: NetworkCommand) -> Result<(), String> {
+    if let Some(tx) = network.sender.lock().await.as_ref() {
+        tx.send(command).await.map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn list_contacts(state: State<'_, HistoryState>) -> Result<Vec<Contact>, String> {
+    with_store(&state, |store| store.contacts())
+}
+
+/// Adds a contact by PeerId, optionally with a VOID code to learn its
+/// addresses, and dials it if the node is running.
+#[tauri::command]
+pub async fn add_contact(
+    peer_id: String,
+    nickname: String,
+    code: Option<String>,
+    state: State<'_, HistoryState>,
+    network: State<'_, NetworkState>,
+) -> Result<Contact, String> {
+    let peer = parse_peer(&peer_id)?;
+    let code = code.map(|c| utils::parse_void_code(&c)).transpose()?;
+    if let Some(code_peer) = code.as_ref().and_then(|c| c.peer_id) {
+        if code_peer != peer {
+            return Err(format!("VOID code belongs to {}, not {}", code_peer, peer));
+        }
+    }
+    let addresses: Vec<String> = code
+        .as_ref()
+        .map(|c| c.addresses.iter().map(|a| a.to_string()).collect())
+        .unwrap_or_default();
+
+    let contact = with_store(&state, |store| store.add_contact(&peer_id, &nickname, &addresses))?;
+    if !contact.blocked {
+        let command = match code {
+            Some(code) => NetworkCommand::DialCode(code),
+            None => NetworkCommand::Dial(peer),
+        };
+        notify_node(&network, command).await?;
+    }
+    Ok(contact)
+}
+
+#[tauri::command]
+pub async fn remove_contact(peer_id: String, state: State<'_, HistoryState>) -> Result<bool, String> {
+    with_store(&state, |store| store.remove_contact(&peer_id))
+}
+
+#[tauri::command]
+pub async fn rename_contact(
+    peer_id: String,
+    nickname: String,
+    state: State<'_, HistoryState>,
+) -> Result<bool, String> {
+    with_store(&state, |store| store.rename_contact(&peer_id, &nickname))
+}
+
+/// Blocks (or unblocks) a contact. Blocked peers can't connect to this node.
+#[tauri::command]
+pub async fn block_contact(
+    peer_id: String,
+    blocked: bool,
+    state: State<'_, HistoryState>,
+    network: State<'_, NetworkState>,
+) -> Result<bool, String> {
+    let peer = parse_peer(&peer_id)?;
+    let updated = with_store(&state, |store| store.set_contact_blocked(&peer_id, blocked))?;
+    if updated {
+        notify_node(&network, NetworkCommand::SetBlocked(peer, blocked)).await?;
+    }
+    Ok(updated)
+}
+
+#[tauri::command]
+pub async fn set_contact_verified(
+    peer_id: String,
+    verified: bool,
+    state: State<'_, HistoryState>,
+) -> Result<bool, String> {
+    let verification = if verified {
+        Verification::Verified
+    } else {
+        Verification::Unverified
+    };
+    with_store(&state, |store| store.set_contact_verification(&peer_id, verification))
+}
@@ -1 +1,605 @@
 // Database implementation
+//
+// Message history lives in SQLite with every message body encrypted. Each
+// conversation has its own random key, sealed by a master key, which in turn is
+// sealed by the user's passphrase (same Argon2 + XChaCha20Poly1305 scheme as
+// the vault). Deleting a conversation drops its key, and `secure_delete` makes
+// SQLite overwrite freed pages, so deleted messages don't linger on disk.
+use crate::storage::outbox::MessageStatus;
+use crate::storage::vault;
+use chacha20poly1305::{
+    XChaCha20Poly1305, XNonce,
+    aead::{Aead, KeyInit, OsRng, Payload},
+};
+use rand::RngCore;
+use rusqlite::{Connection, OptionalExtension, params};
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tauri::{AppHandle, Manager, State};
+
+const NONCE_SIZE: usize = 24;
+const DB_FILE: &str = "void.db";
+
+/// Schema migrations, applied in order and tracked with `PRAGMA user_version`.
+const MIGRATIONS: &[&str] = &[
+    // 1: encrypted message history
+    "CREATE TABLE IF NOT EXISTS meta (
+        key TEXT PRIMARY KEY,
+        value BLOB NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS conversations (
+        peer_id TEXT PRIMARY KEY,
+        sealed_key BLOB NOT NULL,
+        created_at INTEGER NOT NULL
+    );
+    CREATE TABLE IF NOT EXISTS message_history (
+        seq INTEGER PRIMARY KEY AUTOINCREMENT,
+        id TEXT NOT NULL,
+        peer_id TEXT NOT NULL,
+        is_sent INTEGER NOT NULL,
+        timestamp INTEGER NOT NULL,
+        nonce BLOB NOT NULL,
+        ciphertext BLOB NOT NULL,
+        -- ids come from the sending peer, so they are only unique per conversation
+        UNIQUE (peer_id, id)
+    );
+    CREATE INDEX IF NOT EXISTS idx_history_peer ON message_history(peer_id, seq);",
+    // 2: contact roster
+    "CREATE TABLE IF NOT EXISTS contacts (
+        peer_id TEXT PRIMARY KEY,
+        nickname TEXT NOT NULL,
+        identity_key BLOB,
+        verification TEXT NOT NULL DEFAULT 'unverified',
+        blocked INTEGER NOT NULL DEFAULT 0,
+        addresses TEXT NOT NULL DEFAULT '[]',
+        last_seen INTEGER,
+        added_at INTEGER NOT NULL
+    );",
+    // 3: delivery status and persistent outbox
+    "ALTER TABLE message_history ADD COLUMN status TEXT NOT NULL DEFAULT 'delivered';
+    CREATE TABLE IF NOT EXISTS outbox (
+        id TEXT NOT NULL,
+        peer_id TEXT NOT NULL,
+        sent_at INTEGER NOT NULL,
+        queued_at INTEGER NOT NULL,
+        nonce BLOB NOT NULL,
+        ciphertext BLOB NOT NULL,
+        attempts INTEGER NOT NULL DEFAULT 0,
+        next_attempt_at INTEGER NOT NULL,
+        last_error TEXT,
+        -- like history rows, ids are only unique per conversation
+        PRIMARY KEY (peer_id, id)
+    );",
+    // 4: group chats; group messages are stored under the group id
+    "ALTER TABLE message_history ADD COLUMN sender TEXT;
+    CREATE TABLE IF NOT EXISTS groups (
+        id TEXT PRIMARY KEY,
+        name TEXT NOT NULL,
+        owner TEXT NOT NULL,
+        members TEXT NOT NULL DEFAULT '[]',
+        epoch INTEGER NOT NULL,
+        sealed_key BLOB NOT NULL,
+        sealed_previous_key BLOB,
+        active INTEGER NOT NULL DEFAULT 1,
+        created_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+    );",
+    // 5: peer-to-peer file transfers, both directions
+    "CREATE TABLE IF NOT EXISTS file_transfers (
+        id TEXT PRIMARY KEY,
+        peer_id TEXT NOT NULL,
+        outgoing INTEGER NOT NULL,
+        size INTEGER NOT NULL,
+        chunk_size INTEGER NOT NULL,
+        sealed_key BLOB NOT NULL,
+        -- name, path and hash, sealed with the master key
+        nonce BLOB NOT NULL,
+        ciphertext BLOB NOT NULL,
+        state TEXT NOT NULL,
+        transferred INTEGER NOT NULL DEFAULT 0,
+        -- running SHA-256 of what a download has written, sealed
+        sealed_hash BLOB,
+        created_at INTEGER NOT NULL,
+        updated_at INTEGER NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS idx_file_transfers_peer ON file_transfers(peer_id, state);",
+];
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct StoredMessage {
+    /// Monotonic cursor used for pagination.
+    pub seq: i64,
+    pub id: String,
+    pub peer_id: String,
+    pub content: String,
+    pub is_sent: bool,
+    pub timestamp: i64,
+    pub status: MessageStatus,
+    /// Author of a received group message.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sender: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ConversationSummary {
+    pub peer_id: String,
+    pub message_count: i64,
+    pub last_timestamp: Option<i64>,
+}
+
+pub struct MessageStore {
+    pub(super) conn: Connection,
+    pub(super) master_key: [u8; 32],
+    conversation_keys: HashMap<String, [u8; 32]>,
+}
+
+impl MessageStore {
+    /// Opens (or creates) the store at `path`, running pending migrations and
+    /// unlocking the master key with `passphrase`.
+    pub fn open(path: &Path, passphrase: &str) -> Result<Self, String> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+        }
+        let conn = Connection::open(path).map_err(|e| e.to_string())?;
+        conn.execute_batch("PRAGMA secure_delete = ON;")
+            .map_err(|e| e.to_string())?;
+        migrate(&conn)?;
+
+        let sealed: Option<Vec<u8>> = conn
+            .query_row("SELECT value FROM meta WHERE key = 'master_key'", [], |row| {
+                row.get(0)
+            })
+            .optional()
+            .map_err(|e| e.to_string())?;
+
+        let master_key: [u8; 32] = match sealed {
+            Some(sealed) => {
+                let bytes = vault::open(passphrase, &sealed)
+                    .map_err(|_| "Failed to unlock message store: wrong passphrase")?;
+                bytes
+                    .try_into()
+                    .map_err(|_| "Corrupted master key".to_string())?
+            }
+            None => {
+                let mut key = [0u8; 32];
+                OsRng.fill_bytes(&mut key);
+                conn.execute(
+                    "INSERT INTO meta (key, value) VALUES ('master_key', ?1)",
+                    params![vault::seal(passphrase, &key)?],
+                )
+                .map_err(|e| e.to_string())?;
+                key
+            }
+        };
+
+        let mut store = Self {
+            conn,
+            master_key,
+            conversation_keys: HashMap::new(),
+        };
+        store.import_legacy_messages()?;
+        Ok(store)
+    }
+
+    /// Encrypts plaintext rows left behind by older CLI builds and drops the
+    /// old `messages` table.
+    fn import_legacy_messages(&mut self) -> Result<(), String> {
+        let exists: bool = self
+            .conn
+            .query_row(
+                "SELECT EXISTS(SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = 'messages')",
+                [],
+                |row| row.get(0),
+            )
+            .map_err(|e| e.to_string())?;
+        if !exists {
+            return Ok(());
+        }
+
+        let rows: Vec<(i64, String, String, bool, i64)> = {
+            let mut stmt = self
+                .conn
+                .prepare("SELECT id, peer_id, content, is_sent, timestamp FROM messages ORDER BY id")
+                .map_err(|e| e.to_string())?;
+            stmt.query_map([], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+            })
+            .map_err(|e| e.to_string())?
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.to_string())?
+        };
+
+        for (legacy_id, peer_id, content, is_sent, timestamp) in rows {
+            self.insert(
+                &format!("legacy-{}", legacy_id),
+                &peer_id,
+                &content,
+                is_sent,
+                timestamp,
+            )?;
+        }
+        self.conn
+            .execute("DROP TABLE messages", [])
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    pub(super) fn conversation_key(&mut self, peer_id: &str) -> Result<[u8; 32], String> {
+        if let Some(key) = self.conversation_keys.get(peer_id) {
+            return Ok(*key);
+        }
+
+        let sealed: Option<Vec<u8>> = self
+            .conn
+            .query_row(
+                "SELECT sealed_key FROM conversations WHERE peer_id = ?1",
+                params![peer_id],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| e.to_string())?;
+
+        let key: [u8; 32] = match sealed {
+            Some(sealed) => {
+                let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE.min(sealed.len()));
+                decrypt(&self.master_key, peer_id.as_bytes(), nonce, ciphertext)?
+                    .try_into()
+                    .map_err(|_| "Corrupted conversation key".to_string())?
+            }
+            None => {
+                let mut key = [0u8; 32];
+                OsRng.fill_bytes(&mut key);
+                let (nonce, ciphertext) = encrypt(&self.master_key, peer_id.as_bytes(), &key)?;
+                let mut sealed = nonce;
+                sealed.extend_from_slice(&ciphertext);
+                self.conn
+                    .execute(
+                        "INSERT INTO conversations (peer_id, sealed_key, created_at) VALUES (?1, ?2, ?3)",
+                        params![peer_id, sealed, chrono::Utc::now().timestamp()],
+                    )
+                    .map_err(|e| e.to_string())?;
+                key
+            }
+        };
+
+        self.conversation_keys.insert(peer_id.to_string(), key);
+        Ok(key)
+    }
+
+    pub fn insert(
+        &mut self,
+        id: &str,
+        peer_id: &str,
+        content: &str,
+        is_sent: bool,
+        timestamp: i64,
+    ) -> Result<(), String> {
+        let key = self.conversation_key(peer_id)?;
+        let (nonce, ciphertext) = encrypt(&key, id.as_bytes(), content.as_bytes())?;
+        self.conn
+            .execute(
+                "INSERT OR IGNORE INTO message_history (id, peer_id, is_sent, timestamp, nonce, ciphertext)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+                params![id, peer_id, is_sent, timestamp, nonce, ciphertext],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Returns up to `limit` messages older than the `before` cursor, newest first.
+    pub fn history(
+        &mut self,
+        peer_id: &str,
+        before: Option<i64>,
+        limit: u32,
+    ) -> Result<Vec<StoredMessage>, String> {
+        let rows = {
+            let mut stmt = self
+                .conn
+                .prepare(
+                    "SELECT seq, id, peer_id, is_sent, timestamp, status, nonce, ciphertext, sender FROM message_history
+                     WHERE peer_id = ?1 AND seq < ?2 ORDER BY seq DESC LIMIT ?3",
+                )
+                .map_err(|e| e.to_string())?;
+            stmt.query_map(params![peer_id, before.unwrap_or(i64::MAX), limit], read_row)
+                .map_err(|e| e.to_string())?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| e.to_string())?
+        };
+        rows.into_iter().map(|row| self.decrypt_row(row)).collect()
+    }
+
+    /// Case-insensitive substring search. Bodies are encrypted, so matching
+    /// happens after decryption rather than in SQL.
+    pub fn search(
+        &mut self,
+        query: &str,
+        peer_id: Option<&str>,
+        limit: u32,
+    ) -> Result<Vec<StoredMessage>, String> {
+        let rows = {
+            let mut stmt = self
+                .conn
+                .prepare(
+                    "SELECT seq, id, peer_id, is_sent, timestamp, status, nonce, ciphertext, sender FROM message_history
+                     WHERE ?1 IS NULL OR peer_id = ?1 ORDER BY seq DESC",
+                )
+                .map_err(|e| e.to_string())?;
+            stmt.query_map(params![peer_id], read_row)
+                .map_err(|e| e.to_string())?
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(|e| e.to_string())?
+        };
+
+        let needle = query.to_lowercase();
+        let mut results = Vec::new();
+        for row in rows {
+            let message = self.decrypt_row(row)?;
+            if message.content.to_lowercase().contains(&needle) {
+                results.push(message);
+                if results.len() >= limit as usize {
+                    break;
+                }
+            }
+        }
+        Ok(results)
+    }
+
+    pub fn conversations(&self) -> Result<Vec<ConversationSummary>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(
+                "SELECT c.peer_id, COUNT(m.seq), MAX(m.timestamp) FROM conversations c
+                 LEFT JOIN message_history m ON m.peer_id = c.peer_id
+                 GROUP BY c.peer_id ORDER BY MAX(m.timestamp) DESC",
+            )
+            .map_err(|e| e.to_string())?;
+        let summaries = stmt
+            .query_map([], |row| {
+                Ok(ConversationSummary {
+                    peer_id: row.get(0)?,
+                    message_count: row.get(1)?,
+                    last_timestamp: row.get(2)?,
+                })
+            })
+            .map_err(|e| e.to_string())?
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.to_string())?;
+        Ok(summaries)
+    }
+
+    pub fn delete_message(&mut self, peer_id: &str, id: &str) -> Result<bool, String> {
+        let deleted = self
+            .conn
+            .execute(
+                "DELETE FROM message_history WHERE peer_id = ?1 AND id = ?2",
+                params![peer_id, id],
+            )
+            .map_err(|e| e.to_string())?;
+        self.conn
+            .execute(
+                "DELETE FROM outbox WHERE peer_id = ?1 AND id = ?2",
+                params![peer_id, id],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(deleted > 0)
+    }
+
+    /// Deletes a conversation and its key.
+    pub fn delete_conversation(&mut self, peer_id: &str) -> Result<usize, String> {
+        let tx = self.conn.transaction().map_err(|e| e.to_string())?;
+        let deleted = tx
+            .execute("DELETE FROM message_history WHERE peer_id = ?1", params![peer_id])
+            .map_err(|e| e.to_string())?;
+        tx.execute("DELETE FROM outbox WHERE peer_id = ?1", params![peer_id])
+            .map_err(|e| e.to_string())?;
+        tx.execute("DELETE FROM conversations WHERE peer_id = ?1", params![peer_id])
+            .map_err(|e| e.to_string())?;
+        tx.commit().map_err(|e| e.to_string())?;
+        self.conversation_keys.remove(peer_id);
+        Ok(deleted)
+    }
+
+    fn decrypt_row(&mut self, row: EncryptedRow) -> Result<StoredMessage, String> {
+        let key = self.conversation_key(&row.peer_id)?;
+        let plaintext = decrypt(&key, row.id.as_bytes(), &row.nonce, &row.ciphertext)?;
+        Ok(StoredMessage {
+            seq: row.seq,
+            content: String::from_utf8(plaintext).map_err(|e| e.to_string())?,
+            id: row.id,
+            peer_id: row.peer_id,
+            is_sent: row.is_sent,
+            timestamp: row.timestamp,
+            status: row.status,
+            sender: row.sender,
+        })
+    }
+}
+
+struct EncryptedRow {
+    seq: i64,
+    id: String,
+    peer_id: String,
+    is_sent: bool,
+    timestamp: i64,
+    status: MessageStatus,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+    sender: Option<String>,
+}
+
+fn read_row(row: &rusqlite::Row<'_>) -> rusqlite::Result<EncryptedRow> {
+    Ok(EncryptedRow {
+        seq: row.get(0)?,
+        id: row.get(1)?,
+        peer_id: row.get(2)?,
+        is_sent: row.get(3)?,
+        timestamp: row.get(4)?,
+        status: MessageStatus::parse(&row.get::<_, String>(5)?),
+        nonce: row.get(6)?,
+        ciphertext: row.get(7)?,
+        sender: row.get(8)?,
+    })
+}
+
+fn migrate(conn: &Connection) -> Result<(), String> {
+    let version: usize = conn
+        .query_row("PRAGMA user_version", [], |row| row.get(0))
+        .map_err(|e| e.to_string())?;
+
+    for (index, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        conn.execute_batch(&format!(
+            "BEGIN; {} PRAGMA user_version = {}; COMMIT;",
+            migration,
+            index + 1
+        ))
+        .map_err(|e| format!("Migration {} failed: {}", index + 1, e))?;
+    }
+    Ok(())
+}
+
+pub(super) fn encrypt(key: &[u8; 32], aad: &[u8], plaintext: &[u8]) -> Result<(Vec<u8>, Vec<u8>), String> {
+    let cipher = XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(key));
+    let mut nonce = [0u8; NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce);
+    let ciphertext = cipher
+        .encrypt(XNonce::from_slice(&nonce), Payload { msg: plaintext, aad })
+        .map_err(|e| e.to_string())?;
+    Ok((nonce.to_vec(), ciphertext))
+}
+
+pub(super) fn decrypt(key: &[u8; 32], aad: &[u8], nonce: &[u8], ciphertext: &[u8]) -> Result<Vec<u8>, String> {
+    if nonce.len() != NONCE_SIZE {
+        return Err("Invalid nonce".into());
+    }
+    let cipher = XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(key));
+    cipher
+        .decrypt(XNonce::from_slice(nonce), Payload { msg: ciphertext, aad })
+        .map_err(|_| "Failed to decrypt stored message".into())
+}
+
+/// Message history shared by the swarm task and the history commands. Opened
+/// by `start_node` once the passphrase is known.
+pub struct HistoryState {
+    pub store: Arc<Mutex<Option<MessageStore>>>,
+}
+
+impl HistoryState {
+    pub fn new() -> Self {
+        Self {
+            store: Arc::new(Mutex::new(None)),
+        }
+    }
+}
+
+pub fn db_path(app: &AppHandle) -> Result<std::path::PathBuf, String> {
+    Ok(app
+        .path()
+        .app_data_dir()
+        .map_err(|e| e.to_string())?
+        .join(DB_FILE))
+}
+
+pub(super) fn with_store<T>(
+    state: &State<'_, HistoryState>,
+    f: impl FnOnce(&mut MessageStore) -> Result<T, String>,
+) -> Result<T, String> {
+    let mut guard = state.store.lock().map_err(|e| e.to_string())?;
+    let store = guard.as_mut().ok_or("Message store is locked; start the node first")?;
+    f(store)
+}
+
+#[tauri::command]
+pub async fn get_message_history(
+    peer_id: String,
+    before: Option<i64>,
+    limit: Option<u32>,
+    state: State<'_, HistoryState>,
+) -> Result<Vec<StoredMessage>, String> {
+    with_store(&state, |store| store.history(&peer_id, before, limit.unwrap_or(50)))
+}
+
+#[tauri::command]
+pub async fn search_messages(
+    query: String,
+    peer_id: Option<String>,
+    limit: Option<u32>,
+    state: State<'_, HistoryState>,
+) -> Result<Vec<StoredMessage>, String> {
+    with_store(&state, |store| {
+        store.search(&query, peer_id.as_deref(), limit.unwrap_or(100))
+    })
+}
+
+#[tauri::command]
+pub async fn list_conversations(
+    state: State<'_, HistoryState>,
+) -> Result<Vec<ConversationSummary>, String> {
+    with_store(&state, |store| store.conversations())
+}
+
+#[tauri::command]
+pub async fn delete_message(
+    peer_id: String,
+    id: String,
+    state: State<'_, HistoryState>,
+) -> Result<bool, String> {
+    with_store(&state, |store| store.delete_message(&peer_id, &id))
+}
+
+#[tauri::command]
+pub async fn delete_conversation(
+    peer_id: String,
+    state: State<'_, HistoryState>,
+) -> Result<usize, String> {
+    with_store(&state, |store| store.delete_conversation(&peer_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn message_ids_are_scoped_to_their_conversation() {
+        let path = std::env::temp_dir()
+            .join(format!("void-db-{:016x}", rand::random::<u64>()))
+            .join(DB_FILE);
+        let mut store = MessageStore::open(&path, "test").unwrap();
+
+        store.insert("same-id", "alice", "from alice", false, 1).unwrap();
+        store.insert("same-id", "mallory", "from mallory", false, 2).unwrap();
+        // A duplicate within one conversation is still ignored
+        store.insert("same-id", "alice", "replayed", false, 3).unwrap();
+
+        let alice = store.history("alice", None, 10).unwrap();
+        assert_eq!(alice.len(), 1);
+        assert_eq!(alice[0].content, "from alice");
+        assert_eq!(store.history("mallory", None, 10).unwrap()[0].content, "from mallory");
+
+        assert!(store.delete_message("mallory", "same-id").unwrap());
+        assert_eq!(store.history("alice", None, 10).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn outbox_ids_are_scoped_to_their_conversation() {
+        let path = std::env::temp_dir()
+            .join(format!("void-db-{:016x}", rand::random::<u64>()))
+            .join(DB_FILE);
+        let mut store = MessageStore::open(&path, "test").unwrap();
+
+        store.queue_message("same-id", "alice", "to alice", "{}", 1).unwrap();
+        store.queue_message("same-id", "bob", "to bob", "{}", 2).unwrap();
+        assert_eq!(store.pending_messages(None).unwrap().len(), 2);
+
+        assert!(store.mark_delivered("alice", "same-id").unwrap());
+        let pending = store.pending_messages(None).unwrap();
+        assert_eq!(pending.len(), 1);
+        assert_eq!(pending[0].peer_id, "bob");
+        assert!(!store.requeue_message("alice", "same-id").unwrap());
+        assert!(store.requeue_message("bob", "same-id").unwrap());
+    }
+}
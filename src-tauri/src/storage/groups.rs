@@ -0,0 +1,207 @@
+// Group chats
+//
+// Membership and keys of the groups we belong to, next to the message history.
+// Group messages themselves go in `message_history` under the group id, with
+// the author in `sender`. Group keys are sealed with the master key; the key of
+// the previous epoch is kept so messages sent just before a re-key still open.
+use crate::storage::db::{self, HistoryState, MessageStore, with_store};
+use rusqlite::{OptionalExtension, params};
+use serde::Serialize;
+use tauri::State;
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Group {
+    pub id: String,
+    pub name: String,
+    /// PeerId of the member allowed to change membership.
+    pub owner: String,
+    pub members: Vec<String>,
+    /// Bumped, with a new key, on every membership change.
+    pub epoch: u64,
+    /// False once we left or were removed.
+    pub active: bool,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+const GROUP_COLUMNS: &str = "id, name, owner, members, epoch, active, created_at, updated_at";
+
+fn read_group(row: &rusqlite::Row<'_>) -> rusqlite::Result<Group> {
+    let members: String = row.get(3)?;
+    Ok(Group {
+        id: row.get(0)?,
+        name: row.get(1)?,
+        owner: row.get(2)?,
+        members: serde_json::from_str(&members).unwrap_or_default(),
+        epoch: row.get(4)?,
+        active: row.get(5)?,
+        created_at: row.get(6)?,
+        updated_at: row.get(7)?,
+    })
+}
+
+fn key_aad(group_id: &str, epoch: u64) -> Vec<u8> {
+    format!("group:{}:{}", group_id, epoch).into_bytes()
+}
+
+impl MessageStore {
+    pub fn groups(&self) -> Result<Vec<Group>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!(
+                "SELECT {} FROM groups ORDER BY updated_at DESC",
+                GROUP_COLUMNS
+            ))
+            .map_err(|e| e.to_string())?;
+        let groups = stmt
+            .query_map([], read_group)
+            .map_err(|e| e.to_string())?
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.to_string())?;
+        Ok(groups)
+    }
+
+    pub fn group(&self, id: &str) -> Result<Option<Group>, String> {
+        self.conn
+            .query_row(
+                &format!("SELECT {} FROM groups WHERE id = ?1", GROUP_COLUMNS),
+                params![id],
+                read_group,
+            )
+            .optional()
+            .map_err(|e| e.to_string())
+    }
+
+    /// Stores `group` with the key of its current epoch. If the epoch moved on,
+    /// the old key is kept as the previous one.
+    pub fn save_group(&mut self, group: &Group, key: &[u8; 32]) -> Result<(), String> {
+        let (nonce, ciphertext) = db::encrypt(&self.master_key, &key_aad(&group.id, group.epoch), key)?;
+        let mut sealed = nonce;
+        sealed.extend_from_slice(&ciphertext);
+        let members = serde_json::to_string(&group.members).map_err(|e| e.to_string())?;
+
+        self.conn
+            .execute(
+                "INSERT INTO groups (id, name, owner, members, epoch, sealed_key, active, created_at, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+                 ON CONFLICT(id) DO UPDATE SET
+                    name = excluded.name,
+                    owner = excluded.owner,
+                    members = excluded.members,
+                    sealed_previous_key = CASE WHEN groups.epoch = excluded.epoch - 1
+                        THEN groups.sealed_key ELSE NULL END,
+                    epoch = excluded.epoch,
+                    sealed_key = excluded.sealed_key,
+                    active = excluded.active,
+                    updated_at = excluded.updated_at",
+                params![
+                    group.id,
+                    group.name,
+                    group.owner,
+                    members,
+                    group.epoch,
+                    sealed,
+                    group.active,
+                    group.created_at,
+                    group.updated_at
+                ],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Passes a group to a new owner. Epoch and keys stay until the new owner
+    /// re-keys.
+    pub fn hand_over_group(&mut self, id: &str, owner: &str, members: &[String]) -> Result<(), String> {
+        let members = serde_json::to_string(members).map_err(|e| e.to_string())?;
+        self.conn
+            .execute(
+                "UPDATE groups SET owner = ?2, members = ?3, updated_at = ?4 WHERE id = ?1",
+                params![id, owner, members, chrono::Utc::now().timestamp()],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Key of `epoch`, if it is the group's current or previous one.
+    pub fn group_key(&self, id: &str, epoch: u64) -> Result<Option<[u8; 32]>, String> {
+        let row: Option<(u64, Vec<u8>, Option<Vec<u8>>)> = self
+            .conn
+            .query_row(
+                "SELECT epoch, sealed_key, sealed_previous_key FROM groups WHERE id = ?1",
+                params![id],
+                |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)),
+            )
+            .optional()
+            .map_err(|e| e.to_string())?;
+        let sealed = match row {
+            Some((current, sealed, _)) if current == epoch => sealed,
+            Some((current, _, Some(previous))) if current == epoch + 1 => previous,
+            _ => return Ok(None),
+        };
+
+        let (nonce, ciphertext) = sealed.split_at(24.min(sealed.len()));
+        let key = db::decrypt(&self.master_key, &key_aad(id, epoch), nonce, ciphertext)?;
+        key.try_into()
+            .map(Some)
+            .map_err(|_| "Corrupted group key".to_string())
+    }
+
+    /// Marks a group we left or were removed from. Its history stays.
+    pub fn deactivate_group(&mut self, id: &str) -> Result<bool, String> {
+        let updated = self
+            .conn
+            .execute(
+                "UPDATE groups SET active = 0, updated_at = ?2 WHERE id = ?1 AND active = 1",
+                params![id, chrono::Utc::now().timestamp()],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(updated > 0)
+    }
+
+    /// Deletes a group we no longer belong to, with its history and keys.
+    pub fn delete_group(&mut self, id: &str) -> Result<bool, String> {
+        let Some(group) = self.group(id)? else {
+            return Ok(false);
+        };
+        if group.active {
+            return Err("Leave the group before deleting it".into());
+        }
+        self.delete_conversation(id)?;
+        self.conn
+            .execute("DELETE FROM groups WHERE id = ?1", params![id])
+            .map_err(|e| e.to_string())?;
+        Ok(true)
+    }
+
+    /// Stores a group message. `sender` is `None` for our own messages.
+    pub fn insert_group_message(
+        &mut self,
+        id: &str,
+        group_id: &str,
+        sender: Option<&str>,
+        text: &str,
+        timestamp: i64,
+    ) -> Result<(), String> {
+        self.insert(id, group_id, text, sender.is_none(), timestamp)?;
+        self.conn
+            .execute(
+                "UPDATE message_history SET sender = ?3, status = ?4 WHERE peer_id = ?1 AND id = ?2",
+                params![group_id, id, sender, if sender.is_none() { "sent" } else { "delivered" }],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+}
+
+#[tauri::command]
+pub async fn list_groups(state: State<'_, HistoryState>) -> Result<Vec<Group>, String> {
+    with_store(&state, |store| store.groups())
+}
+
+/// Deletes a group after leaving it, along with its message history.
+#[tauri::command]
+pub async fn delete_group(group_id: String, state: State<'_, HistoryState>) -> Result<bool, String> {
+    with_store(&state, |store| store.delete_group(&group_id))
+}
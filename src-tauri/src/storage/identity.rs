@@ -0,0 +1,112 @@
+use crate::storage::vault;
+use libp2p::{PeerId, identity::Keypair};
+use std::fs;
+use std::path::{Path, PathBuf};
+use tauri::{AppHandle, Manager};
+
+const IDENTITY_FILE: &str = "identity.key";
+
+/// Passphrase for the relay identity when none is set. This only keeps the
+/// key from sitting on disk as raw bytes; it is not a secret, so nothing that
+/// holds user data may use it.
+pub const DEFAULT_PASSPHRASE: &str = "void-local-identity";
+
+/// The passphrase the user set. The node identity, messaging sessions and
+/// message history are sealed with it, so there is no fallback.
+pub fn require_passphrase(passphrase: Option<String>) -> Result<String, String> {
+    match passphrase {
+        Some(passphrase) if !passphrase.is_empty() => Ok(passphrase),
+        _ => Err("Set a passphrase; it encrypts your identity and message history".into()),
+    }
+}
+
+pub fn identity_path(app: &AppHandle) -> Result<PathBuf, String> {
+    Ok(app
+        .path()
+        .app_data_dir()
+        .map_err(|e| e.to_string())?
+        .join(IDENTITY_FILE))
+}
+
+/// Loads the node keypair from `path`, generating and persisting a fresh
+/// ed25519 key on first launch.
+pub fn load_or_create(path: &Path, passphrase: &str) -> Result<Keypair, String> {
+    if path.exists() {
+        return load(path, passphrase);
+    }
+
+    let keypair = Keypair::generate_ed25519();
+    save(path, &keypair, passphrase)?;
+    println!("Generated new node identity: {}", PeerId::from(keypair.public()));
+    Ok(keypair)
+}
+
+pub fn load(path: &Path, passphrase: &str) -> Result<Keypair, String> {
+    let sealed = fs::read(path).map_err(|e| e.to_string())?;
+    let encoded = vault::open(passphrase, &sealed)
+        .map_err(|_| "Failed to unlock identity: wrong passphrase or corrupted key file")?;
+    Keypair::from_protobuf_encoding(&encoded).map_err(|e| e.to_string())
+}
+
+/// Seals `keypair` and writes it to `path`. The new file is written next to
+/// the old one and renamed into place so a crash never leaves a half-written key.
+pub fn save(path: &Path, keypair: &Keypair, passphrase: &str) -> Result<(), String> {
+    let encoded = keypair.to_protobuf_encoding().map_err(|e| e.to_string())?;
+    let sealed = vault::seal(passphrase, &encoded)?;
+
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+    }
+
+    let tmp_path = path.with_extension("key.tmp");
+    fs::write(&tmp_path, &sealed).map_err(|e| e.to_string())?;
+    fs::rename(&tmp_path, path).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// Writes the node identity to `dest_path`, sealed with `export_pin` so it can
+/// be moved to another device.
+#[tauri::command]
+pub async fn export_identity(
+    app: AppHandle,
+    dest_path: String,
+    export_pin: String,
+    passphrase: Option<String>,
+) -> Result<String, String> {
+    let passphrase = require_passphrase(passphrase)?;
+    let keypair = load(&identity_path(&app)?, &passphrase)?;
+    save(Path::new(&dest_path), &keypair, &export_pin)?;
+    Ok(PeerId::from(keypair.public()).to_string())
+}
+
+/// Replaces the node identity with one previously written by `export_identity`.
+/// Takes effect the next time the node starts.
+#[tauri::command]
+pub async fn import_identity(
+    app: AppHandle,
+    src_path: String,
+    export_pin: String,
+    passphrase: Option<String>,
+) -> Result<String, String> {
+    let passphrase = require_passphrase(passphrase)?;
+    let keypair = load(Path::new(&src_path), &export_pin)?;
+    save(&identity_path(&app)?, &keypair, &passphrase)?;
+    Ok(PeerId::from(keypair.public()).to_string())
+}
+
+/// Generates a new node identity, discarding the old one. Every VOID code
+/// handed out under the previous PeerId stops working.
+#[tauri::command]
+pub async fn rotate_identity(app: AppHandle, passphrase: Option<String>) -> Result<String, String> {
+    let passphrase = require_passphrase(passphrase)?;
+    let path = identity_path(&app)?;
+
+    // Make sure the caller can actually unlock the current key before replacing it
+    if path.exists() {
+        load(&path, &passphrase)?;
+    }
+
+    let keypair = Keypair::generate_ed25519();
+    save(&path, &keypair, &passphrase)?;
+    Ok(PeerId::from(keypair.public()).to_string())
+}
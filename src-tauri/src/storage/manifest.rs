@@ -0,0 +1,545 @@
+// Vault manifest
+//
+// Index of what is in the vault. Payloads are stored as `<id>.void` under
+// random ids, so the directory listing says nothing about its contents and two
+// files with the same name can't collide. Names, sizes, types, tags and content
+// hashes live in the manifest, sealed with the vault master key, so entries
+// can be listed and searched without decrypting any payload. The hash also
+// catches a payload swapped for another one sealed with the same key.
+//
+// Each payload has its own random data key, kept in the manifest wrapped with
+// the vault key. Rotating the vault key only rewraps these.
+use crate::storage::vault;
+use crate::storage::vault_session::VaultState;
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tauri::{AppHandle, Manager, State};
+use zeroize::Zeroizing;
+
+const MANIFEST_FILE: &str = "manifest.vault";
+const MANIFEST_AAD: &[u8] = b"vault-manifest";
+const MANIFEST_VERSION: u32 = 1;
+const MAX_NAME_LEN: usize = 255;
+const MAX_TAGS: usize = 32;
+const MAX_TAG_LEN: usize = 64;
+
+/// Key a single payload is sealed with; zeroed when dropped.
+pub type DataKey = Zeroizing<[u8; 32]>;
+
+/// Serializes read-modify-write cycles on the manifest.
+static MANIFEST_LOCK: Mutex<()> = Mutex::new(());
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VaultEntry {
+    /// Random id; the payload is `<id>.void`.
+    pub id: String,
+    /// Original file name.
+    pub name: String,
+    /// Plaintext size. Unknown for files adopted from before the manifest.
+    pub size: Option<u64>,
+    pub mime: String,
+    /// Hex SHA-256 of the plaintext, checked on every decrypt.
+    pub sha256: Option<String>,
+    pub tags: Vec<String>,
+    pub added_at: i64,
+    /// Modification time of the original file.
+    pub modified_at: Option<i64>,
+    pub updated_at: i64,
+}
+
+impl VaultEntry {
+    fn matches(&self, query: &str) -> bool {
+        self.name.to_lowercase().contains(query)
+            || self.mime.to_lowercase().contains(query)
+            || self.tags.iter().any(|tag| tag.to_lowercase().contains(query))
+    }
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Manifest {
+    version: u32,
+    entries: Vec<VaultEntry>,
+    /// Wrapped data keys by entry id, base64.
+    #[serde(default)]
+    keys: HashMap<String, String>,
+    /// Set once payloads from before the manifest have been adopted.
+    #[serde(default)]
+    legacy_adopted: bool,
+}
+
+pub(crate) fn vault_dir(app: &AppHandle) -> Result<PathBuf, String> {
+    let dir = app
+        .path()
+        .app_data_dir()
+        .map_err(|e| e.to_string())?
+        .join("vault");
+    if !dir.exists() {
+        fs::create_dir_all(&dir).map_err(|e| e.to_string())?;
+    }
+    Ok(dir)
+}
+
+pub(crate) fn payload_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{}.void", id))
+}
+
+pub(crate) fn new_id() -> String {
+    format!("{:032x}", rand::random::<u128>())
+}
+
+/// Whether `name` has the shape of [`new_id`].
+fn is_id(name: &str) -> bool {
+    name.len() == 32 && name.bytes().all(|b| matches!(b, b'0'..=b'9' | b'a'..=b'f'))
+}
+
+pub(crate) fn new_data_key() -> DataKey {
+    Zeroizing::new(rand::random())
+}
+
+/// Binds a wrapped data key to its entry, so keys can't be swapped around.
+fn data_key_aad(id: &str) -> Vec<u8> {
+    format!("vault-file:{}", id).into_bytes()
+}
+
+/// Best guess from the extension; the vault never looks inside files.
+pub(crate) fn mime_type(name: &str) -> &'static str {
+    let extension = name.rsplit_once('.').map(|(_, e)| e.to_lowercase()).unwrap_or_default();
+    match extension.as_str() {
+        "txt" | "md" | "log" => "text/plain",
+        "csv" => "text/csv",
+        "html" | "htm" => "text/html",
+        "json" => "application/json",
+        "pdf" => "application/pdf",
+        "zip" => "application/zip",
+        "gz" | "tgz" => "application/gzip",
+        "7z" => "application/x-7z-compressed",
+        "doc" => "application/msword",
+        "docx" => "application/vnd.openxmlformats-officedocument.wordprocessingml.document",
+        "xlsx" => "application/vnd.openxmlformats-officedocument.spreadsheetml.sheet",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "svg" => "image/svg+xml",
+        "heic" => "image/heic",
+        "mp3" => "audio/mpeg",
+        "wav" => "audio/wav",
+        "ogg" => "audio/ogg",
+        "flac" => "audio/flac",
+        "mp4" | "m4v" => "video/mp4",
+        "mov" => "video/quicktime",
+        "mkv" => "video/x-matroska",
+        "webm" => "video/webm",
+        _ => "application/octet-stream",
+    }
+}
+
+fn clean_name(name: &str) -> Result<String, String> {
+    let name = name.trim();
+    if name.is_empty() || name.len() > MAX_NAME_LEN || name.contains(['/', '\\']) {
+        return Err(format!("Names must be 1-{} characters without slashes", MAX_NAME_LEN));
+    }
+    Ok(name.to_string())
+}
+
+fn clean_tags(tags: Vec<String>) -> Result<Vec<String>, String> {
+    let mut clean: Vec<String> = Vec::new();
+    for tag in tags {
+        let tag = tag.trim().to_string();
+        if tag.is_empty() || clean.iter().any(|t| t.eq_ignore_ascii_case(&tag)) {
+            continue;
+        }
+        if tag.len() > MAX_TAG_LEN {
+            return Err(format!("Tags are limited to {} characters", MAX_TAG_LEN));
+        }
+        clean.push(tag);
+    }
+    if clean.len() > MAX_TAGS {
+        return Err(format!("Entries are limited to {} tags", MAX_TAGS));
+    }
+    Ok(clean)
+}
+
+impl Manifest {
+    /// Opens the manifest, or starts an empty one.
+    fn load(dir: &Path, key: &[u8; 32]) -> Result<Self, String> {
+        let path = dir.join(MANIFEST_FILE);
+        if !path.exists() {
+            return Ok(Self::empty());
+        }
+        let sealed = fs::read(&path).map_err(|e| e.to_string())?;
+        let plaintext = vault::open_with_key(key, MANIFEST_AAD, &sealed).map_err(|_| "Corrupted vault manifest")?;
+        Self::decode(&plaintext)
+    }
+
+    fn empty() -> Self {
+        Manifest {
+            version: MANIFEST_VERSION,
+            entries: Vec::new(),
+            keys: HashMap::new(),
+            legacy_adopted: false,
+        }
+    }
+
+    fn decode(plaintext: &[u8]) -> Result<Self, String> {
+        serde_json::from_slice(plaintext).map_err(|e| format!("Corrupted vault manifest: {}", e))
+    }
+
+    pub(crate) fn save(&self, dir: &Path, key: &[u8; 32]) -> Result<(), String> {
+        let encoded = serde_json::to_vec(self).map_err(|e| e.to_string())?;
+        let sealed = vault::seal_with_key(key, MANIFEST_AAD, &encoded)?;
+        vault::write_atomically(&dir.join(MANIFEST_FILE), |writer| {
+            writer.write_all(&sealed).map_err(|e| e.to_string())
+        })
+    }
+
+    /// Adopts payloads written before the manifest existed (`<name>.void`)
+    /// under new ids. Anything named like an id is left alone: it belongs to
+    /// an entry, or to one being written. The entries are saved before any
+    /// payload is renamed; [`Manifest::finish_adoption`] completes the renames.
+    fn adopt_legacy(&mut self, dir: &Path, key: &[u8; 32]) -> Result<(), String> {
+        for entry in fs::read_dir(dir).map_err(|e| e.to_string())? {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            if path.extension().and_then(|s| s.to_str()) != Some("void") {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|s| s.to_str()).map(str::to_string) else {
+                continue;
+            };
+            if is_id(&name) {
+                continue;
+            }
+            let id = new_id();
+            let now = chrono::Utc::now().timestamp();
+            self.entries.push(VaultEntry {
+                id,
+                mime: mime_type(&name).to_string(),
+                name,
+                size: None,
+                sha256: None,
+                tags: Vec::new(),
+                added_at: now,
+                modified_at: None,
+                updated_at: now,
+            });
+        }
+        self.legacy_adopted = true;
+        self.save(dir, key)?;
+        self.finish_adoption(dir)
+    }
+
+    /// Moves adopted payloads still under their original name to their id,
+    /// finishing an adoption that was interrupted.
+    fn finish_adoption(&self, dir: &Path) -> Result<(), String> {
+        for entry in self.entries.iter().filter(|e| e.size.is_none()) {
+            let payload = payload_path(dir, &entry.id);
+            let legacy = dir.join(format!("{}.void", entry.name));
+            if !payload.exists() && legacy.exists() {
+                fs::rename(&legacy, &payload).map_err(|e| e.to_string())?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn entry(&self, id: &str) -> Result<&VaultEntry, String> {
+        self.entries.iter().find(|e| e.id == id).ok_or_else(|| "Unknown vault entry".to_string())
+    }
+
+    pub fn entry_mut(&mut self, id: &str) -> Result<&mut VaultEntry, String> {
+        self.entries
+            .iter_mut()
+            .find(|e| e.id == id)
+            .ok_or_else(|| "Unknown vault entry".to_string())
+    }
+
+    pub fn insert(&mut self, entry: VaultEntry) {
+        self.entries.push(entry);
+    }
+
+    pub fn remove(&mut self, id: &str) {
+        self.entries.retain(|e| e.id != id);
+        self.keys.remove(id);
+    }
+
+    /// Key the payload of `id` is sealed with. Payloads from before per-file
+    /// keys are sealed with the vault key itself.
+    pub fn data_key(&self, vault_key: &[u8; 32], id: &str) -> Result<DataKey, String> {
+        let Some(wrapped) = self.keys.get(id) else {
+            return Ok(Zeroizing::new(*vault_key));
+        };
+        let sealed = BASE64.decode(wrapped).map_err(|_| "Corrupted vault manifest")?;
+        let key = Zeroizing::new(
+            vault::open_with_key(vault_key, &data_key_aad(id), &sealed).map_err(|_| "Corrupted data key")?,
+        );
+        let mut data_key = Zeroizing::new([0u8; 32]);
+        if key.len() != data_key.len() {
+            return Err("Corrupted data key".into());
+        }
+        data_key.copy_from_slice(&key);
+        Ok(data_key)
+    }
+
+    pub fn set_data_key(&mut self, vault_key: &[u8; 32], id: &str, data_key: &[u8; 32]) -> Result<(), String> {
+        let sealed = vault::seal_with_key(vault_key, &data_key_aad(id), data_key)?;
+        self.keys.insert(id.to_string(), BASE64.encode(sealed));
+        Ok(())
+    }
+
+    /// Rewraps every data key from `old` to `new`. Payloads sealed with `old`
+    /// directly keep it, now as their data key.
+    fn rewrap(&mut self, old: &[u8; 32], new: &[u8; 32]) -> Result<(), String> {
+        let ids: Vec<String> = self.entries.iter().map(|e| e.id.clone()).collect();
+        let mut keys = HashMap::with_capacity(ids.len());
+        for id in ids {
+            let data_key = self.data_key(old, &id)?;
+            let sealed = vault::seal_with_key(new, &data_key_aad(&id), &data_key)?;
+            keys.insert(id, BASE64.encode(sealed));
+        }
+        self.keys = keys;
+        Ok(())
+    }
+
+    /// Newest first.
+    pub fn entries(&self) -> Vec<VaultEntry> {
+        let mut entries = self.entries.clone();
+        entries.sort_by(|a, b| b.added_at.cmp(&a.added_at));
+        entries
+    }
+}
+
+/// Runs `f` against the manifest.
+pub(crate) fn read<T>(
+    dir: &Path,
+    key: &[u8; 32],
+    f: impl FnOnce(&Manifest) -> Result<T, String>,
+) -> Result<T, String> {
+    let _guard = MANIFEST_LOCK.lock().map_err(|e| e.to_string())?;
+    f(&Manifest::load(dir, key)?)
+}
+
+/// Runs `f` against the manifest and saves it if `f` succeeded.
+pub(crate) fn update<T>(
+    dir: &Path,
+    key: &[u8; 32],
+    f: impl FnOnce(&mut Manifest) -> Result<T, String>,
+) -> Result<T, String> {
+    let _guard = MANIFEST_LOCK.lock().map_err(|e| e.to_string())?;
+    let mut manifest = Manifest::load(dir, key)?;
+    let result = f(&mut manifest)?;
+    manifest.save(dir, key)?;
+    Ok(result)
+}
+
+/// Checks `pin` against a manifest from before the vault had a master key,
+/// so a mistyped first PIN doesn't become the vault PIN. Returns whether
+/// there was one.
+pub(crate) fn check_legacy_pin(dir: &Path, pin: &str) -> Result<bool, String> {
+    let path = dir.join(MANIFEST_FILE);
+    if !path.exists() {
+        return Ok(false);
+    }
+    let sealed = fs::read(&path).map_err(|e| e.to_string())?;
+    vault::open(pin, &sealed).map_err(|_| "Incorrect PIN".to_string())?;
+    Ok(true)
+}
+
+/// Moves what was sealed with `pin` before the vault had a master key under
+/// `key`: the manifest, with payloads from before it adopted, then every
+/// payload `pin` opens. The manifest is saved before payloads are renamed or
+/// replaced, so an interrupted run resumes on the next unlock.
+/// Returns how many payloads are still PIN-sealed.
+pub(crate) fn migrate_legacy(dir: &Path, pin: &str, key: &[u8; 32]) -> Result<usize, String> {
+    let _guard = MANIFEST_LOCK.lock().map_err(|e| e.to_string())?;
+    let path = dir.join(MANIFEST_FILE);
+    let mut manifest = if path.exists() {
+        let sealed = fs::read(&path).map_err(|e| e.to_string())?;
+        match vault::open_with_key(key, MANIFEST_AAD, &sealed) {
+            Ok(plaintext) => Manifest::decode(&plaintext)?,
+            Err(_) => {
+                let plaintext = vault::open(pin, &sealed).map_err(|_| "Incorrect PIN or corrupted vault manifest")?;
+                Manifest::decode(&plaintext)?
+            }
+        }
+    } else {
+        Manifest::empty()
+    };
+    if !manifest.legacy_adopted {
+        manifest.adopt_legacy(dir, key)?;
+    }
+    manifest.finish_adoption(dir)?;
+    manifest.save(dir, key)?;
+
+    let mut remaining = 0;
+    for entry in manifest.entries() {
+        let payload = payload_path(dir, &entry.id);
+        if !payload.exists() || !vault::is_pin_sealed(&payload)? {
+            continue;
+        }
+        if let Err(e) = vault::upgrade_entry(dir, &mut manifest, key, &entry.id, Some(pin)) {
+            eprintln!("Vault file {} left PIN-sealed: {}", entry.id, e);
+            remaining += 1;
+        }
+    }
+    manifest.save(dir, key)?;
+    Ok(remaining)
+}
+
+/// Whether the manifest is sealed with `key`. False if there is none yet.
+pub(crate) fn opens_with(dir: &Path, key: &[u8; 32]) -> Result<bool, String> {
+    let _guard = MANIFEST_LOCK.lock().map_err(|e| e.to_string())?;
+    let path = dir.join(MANIFEST_FILE);
+    if !path.exists() {
+        return Ok(false);
+    }
+    let sealed = fs::read(&path).map_err(|e| e.to_string())?;
+    Ok(vault::open_with_key(key, MANIFEST_AAD, &sealed).is_ok())
+}
+
+/// Moves the manifest from vault key `old` to `new` with a single atomic
+/// write, then runs `commit` before anyone else can touch it.
+pub(crate) fn rotate(
+    dir: &Path,
+    old: &[u8; 32],
+    new: &[u8; 32],
+    commit: impl FnOnce() -> Result<(), String>,
+) -> Result<(), String> {
+    let _guard = MANIFEST_LOCK.lock().map_err(|e| e.to_string())?;
+    let mut manifest = Manifest::load(dir, old)?;
+    manifest.rewrap(old, new)?;
+    manifest.save(dir, new)?;
+    commit()
+}
+
+/// Modification time of a file as a unix timestamp, if the platform has one.
+pub(crate) fn modified_at(file: &File) -> Option<i64> {
+    let modified = file.metadata().ok()?.modified().ok()?;
+    let secs = modified.duration_since(std::time::UNIX_EPOCH).ok()?.as_secs();
+    i64::try_from(secs).ok()
+}
+
+pub(crate) fn new_entry(id: String, name: &str, size: u64, sha256: String, modified_at: Option<i64>) -> VaultEntry {
+    let now = chrono::Utc::now().timestamp();
+    VaultEntry {
+        id,
+        name: name.to_string(),
+        size: Some(size),
+        mime: mime_type(name).to_string(),
+        sha256: Some(sha256),
+        tags: Vec::new(),
+        added_at: now,
+        modified_at,
+        updated_at: now,
+    }
+}
+
+/// Vault entries, newest first.
+#[tauri::command]
+pub async fn list_vault_files(app: AppHandle, state: State<'_, VaultState>) -> Result<Vec<VaultEntry>, String> {
+    let key = state.key()?;
+    let dir = vault_dir(&app)?;
+    tauri::async_runtime::spawn_blocking(move || read(&dir, &key, |manifest| Ok(manifest.entries())))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+#[tauri::command]
+pub async fn rename_vault_file(
+    app: AppHandle,
+    state: State<'_, VaultState>,
+    id: String,
+    name: String,
+) -> Result<VaultEntry, String> {
+    let key = state.key()?;
+    let dir = vault_dir(&app)?;
+    let name = clean_name(&name)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        update(&dir, &key, |manifest| {
+            let entry = manifest.entry_mut(&id)?;
+            entry.mime = mime_type(&name).to_string();
+            entry.name = name;
+            entry.updated_at = chrono::Utc::now().timestamp();
+            Ok(entry.clone())
+        })
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// Replaces the tags of an entry.
+#[tauri::command]
+pub async fn tag_vault_file(
+    app: AppHandle,
+    state: State<'_, VaultState>,
+    id: String,
+    tags: Vec<String>,
+) -> Result<VaultEntry, String> {
+    let key = state.key()?;
+    let dir = vault_dir(&app)?;
+    let tags = clean_tags(tags)?;
+    tauri::async_runtime::spawn_blocking(move || {
+        update(&dir, &key, |manifest| {
+            let entry = manifest.entry_mut(&id)?;
+            entry.tags = tags;
+            entry.updated_at = chrono::Utc::now().timestamp();
+            Ok(entry.clone())
+        })
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// Entries whose name, type or tags contain `query`, ignoring case.
+#[tauri::command]
+pub async fn search_vault(
+    app: AppHandle,
+    state: State<'_, VaultState>,
+    query: String,
+) -> Result<Vec<VaultEntry>, String> {
+    let key = state.key()?;
+    let dir = vault_dir(&app)?;
+    let query = query.trim().to_lowercase();
+    tauri::async_runtime::spawn_blocking(move || {
+        read(&dir, &key, |manifest| {
+            Ok(manifest.entries().into_iter().filter(|e| e.matches(&query)).collect())
+        })
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interrupted_adoption_is_finished() {
+        let dir = std::env::temp_dir().join(format!("void-manifest-{:016x}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let key = [5; 32];
+        fs::write(dir.join("notes.txt.void"), b"sealed").unwrap();
+        let entry_id = new_id();
+        fs::write(payload_path(&dir, &entry_id), b"sealed").unwrap();
+
+        let mut manifest = Manifest::empty();
+        manifest.adopt_legacy(&dir, &key).unwrap();
+        assert_eq!(manifest.entries.len(), 1);
+        let adopted = manifest.entries[0].clone();
+        assert_eq!(adopted.name, "notes.txt");
+        assert!(payload_path(&dir, &adopted.id).exists());
+
+        // As if the run stopped after saving the manifest but before renaming
+        fs::rename(payload_path(&dir, &adopted.id), dir.join("notes.txt.void")).unwrap();
+        let manifest = Manifest::load(&dir, &key).unwrap();
+        assert!(manifest.legacy_adopted);
+        manifest.finish_adoption(&dir).unwrap();
+        assert!(payload_path(&dir, &adopted.id).exists());
+        assert!(!dir.join("notes.txt.void").exists());
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
@@ -1 +1,10 @@
+pub mod contacts;
+pub mod db;
+pub mod groups;
+pub mod identity;
+pub mod manifest;
+pub mod outbox;
+pub mod transfers;
 pub mod vault;
+pub mod vault_header;
+pub mod vault_session;
@@ -0,0 +1,318 @@
+// Outbox
+//
+// Outgoing messages stay here until the peer acknowledges them, so they survive
+// restarts and go out again once the peer is reachable. Bodies are sealed with
+// the conversation key, like the history rows. The delivery state shown to the
+// user lives in `message_history.status`; this table only tracks retries.
+use crate::storage::db::{self, MessageStore};
+use rusqlite::{OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+
+/// Messages still undelivered after this long are marked failed.
+pub const OUTBOX_TTL_SECS: i64 = 7 * 24 * 60 * 60;
+const BASE_RETRY_SECS: i64 = 5;
+const MAX_RETRY_SECS: i64 = 60 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum MessageStatus {
+    /// Waiting in the outbox for the peer to become reachable.
+    Queued,
+    /// Handed to a live connection, no receipt yet.
+    Sent,
+    Delivered,
+    Read,
+    /// Gave up after `OUTBOX_TTL_SECS`; can be retried by hand.
+    Failed,
+}
+
+impl MessageStatus {
+    fn as_str(&self) -> &'static str {
+        match self {
+            MessageStatus::Queued => "queued",
+            MessageStatus::Sent => "sent",
+            MessageStatus::Delivered => "delivered",
+            MessageStatus::Read => "read",
+            MessageStatus::Failed => "failed",
+        }
+    }
+
+    pub(super) fn parse(value: &str) -> Self {
+        match value {
+            "queued" => MessageStatus::Queued,
+            "sent" => MessageStatus::Sent,
+            "read" => MessageStatus::Read,
+            "failed" => MessageStatus::Failed,
+            _ => MessageStatus::Delivered,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OutboxEntry {
+    pub id: String,
+    pub peer_id: String,
+    pub sent_at: i64,
+    /// JSON-encoded message content.
+    pub content: String,
+}
+
+/// Delay before the next attempt after `attempts` failed ones.
+fn backoff_secs(attempts: u32) -> i64 {
+    BASE_RETRY_SECS
+        .saturating_mul(1 << attempts.min(16))
+        .min(MAX_RETRY_SECS)
+}
+
+fn outbox_aad(id: &str) -> Vec<u8> {
+    format!("outbox:{}", id).into_bytes()
+}
+
+impl MessageStore {
+    /// Records an outgoing message in the history and queues it for sending.
+    pub fn queue_message(
+        &mut self,
+        id: &str,
+        peer_id: &str,
+        text: &str,
+        content: &str,
+        sent_at: i64,
+    ) -> Result<(), String> {
+        self.insert(id, peer_id, text, true, sent_at)?;
+        self.conn
+            .execute(
+                "UPDATE message_history SET status = ?3 WHERE id = ?1 AND peer_id = ?2",
+                params![id, peer_id, MessageStatus::Queued.as_str()],
+            )
+            .map_err(|e| e.to_string())?;
+
+        let key = self.conversation_key(peer_id)?;
+        let (nonce, ciphertext) = db::encrypt(&key, &outbox_aad(id), content.as_bytes())?;
+        let now = chrono::Utc::now().timestamp();
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO outbox (id, peer_id, sent_at, queued_at, nonce, ciphertext, next_attempt_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?4)",
+                params![id, peer_id, sent_at, now, nonce, ciphertext],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Queues protocol traffic that has no history row of its own, such as a
+    /// group update or one member's copy of a group message.
+    pub fn queue_control(
+        &mut self,
+        id: &str,
+        peer_id: &str,
+        content: &str,
+        sent_at: i64,
+    ) -> Result<(), String> {
+        let key = self.conversation_key(peer_id)?;
+        let (nonce, ciphertext) = db::encrypt(&key, &outbox_aad(id), content.as_bytes())?;
+        let now = chrono::Utc::now().timestamp();
+        self.conn
+            .execute(
+                "INSERT OR REPLACE INTO outbox (id, peer_id, sent_at, queued_at, nonce, ciphertext, next_attempt_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?4)",
+                params![id, peer_id, sent_at, now, nonce, ciphertext],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Undelivered messages, oldest first. With `peer_id` set, returns all of
+    /// that peer's messages regardless of backoff; otherwise only those due now.
+    pub fn pending_messages(&mut self, peer_id: Option<&str>) -> Result<Vec<OutboxEntry>, String> {
+        let rows: Vec<(String, String, i64, Vec<u8>, Vec<u8>)> = {
+            let mut stmt = self
+                .conn
+                .prepare(
+                    "SELECT o.id, o.peer_id, o.sent_at, o.nonce, o.ciphertext FROM outbox o
+                     LEFT JOIN message_history m ON m.id = o.id AND m.peer_id = o.peer_id
+                     WHERE COALESCE(m.status, 'queued') IN ('queued', 'sent')
+                       AND (o.peer_id = ?1 OR (?1 IS NULL AND o.next_attempt_at <= ?2))
+                     ORDER BY o.sent_at",
+                )
+                .map_err(|e| e.to_string())?;
+            stmt.query_map(params![peer_id, chrono::Utc::now().timestamp()], |row| {
+                Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?, row.get(4)?))
+            })
+            .map_err(|e| e.to_string())?
+            .collect::<Result<_, _>>()
+            .map_err(|e| e.to_string())?
+        };
+
+        let mut entries = Vec::new();
+        for (id, peer_id, sent_at, nonce, ciphertext) in rows {
+            let key = self.conversation_key(&peer_id)?;
+            let content = db::decrypt(&key, &outbox_aad(&id), &nonce, &ciphertext)?;
+            entries.push(OutboxEntry {
+                content: String::from_utf8(content).map_err(|e| e.to_string())?,
+                id,
+                peer_id,
+                sent_at,
+            });
+        }
+        Ok(entries)
+    }
+
+    /// Marks a queued message as handed to a live connection.
+    pub fn mark_sent(&mut self, peer_id: &str, id: &str) -> Result<bool, String> {
+        let updated = self
+            .conn
+            .execute(
+                "UPDATE message_history SET status = 'sent'
+                 WHERE id = ?1 AND peer_id = ?2 AND is_sent = 1 AND status = 'queued'",
+                params![id, peer_id],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(updated > 0)
+    }
+
+    /// Records a failed attempt and schedules the next one. Returns the new
+    /// status (`Failed` once the message outlived the TTL) and the retry time,
+    /// or `None` if the message isn't in the outbox.
+    pub fn schedule_retry(
+        &mut self,
+        peer_id: &str,
+        id: &str,
+        error: &str,
+    ) -> Result<Option<(MessageStatus, Option<i64>)>, String> {
+        let row: Option<(u32, i64)> = self
+            .conn
+            .query_row(
+                "SELECT attempts, queued_at FROM outbox WHERE id = ?1 AND peer_id = ?2",
+                params![id, peer_id],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .optional()
+            .map_err(|e| e.to_string())?;
+        let Some((attempts, queued_at)) = row else {
+            return Ok(None);
+        };
+
+        let now = chrono::Utc::now().timestamp();
+        let next_attempt_at = now + backoff_secs(attempts);
+        self.conn
+            .execute(
+                "UPDATE outbox SET attempts = attempts + 1, next_attempt_at = ?3, last_error = ?4
+                 WHERE id = ?1 AND peer_id = ?2",
+                params![id, peer_id, next_attempt_at, error],
+            )
+            .map_err(|e| e.to_string())?;
+
+        if now - queued_at > OUTBOX_TTL_SECS {
+            // Control messages have nothing to show as failed; just drop them
+            self.conn
+                .execute(
+                    "DELETE FROM outbox WHERE id = ?1 AND peer_id = ?2
+                     AND NOT EXISTS (SELECT 1 FROM message_history WHERE id = ?1 AND peer_id = ?2)",
+                    params![id, peer_id],
+                )
+                .map_err(|e| e.to_string())?;
+            self.conn
+                .execute(
+                    "UPDATE message_history SET status = 'failed' WHERE id = ?1 AND peer_id = ?2 AND is_sent = 1",
+                    params![id, peer_id],
+                )
+                .map_err(|e| e.to_string())?;
+            return Ok(Some((MessageStatus::Failed, None)));
+        }
+        // Not known to have arrived, so it's back to waiting
+        self.conn
+            .execute(
+                "UPDATE message_history SET status = 'queued'
+                 WHERE id = ?1 AND peer_id = ?2 AND is_sent = 1 AND status = 'sent'",
+                params![id, peer_id],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(Some((MessageStatus::Queued, Some(next_attempt_at))))
+    }
+
+    /// Marks a message left in a peer's mailbox as sent and stops retrying it.
+    pub fn mark_stored(&mut self, peer_id: &str, id: &str) -> Result<bool, String> {
+        self.conn
+            .execute("DELETE FROM outbox WHERE id = ?1 AND peer_id = ?2", params![id, peer_id])
+            .map_err(|e| e.to_string())?;
+        let updated = self
+            .conn
+            .execute(
+                "UPDATE message_history SET status = 'sent'
+                 WHERE id = ?1 AND peer_id = ?2 AND is_sent = 1 AND status IN ('queued', 'failed')",
+                params![id, peer_id],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(updated > 0)
+    }
+
+    /// Marks an outgoing message to `peer_id` delivered and drops it from the
+    /// outbox. Only the peer it was addressed to can confirm it.
+    pub fn mark_delivered(&mut self, peer_id: &str, id: &str) -> Result<bool, String> {
+        self.conn
+            .execute("DELETE FROM outbox WHERE id = ?1 AND peer_id = ?2", params![id, peer_id])
+            .map_err(|e| e.to_string())?;
+        let updated = self
+            .conn
+            .execute(
+                "UPDATE message_history SET status = 'delivered'
+                 WHERE id = ?1 AND peer_id = ?2 AND is_sent = 1 AND status IN ('queued', 'sent', 'failed')",
+                params![id, peer_id],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(updated > 0)
+    }
+
+    /// Marks messages exchanged with `peer_id` as read, either ours (from a
+    /// read receipt) or theirs (read locally). Returns the ids that changed.
+    pub fn mark_read(
+        &mut self,
+        peer_id: &str,
+        ids: &[String],
+        is_sent: bool,
+    ) -> Result<Vec<String>, String> {
+        let mut changed = Vec::new();
+        for id in ids {
+            let updated = self
+                .conn
+                .execute(
+                    "UPDATE message_history SET status = 'read'
+                     WHERE id = ?1 AND peer_id = ?2 AND is_sent = ?3 AND status != 'read'",
+                    params![id, peer_id, is_sent],
+                )
+                .map_err(|e| e.to_string())?;
+            if updated > 0 {
+                self.conn
+                    .execute("DELETE FROM outbox WHERE id = ?1 AND peer_id = ?2", params![id, peer_id])
+                    .map_err(|e| e.to_string())?;
+                changed.push(id.clone());
+            }
+        }
+        Ok(changed)
+    }
+
+    /// Puts a failed message to `peer_id` back in the queue with a fresh TTL.
+    /// Returns false if it isn't in the outbox.
+    pub fn requeue_message(&mut self, peer_id: &str, id: &str) -> Result<bool, String> {
+        let now = chrono::Utc::now().timestamp();
+        let updated = self
+            .conn
+            .execute(
+                "UPDATE outbox SET attempts = 0, queued_at = ?3, next_attempt_at = ?3, last_error = NULL
+                 WHERE id = ?1 AND peer_id = ?2",
+                params![id, peer_id, now],
+            )
+            .map_err(|e| e.to_string())?;
+        if updated == 0 {
+            return Ok(false);
+        }
+        self.conn
+            .execute(
+                "UPDATE message_history SET status = 'queued'
+                 WHERE id = ?1 AND peer_id = ?2 AND is_sent = 1 AND status = 'failed'",
+                params![id, peer_id],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(true)
+    }
+}
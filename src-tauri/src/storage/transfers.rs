@@ -0,0 +1,408 @@
+// File transfers
+//
+// Bookkeeping for files sent to and received from peers, so interrupted
+// transfers pick up where they stopped after a reconnect or restart. The
+// per-transfer key, and the name, path and hash of the file, are sealed with
+// the master key. For incoming files `path` is the destination, written as
+// `<path>.part` until the hash checks out, `transferred` counts the bytes
+// written without gaps, and the SHA-256 state of those bytes is kept so a
+// resumed download doesn't have to hash them again.
+use crate::storage::db::{self, HistoryState, MessageStore, with_store};
+use rusqlite::{OptionalExtension, params};
+use serde::{Deserialize, Serialize};
+use tauri::State;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum TransferState {
+    /// Waiting for the receiver to accept or reject.
+    Offered,
+    Transferring,
+    /// Accepted, but the peer went away mid-transfer.
+    Paused,
+    Complete,
+    Rejected,
+    Cancelled,
+    /// The received file didn't match the offered hash, or couldn't be read.
+    Failed,
+}
+
+impl TransferState {
+    fn as_str(&self) -> &'static str {
+        match self {
+            TransferState::Offered => "offered",
+            TransferState::Transferring => "transferring",
+            TransferState::Paused => "paused",
+            TransferState::Complete => "complete",
+            TransferState::Rejected => "rejected",
+            TransferState::Cancelled => "cancelled",
+            TransferState::Failed => "failed",
+        }
+    }
+
+    fn parse(value: &str) -> Self {
+        match value {
+            "offered" => TransferState::Offered,
+            "transferring" => TransferState::Transferring,
+            "paused" => TransferState::Paused,
+            "complete" => TransferState::Complete,
+            "rejected" => TransferState::Rejected,
+            "cancelled" => TransferState::Cancelled,
+            _ => TransferState::Failed,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        matches!(
+            self,
+            TransferState::Complete
+                | TransferState::Rejected
+                | TransferState::Cancelled
+                | TransferState::Failed
+        )
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct FileTransfer {
+    pub id: String,
+    pub peer_id: String,
+    pub outgoing: bool,
+    pub name: String,
+    pub path: Option<String>,
+    pub size: u64,
+    /// Hex SHA-256 of the whole file.
+    pub sha256: String,
+    pub chunk_size: u64,
+    pub state: TransferState,
+    pub transferred: u64,
+    pub created_at: i64,
+    pub updated_at: i64,
+}
+
+impl FileTransfer {
+    pub fn chunk_count(&self) -> u64 {
+        self.size.div_ceil(self.chunk_size.max(1))
+    }
+}
+
+/// The parts of a transfer that say what is being sent.
+#[derive(Serialize, Deserialize)]
+struct TransferDetails {
+    name: String,
+    path: Option<String>,
+    sha256: String,
+}
+
+const TRANSFER_COLUMNS: &str =
+    "id, peer_id, outgoing, size, chunk_size, state, transferred, created_at, updated_at, nonce, ciphertext";
+
+struct SealedTransfer {
+    id: String,
+    peer_id: String,
+    outgoing: bool,
+    size: u64,
+    chunk_size: u64,
+    state: TransferState,
+    transferred: u64,
+    created_at: i64,
+    updated_at: i64,
+    nonce: Vec<u8>,
+    ciphertext: Vec<u8>,
+}
+
+fn read_transfer(row: &rusqlite::Row<'_>) -> rusqlite::Result<SealedTransfer> {
+    Ok(SealedTransfer {
+        id: row.get(0)?,
+        peer_id: row.get(1)?,
+        outgoing: row.get(2)?,
+        size: row.get(3)?,
+        chunk_size: row.get(4)?,
+        state: TransferState::parse(&row.get::<_, String>(5)?),
+        transferred: row.get(6)?,
+        created_at: row.get(7)?,
+        updated_at: row.get(8)?,
+        nonce: row.get(9)?,
+        ciphertext: row.get(10)?,
+    })
+}
+
+fn key_aad(id: &str) -> Vec<u8> {
+    format!("transfer:{}", id).into_bytes()
+}
+
+fn details_aad(id: &str) -> Vec<u8> {
+    format!("transfer-details:{}", id).into_bytes()
+}
+
+fn hash_aad(id: &str) -> Vec<u8> {
+    format!("transfer-hash:{}", id).into_bytes()
+}
+
+impl MessageStore {
+    pub fn transfers(&self) -> Result<Vec<FileTransfer>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!(
+                "SELECT {} FROM file_transfers ORDER BY created_at DESC",
+                TRANSFER_COLUMNS
+            ))
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map([], read_transfer)
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        rows.into_iter().map(|row| self.open_transfer(row)).collect()
+    }
+
+    pub fn transfer(&self, id: &str) -> Result<Option<FileTransfer>, String> {
+        let row = self
+            .conn
+            .query_row(
+                &format!("SELECT {} FROM file_transfers WHERE id = ?1", TRANSFER_COLUMNS),
+                params![id],
+                read_transfer,
+            )
+            .optional()
+            .map_err(|e| e.to_string())?;
+        row.map(|row| self.open_transfer(row)).transpose()
+    }
+
+    /// Incoming transfers from `peer_id` that were accepted but not finished.
+    pub fn resumable_transfers(&self, peer_id: &str) -> Result<Vec<FileTransfer>, String> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!(
+                "SELECT {} FROM file_transfers
+                 WHERE peer_id = ?1 AND outgoing = 0 AND state IN ('transferring', 'paused')",
+                TRANSFER_COLUMNS
+            ))
+            .map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map(params![peer_id], read_transfer)
+            .map_err(|e| e.to_string())?
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e| e.to_string())?;
+        rows.into_iter().map(|row| self.open_transfer(row)).collect()
+    }
+
+    /// Records a new transfer. Ignores one we already know, so a re-delivered
+    /// offer doesn't reset its progress.
+    pub fn insert_transfer(&mut self, transfer: &FileTransfer, key: &[u8; 32]) -> Result<bool, String> {
+        let (nonce, ciphertext) = db::encrypt(&self.master_key, &key_aad(&transfer.id), key)?;
+        let mut sealed = nonce;
+        sealed.extend_from_slice(&ciphertext);
+        let (nonce, ciphertext) = self.seal_details(&transfer.id, &TransferDetails {
+            name: transfer.name.clone(),
+            path: transfer.path.clone(),
+            sha256: transfer.sha256.clone(),
+        })?;
+
+        let inserted = self
+            .conn
+            .execute(
+                "INSERT OR IGNORE INTO file_transfers
+                    (id, peer_id, outgoing, size, chunk_size, sealed_key, nonce, ciphertext, state, transferred, created_at, updated_at)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                params![
+                    transfer.id,
+                    transfer.peer_id,
+                    transfer.outgoing,
+                    transfer.size,
+                    transfer.chunk_size,
+                    sealed,
+                    nonce,
+                    ciphertext,
+                    transfer.state.as_str(),
+                    transfer.transferred,
+                    transfer.created_at,
+                    transfer.updated_at
+                ],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(inserted > 0)
+    }
+
+    pub fn transfer_key(&self, id: &str) -> Result<Option<[u8; 32]>, String> {
+        let sealed: Option<Vec<u8>> = self
+            .conn
+            .query_row(
+                "SELECT sealed_key FROM file_transfers WHERE id = ?1",
+                params![id],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| e.to_string())?;
+        let Some(sealed) = sealed else {
+            return Ok(None);
+        };
+
+        let (nonce, ciphertext) = sealed.split_at(24.min(sealed.len()));
+        let key = db::decrypt(&self.master_key, &key_aad(id), nonce, ciphertext)?;
+        key.try_into()
+            .map(Some)
+            .map_err(|_| "Corrupted transfer key".to_string())
+    }
+
+    pub fn set_transfer_state(&mut self, id: &str, state: TransferState) -> Result<(), String> {
+        self.conn
+            .execute(
+                "UPDATE file_transfers SET state = ?2, updated_at = ?3 WHERE id = ?1",
+                params![id, state.as_str(), chrono::Utc::now().timestamp()],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    pub fn set_transfer_progress(&mut self, id: &str, transferred: u64) -> Result<(), String> {
+        self.conn
+            .execute(
+                "UPDATE file_transfers SET transferred = ?2, updated_at = ?3 WHERE id = ?1",
+                params![id, transferred, chrono::Utc::now().timestamp()],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Records how far a download got, with the hash state of those bytes.
+    pub fn save_download_progress(&mut self, id: &str, transferred: u64, hash_state: &[u8]) -> Result<(), String> {
+        let (nonce, ciphertext) = db::encrypt(&self.master_key, &hash_aad(id), hash_state)?;
+        let mut sealed = nonce;
+        sealed.extend_from_slice(&ciphertext);
+        self.conn
+            .execute(
+                "UPDATE file_transfers SET transferred = ?2, sealed_hash = ?3, updated_at = ?4 WHERE id = ?1",
+                params![id, transferred, sealed, chrono::Utc::now().timestamp()],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Hash state saved by the last `save_download_progress`.
+    pub fn download_hash(&self, id: &str) -> Result<Option<Vec<u8>>, String> {
+        let sealed: Option<Vec<u8>> = self
+            .conn
+            .query_row(
+                "SELECT sealed_hash FROM file_transfers WHERE id = ?1",
+                params![id],
+                |row| row.get(0),
+            )
+            .optional()
+            .map_err(|e| e.to_string())?
+            .flatten();
+        let Some(sealed) = sealed else {
+            return Ok(None);
+        };
+
+        let (nonce, ciphertext) = sealed.split_at(24.min(sealed.len()));
+        db::decrypt(&self.master_key, &hash_aad(id), nonce, ciphertext).map(Some)
+    }
+
+    pub fn set_transfer_path(&mut self, id: &str, path: &str) -> Result<(), String> {
+        let transfer = self.transfer(id)?.ok_or("Unknown transfer")?;
+        let (nonce, ciphertext) = self.seal_details(id, &TransferDetails {
+            name: transfer.name,
+            path: Some(path.to_string()),
+            sha256: transfer.sha256,
+        })?;
+        self.conn
+            .execute(
+                "UPDATE file_transfers SET nonce = ?2, ciphertext = ?3, updated_at = ?4 WHERE id = ?1",
+                params![id, nonce, ciphertext, chrono::Utc::now().timestamp()],
+            )
+            .map_err(|e| e.to_string())?;
+        Ok(())
+    }
+
+    /// Forgets a finished transfer. Files already received are left alone.
+    pub fn delete_transfer(&mut self, id: &str) -> Result<bool, String> {
+        let Some(transfer) = self.transfer(id)? else {
+            return Ok(false);
+        };
+        if !transfer.state.is_finished() {
+            return Err("Cancel the transfer before removing it".into());
+        }
+        self.conn
+            .execute("DELETE FROM file_transfers WHERE id = ?1", params![id])
+            .map_err(|e| e.to_string())?;
+        Ok(true)
+    }
+
+    fn seal_details(&self, id: &str, details: &TransferDetails) -> Result<(Vec<u8>, Vec<u8>), String> {
+        let plaintext = serde_json::to_vec(details).map_err(|e| e.to_string())?;
+        db::encrypt(&self.master_key, &details_aad(id), &plaintext)
+    }
+
+    fn open_transfer(&self, row: SealedTransfer) -> Result<FileTransfer, String> {
+        let plaintext = db::decrypt(&self.master_key, &details_aad(&row.id), &row.nonce, &row.ciphertext)?;
+        let details: TransferDetails = serde_json::from_slice(&plaintext).map_err(|e| e.to_string())?;
+        Ok(FileTransfer {
+            id: row.id,
+            peer_id: row.peer_id,
+            outgoing: row.outgoing,
+            name: details.name,
+            path: details.path,
+            size: row.size,
+            sha256: details.sha256,
+            chunk_size: row.chunk_size,
+            state: row.state,
+            transferred: row.transferred,
+            created_at: row.created_at,
+            updated_at: row.updated_at,
+        })
+    }
+}
+
+#[tauri::command]
+pub async fn list_transfers(state: State<'_, HistoryState>) -> Result<Vec<FileTransfer>, String> {
+    with_store(&state, |store| store.transfers())
+}
+
+/// Removes a finished transfer from the list.
+#[tauri::command]
+pub async fn delete_transfer(transfer_id: String, state: State<'_, HistoryState>) -> Result<bool, String> {
+    with_store(&state, |store| store.delete_transfer(&transfer_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn details_are_sealed() {
+        let path = std::env::temp_dir()
+            .join(format!("void-transfers-{:016x}", rand::random::<u64>()))
+            .join("void.db");
+        let mut store = MessageStore::open(&path, "test").unwrap();
+        let transfer = FileTransfer {
+            id: "transfer".into(),
+            peer_id: "alice".into(),
+            outgoing: false,
+            name: "tax-return.pdf".into(),
+            path: None,
+            size: 10,
+            sha256: "ab".repeat(32),
+            chunk_size: 4,
+            state: TransferState::Offered,
+            transferred: 0,
+            created_at: 1,
+            updated_at: 1,
+        };
+        assert!(store.insert_transfer(&transfer, &[7; 32]).unwrap());
+        store.set_transfer_path("transfer", "/home/alice/tax-return.pdf").unwrap();
+
+        let stored = store.transfer("transfer").unwrap().unwrap();
+        assert_eq!(stored.name, "tax-return.pdf");
+        assert_eq!(stored.path.as_deref(), Some("/home/alice/tax-return.pdf"));
+        assert_eq!(stored.sha256, transfer.sha256);
+        assert_eq!(store.transfer_key("transfer").unwrap(), Some([7; 32]));
+
+        let ciphertext: Vec<u8> = store
+            .conn
+            .query_row("SELECT ciphertext FROM file_transfers WHERE id = 'transfer'", [], |row| row.get(0))
+            .unwrap();
+        assert!(!ciphertext.windows(b"tax-return".len()).any(|w| w == b"tax-return"));
+    }
+}
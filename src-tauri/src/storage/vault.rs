@@ -1,42 +1,386 @@
+use crate::network::files;
+use crate::storage::manifest::{self, Manifest, VaultEntry};
+use crate::storage::vault_header::{self, Cipher, Header, Kdf, KdfParams, MAGIC, NONCE_SIZE, STREAM_NONCE_PREFIX};
+use crate::storage::vault_session::{self, VaultState};
 use argon2::{
     Argon2,
-    password_hash::{PasswordHasher, SaltString, rand_core::OsRng as ArgonOsRng},
+    password_hash::{PasswordHasher, SaltString},
 };
 use chacha20poly1305::{
     XChaCha20Poly1305, XNonce,
-    aead::{Aead, KeyInit, OsRng},
+    aead::{Aead, KeyInit, OsRng, Payload},
 };
 use rand::RngCore;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 use std::fs::{self, File, OpenOptions};
-use std::io::{Read, Write};
-use std::path::Path;
-use tauri::{AppHandle, Manager};
-
-const NONCE_SIZE: usize = 24;
-
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use tauri::{AppHandle, State};
+
+const TAG_SIZE: usize = 16;
+
+/// The format from before the versioned header, still read: single-shot
+/// under a PIN-derived key, [Salt Len] [Salt String] [Nonce] [Ciphertext]
+const LEGACY_VERSION: u8 = 1;
+pub(crate) const STREAM_CHUNK_SIZE: usize = 1024 * 1024;
+/// `decrypt_file` returns the plaintext over IPC; anything larger has to be
+/// decrypted to disk with `decrypt_file_to`.
+const MAX_IN_MEMORY_SIZE: u64 = 64 * 1024 * 1024;
+
+/// Moves a file into the vault: encrypts it under a new opaque id, records it
+/// in the manifest and wipes the original.
 #[tauri::command]
 pub async fn encrypt_file(
     app: AppHandle,
+    state: State<'_, VaultState>,
     file_path: String,
-    pin: String,
-) -> Result<String, String> {
-    let path = Path::new(&file_path);
+) -> Result<VaultEntry, String> {
+    let path = PathBuf::from(&file_path);
     if !path.exists() {
         return Err("File not found".into());
     }
+    let key = state.key()?;
+    let dir = manifest::vault_dir(&app)?;
 
-    // 1. Read file
-    let mut file = File::open(path).map_err(|e| e.to_string())?;
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).map_err(|e| e.to_string())?;
+    tauri::async_runtime::spawn_blocking(move || encrypt_into(&dir, &key, &path))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+fn encrypt_into(dir: &Path, key: &[u8; 32], path: &Path) -> Result<VaultEntry, String> {
+    let name = path
+        .file_name()
+        .ok_or("Invalid file name")?
+        .to_string_lossy()
+        .to_string();
+    // 1. Encrypt chunk by chunk into a temp file, so a failure leaves no half-written vault entry
+    let file = File::open(path).map_err(|e| e.to_string())?;
+    let modified_at = manifest::modified_at(&file);
+    let id = manifest::new_id();
+    let payload = manifest::payload_path(dir, &id);
+    let data_key = manifest::new_data_key();
+    let mut reader = Hashing::new(BufReader::new(file));
+    let tmp = write_tmp(&payload, |writer| seal_stream(&data_key, &mut reader, writer))?;
+
+    // 2. Record it before the payload is in place, so no payload is ever
+    // without its entry
+    let entry = manifest::new_entry(id, &name, reader.len, reader.hex_digest(), modified_at);
+    if let Err(e) = manifest::update(dir, key, |manifest| {
+        manifest.set_data_key(key, &entry.id, &data_key)?;
+        manifest.insert(entry.clone());
+        Ok(())
+    }) {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    if let Err(e) = fs::rename(&tmp, &payload) {
+        let _ = fs::remove_file(&tmp);
+        let _ = manifest::update(dir, key, |manifest| {
+            manifest.remove(&entry.id);
+            Ok(())
+        });
+        return Err(e.to_string());
+    }
+
+    // 3. Secure Wipe
+    secure_wipe(path)?;
+
+    Ok(entry)
+}
+
+/// Returns the plaintext of a vault entry. Only for small files; larger ones
+/// go through `decrypt_file_to`. `pin` is only needed for files still sealed
+/// with a PIN from before the vault key.
+#[tauri::command]
+pub async fn decrypt_file(
+    app: AppHandle,
+    state: State<'_, VaultState>,
+    id: String,
+    pin: Option<String>,
+) -> Result<Vec<u8>, String> {
+    let key = state.key()?;
+    let dir = manifest::vault_dir(&app)?;
+
+    tauri::async_runtime::spawn_blocking(move || {
+        let (entry, data_key) = manifest::read(&dir, &key, |manifest| {
+            Ok((manifest.entry(&id)?.clone(), manifest.data_key(&key, &id)?))
+        })?;
+        let path = manifest::payload_path(&dir, &entry.id);
+        if fs::metadata(&path).map_err(|_| "Vault file not found")?.len() > MAX_IN_MEMORY_SIZE {
+            return Err("File too large to decrypt in memory; use decrypt_file_to".into());
+        }
+
+        let mut writer = Hashing::new(Vec::new());
+        open_payload(&path, &data_key, pin.as_deref(), &mut writer)?;
+        writer.verify(&entry)?;
+        Ok(writer.inner)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// Decrypts a vault entry to `destination` with bounded memory and returns
+/// the path written. An existing file is never replaced: if `destination` is
+/// taken, the file is saved as `name (n)` next to it. Nothing is left behind
+/// unless the whole file authenticated and matches the hash in the manifest.
+#[tauri::command]
+pub async fn decrypt_file_to(
+    app: AppHandle,
+    state: State<'_, VaultState>,
+    id: String,
+    destination: String,
+    pin: Option<String>,
+) -> Result<String, String> {
+    let key = state.key()?;
+    let dir = manifest::vault_dir(&app)?;
+    let destination = PathBuf::from(destination);
+
+    tauri::async_runtime::spawn_blocking(move || {
+        decrypt_into(&dir, &key, &id, &destination, pin.as_deref())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+fn decrypt_into(
+    dir: &Path,
+    key: &[u8; 32],
+    id: &str,
+    destination: &Path,
+    pin: Option<&str>,
+) -> Result<String, String> {
+    let (entry, data_key) = manifest::read(dir, key, |manifest| {
+        Ok((manifest.entry(id)?.clone(), manifest.data_key(key, id)?))
+    })?;
+    let path = manifest::payload_path(dir, &entry.id);
+    let destination = free_destination(destination)?;
+    write_atomically(&destination, |writer| {
+        let mut writer = Hashing::new(writer);
+        open_payload(&path, &data_key, pin, &mut writer)?;
+        writer.verify(&entry)
+    })?;
+    Ok(destination.to_string_lossy().to_string())
+}
+
+/// Moves a file that is still sealed with an old PIN under the vault key.
+/// Files the unlock PIN couldn't open are left for this.
+#[tauri::command]
+pub async fn migrate_vault_file(
+    app: AppHandle,
+    state: State<'_, VaultState>,
+    id: String,
+    pin: String,
+) -> Result<VaultEntry, String> {
+    let key = state.key()?;
+    let dir = manifest::vault_dir(&app)?;
+
+    tauri::async_runtime::spawn_blocking(move || {
+        manifest::update(&dir, &key, |manifest| {
+            upgrade_entry(&dir, manifest, &key, &id, Some(&pin))?;
+            manifest.entry(&id).cloned()
+        })
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VaultUpgrade {
+    pub upgraded: usize,
+    /// Files that are still in an older format, usually for want of their PIN.
+    pub remaining: usize,
+    /// Why each of the remaining files wasn't upgraded.
+    pub failures: Vec<UpgradeFailure>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct UpgradeFailure {
+    pub id: String,
+    pub error: String,
+}
+
+/// Rewrites every vault file in an older format with the current header.
+/// Files sealed with a PIN from before the vault key are only upgraded if
+/// `pin` opens them; `pin` also rewraps the vault key itself.
+#[tauri::command]
+pub async fn upgrade_vault_files(
+    app: AppHandle,
+    state: State<'_, VaultState>,
+    pin: Option<String>,
+) -> Result<VaultUpgrade, String> {
+    let key = state.key()?;
+    let dir = manifest::vault_dir(&app)?;
+
+    tauri::async_runtime::spawn_blocking(move || {
+        if let Some(pin) = &pin {
+            vault_session::upgrade_key_file(&dir, pin)?;
+        }
+        manifest::update(&dir, &key, |manifest| {
+            let mut report = VaultUpgrade {
+                upgraded: 0,
+                remaining: 0,
+                failures: Vec::new(),
+            };
+            for entry in manifest.entries() {
+                match upgrade_entry(&dir, manifest, &key, &entry.id, pin.as_deref()) {
+                    Ok(true) => report.upgraded += 1,
+                    Ok(false) => {}
+                    Err(error) => {
+                        report.remaining += 1;
+                        report.failures.push(UpgradeFailure { id: entry.id, error });
+                    }
+                }
+            }
+            Ok(report)
+        })
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// Rewrites the payload of `id` in the current format if it's older. Files
+/// from before the vault key get a new data key and need `pin`. Returns
+/// whether anything was rewritten.
+///
+/// The manifest is saved with the new data key before the new payload
+/// replaces the old one, so an interrupted upgrade leaves every file readable.
+pub(crate) fn upgrade_entry(
+    dir: &Path,
+    manifest: &mut Manifest,
+    vault_key: &[u8; 32],
+    id: &str,
+    pin: Option<&str>,
+) -> Result<bool, String> {
+    let entry = manifest.entry(id)?.clone();
+    let path = manifest::payload_path(dir, &entry.id);
+    let (tmp, size, sha256, data_key) = match file_version(&path)? {
+        vault_header::VERSION => return Ok(false),
+        LEGACY_VERSION => {
+            let pin = pin.ok_or("File is sealed with a PIN from before the vault key; enter it to upgrade")?;
+            let data_key = manifest::new_data_key();
+            let (tmp, size, sha256) = reseal_file(&path, &data_key, &entry, |reader, writer| {
+                open_legacy(pin, reader, writer)
+            })?;
+            (tmp, size, sha256, data_key)
+        }
+        version => return Err(format!("Unsupported vault file version {}", version)),
+    };
+
+    let mut upgraded = manifest.clone();
+    let saved = upgraded.set_data_key(vault_key, id, &data_key).and_then(|()| {
+        let entry = upgraded.entry_mut(id)?;
+        entry.size = Some(size);
+        entry.sha256 = Some(sha256);
+        upgraded.save(dir, vault_key)
+    });
+    if let Err(e) = saved {
+        let _ = fs::remove_file(&tmp);
+        return Err(e);
+    }
+    *manifest = upgraded;
+    fs::rename(&tmp, &path).map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        e.to_string()
+    })?;
+    Ok(true)
+}
+
+/// Opens a payload with its data key, or with `pin` if it predates the vault key.
+fn open_payload(path: &Path, data_key: &[u8; 32], pin: Option<&str>, writer: &mut impl Write) -> Result<(), String> {
+    let mut reader = BufReader::new(File::open(path).map_err(|_| "Vault file not found")?);
+    match pin {
+        Some(pin) if is_pin_sealed(path)? => open_legacy(pin, &mut reader, writer),
+        _ => open_stream(data_key, &mut reader, writer),
+    }
+}
+
+/// Re-encrypts a payload in the current format under `key` into a temp file
+/// next to it, reading it with `open` and checking it against `entry` on the
+/// way. Returns the temp file and the plaintext size and hash.
+fn reseal_file(
+    path: &Path,
+    key: &[u8; 32],
+    entry: &VaultEntry,
+    open: impl FnOnce(&mut BufReader<File>, &mut Hashing<StreamSealer<&mut BufWriter<File>>>) -> Result<(), String>,
+) -> Result<(PathBuf, u64, String), String> {
+    let file = File::open(path).map_err(|_| "Vault file not found")?;
+    let mut digest = None;
+    let tmp = write_tmp(path, |writer| {
+        let mut sealer = Hashing::new(StreamSealer::new(key, writer)?);
+        open(&mut BufReader::new(file), &mut sealer)?;
+        sealer.verify(entry)?;
+        digest = Some((sealer.len, sealer.hex_digest()));
+        sealer.inner.finish()?;
+        Ok(())
+    })?;
+    let (size, sha256) = digest.ok_or_else(|| "Upgrade failed".to_string())?;
+    Ok((tmp, size, sha256))
+}
+
+/// Passes data through while hashing it, in either direction.
+struct Hashing<T> {
+    inner: T,
+    hasher: Sha256,
+    len: u64,
+}
+
+impl<T> Hashing<T> {
+    fn new(inner: T) -> Self {
+        Self {
+            inner,
+            hasher: Sha256::new(),
+            len: 0,
+        }
+    }
+
+    fn hex_digest(&self) -> String {
+        format!("{:x}", self.hasher.clone().finalize())
+    }
+
+    /// Checks what went through against the manifest entry.
+    fn verify(&self, entry: &VaultEntry) -> Result<(), String> {
+        let size_ok = entry.size.is_none_or(|size| size == self.len);
+        let hash_ok = entry.sha256.as_ref().is_none_or(|hash| *hash == self.hex_digest());
+        if !size_ok || !hash_ok {
+            return Err("Integrity check failed: vault file doesn't match its manifest entry".into());
+        }
+        Ok(())
+    }
+}
+
+impl<R: Read> Read for Hashing<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        self.len += read as u64;
+        Ok(read)
+    }
+}
 
-    // 2. Generate Salt & Derive Key
-    let salt = SaltString::generate(&mut ArgonOsRng);
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.hasher.update(&buf[..written]);
+        self.len += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Derives a 32-byte XChaCha20Poly1305 key from a PIN the way files without
+/// the versioned header were sealed: Argon2 defaults and a base64 salt.
+fn derive_key(pin: &str, salt: &SaltString) -> Result<chacha20poly1305::Key, String> {
     let argon2 = Argon2::default();
 
     // Hash password to get key
     let password_hash = argon2
-        .hash_password(pin.as_bytes(), &salt)
+        .hash_password(pin.as_bytes(), salt)
         .map_err(|e| e.to_string())?;
 
     let hash_bytes = password_hash.hash.ok_or("Hash failed")?;
@@ -47,110 +391,385 @@ pub async fn encrypt_file(
         return Err("Derived key length invalid".into());
     }
 
-    let key = chacha20poly1305::Key::from_slice(key_bytes);
-    let cipher = XChaCha20Poly1305::new(key);
+    Ok(*chacha20poly1305::Key::from_slice(key_bytes))
+}
 
-    // 3. Encrypt
-    let mut nonce_bytes = [0u8; NONCE_SIZE];
-    OsRng.fill_bytes(&mut nonce_bytes);
-    let nonce = XNonce::from_slice(&nonce_bytes); // 24-bytes; unique per message
+/// Encrypts `plaintext` under a PIN-derived key, in one piece, with the
+/// Argon2 defaults.
+pub(crate) fn seal(pin: &str, plaintext: &[u8]) -> Result<Vec<u8>, String> {
+    seal_with(pin, plaintext, KdfParams::DEFAULT)
+}
+
+/// Encrypts `plaintext` under a key derived from `pin` with `params`, which
+/// are kept in the header for opening.
+///
+/// Output format:
+/// [Header] [Ciphertext], see `vault_header`; the header is the associated data.
+pub(crate) fn seal_with(pin: &str, plaintext: &[u8], params: KdfParams) -> Result<Vec<u8>, String> {
+    let header = Header::new(Cipher::XChaCha20Poly1305, Kdf::argon2id(params), 0)?;
+    let key = header.kdf.derive(pin)?;
+    let cipher = XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(key.as_slice()));
 
     let ciphertext = cipher
-        .encrypt(nonce, buffer.as_ref())
+        .encrypt(
+            XNonce::from_slice(&header.nonce),
+            Payload {
+                msg: plaintext,
+                aad: &header.bytes,
+            },
+        )
         .map_err(|e| e.to_string())?;
 
-    // 4. Save to Vault
-    let vault_dir = app
-        .path()
-        .app_data_dir()
-        .map_err(|e| e.to_string())?
-        .join("vault");
-    if !vault_dir.exists() {
-        fs::create_dir_all(&vault_dir).map_err(|e| e.to_string())?;
+    let mut out = header.bytes;
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+/// Reverses [`seal`], failing if the PIN is wrong or the data was tampered
+/// with. Also reads the legacy format without a header.
+pub(crate) fn open(pin: &str, buffer: &[u8]) -> Result<Vec<u8>, String> {
+    if version_of(buffer) == vault_header::VERSION {
+        let mut reader = &buffer[MAGIC.len() + 1..];
+        let header = Header::read_rest(&buffer[..MAGIC.len() + 1], &mut reader)?;
+        if header.cipher != Cipher::XChaCha20Poly1305 {
+            return Err("Not a single-shot vault file".into());
+        }
+        let key = header.kdf.derive(pin)?;
+        let cipher = XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(key.as_slice()));
+        return cipher
+            .decrypt(
+                XNonce::from_slice(&header.nonce),
+                Payload {
+                    msg: reader,
+                    aad: &header.bytes,
+                },
+            )
+            .map_err(|_| "Decryption failed: Incorrect PIN or corrupted file".into());
+    }
+
+    // Legacy: [Salt Len (1 byte)] [Salt String bytes] [Nonce (24 bytes)] [Ciphertext]
+    if buffer.len() < 1 + NONCE_SIZE {
+        return Err("Invalid file format".into());
+    }
+    let salt_len = buffer[0] as usize;
+    if buffer.len() < 1 + salt_len + NONCE_SIZE {
+        return Err("Invalid file format (short)".into());
     }
 
-    let file_name = path.file_name().unwrap().to_string_lossy();
-    let vault_path = vault_dir.join(format!("{}.void", file_name));
+    let salt_bytes = &buffer[1..1 + salt_len];
+    let salt_str = std::str::from_utf8(salt_bytes).map_err(|_| "Invalid salt encoding")?;
+    let salt = SaltString::from_b64(salt_str).map_err(|e| e.to_string())?;
 
-    let mut out_file = File::create(&vault_path).map_err(|e| e.to_string())?;
+    let nonce_start = 1 + salt_len;
+    let nonce_bytes = &buffer[nonce_start..nonce_start + NONCE_SIZE];
+    let ciphertext = &buffer[nonce_start + NONCE_SIZE..];
 
-    // File Format:
-    // [Salt Len (1 byte)] [Salt String bytes] [Nonce (24 bytes)] [Ciphertext]
+    let key = derive_key(pin, &salt)?;
+    let cipher = XChaCha20Poly1305::new(&key);
+    let nonce = XNonce::from_slice(nonce_bytes);
 
-    let salt_str = salt.as_str();
-    let salt_bytes = salt_str.as_bytes();
-    let salt_len = salt_bytes.len();
+    cipher
+        .decrypt(nonce, ciphertext)
+        .map_err(|_| "Decryption failed: Incorrect PIN or corrupted file".into())
+}
 
-    if salt_len > 255 {
-        return Err("Salt too long".into());
+/// Format version of sealed data from its first bytes.
+fn version_of(start: &[u8]) -> u8 {
+    match start.get(..MAGIC.len() + 1) {
+        Some(start) if start.starts_with(MAGIC) => start[MAGIC.len()],
+        _ => LEGACY_VERSION,
     }
+}
 
-    out_file
-        .write_all(&[salt_len as u8])
-        .map_err(|e| e.to_string())?;
-    out_file.write_all(salt_bytes).map_err(|e| e.to_string())?;
-    out_file
-        .write_all(&nonce_bytes)
+/// Whether sealed data is in the current format.
+pub(crate) fn is_current(sealed: &[u8]) -> bool {
+    version_of(sealed) == vault_header::VERSION
+}
+
+/// Encrypts `plaintext` under a raw key, binding it to `aad`.
+///
+/// Output format:
+/// [Nonce (24 bytes)] [Ciphertext]
+pub(crate) fn seal_with_key(key: &[u8; 32], aad: &[u8], plaintext: &[u8]) -> Result<Vec<u8>, String> {
+    let cipher = XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(key));
+
+    let mut nonce_bytes = [0u8; NONCE_SIZE];
+    OsRng.fill_bytes(&mut nonce_bytes);
+
+    let ciphertext = cipher
+        .encrypt(
+            XNonce::from_slice(&nonce_bytes),
+            Payload {
+                msg: plaintext,
+                aad,
+            },
+        )
         .map_err(|e| e.to_string())?;
-    out_file.write_all(&ciphertext).map_err(|e| e.to_string())?;
 
-    // 5. Secure Wipe
-    secure_wipe(path)?;
+    let mut out = Vec::with_capacity(NONCE_SIZE + ciphertext.len());
+    out.extend_from_slice(&nonce_bytes);
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
 
-    Ok(vault_path.to_string_lossy().to_string())
+/// Reverses [`seal_with_key`]. Fails if the key or `aad` differ or the data was tampered with.
+pub(crate) fn open_with_key(key: &[u8; 32], aad: &[u8], sealed: &[u8]) -> Result<Vec<u8>, String> {
+    if sealed.len() < NONCE_SIZE {
+        return Err("Invalid sealed data".into());
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_SIZE);
+    let cipher = XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(key));
+    cipher
+        .decrypt(
+            XNonce::from_slice(nonce),
+            Payload {
+                msg: ciphertext,
+                aad,
+            },
+        )
+        .map_err(|_| "Decryption failed: wrong key or corrupted data".into())
 }
 
-#[tauri::command]
-pub async fn decrypt_file(file_path: String, pin: String) -> Result<Vec<u8>, String> {
-    let path = Path::new(&file_path);
-    if !path.exists() {
-        return Err("Vault file not found".into());
+/// Encrypts everything `reader` yields into `writer` under a raw key, one
+/// chunk at a time.
+///
+/// Output format:
+/// [Header] [Chunks], see `vault_header`.
+///
+/// Every chunk but the last holds exactly `Chunk Size` bytes of plaintext plus
+/// its tag; the last one is shorter, and empty if the plaintext ends on a chunk
+/// boundary. Chunk nonces are the prefix, the chunk index and a last-chunk
+/// flag, so chunks can't be reordered, dropped or truncated unnoticed, and the
+/// header is authenticated with every chunk.
+pub(crate) fn seal_stream(key: &[u8; 32], reader: &mut impl Read, writer: &mut impl Write) -> Result<(), String> {
+    let mut sealer = StreamSealer::new(key, writer)?;
+    std::io::copy(reader, &mut sealer).map_err(|e| e.to_string())?;
+    sealer.finish()?;
+    Ok(())
+}
+
+/// Encrypts whatever is written to it in the [`seal_stream`] format. Nothing
+/// is complete until [`StreamSealer::finish`] writes the last chunk.
+pub(crate) struct StreamSealer<W: Write> {
+    cipher: XChaCha20Poly1305,
+    prefix: [u8; STREAM_NONCE_PREFIX],
+    header: Vec<u8>,
+    index: u32,
+    buffer: Vec<u8>,
+    writer: W,
+}
+
+impl<W: Write> StreamSealer<W> {
+    pub(crate) fn new(key: &[u8; 32], mut writer: W) -> Result<Self, String> {
+        let header = Header::new(Cipher::XChaCha20Poly1305Stream, Kdf::None, STREAM_CHUNK_SIZE as u32)?;
+        writer.write_all(&header.bytes).map_err(|e| e.to_string())?;
+
+        Ok(Self {
+            cipher: XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(key)),
+            prefix: header.nonce.as_slice().try_into().map_err(|_| "Invalid nonce prefix")?,
+            header: header.bytes,
+            index: 0,
+            buffer: Vec::with_capacity(STREAM_CHUNK_SIZE),
+            writer,
+        })
     }
 
-    let mut file = File::open(path).map_err(|e| e.to_string())?;
-    let mut buffer = Vec::new();
-    file.read_to_end(&mut buffer).map_err(|e| e.to_string())?;
+    fn seal_chunk(&mut self, last: bool) -> std::io::Result<()> {
+        let nonce = chunk_nonce(&self.prefix, self.index, last);
+        let ciphertext = self
+            .cipher
+            .encrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: &self.buffer,
+                    aad: &self.header,
+                },
+            )
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        self.writer.write_all(&ciphertext)?;
+        self.buffer.fill(0);
+        self.buffer.clear();
+        self.index = self
+            .index
+            .checked_add(1)
+            .ok_or_else(|| std::io::Error::other("File too large"))?;
+        Ok(())
+    }
 
-    if buffer.len() < 1 + NONCE_SIZE {
-        return Err("Invalid file format".into());
+    /// Writes the final, short chunk and returns the inner writer.
+    pub(crate) fn finish(mut self) -> Result<W, String> {
+        self.seal_chunk(true).map_err(|e| e.to_string())?;
+        self.writer.flush().map_err(|e| e.to_string())?;
+        Ok(self.writer)
     }
+}
 
-    // Parse Header
-    let salt_len = buffer[0] as usize;
-    if buffer.len() < 1 + salt_len + NONCE_SIZE {
-        return Err("Invalid file format (short)".into());
+impl<W: Write> Write for StreamSealer<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // A full chunk is never the last one, so it can go out right away
+        let take = buf.len().min(STREAM_CHUNK_SIZE - self.buffer.len());
+        self.buffer.extend_from_slice(&buf[..take]);
+        if self.buffer.len() == STREAM_CHUNK_SIZE {
+            self.seal_chunk(false)?;
+        }
+        Ok(take)
     }
 
-    let salt_bytes = &buffer[1..1 + salt_len];
-    let salt_str = std::str::from_utf8(salt_bytes).map_err(|_| "Invalid salt encoding")?;
-    let salt = SaltString::from_b64(salt_str).map_err(|e| e.to_string())?;
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()
+    }
+}
 
-    let nonce_start = 1 + salt_len;
-    let nonce_bytes = &buffer[nonce_start..nonce_start + NONCE_SIZE];
-    let ciphertext = &buffer[nonce_start + NONCE_SIZE..];
+/// Reverses [`seal_stream`], writing plaintext as each chunk authenticates.
+pub(crate) fn open_stream(key: &[u8; 32], reader: &mut impl Read, writer: &mut impl Write) -> Result<(), String> {
+    let mut start = [0u8; MAGIC.len() + 1];
+    let read = read_full(reader, &mut start)?;
+    let cipher = XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(key));
+    match version_of(&start[..read]) {
+        vault_header::VERSION => {
+            let header = Header::read_rest(&start, reader)?;
+            if header.cipher != Cipher::XChaCha20Poly1305Stream || header.kdf != Kdf::None {
+                return Err("Not a vault payload".into());
+            }
+            decrypt_chunks(&cipher, &header.nonce, header.chunk_size, &header.bytes, reader, writer)
+        }
+        LEGACY_VERSION => {
+            Err("File is sealed with a PIN from before the vault key; migrate it first".into())
+        }
+        version => Err(format!("Unsupported vault file version {}", version)),
+    }
+}
 
-    // Derive Key
-    let argon2 = Argon2::default();
-    let password_hash = argon2
-        .hash_password(pin.as_bytes(), &salt)
-        .map_err(|e| e.to_string())?;
+/// Format version of the file at `path`.
+pub(crate) fn file_version(path: &Path) -> Result<u8, String> {
+    let mut start = [0u8; MAGIC.len() + 1];
+    let read = read_full(&mut File::open(path).map_err(|e| e.to_string())?, &mut start)?;
+    Ok(version_of(&start[..read]))
+}
 
-    let hash_bytes = password_hash.hash.ok_or("Hash failed")?;
-    let key_bytes = hash_bytes.as_bytes();
+/// Whether the file at `path` predates the vault key and is sealed with a PIN.
+pub(crate) fn is_pin_sealed(path: &Path) -> Result<bool, String> {
+    Ok(file_version(path)? == LEGACY_VERSION)
+}
 
-    if key_bytes.len() != 32 {
-        return Err("Derived key length invalid".into());
+/// Reads a file sealed with a PIN before the vault had a master key, in the
+/// single-shot [`seal`] format. It is read whole.
+pub(crate) fn open_legacy(pin: &str, reader: &mut impl Read, writer: &mut impl Write) -> Result<(), String> {
+    let mut buffer = Vec::new();
+    reader.read_to_end(&mut buffer).map_err(|e| e.to_string())?;
+    let plaintext = open(pin, &buffer)?;
+    writer.write_all(&plaintext).map_err(|e| e.to_string())
+}
+
+fn decrypt_chunks(
+    cipher: &XChaCha20Poly1305,
+    prefix: &[u8],
+    chunk_size: u32,
+    header: &[u8],
+    reader: &mut impl Read,
+    writer: &mut impl Write,
+) -> Result<(), String> {
+    let prefix: &[u8; STREAM_NONCE_PREFIX] = prefix.try_into().map_err(|_| "Invalid file format")?;
+    let chunk_size = chunk_size as usize;
+    if chunk_size == 0 || chunk_size > 16 * STREAM_CHUNK_SIZE {
+        return Err("Invalid chunk size".into());
     }
 
-    let key = chacha20poly1305::Key::from_slice(key_bytes);
-    let cipher = XChaCha20Poly1305::new(key);
-    let nonce = XNonce::from_slice(nonce_bytes);
+    let mut buffer = vec![0u8; chunk_size + TAG_SIZE];
+    let mut index: u32 = 0;
+    loop {
+        let read = read_full(reader, &mut buffer)?;
+        let last = read < buffer.len();
+        let nonce = chunk_nonce(prefix, index, last);
+        let mut plaintext = cipher
+            .decrypt(
+                XNonce::from_slice(&nonce),
+                Payload {
+                    msg: &buffer[..read],
+                    aad: header,
+                },
+            )
+            .map_err(|_| "Decryption failed: wrong key or corrupted file".to_string())?;
+        writer.write_all(&plaintext).map_err(|e| e.to_string())?;
+        plaintext.fill(0);
+        if last {
+            break;
+        }
+        index = index.checked_add(1).ok_or("Invalid file format")?;
+    }
+    writer.flush().map_err(|e| e.to_string())
+}
 
-    let plaintext = cipher
-        .decrypt(nonce, ciphertext)
-        .map_err(|_| "Decryption failed: Incorrect PIN or corrupted file")?;
+fn chunk_nonce(prefix: &[u8; STREAM_NONCE_PREFIX], index: u32, last: bool) -> [u8; NONCE_SIZE] {
+    let mut nonce = [0u8; NONCE_SIZE];
+    nonce[..STREAM_NONCE_PREFIX].copy_from_slice(prefix);
+    nonce[STREAM_NONCE_PREFIX..NONCE_SIZE - 1].copy_from_slice(&index.to_be_bytes());
+    nonce[NONCE_SIZE - 1] = last as u8;
+    nonce
+}
+
+/// Fills `buffer` unless the reader runs out first; returns how much was read.
+fn read_full(reader: &mut impl Read, buffer: &mut [u8]) -> Result<usize, String> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match reader.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+    Ok(filled)
+}
+
+fn tmp_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.tmp", path.to_string_lossy()))
+}
+
+/// `path`, or a numbered name next to it if a file, or another decryption,
+/// already has it.
+fn free_destination(path: &Path) -> Result<PathBuf, String> {
+    let name = path
+        .file_name()
+        .ok_or("Invalid destination")?
+        .to_string_lossy()
+        .to_string();
+    let dir = path.parent().unwrap_or(Path::new(""));
+    Ok(files::unique_path_by(dir, &name, |path| tmp_path(path).exists()))
+}
+
+/// Runs `write` against `<path>.tmp` and moves it to `path` only if it succeeded.
+pub(crate) fn write_atomically(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<(), String>,
+) -> Result<(), String> {
+    let tmp = write_tmp(path, write)?;
+    fs::rename(&tmp, path).map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        e.to_string()
+    })
+}
 
-    Ok(plaintext)
+/// Runs `write` against `<path>.tmp` and syncs it. Returns the temp path, for
+/// the caller to move into place; nothing is left behind on failure.
+fn write_tmp(
+    path: &Path,
+    write: impl FnOnce(&mut BufWriter<File>) -> Result<(), String>,
+) -> Result<PathBuf, String> {
+    let tmp = tmp_path(path);
+    let result = File::create(&tmp).map_err(|e| e.to_string()).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        write(&mut writer)?;
+        let file = writer.into_inner().map_err(|e| e.to_string())?;
+        file.sync_all().map_err(|e| e.to_string())
+    });
+    match result {
+        Ok(()) => Ok(tmp),
+        Err(e) => {
+            let _ = fs::remove_file(&tmp);
+            Err(e)
+        }
+    }
 }
 
 fn secure_wipe(path: &Path) -> Result<(), String> {
@@ -177,26 +796,85 @@ fn secure_wipe(path: &Path) -> Result<(), String> {
     Ok(())
 }
 
-#[tauri::command]
-pub async fn list_vault_files(app: AppHandle) -> Result<Vec<String>, String> {
-    let vault_dir = app
-        .path()
-        .app_data_dir()
-        .map_err(|e| e.to_string())?
-        .join("vault");
-    if !vault_dir.exists() {
-        return Ok(vec![]);
-    }
-
-    let mut files = Vec::new();
-    for entry in fs::read_dir(vault_dir).map_err(|e| e.to_string())? {
-        let entry = entry.map_err(|e| e.to_string())?;
-        let path = entry.path();
-        if path.extension().and_then(|s| s.to_str()) == Some("void") {
-            if let Some(name) = path.file_name().and_then(|s| s.to_str()) {
-                files.push(name.to_string());
-            }
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypt_decrypt_round_trip() {
+        let dir = std::env::temp_dir().join(format!("void-vault-{:016x}", rand::random::<u64>()));
+        let downloads = dir.join("downloads");
+        fs::create_dir_all(&downloads).unwrap();
+        let key = [9; 32];
+        let source = dir.join("notes.txt");
+        let contents: Vec<u8> = (0..2 * STREAM_CHUNK_SIZE as u32 + 17).map(|i| i as u8).collect();
+        fs::write(&source, &contents).unwrap();
+
+        let entry = encrypt_into(&dir, &key, &source).unwrap();
+        assert!(!source.exists());
+        assert!(manifest::payload_path(&dir, &entry.id).exists());
+        assert_eq!(entry.size, Some(contents.len() as u64));
+
+        let destination = downloads.join("notes.txt");
+        let saved = decrypt_into(&dir, &key, &entry.id, &destination, None).unwrap();
+        assert_eq!(PathBuf::from(&saved), destination);
+        assert_eq!(fs::read(&saved).unwrap(), contents);
+
+        // The first copy is never overwritten
+        let again = decrypt_into(&dir, &key, &entry.id, &destination, None).unwrap();
+        assert_ne!(again, saved);
+        assert_eq!(fs::read(&again).unwrap(), contents);
+
+        assert!(decrypt_into(&dir, &[8; 32], &entry.id, &destination, None).is_err());
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    /// A payload in the format from before the vault key.
+    fn seal_legacy(pin: &str, plaintext: &[u8]) -> Vec<u8> {
+        let salt = SaltString::encode_b64(&rand::random::<[u8; 16]>()).unwrap();
+        let cipher = XChaCha20Poly1305::new(&derive_key(pin, &salt).unwrap());
+        let nonce = rand::random::<[u8; NONCE_SIZE]>();
+        let mut sealed = vec![salt.as_str().len() as u8];
+        sealed.extend_from_slice(salt.as_str().as_bytes());
+        sealed.extend_from_slice(&nonce);
+        sealed.extend_from_slice(&cipher.encrypt(XNonce::from_slice(&nonce), plaintext).unwrap());
+        sealed
+    }
+
+    #[test]
+    fn interrupted_upgrade_keeps_files_readable() {
+        let dir = std::env::temp_dir().join(format!("void-vault-{:016x}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let key = [9; 32];
+        let mut ids = Vec::new();
+        for name in ["a.txt", "b.txt"] {
+            let id = manifest::new_id();
+            let sha256 = format!("{:x}", Sha256::digest(name.as_bytes()));
+            let entry = manifest::new_entry(id.clone(), name, name.len() as u64, sha256, None);
+            manifest::update(&dir, &key, |manifest| {
+                manifest.insert(entry);
+                Ok(())
+            })
+            .unwrap();
+            fs::write(manifest::payload_path(&dir, &id), seal_legacy("1234", name.as_bytes())).unwrap();
+            ids.push(id);
         }
+
+        // Upgrade the first file, then stop before the run would save
+        let interrupted = manifest::update(&dir, &key, |manifest| {
+            assert!(upgrade_entry(&dir, manifest, &key, &ids[0], Some("1234"))?);
+            Err::<(), _>("interrupted".to_string())
+        });
+        assert!(interrupted.is_err());
+        assert!(!is_pin_sealed(&manifest::payload_path(&dir, &ids[0])).unwrap());
+        assert!(is_pin_sealed(&manifest::payload_path(&dir, &ids[1])).unwrap());
+
+        let out = dir.join("out");
+        fs::create_dir_all(&out).unwrap();
+        let a = decrypt_into(&dir, &key, &ids[0], &out.join("a.txt"), None).unwrap();
+        assert_eq!(fs::read(a).unwrap(), b"a.txt");
+        let b = decrypt_into(&dir, &key, &ids[1], &out.join("b.txt"), Some("1234")).unwrap();
+        assert_eq!(fs::read(b).unwrap(), b"b.txt");
+        let _ = fs::remove_dir_all(&dir);
     }
-    Ok(files)
 }
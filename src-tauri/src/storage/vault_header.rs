@@ -0,0 +1,366 @@
+// Vault file header
+//
+// Every file the vault writes starts with a header saying how it was sealed:
+//
+// [Magic "VOID"] [Version (1 byte)] [Cipher (1 byte)] [KDF (1 byte)]
+// [KDF params: m_cost, t_cost, p_cost (u32 LE each), Salt Len (1 byte), Salt]  (Argon2id only)
+// [Nonce (24 bytes)]                                   (single-shot cipher)
+// [Nonce Prefix (19 bytes)] [Chunk Size (4 bytes LE)]  (stream cipher)
+//
+// The encoded header is the associated data of every AEAD call, so changing
+// any of it, down to the Argon2 cost, makes decryption fail.
+use argon2::Argon2;
+use serde::{Deserialize, Serialize};
+use std::io::Read;
+use std::time::{Duration, Instant};
+use zeroize::Zeroizing;
+
+pub const MAGIC: &[u8; 4] = b"VOID";
+pub const VERSION: u8 = 2;
+pub const NONCE_SIZE: usize = 24;
+/// The rest of each chunk nonce is a big-endian counter and a last-chunk flag.
+pub const STREAM_NONCE_PREFIX: usize = NONCE_SIZE - 5;
+const SALT_SIZE: usize = 16;
+
+// Upper bounds for KDF params read from a file, so a crafted header can't make
+// an unlock take all memory or run forever.
+const MAX_M_COST: u32 = 4 * 1024 * 1024;
+const MAX_T_COST: u32 = 64;
+const MAX_P_COST: u32 = 16;
+/// Less memory than this isn't made up for by more passes.
+const MIN_M_COST: u32 = 8 * 1024;
+/// Calibration stops adding memory here, so unlocking works on small devices.
+const CALIBRATION_MAX_M_COST: u32 = 256 * 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Cipher {
+    /// One XChaCha20-Poly1305 message.
+    XChaCha20Poly1305 = 1,
+    /// XChaCha20-Poly1305 over fixed-size chunks.
+    XChaCha20Poly1305Stream = 2,
+}
+
+impl Cipher {
+    fn parse(id: u8) -> Result<Self, String> {
+        match id {
+            1 => Ok(Cipher::XChaCha20Poly1305),
+            2 => Ok(Cipher::XChaCha20Poly1305Stream),
+            _ => Err(format!("Unsupported vault cipher {}", id)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct KdfParams {
+    /// Memory in KiB.
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+impl KdfParams {
+    pub const DEFAULT: KdfParams = KdfParams {
+        m_cost: argon2::Params::DEFAULT_M_COST,
+        t_cost: argon2::Params::DEFAULT_T_COST,
+        p_cost: argon2::Params::DEFAULT_P_COST,
+    };
+
+    fn check(&self) -> Result<(), String> {
+        if self.m_cost > MAX_M_COST || self.t_cost > MAX_T_COST || self.p_cost > MAX_P_COST {
+            return Err("Vault file asks for unreasonable key derivation costs".into());
+        }
+        Ok(())
+    }
+
+    /// Fails for costs weaker than the Argon2 defaults. Less memory has to be
+    /// made up for with more passes.
+    pub fn check_minimum(&self) -> Result<(), String> {
+        self.check()?;
+        let work = self.m_cost as u64 * self.t_cost as u64;
+        let default_work = Self::DEFAULT.m_cost as u64 * Self::DEFAULT.t_cost as u64;
+        if self.m_cost < MIN_M_COST || self.p_cost == 0 || work < default_work {
+            return Err("Key derivation costs can't be weaker than the Argon2 defaults".into());
+        }
+        Ok(())
+    }
+
+    /// Picks costs that take about `target` to derive a key on this machine.
+    /// Memory grows first, since it's what makes guessing on GPUs expensive,
+    /// and passes make up the rest. One lane, as lanes are computed one after
+    /// another here and would only slow us down.
+    pub fn calibrate(target: Duration) -> Result<Self, String> {
+        let mut params = KdfParams {
+            m_cost: Self::DEFAULT.m_cost,
+            t_cost: 1,
+            p_cost: 1,
+        };
+        let mut elapsed = params.time()?;
+        while elapsed * 2 <= target && params.m_cost * 2 <= CALIBRATION_MAX_M_COST {
+            params.m_cost *= 2;
+            elapsed = params.time()?;
+        }
+        let passes = target.as_secs_f64() / elapsed.as_secs_f64().max(0.001);
+        params.t_cost = (passes as u32).clamp(1, MAX_T_COST);
+        while params.check_minimum().is_err() {
+            params.t_cost += 1;
+        }
+        println!(
+            "Calibrated Argon2id: {} KiB, {} passes ({:?} per pass)",
+            params.m_cost, params.t_cost, elapsed
+        );
+        Ok(params)
+    }
+
+    fn time(&self) -> Result<Duration, String> {
+        let started = Instant::now();
+        self.derive("calibration", &[0u8; SALT_SIZE])?;
+        Ok(started.elapsed())
+    }
+
+    /// Derives a 32-byte key from `pin` with Argon2id.
+    pub fn derive(&self, pin: &str, salt: &[u8]) -> Result<Zeroizing<[u8; 32]>, String> {
+        self.check()?;
+        let params = argon2::Params::new(self.m_cost, self.t_cost, self.p_cost, Some(32)).map_err(|e| e.to_string())?;
+        let argon2 = Argon2::new(argon2::Algorithm::Argon2id, argon2::Version::V0x13, params);
+        let mut key = Zeroizing::new([0u8; 32]);
+        argon2
+            .hash_password_into(pin.as_bytes(), salt, &mut key[..])
+            .map_err(|e| e.to_string())?;
+        Ok(key)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Kdf {
+    /// Sealed with a raw key, such as a data key.
+    None,
+    Argon2id { params: KdfParams, salt: Vec<u8> },
+}
+
+impl Kdf {
+    /// Argon2id with a fresh salt.
+    pub fn argon2id(params: KdfParams) -> Self {
+        Kdf::Argon2id {
+            params,
+            salt: rand::random::<[u8; SALT_SIZE]>().to_vec(),
+        }
+    }
+
+    /// Key for `pin` under this KDF.
+    pub fn derive(&self, pin: &str) -> Result<Zeroizing<[u8; 32]>, String> {
+        match self {
+            Kdf::None => Err("Sealed with a key, not a PIN".into()),
+            Kdf::Argon2id { params, salt } => params.derive(pin, salt),
+        }
+    }
+
+    fn id(&self) -> u8 {
+        match self {
+            Kdf::None => 0,
+            Kdf::Argon2id { .. } => 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Header {
+    pub cipher: Cipher,
+    pub kdf: Kdf,
+    /// The nonce for single-shot files, the nonce prefix for streams.
+    pub nonce: Vec<u8>,
+    /// Plaintext bytes per chunk; 0 for single-shot files.
+    pub chunk_size: u32,
+    /// The encoded header, authenticated as associated data.
+    pub bytes: Vec<u8>,
+}
+
+impl Header {
+    /// A header with a fresh nonce.
+    pub fn new(cipher: Cipher, kdf: Kdf, chunk_size: u32) -> Result<Self, String> {
+        let nonce = match cipher {
+            Cipher::XChaCha20Poly1305 => rand::random::<[u8; NONCE_SIZE]>().to_vec(),
+            Cipher::XChaCha20Poly1305Stream => rand::random::<[u8; NONCE_SIZE]>()[..STREAM_NONCE_PREFIX].to_vec(),
+        };
+
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&[VERSION, cipher as u8, kdf.id()]);
+        if let Kdf::Argon2id { params, salt } = &kdf {
+            let salt_len = u8::try_from(salt.len()).map_err(|_| "Salt too long")?;
+            bytes.extend_from_slice(&params.m_cost.to_le_bytes());
+            bytes.extend_from_slice(&params.t_cost.to_le_bytes());
+            bytes.extend_from_slice(&params.p_cost.to_le_bytes());
+            bytes.push(salt_len);
+            bytes.extend_from_slice(salt);
+        }
+        bytes.extend_from_slice(&nonce);
+        if cipher == Cipher::XChaCha20Poly1305Stream {
+            bytes.extend_from_slice(&chunk_size.to_le_bytes());
+        }
+
+        Ok(Self {
+            cipher,
+            kdf,
+            nonce,
+            chunk_size,
+            bytes,
+        })
+    }
+
+    /// Reads the rest of a header whose magic and version are already in
+    /// `start`, leaving `reader` at the ciphertext.
+    pub fn read_rest(start: &[u8], reader: &mut impl Read) -> Result<Self, String> {
+        let mut bytes = start.to_vec();
+        let ids = take(reader, &mut bytes, 2)?;
+        let cipher = Cipher::parse(ids[0])?;
+        let kdf = match ids[1] {
+            0 => Kdf::None,
+            1 => {
+                let costs = take(reader, &mut bytes, 13)?;
+                let cost = |i: usize| u32::from_le_bytes(costs[i * 4..i * 4 + 4].try_into().expect("4 bytes"));
+                let params = KdfParams {
+                    m_cost: cost(0),
+                    t_cost: cost(1),
+                    p_cost: cost(2),
+                };
+                params.check()?;
+                let salt = take(reader, &mut bytes, costs[12] as usize)?;
+                Kdf::Argon2id { params, salt }
+            }
+            id => return Err(format!("Unsupported vault key derivation {}", id)),
+        };
+
+        let (nonce, chunk_size) = match cipher {
+            Cipher::XChaCha20Poly1305 => (take(reader, &mut bytes, NONCE_SIZE)?, 0),
+            Cipher::XChaCha20Poly1305Stream => {
+                let nonce = take(reader, &mut bytes, STREAM_NONCE_PREFIX)?;
+                let size = take(reader, &mut bytes, 4)?;
+                (nonce, u32::from_le_bytes(size.try_into().expect("4 bytes")))
+            }
+        };
+
+        Ok(Self {
+            cipher,
+            kdf,
+            nonce,
+            chunk_size,
+            bytes,
+        })
+    }
+}
+
+/// Reads `len` more header bytes, keeping a copy in `bytes`.
+fn take(reader: &mut impl Read, bytes: &mut Vec<u8>, len: usize) -> Result<Vec<u8>, String> {
+    let mut field = vec![0u8; len];
+    reader.read_exact(&mut field).map_err(|_| "Invalid file format (short header)")?;
+    bytes.extend_from_slice(&field);
+    Ok(field)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::vault;
+    use chacha20poly1305::{
+        XChaCha20Poly1305, XNonce,
+        aead::{Aead, KeyInit},
+    };
+
+    /// Cheap enough that tests don't wait on Argon2.
+    const TEST_PARAMS: KdfParams = KdfParams {
+        m_cost: 64,
+        t_cost: 1,
+        p_cost: 1,
+    };
+
+    fn parse(bytes: &[u8]) -> Header {
+        Header::read_rest(&bytes[..MAGIC.len() + 1], &mut &bytes[MAGIC.len() + 1..]).unwrap()
+    }
+
+    #[test]
+    fn header_round_trip() {
+        let kdf = Kdf::argon2id(TEST_PARAMS);
+        let header = Header::new(Cipher::XChaCha20Poly1305Stream, kdf.clone(), 4096).unwrap();
+        let mut bytes = header.bytes.clone();
+        bytes.extend_from_slice(b"ciphertext");
+
+        let mut reader = &bytes[MAGIC.len() + 1..];
+        let read = Header::read_rest(&bytes[..MAGIC.len() + 1], &mut reader).unwrap();
+        assert_eq!(read.cipher, Cipher::XChaCha20Poly1305Stream);
+        assert_eq!(read.kdf, kdf);
+        assert_eq!(read.nonce, header.nonce);
+        assert_eq!(read.chunk_size, 4096);
+        assert_eq!(read.bytes, header.bytes);
+        assert_eq!(reader, b"ciphertext");
+
+        let header = Header::new(Cipher::XChaCha20Poly1305, Kdf::None, 0).unwrap();
+        let read = parse(&header.bytes);
+        assert_eq!(read.kdf, Kdf::None);
+        assert_eq!(read.nonce.len(), NONCE_SIZE);
+        assert_eq!(read.bytes, header.bytes);
+    }
+
+    #[test]
+    fn header_is_authenticated() {
+        let mut sealed = vault::seal_with("1234", b"secret", TEST_PARAMS).unwrap();
+        assert_eq!(vault::open("1234", &sealed).unwrap(), b"secret");
+
+        // [Magic] [Version] [Cipher] [KDF] [m_cost] [t_cost] ...
+        let t_cost = MAGIC.len() + 3 + 4;
+        sealed[t_cost] ^= 0x02;
+        assert!(parse(&sealed).kdf.derive("1234").is_ok());
+        assert!(vault::open("1234", &sealed).is_err());
+    }
+
+    #[test]
+    fn reads_legacy_files() {
+        use argon2::password_hash::{PasswordHasher, SaltString};
+
+        // [Salt Len] [Salt String] [Nonce] [Ciphertext], under Argon2 defaults
+        let salt = SaltString::encode_b64(&rand::random::<[u8; SALT_SIZE]>()).unwrap();
+        let hash = Argon2::default().hash_password(b"1234", &salt).unwrap().hash.unwrap();
+        let cipher = XChaCha20Poly1305::new(chacha20poly1305::Key::from_slice(hash.as_bytes()));
+        let nonce = rand::random::<[u8; NONCE_SIZE]>();
+        let mut legacy = vec![salt.as_str().len() as u8];
+        legacy.extend_from_slice(salt.as_str().as_bytes());
+        legacy.extend_from_slice(&nonce);
+        legacy.extend_from_slice(&cipher.encrypt(XNonce::from_slice(&nonce), b"old secret".as_ref()).unwrap());
+
+        assert!(!vault::is_current(&legacy));
+        assert_eq!(vault::open("1234", &legacy).unwrap(), b"old secret");
+        let mut plaintext = Vec::new();
+        vault::open_legacy("1234", &mut legacy.as_slice(), &mut plaintext).unwrap();
+        assert_eq!(plaintext, b"old secret");
+        assert!(vault::open("4321", &legacy).is_err());
+    }
+
+    #[test]
+    fn streams_detect_truncation_and_reordering() {
+        let key = [3; 32];
+        let chunk = vault::STREAM_CHUNK_SIZE;
+        let plaintext: Vec<u8> = (0..2 * chunk + 100).map(|i| (i % 251) as u8).collect();
+        let mut sealed = Vec::new();
+        vault::seal_stream(&key, &mut plaintext.as_slice(), &mut sealed).unwrap();
+
+        let open = |sealed: &[u8]| {
+            let mut out = Vec::new();
+            vault::open_stream(&key, &mut &sealed[..], &mut out).map(|()| out)
+        };
+        assert_eq!(open(&sealed).unwrap(), plaintext);
+
+        let header = parse(&sealed).bytes.len();
+        let sealed_chunk = chunk + 16;
+
+        // Dropping the last chunk, or cutting one short
+        assert!(open(&sealed[..header + 2 * sealed_chunk]).is_err());
+        assert!(open(&sealed[..sealed.len() - 1]).is_err());
+
+        // Swapping the first two chunks
+        let mut swapped = sealed[..header].to_vec();
+        swapped.extend_from_slice(&sealed[header + sealed_chunk..header + 2 * sealed_chunk]);
+        swapped.extend_from_slice(&sealed[header..header + sealed_chunk]);
+        swapped.extend_from_slice(&sealed[header + 2 * sealed_chunk..]);
+        assert_eq!(swapped.len(), sealed.len());
+        assert!(open(&swapped).is_err());
+    }
+}
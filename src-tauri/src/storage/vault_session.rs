@@ -0,0 +1,487 @@
+// Vault session
+//
+// The vault has a single random master key that encrypts the manifest and
+// every payload. It is stored wrapped with the PIN and, optionally, with a
+// recovery key. Unlocking unwraps it into memory for the session; it is wiped
+// on lock and after a period without vault activity.
+//
+// Changing the PIN only rewraps the master key. Rotating it rewraps the data
+// keys in the manifest; the new key is recorded as pending first, so a rotation
+// cut short is finished or rolled back on the next unlock, depending on which
+// key the manifest ended up under.
+use crate::security::pin_strength::{self, PinRating};
+use crate::storage::vault_header::KdfParams;
+use crate::storage::{manifest, vault};
+use base64::{Engine as _, engine::general_purpose::STANDARD as BASE64};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, State};
+use zeroize::Zeroizing;
+
+const KEY_FILE: &str = "keys.vault";
+const KEY_FILE_VERSION: u32 = 1;
+const ROTATION_AAD: &[u8] = b"vault-key-rotation";
+const DEFAULT_AUTO_LOCK_SECS: u64 = 5 * 60;
+const MIN_AUTO_LOCK_SECS: u64 = 30;
+const MAX_AUTO_LOCK_SECS: u64 = 24 * 60 * 60;
+/// How long deriving the PIN key should take after calibration.
+const DEFAULT_UNLOCK_MS: u64 = 1000;
+const MIN_UNLOCK_MS: u64 = 250;
+const MAX_UNLOCK_MS: u64 = 10_000;
+
+/// The vault master key; zeroed when dropped.
+pub type MasterKey = Zeroizing<[u8; 32]>;
+
+/// Master key wrappings, each sealed with `vault::seal`.
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct KeyFile {
+    version: u32,
+    pin: String,
+    recovery: Option<String>,
+    /// Argon2 costs for the PIN wrapping, calibrated when the vault was set up.
+    /// The recovery key is random enough for the defaults.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    kdf: Option<KdfParams>,
+    /// New master key of an unfinished rotation, sealed with the current one.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pending: Option<String>,
+    created_at: i64,
+}
+
+impl KeyFile {
+    fn load(dir: &Path) -> Result<Option<Self>, String> {
+        let path = dir.join(KEY_FILE);
+        if !path.exists() {
+            return Ok(None);
+        }
+        let data = std::fs::read(&path).map_err(|e| e.to_string())?;
+        serde_json::from_slice(&data)
+            .map(Some)
+            .map_err(|e| format!("Corrupted vault key file: {}", e))
+    }
+
+    fn save(&self, dir: &Path) -> Result<(), String> {
+        let data = serde_json::to_vec_pretty(self).map_err(|e| e.to_string())?;
+        vault::write_atomically(&dir.join(KEY_FILE), |writer| {
+            std::io::Write::write_all(writer, &data).map_err(|e| e.to_string())
+        })
+    }
+
+    fn wrap(secret: &str, key: &[u8; 32], params: KdfParams) -> Result<String, String> {
+        Ok(BASE64.encode(vault::seal_with(secret, key, params)?))
+    }
+
+    fn pin_kdf(&self) -> KdfParams {
+        self.kdf.unwrap_or(KdfParams::DEFAULT)
+    }
+
+    fn unwrap(secret: &str, wrapped: &str, error: &str) -> Result<MasterKey, String> {
+        let sealed = BASE64.decode(wrapped).map_err(|_| "Corrupted vault key file")?;
+        let key = Zeroizing::new(vault::open(secret, &sealed).map_err(|_| error.to_string())?);
+        let mut master = Zeroizing::new([0u8; 32]);
+        if key.len() != master.len() {
+            return Err("Corrupted vault key file".into());
+        }
+        master.copy_from_slice(&key);
+        Ok(master)
+    }
+
+    /// Finishes or drops a rotation that was cut short, returning the key the
+    /// vault is under now. `pin` is what the key gets wrapped with.
+    fn settle_rotation(&mut self, dir: &Path, key: MasterKey, pin: &str) -> Result<MasterKey, String> {
+        let Some(pending) = self.pending.take() else {
+            return Ok(key);
+        };
+        let sealed = BASE64.decode(pending).map_err(|_| "Corrupted vault key file")?;
+        let opened = Zeroizing::new(vault::open_with_key(&key, ROTATION_AAD, &sealed)?);
+        let mut next = Zeroizing::new([0u8; 32]);
+        if opened.len() != next.len() {
+            return Err("Corrupted vault key file".into());
+        }
+        next.copy_from_slice(&opened);
+
+        let key = if manifest::opens_with(dir, &next)? {
+            println!("Finishing interrupted vault key rotation");
+            self.pin = Self::wrap(pin, &next, self.pin_kdf())?;
+            self.recovery = None;
+            next
+        } else {
+            println!("Rolling back interrupted vault key rotation");
+            key
+        };
+        self.save(dir)?;
+        Ok(key)
+    }
+}
+
+/// Recovery keys are shown as dash-separated groups; only the hex digits count.
+fn normalize_recovery_key(recovery_key: &str) -> Zeroizing<String> {
+    Zeroizing::new(
+        recovery_key
+            .chars()
+            .filter(|c| c.is_ascii_hexdigit())
+            .map(|c| c.to_ascii_lowercase())
+            .collect(),
+    )
+}
+
+struct Session {
+    key: MasterKey,
+    last_used: Instant,
+    auto_lock: Duration,
+    /// Tells a stale auto-lock timer from the one of the current session.
+    generation: u64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct VaultStatus {
+    /// False until the first unlock creates the master key.
+    pub initialized: bool,
+    pub unlocked: bool,
+    pub has_recovery_key: bool,
+    pub auto_lock_secs: Option<u64>,
+    /// Argon2 costs of the PIN.
+    pub kdf: Option<KdfParams>,
+    /// How the PIN rates today; only known right after unlocking.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub pin_rating: Option<PinRating>,
+    /// Files still sealed with a PIN from before the master key.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub legacy_files: Option<usize>,
+}
+
+#[derive(Clone)]
+pub struct VaultState {
+    session: Arc<Mutex<Option<Session>>>,
+}
+
+impl VaultState {
+    pub fn new() -> Self {
+        Self {
+            session: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Master key of the unlocked vault. Counts as activity for auto-lock.
+    pub fn key(&self) -> Result<MasterKey, String> {
+        let mut guard = self.session.lock().map_err(|e| e.to_string())?;
+        let session = guard.as_mut().ok_or("Vault is locked")?;
+        if session.last_used.elapsed() >= session.auto_lock {
+            *guard = None;
+            return Err("Vault is locked".into());
+        }
+        session.last_used = Instant::now();
+        Ok(session.key.clone())
+    }
+
+    /// Swaps the key of the running session, keeping its auto-lock timer.
+    fn replace_key(&self, key: MasterKey) -> Result<(), String> {
+        let mut guard = self.session.lock().map_err(|e| e.to_string())?;
+        let session = guard.as_mut().ok_or("Vault is locked")?;
+        session.key = key;
+        session.last_used = Instant::now();
+        Ok(())
+    }
+
+    /// Drops the session key. Returns false if the vault wasn't unlocked.
+    fn lock(&self) -> Result<bool, String> {
+        Ok(self.session.lock().map_err(|e| e.to_string())?.take().is_some())
+    }
+
+    fn status(&self, dir: &Path) -> Result<VaultStatus, String> {
+        let key_file = KeyFile::load(dir)?;
+        let guard = self.session.lock().map_err(|e| e.to_string())?;
+        let session = guard.as_ref().filter(|s| s.last_used.elapsed() < s.auto_lock);
+        Ok(VaultStatus {
+            initialized: key_file.is_some(),
+            unlocked: session.is_some(),
+            has_recovery_key: key_file.as_ref().is_some_and(|f| f.recovery.is_some()),
+            auto_lock_secs: session.map(|s| s.auto_lock.as_secs()),
+            kdf: key_file.as_ref().map(KeyFile::pin_kdf),
+            pin_rating: None,
+            legacy_files: None,
+        })
+    }
+
+    /// Starts a session with `key` and a timer that locks it once idle.
+    fn start(&self, app: AppHandle, key: MasterKey, auto_lock_secs: Option<u64>) -> Result<(), String> {
+        let auto_lock = Duration::from_secs(
+            auto_lock_secs
+                .unwrap_or(DEFAULT_AUTO_LOCK_SECS)
+                .clamp(MIN_AUTO_LOCK_SECS, MAX_AUTO_LOCK_SECS),
+        );
+        let generation = rand::random::<u64>();
+        *self.session.lock().map_err(|e| e.to_string())? = Some(Session {
+            key,
+            last_used: Instant::now(),
+            auto_lock,
+            generation,
+        });
+
+        let session = self.session.clone();
+        tauri::async_runtime::spawn(async move {
+            let mut wait = auto_lock;
+            loop {
+                tokio::time::sleep(wait).await;
+                let Ok(mut guard) = session.lock() else {
+                    return;
+                };
+                let Some(current) = guard.as_ref().filter(|s| s.generation == generation) else {
+                    // Locked by hand or unlocked again since
+                    return;
+                };
+                let idle = current.last_used.elapsed();
+                if idle < current.auto_lock {
+                    wait = current.auto_lock - idle;
+                    continue;
+                }
+                *guard = None;
+                drop(guard);
+                println!("Vault locked after {}s idle", auto_lock.as_secs());
+                let _ = app.emit("vault-locked", serde_json::json!({ "reason": "timeout" }));
+                return;
+            }
+        });
+        Ok(())
+    }
+}
+
+/// Rewraps the master key with `pin` if its wrapping predates the versioned
+/// header. The recovery wrapping stays as it is until a new recovery key.
+pub(crate) fn upgrade_key_file(dir: &Path, pin: &str) -> Result<bool, String> {
+    let Some(mut file) = KeyFile::load(dir)? else {
+        return Ok(false);
+    };
+    let sealed = BASE64.decode(&file.pin).map_err(|_| "Corrupted vault key file")?;
+    if vault::is_current(&sealed) {
+        return Ok(false);
+    }
+    let key = KeyFile::unwrap(pin, &file.pin, "Incorrect PIN")?;
+    file.pin = KeyFile::wrap(pin, &key, file.pin_kdf())?;
+    file.save(dir)?;
+    Ok(true)
+}
+
+/// Creates the master key on first unlock, wrapped with `pin` under Argon2
+/// costs calibrated for this machine.
+fn initialize(dir: &Path, pin: &str) -> Result<MasterKey, String> {
+    let key = Zeroizing::new(rand::random::<[u8; 32]>());
+    let params = KdfParams::calibrate(Duration::from_millis(DEFAULT_UNLOCK_MS))?;
+    KeyFile {
+        version: KEY_FILE_VERSION,
+        pin: KeyFile::wrap(pin, &key, params)?,
+        recovery: None,
+        kdf: Some(params),
+        pending: None,
+        created_at: chrono::Utc::now().timestamp(),
+    }
+    .save(dir)?;
+    Ok(key)
+}
+
+#[tauri::command]
+pub async fn vault_status(app: AppHandle, state: State<'_, VaultState>) -> Result<VaultStatus, String> {
+    state.status(&manifest::vault_dir(&app)?)
+}
+
+/// Unlocks the vault for a session. The first unlock sets the PIN and moves
+/// files sealed with it under the new master key.
+#[tauri::command]
+pub async fn unlock_vault(
+    app: AppHandle,
+    state: State<'_, VaultState>,
+    pin: String,
+    auto_lock_secs: Option<u64>,
+) -> Result<VaultStatus, String> {
+    let pin = Zeroizing::new(pin);
+    if pin.is_empty() {
+        return Err("Enter a PIN".into());
+    }
+    let dir = manifest::vault_dir(&app)?;
+
+    let unlock_dir = dir.clone();
+    let pin_rating = pin_strength::assess(&pin).rating;
+    let (key, legacy_files) = tauri::async_runtime::spawn_blocking(move || {
+        let key = match KeyFile::load(&unlock_dir)? {
+            Some(mut file) => {
+                let key = KeyFile::unwrap(&pin, &file.pin, "Incorrect PIN")?;
+                file.settle_rotation(&unlock_dir, key, &pin)?
+            }
+            None => {
+                // A vault from before the master key keeps its PIN, weak or not
+                if !manifest::check_legacy_pin(&unlock_dir, &pin)? {
+                    pin_strength::check(&pin)?;
+                }
+                initialize(&unlock_dir, &pin)?
+            }
+        };
+        let legacy_files = manifest::migrate_legacy(&unlock_dir, &pin, &key)?;
+        Ok::<_, String>((key, legacy_files))
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+
+    state.start(app, key, auto_lock_secs)?;
+    let mut status = state.status(&dir)?;
+    status.pin_rating = Some(pin_rating);
+    status.legacy_files = Some(legacy_files);
+    Ok(status)
+}
+
+#[tauri::command]
+pub async fn lock_vault(app: AppHandle, state: State<'_, VaultState>) -> Result<bool, String> {
+    let locked = state.lock()?;
+    if locked {
+        let _ = app.emit("vault-locked", serde_json::json!({ "reason": "manual" }));
+    }
+    Ok(locked)
+}
+
+/// Creates a recovery key for the unlocked vault, replacing any previous
+/// one. It is only ever returned here, so the user has to write it down.
+#[tauri::command]
+pub async fn create_recovery_key(app: AppHandle, state: State<'_, VaultState>) -> Result<String, String> {
+    let key = state.key()?;
+    let dir = manifest::vault_dir(&app)?;
+
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut file = KeyFile::load(&dir)?.ok_or("Vault is not initialized")?;
+        let secret = rand::random::<[u8; 20]>();
+        let hex = Zeroizing::new(secret.iter().map(|b| format!("{:02x}", b)).collect::<String>());
+        file.recovery = Some(KeyFile::wrap(&hex, &key, KdfParams::DEFAULT)?);
+        file.save(&dir)?;
+
+        let groups: Vec<&str> = hex
+            .as_bytes()
+            .chunks(5)
+            .map(|chunk| std::str::from_utf8(chunk).expect("hex is ascii"))
+            .collect();
+        Ok(groups.join("-").to_uppercase())
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// Unlocks the vault with the recovery key and sets a new PIN.
+#[tauri::command]
+pub async fn recover_vault(
+    app: AppHandle,
+    state: State<'_, VaultState>,
+    recovery_key: String,
+    new_pin: String,
+    auto_lock_secs: Option<u64>,
+) -> Result<VaultStatus, String> {
+    let recovery_key = normalize_recovery_key(&recovery_key);
+    let new_pin = Zeroizing::new(new_pin);
+    pin_strength::check(&new_pin)?;
+    let dir = manifest::vault_dir(&app)?;
+
+    let recover_dir = dir.clone();
+    let key = tauri::async_runtime::spawn_blocking(move || {
+        let mut file = KeyFile::load(&recover_dir)?.ok_or("Vault is not initialized")?;
+        let wrapped = file.recovery.as_deref().ok_or("No recovery key was set up")?;
+        let key = KeyFile::unwrap(&recovery_key, wrapped, "Incorrect recovery key")?;
+        let key = file.settle_rotation(&recover_dir, key, &new_pin)?;
+        file.pin = KeyFile::wrap(&new_pin, &key, file.pin_kdf())?;
+        file.save(&recover_dir)?;
+        Ok::<_, String>(key)
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+
+    state.start(app, key, auto_lock_secs)?;
+    state.status(&dir)
+}
+
+/// Changes the PIN. Only the master key is rewrapped; no file is touched.
+#[tauri::command]
+pub async fn change_vault_pin(app: AppHandle, old_pin: String, new_pin: String) -> Result<(), String> {
+    let old_pin = Zeroizing::new(old_pin);
+    let new_pin = Zeroizing::new(new_pin);
+    pin_strength::check(&new_pin)?;
+    let dir = manifest::vault_dir(&app)?;
+
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut file = KeyFile::load(&dir)?.ok_or("Vault is not initialized")?;
+        let key = KeyFile::unwrap(&old_pin, &file.pin, "Incorrect PIN")?;
+        file.pin = KeyFile::wrap(&new_pin, &key, file.pin_kdf())?;
+        file.save(&dir)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}
+
+/// Replaces the master key of the unlocked vault. Data keys are rewrapped;
+/// payloads stay as they are. The recovery key belongs to the old master key,
+/// so it is dropped and has to be created again.
+#[tauri::command]
+pub async fn rotate_vault_key(app: AppHandle, state: State<'_, VaultState>, pin: String) -> Result<VaultStatus, String> {
+    let pin = Zeroizing::new(pin);
+    let current = state.key()?;
+    let dir = manifest::vault_dir(&app)?;
+
+    let rotate_dir = dir.clone();
+    let session = state.inner().clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut file = KeyFile::load(&rotate_dir)?.ok_or("Vault is not initialized")?;
+        let key = KeyFile::unwrap(&pin, &file.pin, "Incorrect PIN")?;
+        if *key != *current {
+            return Err("Vault key changed during the session; unlock again".to_string());
+        }
+
+        // 1. Record the new key, so a crash below can be settled at unlock
+        let next: MasterKey = Zeroizing::new(rand::random());
+        file.pending = Some(BASE64.encode(vault::seal_with_key(&key, ROTATION_AAD, next.as_slice())?));
+        file.save(&rotate_dir)?;
+
+        // 2. Move the manifest over, then make the new key the only one
+        manifest::rotate(&rotate_dir, &key, &next, || {
+            session.replace_key(next.clone())?;
+            file.pin = KeyFile::wrap(&pin, &next, file.pin_kdf())?;
+            file.recovery = None;
+            file.pending = None;
+            file.save(&rotate_dir)
+        })
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+
+    println!("Vault key rotated");
+    state.status(&dir)
+}
+
+/// Rewraps the master key with new Argon2 costs for the PIN: `params` if
+/// given, otherwise calibrated to take about `target_ms` on this machine.
+#[tauri::command]
+pub async fn tune_vault_kdf(
+    app: AppHandle,
+    pin: String,
+    params: Option<KdfParams>,
+    target_ms: Option<u64>,
+) -> Result<KdfParams, String> {
+    let pin = Zeroizing::new(pin);
+    if let Some(params) = &params {
+        params.check_minimum()?;
+    }
+    let target = Duration::from_millis(target_ms.unwrap_or(DEFAULT_UNLOCK_MS).clamp(MIN_UNLOCK_MS, MAX_UNLOCK_MS));
+    let dir = manifest::vault_dir(&app)?;
+
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut file = KeyFile::load(&dir)?.ok_or("Vault is not initialized")?;
+        let key = KeyFile::unwrap(&pin, &file.pin, "Incorrect PIN")?;
+        let params = match params {
+            Some(params) => params,
+            None => KdfParams::calibrate(target)?,
+        };
+        file.pin = KeyFile::wrap(&pin, &key, params)?;
+        file.kdf = Some(params);
+        file.save(&dir)?;
+        Ok(params)
+    })
+    .await
+    .map_err(|e| e.to_string())?
+}